@@ -0,0 +1,277 @@
+//! Client of the ai00 server: typed requests and responses of the OpenAI compatible APIs,
+//! streams of chunks parsed from the server-sent events, and retries with backoff.
+//!
+//! ```no_run
+//! use ai00_client::{ChatMessage, ChatRequest, Client};
+//! use futures_util::StreamExt;
+//!
+//! async fn chat() -> anyhow::Result<()> {
+//!     let client = Client::new("http://localhost:65530");
+//!     let request = ChatRequest::new([ChatMessage::user("Tell me about water.")]);
+//!
+//!     let response = client.chat(&request).await?;
+//!     println!("{}", response.choices[0].message.content);
+//!
+//!     let mut stream = client.chat_stream(&request).await?;
+//!     while let Some(chunk) = stream.next().await {
+//!         if let Some(content) = &chunk?.choices[0].delta.content {
+//!             print!("{content}");
+//!         }
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use std::time::Duration;
+
+use anyhow::Result;
+use futures_util::{stream::BoxStream, StreamExt};
+use reqwest::{header::RETRY_AFTER, Method, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+
+mod sse;
+pub mod types;
+
+pub use types::*;
+
+/// An error response of the server.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+    /// The kind of a failed generation, e.g., `context_length_exceeded`, if the server tells it.
+    pub code: Option<String>,
+}
+
+impl ApiError {
+    /// Read the error from the body, which is either plain text or an OpenAI error object.
+    fn new(status: StatusCode, body: String) -> Self {
+        #[derive(serde::Deserialize)]
+        struct ErrorBody {
+            message: String,
+            code: Option<String>,
+        }
+        #[derive(serde::Deserialize)]
+        struct ErrorResponse {
+            error: ErrorBody,
+        }
+
+        match serde_json::from_str::<ErrorResponse>(&body) {
+            Ok(ErrorResponse { error }) => Self {
+                status,
+                message: error.message,
+                code: error.code,
+            },
+            Err(_) => Self {
+                status,
+                message: body,
+                code: None,
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.message.as_str() {
+            "" => write!(f, "server responded {}", self.status),
+            message => write!(f, "server responded {}: {message}", self.status),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// How failed requests are retried: connection failures, timeouts, `429` and `502` to `504`.
+/// Streams are only retried until they start.
+#[derive(Debug, Clone)]
+pub struct RetryOption {
+    /// Number of retries after the first attempt. `0` never retries.
+    pub max_retries: usize,
+    /// Wait before the first retry, doubled after each one.
+    pub initial_backoff: Duration,
+    /// Longest wait between two attempts, including the ones the server asks for with `Retry-After`.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryOption {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(8),
+        }
+    }
+}
+
+/// Client of one server. Cheap to clone; clones share the connection pool.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    token: Option<String>,
+    retry: RetryOption,
+}
+
+impl Client {
+    /// Create a client of the server at `base_url`, e.g., `http://localhost:65530`,
+    /// including the `base_path` of the server if it has one.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self::with_http(reqwest::Client::new(), base_url)
+    }
+
+    /// Create a client that sends requests with the given http client, e.g., one with timeouts or proxies.
+    pub fn with_http(http: reqwest::Client, base_url: impl Into<String>) -> Self {
+        let base_url = base_url.into().trim_end_matches('/').to_owned();
+        Self {
+            http,
+            base_url,
+            token: None,
+            retry: Default::default(),
+        }
+    }
+
+    /// Authenticate with a token, e.g., one from [`Client::exchange`] or an OIDC provider.
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryOption) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Exchange an app id and its secret for a token.
+    pub async fn exchange(&self, app_id: &str, app_secret: &str) -> Result<String> {
+        #[derive(serde::Deserialize)]
+        struct AuthResponse {
+            token: Option<String>,
+            message: Option<String>,
+        }
+
+        let body = serde_json::json!({ "app_id": app_id, "app_secret": app_secret });
+        let response = self.send(Method::POST, "/api/auth/exchange", &body).await?;
+        let response: AuthResponse = response.json().await?;
+        response
+            .token
+            .ok_or_else(|| anyhow::anyhow!(response.message.unwrap_or_default()))
+    }
+
+    pub async fn models(&self) -> Result<ModelList> {
+        let response = self
+            .send(Method::GET, "/api/oai/v1/models", &Option::<()>::None)
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse> {
+        self.post("/api/oai/v1/chat/completions", request).await
+    }
+
+    pub async fn chat_stream(
+        &self,
+        request: &ChatRequest,
+    ) -> Result<BoxStream<'static, Result<ChatChunk>>> {
+        self.stream("/api/oai/v1/chat/completions", request).await
+    }
+
+    pub async fn complete(&self, request: &CompletionRequest) -> Result<CompletionResponse> {
+        self.post("/api/oai/v1/completions", request).await
+    }
+
+    pub async fn complete_stream(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<BoxStream<'static, Result<CompletionChunk>>> {
+        self.stream("/api/oai/v1/completions", request).await
+    }
+
+    pub async fn embed(&self, request: &EmbeddingRequest) -> Result<EmbeddingResponse> {
+        self.post("/api/oai/v1/embeddings", request).await
+    }
+
+    async fn post<T: DeserializeOwned>(&self, path: &str, request: &impl Serialize) -> Result<T> {
+        let body = Self::body(request, false)?;
+        let response = self.send(Method::POST, path, &body).await?;
+        Ok(response.json().await?)
+    }
+
+    async fn stream<T: DeserializeOwned + Send + 'static>(
+        &self,
+        path: &str,
+        request: &impl Serialize,
+    ) -> Result<BoxStream<'static, Result<T>>> {
+        let body = Self::body(request, true)?;
+        let response = self.send(Method::POST, path, &body).await?;
+        let chunks = sse::events(response)
+            .map(|data| data.and_then(|data| Ok(serde_json::from_str::<T>(&data)?)));
+        Ok(chunks.boxed())
+    }
+
+    fn body(request: &impl Serialize, stream: bool) -> Result<serde_json::Value> {
+        let mut body = serde_json::to_value(request)?;
+        body["stream"] = stream.into();
+        Ok(body)
+    }
+
+    /// Whether a failed attempt is worth another one.
+    fn retryable(status: StatusCode) -> bool {
+        matches!(
+            status,
+            StatusCode::TOO_MANY_REQUESTS
+                | StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT
+        )
+    }
+
+    /// Send a request, retrying as the [`RetryOption`] says. Error statuses turn into [`ApiError`]s.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: &impl Serialize,
+    ) -> Result<reqwest::Response> {
+        let url = format!("{}{path}", self.base_url);
+        let mut backoff = self.retry.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            let mut request = self.http.request(method.clone(), &url);
+            if method != Method::GET {
+                request = request.json(body);
+            }
+            if let Some(token) = &self.token {
+                request = request.bearer_auth(token);
+            }
+            attempt += 1;
+            let retries = attempt <= self.retry.max_retries;
+
+            let wait = match request.send().await {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) if retries && Self::retryable(response.status()) => {
+                    log::warn!("{url} responded {}, retrying", response.status());
+                    response
+                        .headers()
+                        .get(RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.parse().ok())
+                        .map(Duration::from_secs)
+                        .unwrap_or(backoff)
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    let body = response.text().await.unwrap_or_default();
+                    return Err(ApiError::new(status, body).into());
+                }
+                Err(err) if retries && (err.is_connect() || err.is_timeout()) => {
+                    log::warn!("failed to reach {url}, retrying: {err}");
+                    backoff
+                }
+                Err(err) => return Err(err.into()),
+            };
+            tokio::time::sleep(wait.min(self.retry.max_backoff)).await;
+            backoff = (backoff * 2).min(self.retry.max_backoff);
+        }
+    }
+}
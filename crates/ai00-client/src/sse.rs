@@ -0,0 +1,77 @@
+//! Parsing of server-sent events, as far as the server uses them: `data` fields, ended by `[DONE]`.
+
+use anyhow::Result;
+use futures_util::{stream::BoxStream, Stream, StreamExt};
+
+/// The data that ends a stream.
+const DONE: &str = "[DONE]";
+
+struct Parser {
+    body: BoxStream<'static, reqwest::Result<bytes::Bytes>>,
+    buffer: Vec<u8>,
+    data: Vec<String>,
+    finished: bool,
+}
+
+impl Parser {
+    /// Take the data of the event if a blank line ends it.
+    fn line(&mut self, line: &str) -> Option<String> {
+        if line.is_empty() {
+            return (!self.data.is_empty()).then(|| {
+                let data = self.data.join("\n");
+                self.data.clear();
+                data
+            });
+        }
+        // other fields and comments carry nothing the client needs
+        if let Some(value) = line.strip_prefix("data:") {
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            self.data.push(value.to_owned());
+        }
+        None
+    }
+
+    async fn next(&mut self) -> Option<Result<String>> {
+        while !self.finished {
+            while let Some(end) = self.buffer.iter().position(|&x| x == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim_end_matches(['\n', '\r']);
+                if let Some(data) = self.line(line) {
+                    return Some(Ok(data));
+                }
+            }
+            match self.body.next().await {
+                Some(Ok(bytes)) => self.buffer.extend_from_slice(&bytes),
+                Some(Err(err)) => {
+                    self.finished = true;
+                    return Some(Err(err.into()));
+                }
+                None => {
+                    // the last event may miss its blank line
+                    self.finished = true;
+                    let rest = String::from_utf8_lossy(&self.buffer).into_owned();
+                    self.line(&rest);
+                    return self.line("").map(Ok);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The data of the events in the body of a response, up to `[DONE]`.
+pub fn events(response: reqwest::Response) -> impl Stream<Item = Result<String>> {
+    let parser = Parser {
+        body: response.bytes_stream().boxed(),
+        buffer: vec![],
+        data: vec![],
+        finished: false,
+    };
+    futures_util::stream::unfold(parser, |mut parser| async move {
+        match parser.next().await? {
+            Ok(data) if data == DONE => None,
+            item => Some((item, parser)),
+        }
+    })
+}
@@ -0,0 +1,334 @@
+//! Requests and responses of the OpenAI compatible APIs, as the server speaks them.
+//!
+//! Requests carry the common options as fields; any other option of the server goes into `extra`,
+//! e.g., `bnf_schema`, `reasoning` or `cjk`.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    #[serde(alias = "System")]
+    System,
+    #[serde(alias = "User")]
+    User,
+    #[serde(alias = "Assistant")]
+    Assistant,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+    /// Reasoning of the model, if delivered apart from the content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
+    /// On the last message of the assistant: the model continues this text instead of starting a new reply.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub prefix: bool,
+}
+
+impl ChatMessage {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new(Role::System, content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new(Role::User, content)
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new(Role::Assistant, content)
+    }
+}
+
+/// The sampler options shared by chats and completions. Options left out take the defaults of the server.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct SamplerOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub penalty_decay: Option<f32>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ChatRequest {
+    pub messages: Vec<ChatMessage>,
+    /// Output token limit. 256 if not given, cut down to the room the prompt leaves in the context.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    /// Stop sequences. The server stops at `"\n\n"` if not given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    /// Number of choices to generate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<usize>,
+    #[serde(flatten)]
+    pub sampler: SamplerOptions,
+    /// Other options of the server.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl ChatRequest {
+    pub fn new(messages: impl IntoIterator<Item = ChatMessage>) -> Self {
+        Self {
+            messages: messages.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct CompletionRequest {
+    pub prompt: String,
+    /// Output token limit. 256 if not given, cut down to the room the prompt leaves in the context.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    /// Number of choices to generate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<usize>,
+    #[serde(flatten)]
+    pub sampler: SamplerOptions,
+    /// Other options of the server.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl CompletionRequest {
+    pub fn new(prompt: impl Into<String>) -> Self {
+        Self {
+            prompt: prompt.into(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct EmbeddingRequest {
+    pub input: Vec<String>,
+    /// Length the embedding is truncated to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<usize>,
+    /// Other options of the server.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl EmbeddingRequest {
+    pub fn new(input: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            input: input.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    Stop,
+    Length,
+    ContentFilter,
+    ToolCalls,
+    /// Generation was interrupted before it could finish, e.g., the model was unloaded.
+    Abort,
+    /// The generation is still in progress.
+    #[default]
+    #[serde(other)]
+    Null,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct Usage {
+    #[serde(alias = "prompt_tokens")]
+    pub prompt: usize,
+    #[serde(alias = "completion_tokens")]
+    pub completion: usize,
+    #[serde(alias = "total_tokens")]
+    pub total: usize,
+    pub duration: Duration,
+}
+
+/// Latency measurements, present if the request asks for `timings`.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct Timings {
+    pub queue: Duration,
+    pub first_token: Option<Duration>,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatChoice {
+    pub message: ChatMessage,
+    pub index: usize,
+    #[serde(default, deserialize_with = "nullable")]
+    pub finish_reason: FinishReason,
+    #[serde(default)]
+    pub stop_sequence: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatResponse {
+    pub model: String,
+    pub choices: Vec<ChatChoice>,
+    #[serde(default)]
+    pub usage: Usage,
+    #[serde(default)]
+    pub timings: Option<Timings>,
+    /// Whether the response is served from the cache.
+    #[serde(default)]
+    pub cached: bool,
+    /// Questions the user may ask next, if the request asks for `suggestions`.
+    #[serde(default)]
+    pub suggestions: Vec<String>,
+}
+
+/// What a chunk of a chat stream adds to a choice. Only one of the fields is set at a time.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct ChatDelta {
+    pub role: Option<Role>,
+    pub content: Option<String>,
+    pub reasoning_content: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatChunkChoice {
+    #[serde(default, deserialize_with = "empty_or")]
+    pub delta: ChatDelta,
+    pub index: usize,
+    #[serde(default, deserialize_with = "nullable")]
+    pub finish_reason: FinishReason,
+    #[serde(default)]
+    pub stop_sequence: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatChunk {
+    pub model: String,
+    pub choices: Vec<ChatChunkChoice>,
+    #[serde(default)]
+    pub timings: Option<Timings>,
+    /// Questions the user may ask next, in the last chunk if the request asks for `suggestions`.
+    #[serde(default)]
+    pub suggestions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompletionChoice {
+    pub text: String,
+    pub index: usize,
+    #[serde(default, deserialize_with = "nullable")]
+    pub finish_reason: FinishReason,
+    #[serde(default)]
+    pub stop_sequence: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompletionResponse {
+    pub model: String,
+    pub choices: Vec<CompletionChoice>,
+    #[serde(default)]
+    pub usage: Usage,
+    #[serde(default)]
+    pub timings: Option<Timings>,
+    /// Whether the response is served from the cache.
+    #[serde(default)]
+    pub cached: bool,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct CompletionDelta {
+    pub content: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompletionChunkChoice {
+    #[serde(default, deserialize_with = "empty_or")]
+    pub delta: CompletionDelta,
+    pub index: usize,
+    #[serde(default, deserialize_with = "nullable")]
+    pub finish_reason: FinishReason,
+    #[serde(default)]
+    pub stop_sequence: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompletionChunk {
+    pub model: String,
+    pub choices: Vec<CompletionChunkChoice>,
+    #[serde(default)]
+    pub timings: Option<Timings>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Embedding {
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EmbeddingResponse {
+    pub model: String,
+    pub data: Vec<Embedding>,
+    #[serde(default)]
+    pub usage: Usage,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Model {
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelList {
+    pub data: Vec<Model>,
+}
+
+/// The server sends `null` for a finish reason in progress.
+fn nullable<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// The server sends an empty string for a chunk that adds nothing.
+fn empty_or<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: serde::de::DeserializeOwned + Default,
+{
+    match Value::deserialize(deserializer)? {
+        Value::String(text) if text.is_empty() => Ok(T::default()),
+        Value::Null => Ok(T::default()),
+        value => serde_json::from_value(value).map_err(serde::de::Error::custom),
+    }
+}
@@ -0,0 +1,311 @@
+//! High-level API to embed the inference engine directly, without running the HTTP server.
+//!
+//! ```no_run
+//! use ai00_core::{engine::Engine, GenerateRequest, ReloadRequest, Token};
+//! use futures_util::StreamExt;
+//!
+//! async fn run() -> anyhow::Result<()> {
+//!     let engine = Engine::load(ReloadRequest {
+//!         model_path: "assets/models/RWKV-x060-World-3B-v2.1-20240417-ctx4096.st".into(),
+//!         tokenizer_path: "assets/tokenizer/rwkv_vocab_v20230424.json".into(),
+//!         ..Default::default()
+//!     })
+//!     .await?;
+//!
+//!     let request = GenerateRequest {
+//!         prompt: "User: Hi!\n\nAssistant:".into(),
+//!         max_tokens: 100,
+//!         stop: vec!["\n\n".into()],
+//!         ..Default::default()
+//!     };
+//!     let mut stream = engine.generate_stream(request).await?;
+//!     while let Some(token) = stream.next().await {
+//!         if let Token::Content(content) = token {
+//!             print!("{content}");
+//!         }
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::{anyhow, bail, Result};
+use derivative::Derivative;
+use flume::{r#async::RecvStream, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use web_rwkv::tokenizer::Tokenizer;
+
+use crate::{
+    model_route, reload,
+    run::StateId,
+    sampler::{
+        dry::DryParams,
+        nucleus::{NucleusParams, NucleusSampler},
+        xtc::XtcParams,
+    },
+    FinishReason, GenerateRequest, ReloadRequest, RuntimeEvent, RuntimeInfo, RuntimeStats,
+    SaveRequest, StateSaveRequest, ThreadRequest, Token, TokenCounter,
+};
+
+/// The options of a generation that can be written down, e.g., in JSON by the bindings.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct GenerateOption {
+    pub prompt: String,
+    #[derivative(Default(value = "256"))]
+    pub max_tokens: usize,
+    pub stop: Vec<String>,
+    /// Initial state to start from.
+    pub state: StateId,
+    #[serde(flatten)]
+    pub sampler: NucleusParams,
+    #[serde(flatten)]
+    pub dry: DryParams,
+    #[serde(flatten)]
+    pub xtc: XtcParams,
+    pub top_n_sigma: f32,
+}
+
+impl From<GenerateOption> for GenerateRequest {
+    fn from(value: GenerateOption) -> Self {
+        let GenerateOption {
+            prompt,
+            max_tokens,
+            stop,
+            state,
+            sampler,
+            dry,
+            xtc,
+            top_n_sigma,
+        } = value;
+        Self {
+            prompt,
+            max_tokens,
+            stop,
+            state,
+            sampler: Arc::new(RwLock::new(NucleusSampler::new(sampler))),
+            dry,
+            xtc,
+            top_n_sigma,
+            ..Default::default()
+        }
+    }
+}
+
+/// The output of a finished generation.
+#[derive(Debug, Default, Clone)]
+pub struct Generation {
+    pub text: String,
+    pub finish_reason: FinishReason,
+    /// The stop sequence that ended the generation, if any.
+    pub stop_sequence: Option<String>,
+    pub counter: TokenCounter,
+}
+
+/// Handle of a running engine. Cheap to clone; clones drive the same runtime,
+/// which exits once all of them are dropped.
+#[derive(Debug, Clone)]
+pub struct Engine {
+    sender: Sender<ThreadRequest>,
+}
+
+impl Engine {
+    /// Start an engine without a model. Must be called within a tokio runtime.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let (sender, receiver) = flume::unbounded();
+        tokio::spawn(model_route(receiver));
+        Self { sender }
+    }
+
+    /// Start an engine and load a model into it.
+    pub async fn load(request: ReloadRequest) -> Result<Self> {
+        let engine = Self::new();
+        engine.reload(request).await?;
+        Ok(engine)
+    }
+
+    /// The channel to the runtime, for requests this API does not cover.
+    pub fn sender(&self) -> Sender<ThreadRequest> {
+        self.sender.clone()
+    }
+
+    /// Replace the model. Generations keep running on the current model until the new one is ready.
+    pub async fn reload(&self, request: ReloadRequest) -> Result<()> {
+        let (sender, receiver) = flume::bounded(1);
+        self.send(ThreadRequest::Reload {
+            request: Box::new(request),
+            sender: Some(sender),
+        })?;
+        match receiver.recv_async().await? {
+            true => Ok(()),
+            false => bail!("failed to load the model"),
+        }
+    }
+
+    pub fn unload(&self) -> Result<()> {
+        self.send(ThreadRequest::Unload)
+    }
+
+    /// Save the model as loaded, i.e., with quantization and LoRA applied.
+    pub async fn save(&self, request: SaveRequest) -> Result<()> {
+        let (sender, receiver) = flume::bounded(1);
+        self.send(ThreadRequest::Save { request, sender })?;
+        match receiver.recv_async().await {
+            Ok(true) => Ok(()),
+            Ok(false) => bail!("failed to save the model"),
+            Err(_) => bail!("runtime not loaded"),
+        }
+    }
+
+    /// Load an initial state from a file. Returns its id, to start generations from.
+    pub async fn load_state(&self, state: reload::State) -> Result<StateId> {
+        let id = state.id;
+        let (sender, receiver) = flume::bounded(1);
+        self.send(ThreadRequest::StateLoad {
+            request: state,
+            sender: Some(sender),
+        })?;
+        match receiver.recv_async().await? {
+            true => Ok(id),
+            false => bail!("failed to load the state"),
+        }
+    }
+
+    /// Read the `prompt` from the initial state `state`, and save where it ends up as an initial state.
+    /// Only the time states are saved, which is what the files of initial states hold.
+    pub async fn save_state(
+        &self,
+        prompt: impl Into<String>,
+        state: StateId,
+        path: impl Into<PathBuf>,
+    ) -> Result<()> {
+        let prompt = prompt.into();
+        let tokens = self.tokenizer().await?.encode(prompt.as_bytes())?;
+        if tokens.is_empty() {
+            bail!("nothing to read into the state");
+        }
+        // the state after the prompt is cached once the generation completes
+        let request = GenerateRequest {
+            prompt,
+            max_tokens: 1,
+            state,
+            ..Default::default()
+        };
+        self.generate(request).await?;
+
+        let request = StateSaveRequest {
+            path: path.into(),
+            tokens,
+            state,
+        };
+        let (sender, receiver) = flume::bounded(1);
+        self.send(ThreadRequest::StateSave { request, sender })?;
+        match receiver.recv_async().await {
+            Ok(true) => Ok(()),
+            Ok(false) => bail!("failed to save the state"),
+            Err(_) => bail!("runtime not loaded"),
+        }
+    }
+
+    pub fn unload_state(&self, id: StateId) -> Result<()> {
+        self.send(ThreadRequest::StateUnload(id))
+    }
+
+    pub async fn info(&self) -> Result<RuntimeInfo> {
+        let (sender, receiver) = flume::bounded(1);
+        self.send(ThreadRequest::Info(sender))?;
+        receiver
+            .recv_async()
+            .await
+            .map_err(|_| anyhow!("runtime not loaded"))
+    }
+
+    pub async fn stats(&self) -> Result<RuntimeStats> {
+        let (sender, receiver) = flume::bounded(1);
+        self.send(ThreadRequest::Stats(sender))?;
+        Ok(receiver.recv_async().await?)
+    }
+
+    pub async fn tokenizer(&self) -> Result<Arc<Tokenizer>> {
+        Ok(self.info().await?.tokenizer)
+    }
+
+    /// Receive the [`RuntimeEvent`]s from now on.
+    pub fn subscribe(&self) -> Result<Receiver<RuntimeEvent>> {
+        let (sender, receiver) = flume::unbounded();
+        self.send(ThreadRequest::Subscribe(sender))?;
+        Ok(receiver)
+    }
+
+    /// Start a generation. The stream yields [`Token::Start`], the [`Token::Content`]s,
+    /// then [`Token::Stop`] and [`Token::Done`], or [`Token::Error`] if it fails; dropping it cancels the generation.
+    pub async fn generate_stream(
+        &self,
+        request: GenerateRequest,
+    ) -> Result<RecvStream<'static, Token>> {
+        Ok(self.start(request).await?.into_stream())
+    }
+
+    /// Run a generation to its end.
+    pub async fn generate(&self, request: GenerateRequest) -> Result<Generation> {
+        let receiver = self.start(request).await?;
+
+        let mut generation = Generation::default();
+        while let Ok(token) = receiver.recv_async().await {
+            match token {
+                Token::Content(content) => generation.text += &content,
+                Token::Stop(finish_reason, stop_sequence, counter) => {
+                    generation.finish_reason = finish_reason;
+                    generation.stop_sequence = stop_sequence;
+                    generation.counter = counter;
+                }
+                Token::Done => return Ok(generation),
+                Token::Error(error) => bail!(error),
+                Token::Start | Token::Embed(_) => {}
+            }
+        }
+        bail!("generation aborted")
+    }
+
+    /// Embed a text with the output of the `layer`-th layer counting from the last.
+    pub async fn embed(&self, text: impl Into<String>, layer: usize) -> Result<Vec<f32>> {
+        let request = GenerateRequest {
+            prompt: text.into(),
+            max_tokens: 1,
+            embed: true,
+            embed_layer: layer,
+            ..Default::default()
+        };
+        let receiver = self.start(request).await?;
+
+        while let Ok(token) = receiver.recv_async().await {
+            match token {
+                Token::Embed(embed) => return Ok(embed),
+                Token::Error(error) => bail!(error),
+                _ => {}
+            }
+        }
+        bail!("embedding aborted")
+    }
+
+    async fn start(&self, request: GenerateRequest) -> Result<Receiver<Token>> {
+        let tokenizer = self.tokenizer().await?;
+        let (sender, receiver) = flume::unbounded();
+        self.send(ThreadRequest::Generate {
+            request: Box::new(request),
+            tokenizer,
+            sender,
+        })?;
+        Ok(receiver)
+    }
+
+    fn send(&self, request: ThreadRequest) -> Result<()> {
+        self.sender
+            .send(request)
+            .map_err(|_| anyhow!("engine exited"))
+    }
+}
@@ -0,0 +1,210 @@
+//! Watches the temperature and the power draw of the GPU, and reduces the load while they are over
+//! the limits: fewer lanes are picked up and each round of inference is preceded by a pause.
+//! Lanes are given back one at a time once the readings fall below the limits by a margin.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tokio::sync::Mutex;
+
+use crate::reload::GovernorOption;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Reading {
+    /// Temperature in °C.
+    temperature: Option<f32>,
+    /// Power draw in watts.
+    power: Option<f32>,
+}
+
+enum Sensor {
+    #[cfg(feature = "nvml")]
+    Nvml(Box<nvml_wrapper::Nvml>, u32),
+    Sysfs {
+        /// Files of millidegrees; the hottest counts.
+        temperature: Vec<PathBuf>,
+        /// File of microwatts.
+        power: Option<PathBuf>,
+    },
+}
+
+/// Read a sysfs value, scaled down by `1000 ^ scale`.
+fn read_sysfs(path: &Path, scale: i32) -> Option<f32> {
+    let value: f32 = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    Some(value / 1000f32.powi(scale))
+}
+
+/// The first file found in the `hwmon` directories of the GPUs, trying the names in order.
+fn find_hwmon(names: &[&str]) -> Option<PathBuf> {
+    let cards = std::fs::read_dir("/sys/class/drm").ok()?;
+    let hwmons = cards
+        .flatten()
+        .filter(|card| card.file_name().to_string_lossy().starts_with("card"))
+        .filter_map(|card| std::fs::read_dir(card.path().join("device/hwmon")).ok())
+        .flat_map(|hwmons| hwmons.flatten().map(|hwmon| hwmon.path()))
+        .collect::<Vec<_>>();
+    names.iter().find_map(|name| {
+        hwmons
+            .iter()
+            .map(|hwmon| hwmon.join(name))
+            .find(|path| path.exists())
+    })
+}
+
+/// Thermal zones of the system, which is where integrated GPUs of laptops get their heat from.
+fn find_thermal_zones() -> Vec<PathBuf> {
+    let Ok(zones) = std::fs::read_dir("/sys/class/thermal") else {
+        return vec![];
+    };
+    zones
+        .flatten()
+        .filter(|zone| {
+            zone.file_name()
+                .to_string_lossy()
+                .starts_with("thermal_zone")
+        })
+        .map(|zone| zone.path().join("temp"))
+        .filter(|path| path.exists())
+        .collect()
+}
+
+impl Sensor {
+    fn new(option: &GovernorOption) -> Option<Self> {
+        #[cfg(feature = "nvml")]
+        match nvml_wrapper::Nvml::init() {
+            Ok(nvml) if nvml.device_by_index(option.device).is_ok() => {
+                log::info!("governor reads gpu {} through nvml", option.device);
+                return Some(Self::Nvml(Box::new(nvml), option.device));
+            }
+            Ok(_) => log::warn!("governor: no gpu {} in nvml", option.device),
+            Err(err) => log::warn!("governor: nvml unavailable: {err}"),
+        }
+
+        let temperature = match &option.temperature_path {
+            Some(path) => vec![path.clone()],
+            None => match find_hwmon(&["temp1_input"]) {
+                Some(path) => vec![path],
+                None => find_thermal_zones(),
+            },
+        };
+        let power = option
+            .power_path
+            .clone()
+            .or_else(|| find_hwmon(&["power1_average", "power1_input"]));
+
+        if temperature.is_empty() && power.is_none() {
+            return None;
+        }
+        for path in temperature.iter().chain(power.iter()) {
+            log::info!("governor reads {}", path.to_string_lossy());
+        }
+        Some(Self::Sysfs { temperature, power })
+    }
+
+    fn read(&self) -> Reading {
+        match self {
+            #[cfg(feature = "nvml")]
+            Self::Nvml(nvml, index) => {
+                use nvml_wrapper::enum_wrappers::device::TemperatureSensor;
+                let Ok(device) = nvml.device_by_index(*index) else {
+                    return Reading::default();
+                };
+                let temperature = device.temperature(TemperatureSensor::Gpu).ok();
+                let power = device.power_usage().ok();
+                Reading {
+                    temperature: temperature.map(|x| x as f32),
+                    power: power.map(|x| x as f32 / 1000.0),
+                }
+            }
+            Self::Sysfs { temperature, power } => Reading {
+                temperature: temperature
+                    .iter()
+                    .filter_map(|path| read_sysfs(path, 1))
+                    .reduce(f32::max),
+                power: power.as_ref().and_then(|path| read_sysfs(path, 2)),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GovernorState {
+    /// Lanes that may run at once.
+    lanes: usize,
+    /// Whether the last reading is over the limits.
+    hot: bool,
+}
+
+pub struct Governor {
+    option: GovernorOption,
+    max_batch: usize,
+    sensor: Sensor,
+    state: Mutex<GovernorState>,
+}
+
+impl Governor {
+    /// A governor if any limit is set and the GPU can be watched.
+    pub fn new(option: &GovernorOption, max_batch: usize) -> Option<Self> {
+        if !option.is_enabled() {
+            return None;
+        }
+        let Some(sensor) = Sensor::new(option) else {
+            log::warn!("governor disabled: no temperature or power sensor found");
+            return None;
+        };
+        let state = GovernorState {
+            lanes: max_batch,
+            hot: false,
+        };
+        Some(Self {
+            option: option.clone(),
+            max_batch,
+            sensor,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Take a reading and adjust the load: one lane less while over the limits,
+    /// one lane more while under them by the margin.
+    pub async fn update(&self) {
+        let Reading { temperature, power } = self.sensor.read();
+        let ratio = |value: Option<f32>, limit: f32| match (value, limit > 0.0) {
+            (Some(value), true) => value / limit,
+            _ => 0.0,
+        };
+        let ratio = ratio(temperature, self.option.max_temperature)
+            .max(ratio(power, self.option.max_power));
+
+        let min_batch = self.option.min_batch.clamp(1, self.max_batch.max(1));
+        let mut state = self.state.lock().await;
+        let lanes = state.lanes;
+        state.hot = ratio > 1.0;
+        if state.hot {
+            state.lanes = lanes.saturating_sub(1).max(min_batch);
+        } else if ratio < 1.0 - self.option.margin {
+            state.lanes = (lanes + 1).min(self.max_batch);
+        }
+
+        if state.lanes != lanes {
+            let temperature = temperature.map_or("-".into(), |x| format!("{x:.0}°C"));
+            let power = power.map_or("-".into(), |x| format!("{x:.0}W"));
+            log::info!(
+                "governor: {temperature}, {power}; lanes {lanes} -> {}",
+                state.lanes
+            );
+        }
+    }
+
+    /// Lanes that may run at once.
+    pub async fn lanes(&self) -> usize {
+        self.state.lock().await.lanes
+    }
+
+    /// The pause before a round of inference, if over the limits.
+    pub async fn pause(&self) -> Option<Duration> {
+        let hot = self.state.lock().await.hot;
+        (hot && self.option.pause > 0).then(|| Duration::from_millis(self.option.pause))
+    }
+}
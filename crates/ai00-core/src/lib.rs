@@ -1,4 +1,5 @@
 use std::{
+    cmp::Reverse,
     collections::HashMap,
     path::{Path, PathBuf},
     sync::Arc,
@@ -11,7 +12,7 @@ use flume::{Receiver, Sender};
 use half::f16;
 use itertools::Itertools;
 use memmap2::Mmap;
-use reload::{AdapterOption, BnfOption, Precision};
+use reload::{AdapterOption, BnfOption, GovernorOption, Precision, StateCacheOption};
 use safetensors::SafeTensors;
 use salvo::oapi::ToSchema;
 use serde::{de::DeserializeSeed, Deserialize, Serialize};
@@ -30,31 +31,112 @@ use web_rwkv::{
         },
         v4, v5, v6,
     },
-    tensor::{serialization::Seed, TensorCpu},
+    tensor::{serialization::Seed, TensorCpu, TensorShape},
     tokenizer::Tokenizer,
-    wgpu::{Backends, Maintain, PowerPreference},
+    wgpu::{Backends, Instance, Maintain, PowerPreference},
 };
 
 use crate::{
     run::{GenerateContext, InitState, Runtime, StateId, Tokens},
-    sampler::Sampler,
+    sampler::{dry::DryParams, reasoning::ReasoningBudget, xtc::XtcParams, Sampler},
+    supervisor::{Health, Restart, Supervisor},
 };
 
+pub mod engine;
+mod governor;
 pub mod reload;
 pub mod run;
 pub mod sampler;
+pub mod storage;
+mod supervisor;
 
 pub const MAX_TOKENS: usize = 4096;
 
+tokio::task_local! {
+    /// Id of the call being served, which loggers may tag their lines with.
+    pub static REQUEST_ID: String;
+}
+
+/// Id of the call being served in this task, if any.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
 #[derive(Debug)]
 pub enum Token {
     Start,
     Content(String),
-    Stop(FinishReason, TokenCounter),
+    /// Generation finished, with the reason, the stop sequence that ended it if any, and the usage.
+    Stop(FinishReason, Option<String>, TokenCounter),
     Embed(Vec<f32>),
     Done,
+    /// Generation failed. Nothing follows.
+    Error(RuntimeError),
+}
+
+/// Why a generation fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeError {
+    /// The device runs out of memory.
+    OutOfMemory(String),
+    /// The prompt leaves no room for the output in the context of the model.
+    ContextExceeded {
+        prompt: usize,
+        context: usize,
+        reserve: usize,
+    },
+    /// The prompt cannot be tokenized, or its tokens are not in the vocabulary.
+    InvalidInput(String),
+    /// No model is loaded, or the one loaded crashed too often.
+    ModelNotLoaded,
+    /// The model is unloaded or replaced before the generation finishes.
+    Canceled,
+    /// The device is lost or stuck, e.g., a forward pass timed out.
+    BackendLost(String),
+    /// The first token would be later than the server is allowed to keep a caller waiting.
+    Overloaded { estimate: Duration, limit: Duration },
+}
+
+impl RuntimeError {
+    /// Classify the error a crash of the model loop comes with.
+    pub(crate) fn from_crash(error: String) -> Self {
+        let lower = error.to_lowercase();
+        match lower.contains("out of memory") || lower.contains("outofmemory") {
+            true => Self::OutOfMemory(error),
+            false => Self::BackendLost(error),
+        }
+    }
 }
 
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OutOfMemory(error) => write!(f, "out of device memory: {error}"),
+            Self::ContextExceeded {
+                prompt,
+                context,
+                reserve,
+            } => write!(
+                f,
+                "prompt has {prompt} tokens, but the context of {context} tokens only leaves {} with {reserve} reserved",
+                context.saturating_sub(*reserve)
+            ),
+            Self::InvalidInput(error) => write!(f, "invalid input: {error}"),
+            Self::ModelNotLoaded => write!(f, "no model is loaded"),
+            Self::Canceled => write!(f, "generation canceled: the model is unloaded"),
+            Self::BackendLost(error) => write!(f, "backend lost: {error}"),
+            Self::Overloaded { estimate, limit } => write!(
+                f,
+                "server overloaded: the first token is estimated in {:.1}s, over the limit of {:.1}s",
+                estimate.as_secs_f32(),
+                limit.as_secs_f32()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
 pub struct TokenCounter {
     #[serde(alias = "prompt_tokens")]
@@ -76,6 +158,10 @@ pub enum FinishReason {
     Length,
     /// Omitted content due to a flag from our content filters.
     ContentFilter,
+    /// The model called a tool.
+    ToolCalls,
+    /// Generation was interrupted before it could finish, e.g., the model was unloaded.
+    Abort,
     /// API response still in progress or incomplete.
     #[default]
     #[serde(untagged)]
@@ -88,6 +174,8 @@ pub enum ThreadRequest {
     Adapter(Sender<AdapterList>),
     /// Get the current runtime info.
     Info(Sender<RuntimeInfo>),
+    /// Get the current resource usage and scheduling statistics.
+    Stats(Sender<RuntimeStats>),
     /// Request the runtime to complement a prompt.
     Generate {
         request: Box<GenerateRequest>,
@@ -108,16 +196,68 @@ pub enum ThreadRequest {
     },
     /// Unload an initial state given its id.
     StateUnload(StateId),
+    /// Drop the cached states along the token sequences. Sends the number of states dropped.
+    Forget {
+        tokens: Vec<Vec<u16>>,
+        dry_run: bool,
+        sender: Sender<usize>,
+    },
     /// Save the current model with config.
     Save {
         request: SaveRequest,
         sender: Sender<bool>,
     },
+    /// Save a cached state as an initial state.
+    StateSave {
+        request: StateSaveRequest,
+        sender: Sender<bool>,
+    },
+    /// Receive the [`RuntimeEvent`]s from now on.
+    Subscribe(Sender<RuntimeEvent>),
+}
+
+/// Changes of the runtime state, sent to the subscribers.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RuntimeEvent {
+    /// A reload is started; the current model keeps serving until the new one is ready.
+    ReloadStarted {
+        model_path: PathBuf,
+    },
+    ModelLoaded {
+        model_path: PathBuf,
+    },
+    LoadFailed {
+        model_path: PathBuf,
+        error: String,
+    },
+    ModelUnloaded,
+    /// The model loop panicked or the device is lost. The model is unloaded, and reloaded unless it crashes too often.
+    Crashed {
+        error: String,
+    },
+}
+
+/// Senders of the runtime events; dropped receivers are removed on the next event.
+#[derive(Debug, Default, Clone)]
+struct Subscribers(Arc<std::sync::Mutex<Vec<Sender<RuntimeEvent>>>>);
+
+impl Subscribers {
+    fn add(&self, sender: Sender<RuntimeEvent>) {
+        self.0.lock().unwrap().push(sender);
+    }
+
+    fn send(&self, event: RuntimeEvent) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
 }
 
 #[derive(Default)]
 pub enum Environment {
-    Loaded(Runtime),
+    Loaded(Box<Runtime>),
     #[default]
     None,
 }
@@ -130,14 +270,128 @@ pub struct RuntimeInfo {
     pub tokenizer: Arc<Tokenizer>,
 }
 
+#[derive(Debug, Default, Clone, Serialize, ToSchema)]
+pub struct RuntimeStats {
+    /// Whether a model is loaded.
+    pub loaded: bool,
+    /// Number of requests waiting for a free slot.
+    pub queued: usize,
+    /// Occupancy of the batch slots.
+    pub slots: SlotStats,
+    /// Number of states kept in the state cache.
+    pub cached_states: usize,
+    /// Number of GPU buffers currently allocated, if the backend reports it.
+    pub buffers: Option<usize>,
+    /// The last crash of the model, until a model is loaded again.
+    pub fault: Option<String>,
+    /// Automatic reloads after crashes so far.
+    pub restarts: usize,
+    /// Tokens left to compute before a new request is picked up, in the queue and in the slots.
+    pub backlog: usize,
+    /// Prompt tokens read per second recently, if any prompt has been read since the model was last idle.
+    pub prefill_rate: Option<f32>,
+    /// Time until a slot frees up, if known: zero with an idle one, or else the tokens the closest lane still owes.
+    pub slot_wait: Option<Duration>,
+}
+
+impl RuntimeStats {
+    /// Estimated time to the first token of a request with a prompt of `tokens`, if the prefill rate is known:
+    /// the wait for a slot, then the prompts ahead and its own at the prefill rate.
+    /// The prompt cache is not taken into account, so the estimate errs on the long side.
+    pub fn first_token(&self, tokens: usize) -> Option<Duration> {
+        let rate = self.prefill_rate.filter(|&x| x > 0.0)?;
+        let seconds = (self.backlog + tokens) as f32 / rate;
+        let wait = self.slot_wait.unwrap_or_default();
+        Some(wait + Duration::from_secs_f32(seconds))
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, ToSchema)]
+pub struct SlotStats {
+    pub idle: usize,
+    pub waiting: usize,
+    pub busy: usize,
+}
+
 #[derive(Debug, Default, Clone)]
-pub struct AdapterList(pub Vec<String>);
+pub struct AdapterList(pub Vec<AdapterInfo>);
+
+/// Description of a GPU adapter. The index is what [`AdapterOption::Manual`] expects.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct AdapterInfo {
+    pub index: usize,
+    pub name: String,
+    pub vendor: u32,
+    pub device: u32,
+    pub device_type: String,
+    pub driver: String,
+    pub driver_info: String,
+    pub backend: String,
+}
+
+impl std::fmt::Display for AdapterInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.name, self.backend)
+    }
+}
+
+/// One sampling step of a traced generation.
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceStep {
+    /// The chosen token.
+    pub token: u16,
+    /// Probability of the chosen token after all transforms.
+    pub prob: f32,
+    /// The most likely tokens of the step with their probabilities.
+    pub candidates: Vec<(u16, f32)>,
+}
+
+/// Where the sampling steps of a traced generation are recorded.
+pub type Trace = Arc<std::sync::Mutex<Vec<TraceStep>>>;
+
+/// Caps the slots held at once by the generations of one owner.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotQuota {
+    /// Who the generation is accounted to, e.g. an API key or a client address.
+    pub owner: String,
+    /// Maximum slots the owner may hold at once.
+    pub max_slots: usize,
+}
+
+/// Caps the rate of tokens generated by the generations of one owner together.
+/// Over the cap, decoding steps of the owner are skipped until the rate falls back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateQuota {
+    /// Who the generation is accounted to, e.g. an API key or a client address.
+    pub owner: String,
+    /// Output tokens per second the owner may generate.
+    pub tokens_per_sec: f32,
+    /// Whether the cap holds even when no other generation is running.
+    /// Otherwise the owner is only throttled while others have work to do.
+    pub hard: bool,
+}
+
+/// How urgent a generation is. A request waiting for a lane preempts a running generation of a lower priority,
+/// which is checkpointed and resumes once a lane is free again.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Background work, e.g., batch jobs, which yields to anything else.
+    Low,
+    #[default]
+    Normal,
+    High,
+}
 
 #[derive(Clone, Derivative)]
 #[derivative(Debug, Default)]
 pub struct GenerateRequest {
     /// The prompt for the model.
     pub prompt: String,
+    /// Pre-tokenized prompt. If present, `prompt` is not tokenized.
+    pub prompt_tokens: Option<Vec<u16>>,
     /// All text the model output earlier.
     pub model_text: String,
     /// Output token limit.
@@ -148,6 +402,16 @@ pub struct GenerateRequest {
     pub bias: Arc<HashMap<u16, f32>>,
     /// Optional BNF schema for formatted generation.
     pub bnf_schema: Option<String>,
+    /// Optional cap on the tokens generated inside reasoning blocks.
+    pub reasoning_budget: Option<ReasoningBudget>,
+    /// Scale of the Gumbel noise added to the logits. `0` disables it.
+    pub logit_noise: f32,
+    /// Penalty of tokens that extend repetitions.
+    pub dry: DryParams,
+    /// Exclusion of the top choices.
+    pub xtc: XtcParams,
+    /// Keep only the tokens within this many standard deviations of the top logit. `0` disables it.
+    pub top_n_sigma: f32,
     /// Sampler parameters.
     #[derivative(
         Debug = "ignore",
@@ -160,13 +424,28 @@ pub struct GenerateRequest {
     pub embed_layer: usize,
     /// Initial state ID.
     pub state: StateId,
+    /// Pause generation while this many tokens are not yet received by the caller.
+    pub max_pending: Option<usize>,
+    /// Optional cap on the slots held by the owner of this request.
+    pub quota: Option<SlotQuota>,
+    /// Optional cap on the output rate of the owner of this request.
+    pub rate: Option<RateQuota>,
+    /// Requests of a higher priority are picked up first, and take the lanes of those of a lower one.
+    pub priority: Priority,
+    /// Id of the call this request serves, which the log lines about it carry.
+    pub request_id: Option<String>,
+    /// If present, every sampling step is recorded into it.
+    pub trace: Option<Trace>,
+    /// User-defined logits processors, applied in order.
+    pub processors: Vec<sampler::processor::ProcessorRequest>,
 }
 
-#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derive(Debug, Derivative, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
 #[derivative(Default)]
 #[serde(default)]
 pub struct ReloadRequest {
     /// Path to the model.
+    #[salvo(schema(value_type = String))]
     pub model_path: PathBuf,
     /// List of LoRA blended on the model.
     pub lora: Vec<reload::Lora>,
@@ -175,7 +454,10 @@ pub struct ReloadRequest {
     /// Specify layers that needs to be quantized.
     pub quant: usize,
     /// Quantization type (`Int8` or `NF4`).
+    #[salvo(schema(value_type = String))]
     pub quant_type: Quant,
+    /// Quantization of layer ranges, overriding `quant` and `quant_type`.
+    pub quant_ranges: Vec<reload::QuantRange>,
     /// Precision for intermediate tensors (`Fp16` or `Fp32`).
     pub precision: Precision,
     /// Maximum tokens to be processed in parallel at once.
@@ -184,24 +466,71 @@ pub struct ReloadRequest {
     /// Number of states that are cached on GPU.
     #[derivative(Default(value = "8"))]
     pub max_batch: usize,
-    /// Device to put the embed tensor.
+    /// Device to put the embed tensor (`Cpu` or `Gpu`).
+    #[salvo(schema(value_type = String))]
     pub embed_device: EmbedDevice,
+    /// Interval in tokens between state checkpoints kept in the prompt cache. `0` disables checkpoints.
+    pub checkpoint_interval: usize,
+    /// Context length the model is trained on. `0` if unknown, in which case it is not enforced.
+    pub context_length: usize,
+    /// Tokens of the context kept free when fitting `max_tokens` of a request.
+    pub context_reserve: usize,
+    /// Automatic reloads after the model crashes, within 10 minutes, before it is left unloaded.
+    #[derivative(Default(value = "3"))]
+    pub max_restarts: usize,
+    /// Seconds a forward pass may take before the request likely to hang it is failed. `0` disables the watchdog.
+    #[derivative(Default(value = "60"))]
+    pub infer_timeout: u64,
     /// Path to the tokenizer.
+    #[salvo(schema(value_type = String))]
     pub tokenizer_path: PathBuf,
     /// BNF options.
     pub bnf: BnfOption,
+    /// How the states backed from slots are cached.
+    pub state_cache: StateCacheOption,
+    /// Limits of the GPU temperature and power draw.
+    pub governor: GovernorOption,
     /// Adapter selection.
     pub adapter: AdapterOption,
 }
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+impl ReloadRequest {
+    /// Returns `true` if the two requests only differ in initial states,
+    /// in which case the model weights can be kept and only the states are swapped.
+    pub fn differs_only_in_states(&self, other: &Self) -> bool {
+        let lhs = Self {
+            state: vec![],
+            ..self.clone()
+        };
+        let rhs = Self {
+            state: vec![],
+            ..other.clone()
+        };
+        lhs == rhs
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
 #[serde(default)]
 pub struct SaveRequest {
     /// Path to save the model.
     #[serde(alias = "model_path")]
+    #[salvo(schema(value_type = String))]
     pub path: PathBuf,
 }
 
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct StateSaveRequest {
+    /// Path to save the state.
+    #[salvo(schema(value_type = String))]
+    pub path: PathBuf,
+    /// The tokens read into the state, which must be in the prompt cache.
+    pub tokens: Vec<u16>,
+    /// Initial state the tokens are read from.
+    pub state: StateId,
+}
+
 #[derive(Debug, Deserialize)]
 struct Prefab {
     info: ModelInfo,
@@ -213,21 +542,33 @@ enum LoadType {
     Prefab,
 }
 
-fn list_adapters() -> AdapterList {
+fn list_adapters(instance: &Instance) -> AdapterList {
     let backends = Backends::all();
-    let instance = web_rwkv::wgpu::Instance::default();
     let list = instance
         .enumerate_adapters(backends)
         .into_iter()
         .map(|adapter| adapter.get_info())
-        .map(|info| format!("{} ({:?})", info.name, info.backend))
+        .enumerate()
+        .map(|(index, info)| AdapterInfo {
+            index,
+            name: info.name,
+            vendor: info.vendor,
+            device: info.device,
+            device_type: format!("{:?}", info.device_type),
+            driver: info.driver,
+            driver_info: info.driver_info,
+            backend: format!("{:?}", info.backend),
+        })
         .collect();
     AdapterList(list)
 }
 
-async fn create_context(adapter: AdapterOption, info: &ModelInfo) -> Result<Context> {
+async fn create_context(
+    instance: &Instance,
+    adapter: AdapterOption,
+    info: &ModelInfo,
+) -> Result<Context> {
     let backends = Backends::all();
-    let instance = web_rwkv::wgpu::Instance::default();
     let adapter = match adapter {
         AdapterOption::Auto => instance.adapter(PowerPreference::HighPerformance).await,
         AdapterOption::Economical => instance.adapter(PowerPreference::LowPower).await,
@@ -275,38 +616,58 @@ async fn load_init_state<R: Reader>(
     info: &ModelInfo,
     model: R,
 ) -> Result<TensorCpu<f32>> {
-    let state = match info.version {
+    match info.version {
         ModelVersion::V4 => Err(anyhow!("v4 does not support init state yet")),
         ModelVersion::V5 => v5::read_state(context, info, model).await,
         ModelVersion::V6 => v6::read_state(context, info, model).await,
-    };
-    state.map_err(Into::into)
+    }
 }
 
-async fn load_runtime(
-    context: &Context,
-    reload: &ReloadRequest,
-    info: ModelInfo,
-    load: LoadType,
-) -> Result<Runtime> {
-    let ReloadRequest {
-        model_path,
-        lora,
-        state,
-        quant,
-        quant_type,
-        max_batch,
-        embed_device,
-        tokenizer_path,
-        ..
-    } = reload.clone();
-
-    let tokenizer = load_tokenizer(tokenizer_path).await?;
-    let vocab = load_vocab(&tokenizer);
+/// Write a state in the format [`load_init_state`] reads. The format only has the time states,
+/// so the token shifts are left out.
+fn save_init_state(info: &ModelInfo, state: &TensorCpu<f32>, path: &Path) -> Result<()> {
+    if info.version == ModelVersion::V4 {
+        bail!("v4 does not support init state yet");
+    }
+    let head_size = info.num_emb / info.num_head;
+    state.check_shape([info.num_emb, head_size + 2, info.num_layer, 1])?;
 
-    let file = File::open(model_path).await?;
-    let data = unsafe { Mmap::map(&file) }?;
+    let data = state.to_vec();
+    let mut tensors = vec![];
+    for layer in 0..info.num_layer {
+        let offset = layer * info.num_emb * (head_size + 2);
+        // from `[num_emb, head_size]` rows after the token shift to `[num_head, head_size, head_size]`
+        let bytes = (0..info.num_head)
+            .cartesian_product(0..head_size)
+            .cartesian_product(0..head_size)
+            .flat_map(|((head, row), col)| {
+                let index = offset + (1 + row) * info.num_emb + head * head_size + col;
+                f16::from_f32(data[index]).to_le_bytes()
+            })
+            .collect_vec();
+        tensors.push((format!("blocks.{layer}.att.time_state"), bytes));
+    }
+    let shape = vec![info.num_head, head_size, head_size];
+    let views = tensors
+        .iter()
+        .map(|(name, bytes)| {
+            let view = safetensors::tensor::TensorView::new(
+                safetensors::Dtype::F16,
+                shape.clone(),
+                bytes,
+            )?;
+            Ok((name.clone(), view))
+        })
+        .collect::<Result<Vec<_>, safetensors::SafeTensorError>>()?;
+    safetensors::serialize_to_file(views, &None, path)?;
+    Ok(())
+}
 
+async fn load_init_states(
+    context: &Context,
+    info: &ModelInfo,
+    state: Vec<reload::State>,
+) -> Result<Vec<InitState>> {
     let mut states = vec![];
     for reload::State {
         path,
@@ -325,7 +686,7 @@ async fn load_runtime(
         let file = File::open(path).await?;
         let data = unsafe { Mmap::map(&file) }?;
         let model = SafeTensors::deserialize(&data)?;
-        match load_init_state(context, &info, model).await {
+        match load_init_state(context, info, model).await {
             Ok(data) => {
                 let state = InitState {
                     name,
@@ -339,6 +700,35 @@ async fn load_runtime(
             Err(err) => log::warn!("initial state not loaded: {}", err),
         }
     }
+    Ok(states)
+}
+
+async fn load_runtime(
+    context: &Context,
+    reload: &ReloadRequest,
+    info: ModelInfo,
+    load: LoadType,
+) -> Result<Runtime> {
+    let ReloadRequest {
+        model_path,
+        lora,
+        state,
+        quant,
+        quant_type,
+        quant_ranges,
+        max_batch,
+        embed_device,
+        tokenizer_path,
+        ..
+    } = reload.clone();
+
+    let tokenizer = load_tokenizer(tokenizer_path).await?;
+    let vocab = load_vocab(&tokenizer);
+
+    let file = File::open(model_path).await?;
+    let data = unsafe { Mmap::map(&file) }?;
+
+    let mut states = load_init_states(context, &info, state).await?;
 
     let runtime = match load {
         LoadType::SafeTensors => {
@@ -356,7 +746,12 @@ async fn load_runtime(
             }
 
             let model = SafeTensors::deserialize(&data)?;
-            let quant = (0..quant).map(|layer| (layer, quant_type)).collect();
+            let mut quant: HashMap<_, _> = (0..quant).map(|layer| (layer, quant_type)).collect();
+            for range in &quant_ranges {
+                let layers = range.layers(info.num_layer);
+                log::info!("layers {:?} quantized as {:?}", layers, range.quant_type);
+                quant.extend(layers.map(|layer| (layer, range.quant_type)));
+            }
             let lora = {
                 let mut x = Vec::with_capacity(lora.len());
                 for lora in lora.into_iter() {
@@ -452,11 +847,24 @@ async fn load_runtime(
 pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
     let env: Arc<RwLock<Environment>> = Default::default();
     let queue: Arc<Mutex<Vec<GenerateContext>>> = Default::default();
+    let instance = Arc::new(Instance::default());
+    let subscribers = Subscribers::default();
+    let health = Health::default();
+    let (fault_sender, faults) = flume::unbounded();
+    let (restart_sender, restarts) = flume::unbounded();
 
+    tokio::spawn(crate::run::maintain(env.clone()));
     let sender = {
         let (sender, receiver) = flume::unbounded();
-        let env = env.clone();
-        tokio::spawn(crate::run::run(receiver, env));
+        let supervisor = Supervisor {
+            env: env.clone(),
+            queue: queue.clone(),
+            health: health.clone(),
+            subscribers: subscribers.clone(),
+            faults,
+            restarts: restart_sender,
+        };
+        tokio::spawn(supervisor.run(receiver));
         sender
     };
 
@@ -469,6 +877,12 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
             loop {
                 let mut queue = queue.lock().await;
                 let mut temp = vec![];
+                // the generations preempted go back in line
+                if let Environment::Loaded(runtime) = &*env.read().await {
+                    queue.append(&mut runtime.preempted().await);
+                }
+                // stable, so that requests of the same priority keep their order
+                queue.sort_by_key(|context| Reverse(context.request.priority));
                 for context in queue.drain(..) {
                     temp.append(&mut env.read().await.enqueue(context).await);
                     let _ = sender.send(());
@@ -483,16 +897,74 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
     tokio::spawn(dequeue);
 
     loop {
-        let Ok(request) = receiver.recv_async().await else {
-            log::info!("core exit");
-            break Ok(());
+        // the prompt cache of a runtime lost to its device, handed over to the reload
+        let (request, salvage) = tokio::select! {
+            request = receiver.recv_async() => match request {
+                Ok(request) => (request, None),
+                Err(_) => {
+                    log::info!("core exit");
+                    break Ok(());
+                }
+            },
+            Ok(Restart { request, salvage }) = restarts.recv_async() => {
+                let request = ThreadRequest::Reload {
+                    request: Box::new(request),
+                    sender: None,
+                };
+                (request, salvage)
+            }
         };
 
         let listen = async {
             match request {
                 ThreadRequest::Adapter(sender) => {
+                    let instance = instance.clone();
+                    tokio::spawn(async move {
+                        let _ = sender.send(list_adapters(&instance));
+                    });
+                }
+                ThreadRequest::Stats(sender) => {
+                    let env = env.clone();
+                    let queue = queue.clone();
+                    let instance = instance.clone();
+                    let health = health.clone();
                     tokio::spawn(async move {
-                        let _ = sender.send(list_adapters());
+                        let (queued, backlog) = {
+                            let queue = queue.lock().await;
+                            let backlog = queue.iter().map(|x| x.suffix.len()).sum::<usize>();
+                            (queue.len(), backlog)
+                        };
+                        let fault = health.fault();
+                        let restarts = health.restarts();
+                        let env = &(*env.read().await);
+                        let stats = match env {
+                            Environment::Loaded(runtime) => {
+                                let backend = runtime.context().adapter.get_info().backend;
+                                let buffers = instance
+                                    .generate_report()
+                                    .map(|report| report.hub_report(backend).buffers.num_allocated);
+                                RuntimeStats {
+                                    loaded: true,
+                                    queued,
+                                    slots: runtime.slot_stats().await,
+                                    cached_states: runtime.num_cached_states().await,
+                                    buffers,
+                                    fault,
+                                    restarts,
+                                    backlog: backlog + runtime.backlog().await,
+                                    prefill_rate: runtime.prefill_rate().await,
+                                    slot_wait: runtime.slot_wait().await,
+                                }
+                            }
+                            Environment::None => RuntimeStats {
+                                queued,
+                                backlog,
+                                fault,
+                                restarts,
+                                ..Default::default()
+                            },
+                        };
+                        let _ = sender.send(stats);
                     });
                 }
                 ThreadRequest::Info(sender) => {
@@ -520,9 +992,44 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
                     let request = *request;
                     let sender = sender.clone();
                     let env = env.clone();
+                    let instance = instance.clone();
+                    let subscribers = subscribers.clone();
+                    let health = health.clone();
+                    let fault_sender = fault_sender.clone();
+                    let model_path = request.model_path.clone();
+                    subscribers.send(RuntimeEvent::ReloadStarted {
+                        model_path: model_path.clone(),
+                    });
                     let reload = async move {
                         let sender = sender.clone();
 
+                        // only initial states changed: keep the weights and swap states in place
+                        if let Environment::Loaded(runtime) = &mut *env.write().await {
+                            if runtime.reload().differs_only_in_states(&request) {
+                                let current = &runtime.reload().state;
+                                let removed = current
+                                    .iter()
+                                    .filter(|state| !request.state.contains(state))
+                                    .map(|state| state.id)
+                                    .collect_vec();
+                                let added = request
+                                    .state
+                                    .iter()
+                                    .filter(|state| !current.contains(state))
+                                    .cloned()
+                                    .collect_vec();
+                                log::info!("reloading {} initial states in place", added.len());
+
+                                let added =
+                                    load_init_states(runtime.context(), runtime.info(), added)
+                                        .await?;
+                                runtime.update_init_states(request, &removed, added);
+
+                                let _ = sender.send(());
+                                return anyhow::Ok(());
+                            }
+                        }
+
                         let file = File::open(&request.model_path).await?;
                         let data = unsafe { Mmap::map(&file)? };
 
@@ -539,23 +1046,36 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
                         log::info!("{:#?}", info);
                         log::info!("type: {:?}", load);
 
-                        let context = create_context(request.adapter, &info).await?;
+                        let context = create_context(&instance, request.adapter, &info).await?;
                         log::info!("{:#?}", context.adapter.get_info());
+                        supervisor::watch_device(&context, fault_sender.clone());
 
                         let mut env = env.write().await;
                         // drop(mem::take(&mut *env));
                         'unload: {
                             let env = std::mem::take(&mut *env);
                             let context = match env {
-                                Environment::Loaded(runtime) => runtime.context().clone(),
+                                Environment::Loaded(runtime) => {
+                                    let context = runtime.context().clone();
+                                    runtime.cancel();
+                                    context
+                                }
                                 Environment::None => break 'unload,
                             };
                             context.queue.submit(None);
                             context.device.poll(Maintain::Wait);
                         }
 
-                        let runtime = load_runtime(&context, &request, info, load).await?;
-                        *env = Environment::Loaded(runtime);
+                        // the states of a salvaged cache are still in their files; others are left over
+                        if salvage.is_none() {
+                            run::clean_offload(&request.state_cache.offload_path);
+                        }
+                        let mut runtime = load_runtime(&context, &request, info, load).await?;
+                        runtime.report_faults(fault_sender);
+                        if let Some(salvage) = salvage {
+                            runtime.restore(salvage).await;
+                        }
+                        *env = Environment::Loaded(Box::new(runtime));
 
                         let _ = sender.send(());
                         anyhow::Ok(())
@@ -569,10 +1089,16 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
                         match reload.await {
                             Ok(_) => {
                                 callback(true);
+                                health.recover();
+                                subscribers.send(RuntimeEvent::ModelLoaded { model_path });
                                 log::info!("model loaded")
                             }
                             Err(err) => {
                                 callback(false);
+                                subscribers.send(RuntimeEvent::LoadFailed {
+                                    model_path,
+                                    error: err.to_string(),
+                                });
                                 log::error!("load runtime failed: {}", err);
                             }
                         };
@@ -580,15 +1106,21 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
                 }
                 ThreadRequest::Unload => {
                     let env = env.clone();
+                    let subscribers = subscribers.clone();
                     tokio::spawn(async move {
                         let mut env = env.write().await;
                         let env = std::mem::take(&mut *env);
                         log::info!("runtime unloaded");
 
                         let context = match env {
-                            Environment::Loaded(runtime) => runtime.context().clone(),
+                            Environment::Loaded(runtime) => {
+                                let context = runtime.context().clone();
+                                runtime.cancel();
+                                context
+                            }
                             Environment::None => return,
                         };
+                        subscribers.send(RuntimeEvent::ModelUnloaded);
                         context.queue.submit(None);
                         context.device.poll(Maintain::Wait);
                     });
@@ -663,14 +1195,52 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
                         runtime.unload_init_state(id).await;
                     });
                 }
+                ThreadRequest::Forget {
+                    tokens,
+                    dry_run,
+                    sender,
+                } => {
+                    let env = env.clone();
+                    tokio::spawn(async move {
+                        let count = match &*env.read().await {
+                            Environment::Loaded(runtime) => runtime.forget(&tokens, dry_run).await,
+                            Environment::None => 0,
+                        };
+                        let _ = sender.send(count);
+                    });
+                }
                 ThreadRequest::Generate {
                     request,
                     tokenizer,
                     sender: token_sender,
                 } => {
+                    if health.is_down() {
+                        log::warn!("generation refused: the model crashed and is left unloaded");
+                        let _ = token_sender.send(Token::Error(RuntimeError::ModelNotLoaded));
+                        return Ok(());
+                    }
                     let request = *request;
-                    let tokens = Tokens(tokenizer.encode(request.prompt.as_bytes())?);
-                    let model_tokens = Tokens(tokenizer.encode(request.model_text.as_bytes())?);
+                    let tokens = match &request.prompt_tokens {
+                        // make sure that all tokens are in the vocabulary
+                        Some(tokens) => tokenizer
+                            .decode(tokens)
+                            .map(|_| tokens.clone())
+                            .map_err(|err| format!("input_ids: {err}")),
+                        None => tokenizer
+                            .encode(request.prompt.as_bytes())
+                            .map_err(|err| format!("prompt: {err}")),
+                    };
+                    let model_tokens = tokenizer
+                        .encode(request.model_text.as_bytes())
+                        .map_err(|err| format!("model text: {err}"));
+                    let (tokens, model_tokens) = match (tokens, model_tokens) {
+                        (Ok(tokens), Ok(model_tokens)) => (Tokens(tokens), Tokens(model_tokens)),
+                        (Err(err), _) | (_, Err(err)) => {
+                            let error = RuntimeError::InvalidInput(err);
+                            let _ = token_sender.send(Token::Error(error));
+                            return Ok(());
+                        }
+                    };
                     // init sampler state here
                     request.sampler.write().await.init(&model_tokens);
 
@@ -682,10 +1252,11 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
                         model_text: vec![],
                         buffer: vec![],
                         model_tokens: vec![],
-                        transformers: vec![],
+                        transformers: None,
                         instant: None,
                         request,
                         sender: token_sender,
+                        started: false,
                     };
 
                     let env = env.clone();
@@ -713,6 +1284,32 @@ pub async fn model_route(receiver: Receiver<ThreadRequest>) -> Result<()> {
                         }
                     });
                 }
+                ThreadRequest::StateSave { request, sender } => {
+                    let env = env.clone();
+                    tokio::spawn(async move {
+                        let env = &(*env.read().await);
+                        if let Environment::Loaded(runtime) = env {
+                            log::info!("serializing state into {:?}", &request.path);
+                            let save = async {
+                                let state =
+                                    runtime.cached_state(request.state, &request.tokens).await?;
+                                let info = runtime.info().clone();
+                                tokio::task::spawn_blocking(move || {
+                                    save_init_state(&info, &state, &request.path)
+                                })
+                                .await?
+                            };
+                            let _ = match save.await {
+                                Ok(()) => sender.send(true),
+                                Err(err) => {
+                                    log::error!("{}", err);
+                                    sender.send(false)
+                                }
+                            };
+                        }
+                    });
+                }
+                ThreadRequest::Subscribe(sender) => subscribers.add(sender),
             };
             anyhow::Ok(())
         };
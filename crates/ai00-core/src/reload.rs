@@ -1,12 +1,13 @@
-use std::path::PathBuf;
+use std::{ops::Range, path::PathBuf};
 
 use derivative::Derivative;
+use salvo::oapi::ToSchema;
 use serde::{Deserialize, Serialize};
 use web_rwkv::runtime::model::{EmbedDevice, Quant};
 
 use crate::run::StateId;
 
-#[derive(Debug, Clone, Derivative, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Derivative, Serialize, Deserialize)]
 #[derivative(Default)]
 #[serde(default)]
 pub struct Model {
@@ -21,6 +22,8 @@ pub struct Model {
     pub quant: usize,
     /// Quantization type (`Int8` or `NF4`).
     pub quant_type: Quant,
+    /// Quantization of layer ranges, overriding `quant` and `quant_type`.
+    pub quant_ranges: Vec<QuantRange>,
     /// Precision for intermediate tensors (`Fp16` or `Fp32`).
     pub precision: Precision,
     /// Maximum tokens to be processed in parallel at once.
@@ -31,14 +34,54 @@ pub struct Model {
     pub max_batch: usize,
     /// Device to put the embed tensor.
     pub embed_device: EmbedDevice,
+    /// Interval in tokens between state checkpoints kept in the prompt cache. `0` disables checkpoints.
+    pub checkpoint_interval: usize,
+    /// Context length the model is trained on. `0` if unknown, in which case it is not enforced.
+    pub context_length: usize,
+    /// Tokens of the context kept free when fitting `max_tokens` of a request.
+    pub context_reserve: usize,
+    /// Automatic reloads after the model crashes, within 10 minutes, before it is left unloaded.
+    #[derivative(Default(value = "3"))]
+    pub max_restarts: usize,
+    /// Seconds a forward pass may take before the request likely to hang it is failed. `0` disables the watchdog.
+    #[derivative(Default(value = "60"))]
+    pub infer_timeout: u64,
+}
+
+/// Quantization of a range of layers.
+#[derive(Debug, Clone, PartialEq, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct QuantRange {
+    /// First layer of the range. Negative values count from the end, e.g. `-2` is the second to last layer.
+    pub start: isize,
+    /// Layer after the last one of the range, counted the same as `start`. The range runs to the end if not given.
+    pub end: Option<isize>,
+    /// Quantization type of the range (`None`, `Int8` or `NF4`).
+    #[salvo(schema(value_type = String))]
+    pub quant_type: Quant,
+}
+
+impl QuantRange {
+    /// The layers of the range in a model of `num_layer` layers.
+    pub fn layers(&self, num_layer: usize) -> Range<usize> {
+        let index = |index: isize| match index {
+            index if index < 0 => num_layer.saturating_sub(index.unsigned_abs()),
+            index => num_layer.min(index as usize),
+        };
+        let start = index(self.start);
+        let end = self.end.map(index).unwrap_or(num_layer);
+        start..end.max(start)
+    }
 }
 
 /// Low-rank adaptor.
-#[derive(Debug, Clone, Derivative, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Derivative, Serialize, Deserialize, ToSchema)]
 #[derivative(Default)]
 #[serde(default)]
 pub struct Lora {
     /// Path to the LoRA.
+    #[salvo(schema(value_type = String))]
     pub path: PathBuf,
     /// Blend factor.
     #[derivative(Default(value = "1.0"))]
@@ -46,11 +89,12 @@ pub struct Lora {
 }
 
 /// State-tuned initial state.
-#[derive(Debug, Clone, Derivative, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Derivative, Serialize, Deserialize, ToSchema)]
 #[derivative(Default)]
 #[serde(default)]
 pub struct State {
     /// Path to the initial state.
+    #[salvo(schema(value_type = String))]
     pub path: PathBuf,
     /// Given name for the state.
     pub name: Option<String>,
@@ -61,7 +105,7 @@ pub struct State {
     pub default: bool,
 }
 
-#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derive(Debug, Derivative, Clone, PartialEq, Serialize, Deserialize)]
 #[derivative(Default)]
 #[serde(default)]
 pub struct Tokenizer {
@@ -69,7 +113,7 @@ pub struct Tokenizer {
     pub path: PathBuf,
 }
 
-#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derive(Debug, Derivative, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
 #[derivative(Default)]
 #[serde(default)]
 pub struct BnfOption {
@@ -81,14 +125,81 @@ pub struct BnfOption {
     pub start_nonterminal: String,
 }
 
-#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum StateCompression {
+    /// Half the size, with little loss.
+    #[default]
+    Fp16,
+    /// A quarter of the size, with a scale for every block of values.
+    Int8,
+}
+
+#[derive(Debug, Derivative, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct StateCacheOption {
+    /// Maximum number of cached states of each initial state, in memory and on disk.
+    #[derivative(Default(value = "256"))]
+    pub capacity: usize,
+    /// Maximum number of cached states kept in memory; the least recently used others are offloaded to disk.
+    /// `0` keeps all in memory.
+    pub memory_capacity: usize,
+    /// Directory the offloaded states are written to. States left over from earlier runs are removed at each load.
+    #[derivative(Default(value = "\"assets/cache/states\".into()"))]
+    #[salvo(schema(value_type = String))]
+    pub offload_path: PathBuf,
+    /// Encrypt the offloaded states with a key that only lives in memory, so that they cannot be read once the model is unloaded.
+    /// The server turns it on whenever a storage key is given.
+    pub encrypt: bool,
+    /// Seconds a cached state stays unused before it is compressed. `0` never compresses.
+    pub compress_after: u64,
+    /// How idle states are compressed (`Fp16` or `Int8`).
+    pub compression: StateCompression,
+}
+
+/// Limits of the GPU temperature and power draw, over which fewer lanes are run and decoding is paused between rounds.
+/// Readings come from NVML with the `nvml` feature, or else from `hwmon` and thermal zones in sysfs.
+#[derive(Debug, Derivative, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct GovernorOption {
+    /// Temperature in °C over which the load is reduced. `0` disables the limit.
+    pub max_temperature: f32,
+    /// Power draw in watts over which the load is reduced. `0` disables the limit.
+    pub max_power: f32,
+    /// Fewest lanes kept running while over the limits.
+    #[derivative(Default(value = "1"))]
+    pub min_batch: usize,
+    /// Milliseconds paused before each round of inference while over the limits.
+    #[derivative(Default(value = "50"))]
+    pub pause: u64,
+    /// How far below the limits, as a fraction of them, readings must fall before lanes are given back.
+    #[derivative(Default(value = "0.05"))]
+    pub margin: f32,
+    /// Index of the GPU watched through NVML.
+    pub device: u32,
+    /// Sysfs file of the temperature in millidegrees, instead of the one found.
+    #[salvo(schema(value_type = Option<String>))]
+    pub temperature_path: Option<PathBuf>,
+    /// Sysfs file of the power draw in microwatts, instead of the one found.
+    #[salvo(schema(value_type = Option<String>))]
+    pub power_path: Option<PathBuf>,
+}
+
+impl GovernorOption {
+    pub fn is_enabled(&self) -> bool {
+        self.max_temperature > 0.0 || self.max_power > 0.0
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 pub enum Precision {
     #[default]
     Fp16,
     Fp32,
 }
 
-#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 pub enum AdapterOption {
     #[default]
     Auto,
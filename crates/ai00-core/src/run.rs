@@ -1,8 +1,12 @@
 use std::{
     cmp::Ordering,
     collections::HashMap,
-    path::PathBuf,
-    sync::Arc,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
@@ -10,6 +14,7 @@ use anyhow::Result;
 use bnf_sampler::{grammar::Grammar, vocabulary::Vocabulary};
 use derivative::Derivative;
 use flume::{Receiver, Sender};
+use half::f16;
 use itertools::Itertools;
 use qp_trie::Trie;
 use salvo::oapi::ToSchema;
@@ -23,20 +28,41 @@ use web_rwkv::{
         softmax::softmax,
         Job, JobBuilder, JobRuntime,
     },
-    tensor::{TensorCpu, TensorInit},
+    tensor::{shape::Shape, TensorCpu, TensorInit, TensorShape},
     tokenizer::Tokenizer,
+    wgpu::Maintain,
 };
 
 use crate::{
-    sampler::{bnf::BnfSampler, Transformer},
-    Environment, FinishReason, GenerateRequest, ReloadRequest, Token, TokenCounter,
+    governor::Governor,
+    reload::{StateCacheOption, StateCompression},
+    sampler::{
+        bnf::BnfSampler,
+        dry::DryPenalty,
+        noise::GumbelNoise,
+        processor::{self, ProcessorRequest},
+        reasoning::ReasoningLimiter,
+        sigma::TopNSigma,
+        xtc::ExcludeTopChoices,
+        Transformer,
+    },
+    storage::Sealer,
+    Environment, FinishReason, GenerateRequest, Priority, RateQuota, ReloadRequest, RuntimeError,
+    SlotStats, Token, TokenCounter, TraceStep, REQUEST_ID,
 };
 
 const END_OF_LINE_TOKEN: u16 = 261;
 const PROMPT_CACHE_TOKENS: usize = 32;
-const MAX_CACHE_ITEMS: usize = 256;
+/// Number of values sharing one scale in a state compressed to `Int8`.
+const INT8_BLOCK: usize = 64;
 const SAMPLER_ARENA_CAPACITY: usize = 1048576;
 const GRAMMAR_ARENA_CAPACITY: usize = 1024;
+const PAUSE_INTERVAL: Duration = Duration::from_millis(10);
+const TRACE_CANDIDATES: usize = 8;
+/// Idle time after which the token bucket of an owner is dropped; it would be full again by then.
+const BUCKET_TTL: Duration = Duration::from_secs(60);
+/// Weight of the latest forward pass in the measured speeds.
+const SPEED_SMOOTHING: f32 = 0.2;
 
 #[derive(Debug)]
 pub enum SlotResult {
@@ -130,6 +156,14 @@ impl Payload {
     pub fn is_empty(&self) -> bool {
         matches!(self, Self::Empty)
     }
+
+    /// Returns `true` if the payload is [`Busy`].
+    ///
+    /// [`Busy`]: Payload::Busy
+    #[must_use]
+    pub fn is_busy(&self) -> bool {
+        matches!(self, Self::Busy(_))
+    }
 }
 
 #[repr(transparent)]
@@ -225,15 +259,58 @@ pub struct GenerateContext {
     pub buffer: Vec<u8>,
     /// Tokens that are output by the model.
     pub model_tokens: Vec<u16>,
-    /// Compiled BNF schema, if any.
+    /// Compiled BNF schema and the other logits transformers. Built when first queued, and kept from then on,
+    /// so that a preempted generation goes on with e.g. its grammar, DRY history and noise as they are.
     #[derivative(Debug = "ignore")]
-    pub transformers: Vec<Arc<RwLock<dyn Transformer + Send + Sync>>>,
+    pub transformers: Option<Vec<Arc<RwLock<dyn Transformer + Send + Sync>>>>,
     /// For measuring time used.
     pub instant: Option<Instant>,
     /// Generate request provided by the caller.
     pub request: GenerateRequest,
     /// To send back generated tokens.
     pub sender: Sender<Token>,
+    /// Whether [`Token::Start`] has been sent, so that a replayed request does not send it again.
+    pub started: bool,
+}
+
+impl GenerateContext {
+    /// Whether the model has not generated any token for the request yet, so that it can start over unnoticed.
+    pub(crate) fn is_prefilling(&self) -> bool {
+        self.model_tokens.is_empty()
+    }
+
+    /// Reset the request to its prompt, to be queued again.
+    pub(crate) fn replay(self) -> Self {
+        Self {
+            prompt_cached: false,
+            prefix: Default::default(),
+            suffix: Tokens(self.prompt_tokens.clone()),
+            transformers: None,
+            instant: None,
+            ..self
+        }
+    }
+
+    /// Run `f` with the id of the call in scope, so that the log lines in it carry the id.
+    fn scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        match &self.request.request_id {
+            Some(id) => REQUEST_ID.sync_scope(id.clone(), f),
+            None => f(),
+        }
+    }
+
+    /// Finish the request with the error.
+    pub(crate) fn fail(self, error: RuntimeError) {
+        let _ = self.sender.send(Token::Error(error));
+    }
+
+    /// Returns `true` if the receiver lags behind too much so that generation should pause.
+    fn is_congested(&self) -> bool {
+        match self.request.max_pending {
+            Some(max) => !self.sender.is_disconnected() && self.sender.len() >= max,
+            None => false,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -242,6 +319,39 @@ struct CachedItem<T> {
     instant: Instant,
 }
 
+/// Output tokens an owner may generate right now, refilled at the rate of its quota.
+/// Holds at most one second of tokens, and goes into debt when lanes of the owner run together.
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f32,
+    instant: Instant,
+}
+
+impl TokenBucket {
+    fn new(quota: &RateQuota) -> Self {
+        Self {
+            tokens: Self::burst(quota),
+            instant: Instant::now(),
+        }
+    }
+
+    fn burst(quota: &RateQuota) -> f32 {
+        quota.tokens_per_sec.max(1.0)
+    }
+
+    /// Refill the bucket for the time elapsed. Returns `true` if a token may be generated.
+    fn refill(&mut self, quota: &RateQuota) -> bool {
+        let elapsed = self.instant.elapsed().as_secs_f32();
+        self.instant = Instant::now();
+        self.tokens = (self.tokens + elapsed * quota.tokens_per_sec).min(Self::burst(quota));
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self, quota: &RateQuota) {
+        self.tokens = (self.tokens - 1.0).max(-Self::burst(quota));
+    }
+}
+
 impl<T> CachedItem<T> {
     pub fn new(backed: T) -> Self {
         Self {
@@ -267,16 +377,255 @@ impl<T> Clone for CachedItem<T> {
         }
     }
 }
+
+/// A state in the prompt cache, compressed once it stays unused for long, or offloaded to disk.
+#[derive(Derivative)]
+#[derivative(Debug)]
+enum BackedState {
+    Full(TensorCpu<f32>),
+    Fp16(Shape, Vec<f16>),
+    Int8 {
+        shape: Shape,
+        data: Vec<i8>,
+        /// One for each [`INT8_BLOCK`] values.
+        scale: Vec<f32>,
+    },
+    /// Written to the file, which is removed along with the state.
+    Disk {
+        path: PathBuf,
+        /// Filled by a prefetch ahead of the checkout, which takes it out of memory again.
+        loaded: Mutex<Option<TensorCpu<f32>>>,
+        prefetching: AtomicBool,
+        /// Set if the file is encrypted.
+        #[derivative(Debug = "ignore")]
+        cipher: Option<Arc<Sealer>>,
+    },
+}
+
+impl Drop for BackedState {
+    fn drop(&mut self) {
+        if let Self::Disk { path, .. } = self {
+            if let Err(err) = std::fs::remove_file(&path) {
+                log::warn!("failed to remove offloaded state {}: {err}", path.display());
+            }
+        }
+    }
+}
+
+impl BackedState {
+    fn compress(&self, compression: StateCompression) -> Option<Self> {
+        let Self::Full(tensor) = self else {
+            return None;
+        };
+        let shape = tensor.shape();
+        let state = match compression {
+            StateCompression::Fp16 => {
+                Self::Fp16(shape, tensor.iter().copied().map(f16::from_f32).collect())
+            }
+            StateCompression::Int8 => {
+                let mut data = Vec::with_capacity(tensor.len());
+                let mut scale = Vec::with_capacity(tensor.len().div_ceil(INT8_BLOCK));
+                for block in tensor.chunks(INT8_BLOCK) {
+                    let max = block.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+                    let factor = if max > 0.0 { max / 127.0 } else { 1.0 };
+                    data.extend(block.iter().map(|x| (x / factor).round() as i8));
+                    scale.push(factor);
+                }
+                Self::Int8 { shape, data, scale }
+            }
+        };
+        Some(state)
+    }
+
+    /// The state in full precision, unless it is on disk.
+    fn decompress(&self) -> Option<TensorCpu<f32>> {
+        let (shape, data) = match self {
+            Self::Full(tensor) => return Some(tensor.clone()),
+            Self::Fp16(shape, data) => (*shape, data.iter().map(|x| x.to_f32()).collect_vec()),
+            Self::Int8 { shape, data, scale } => {
+                let data = data
+                    .chunks(INT8_BLOCK)
+                    .zip(scale)
+                    .flat_map(|(block, scale)| block.iter().map(move |&x| x as f32 * scale))
+                    .collect_vec();
+                (*shape, data)
+            }
+            Self::Disk { .. } => return None,
+        };
+        let tensor = TensorCpu::from_data(shape, data).expect("compressed state keeps its shape");
+        Some(tensor)
+    }
+
+    /// The state in full precision, read from the disk if offloaded and not prefetched.
+    async fn restore(&self) -> Result<TensorCpu<f32>> {
+        let Self::Disk {
+            loaded,
+            prefetching,
+            ..
+        } = self
+        else {
+            return Ok(self.decompress().expect("state is in memory"));
+        };
+        let mut loaded = loaded.lock().await;
+        prefetching.store(false, AtomicOrdering::Relaxed);
+        match loaded.take() {
+            Some(state) => Ok(state),
+            None => self.load().await,
+        }
+    }
+
+    /// Read an offloaded state into memory ahead of its checkout.
+    async fn prefetch(&self) -> Result<()> {
+        let Self::Disk { loaded, .. } = self else {
+            return Ok(());
+        };
+        let mut loaded = loaded.lock().await;
+        if loaded.is_none() {
+            *loaded = Some(self.load().await?);
+        }
+        Ok(())
+    }
+
+    /// Drop an offloaded state prefetched for a checkout that never came. Returns whether there was one.
+    fn unload(&self) -> bool {
+        let Self::Disk {
+            loaded,
+            prefetching,
+            ..
+        } = self
+        else {
+            return false;
+        };
+        let Ok(mut loaded) = loaded.try_lock() else {
+            return false;
+        };
+        prefetching.store(false, AtomicOrdering::Relaxed);
+        loaded.take().is_some()
+    }
+
+    /// Whether the state takes up memory: any but an offloaded one, unless it is prefetched.
+    fn resident(&self) -> bool {
+        match self {
+            Self::Disk { loaded, .. } => loaded.try_lock().map_or(true, |loaded| loaded.is_some()),
+            _ => true,
+        }
+    }
+
+    /// Read an offloaded state from its file.
+    async fn load(&self) -> Result<TensorCpu<f32>> {
+        let Self::Disk { path, cipher, .. } = self else {
+            anyhow::bail!("state is not offloaded");
+        };
+        let bytes = tokio::fs::read(path).await?;
+        let cipher = cipher.clone();
+        tokio::task::spawn_blocking(move || match cipher {
+            Some(cipher) => Self::read(&cipher.open(&bytes)?),
+            None => Self::read(&bytes),
+        })
+        .await?
+    }
+
+    /// Encode the state in its current form: a tag, the shape, and the values.
+    fn write(&self) -> Option<Vec<u8>> {
+        let (tag, shape) = match self {
+            Self::Full(tensor) => (0u8, tensor.shape()),
+            Self::Fp16(shape, _) => (1, *shape),
+            Self::Int8 { shape, .. } => (2, *shape),
+            Self::Disk { .. } => return None,
+        };
+        let mut bytes = vec![tag];
+        for dim in shape.iter() {
+            bytes.extend((*dim as u64).to_le_bytes());
+        }
+        match self {
+            Self::Full(tensor) => tensor.iter().for_each(|x| bytes.extend(x.to_le_bytes())),
+            Self::Fp16(_, data) => data.iter().for_each(|x| bytes.extend(x.to_le_bytes())),
+            Self::Int8 { data, scale, .. } => {
+                bytes.extend((scale.len() as u64).to_le_bytes());
+                scale.iter().for_each(|x| bytes.extend(x.to_le_bytes()));
+                bytes.extend(data.iter().map(|&x| x as u8));
+            }
+            Self::Disk { .. } => unreachable!(),
+        }
+        Some(bytes)
+    }
+
+    /// Decode a state written by [`BackedState::write`] into full precision.
+    fn read(bytes: &[u8]) -> Result<TensorCpu<f32>> {
+        fn split<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N]> {
+            if bytes.len() < N {
+                anyhow::bail!("offloaded state is truncated");
+            }
+            let (head, tail) = bytes.split_at(N);
+            *bytes = tail;
+            Ok(head.try_into()?)
+        }
+
+        let mut bytes = bytes;
+        let [tag] = split::<1>(&mut bytes)?;
+        let mut shape = [0usize; 4];
+        for dim in shape.iter_mut() {
+            *dim = u64::from_le_bytes(split(&mut bytes)?) as usize;
+        }
+        let shape = Shape::from(shape);
+        let state = match tag {
+            0 => {
+                let data = bytes
+                    .chunks_exact(4)
+                    .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]));
+                Self::Full(TensorCpu::from_data(shape, data.collect_vec())?)
+            }
+            1 => {
+                let data = bytes
+                    .chunks_exact(2)
+                    .map(|x| f16::from_le_bytes([x[0], x[1]]));
+                Self::Fp16(shape, data.collect())
+            }
+            2 => {
+                let len = u64::from_le_bytes(split(&mut bytes)?) as usize;
+                let scale = (0..len)
+                    .map(|_| split(&mut bytes).map(f32::from_le_bytes))
+                    .try_collect()?;
+                let data = bytes.iter().map(|&x| x as i8).collect();
+                Self::Int8 { shape, data, scale }
+            }
+            tag => anyhow::bail!("unknown tag {tag} of offloaded state"),
+        };
+        Ok(state.decompress().expect("state is in memory"))
+    }
+}
+
 #[derive(Debug, Default)]
 struct Cache {
     state: Option<InitState>,
-    cache: Trie<Tokens, CachedItem<TensorCpu<f32>>>,
+    cache: Trie<Tokens, CachedItem<BackedState>>,
 }
 
 impl Cache {
-    fn maintain(&mut self) {
+    fn maintain(&mut self, option: &StateCacheOption) {
         let cache = &mut self.cache;
-        if cache.count() <= MAX_CACHE_ITEMS {
+
+        if option.compress_after > 0 {
+            let idle = Duration::from_secs(option.compress_after);
+            let compressed = cache
+                .iter()
+                .filter(|(_, item)| item.instant.elapsed() >= idle)
+                .filter_map(|(tokens, item)| {
+                    let state = item.item.compress(option.compression)?;
+                    Some((tokens.to_owned(), item.instant, state))
+                })
+                .collect_vec();
+            for (tokens, instant, state) in compressed {
+                let item = CachedItem {
+                    item: Arc::new(state),
+                    instant,
+                };
+                log::info!("compressed cached state of length {}", tokens.len());
+                cache.insert(tokens, item);
+            }
+        }
+
+        if cache.count() <= option.capacity {
             return;
         }
 
@@ -284,7 +633,7 @@ impl Cache {
         for (tokens, _) in cache
             .iter()
             .sorted_unstable_by_key(|(_, item)| item.instant.elapsed())
-            .skip(MAX_CACHE_ITEMS)
+            .skip(option.capacity)
         {
             remove.push(tokens.to_owned());
         }
@@ -308,6 +657,40 @@ impl CacheHub {
             None => &mut self.default,
         }
     }
+
+    /// The cache of an initial state, or the default one if `None`.
+    fn get_mut(&mut self, id: Option<StateId>) -> Option<&mut Cache> {
+        match id {
+            Some(id) => self.backed.get_mut(&id),
+            None => Some(&mut self.default),
+        }
+    }
+
+    /// The states in memory beyond the `capacity` most recently used ones, which are to be offloaded.
+    fn spills(&self, capacity: usize) -> Vec<(Option<StateId>, Tokens, CachedItem<BackedState>)> {
+        if capacity == 0 {
+            return vec![];
+        }
+        std::iter::once((None, &self.default))
+            .chain(self.backed.iter().map(|(id, cache)| (Some(*id), cache)))
+            .flat_map(|(id, cache)| {
+                cache
+                    .cache
+                    .iter()
+                    .filter(|(_, item)| item.item.resident())
+                    .map(move |(tokens, item)| (id, tokens.to_owned(), item.clone()))
+            })
+            .sorted_unstable_by_key(|(_, _, item)| item.instant.elapsed())
+            .skip(capacity)
+            .collect()
+    }
+}
+
+/// The prompt cache of a runtime whose device is lost, to be taken over by the next one.
+pub(crate) struct Salvage {
+    /// The model it belongs to.
+    reload: ReloadRequest,
+    caches: CacheHub,
 }
 
 #[derive(
@@ -360,17 +743,31 @@ impl<M: Serialize> ModelSerialize for Model<M> {
     }
 }
 
+/// Run `future` with the id of the call in scope, so that the log lines in it carry the id.
+async fn scoped<F: Future>(id: Option<String>, future: F) -> F::Output {
+    match id {
+        Some(id) => REQUEST_ID.scope(id, future).await,
+        None => future.await,
+    }
+}
+
 impl Environment {
     pub async fn enqueue(&self, context: GenerateContext) -> Vec<GenerateContext> {
         let mut queue = vec![];
         match self {
             Environment::Loaded(runtime) => {
-                match runtime.queue(context).await.expect("queue task error") {
-                    SlotResult::Success(batch) => log::info!("queued task at slot {batch}"),
-                    SlotResult::Fault(batch) => log::info!("swapped task at slot {batch}"),
-                    SlotResult::Failure(context) => queue.push(*context),
-                    SlotResult::Error(reason) => log::warn!("queue task failed: {}", reason),
-                }
+                let id = context.request.request_id.clone();
+                let requeue = scoped(id, async {
+                    match runtime.queue(context).await.expect("queue task error") {
+                        SlotResult::Success(batch) => log::info!("queued task at slot {batch}"),
+                        SlotResult::Fault(batch) => log::info!("swapped task at slot {batch}"),
+                        SlotResult::Failure(context) => return Some(*context),
+                        SlotResult::Error(reason) => log::warn!("queue task failed: {}", reason),
+                    }
+                    None
+                })
+                .await;
+                queue.extend(requeue);
             }
             Environment::None => queue.push(context),
         };
@@ -378,6 +775,24 @@ impl Environment {
     }
 }
 
+/// Speeds of the recent forward passes, smoothed. Forgotten whenever the model runs dry.
+#[derive(Debug, Default, Clone, Copy)]
+struct Speed {
+    /// Prompt tokens read per second, over the passes reading any prompt.
+    prefill: Option<f32>,
+    /// Seconds a pass takes, over the passes of decoding lanes only.
+    step: Option<f32>,
+}
+
+impl Speed {
+    fn smooth(value: &mut Option<f32>, sample: f32) {
+        *value = Some(match *value {
+            Some(x) => x + (sample - x) * SPEED_SMOOTHING,
+            None => sample,
+        });
+    }
+}
+
 pub struct Runtime {
     context: Context,
     reload: ReloadRequest,
@@ -388,7 +803,31 @@ pub struct Runtime {
     tokenizer: Arc<Tokenizer>,
     vocab: Arc<Vocabulary>,
     slots: Mutex<Vec<SlotState>>,
+    /// Lanes under processing, kept here rather than in the model loop so that they outlive an abort of it.
+    payloads: Mutex<Vec<Payload>>,
     caches: Mutex<CacheHub>,
+    /// Number of slots held by each quota owner.
+    owners: Mutex<HashMap<String, usize>>,
+    /// Token buckets of the owners whose output rate is capped.
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+    /// Reduces the load while the GPU is too hot or draws too much power.
+    governor: Option<Governor>,
+    /// Encrypts the offloaded states. The key is generated at each load and never leaves the memory.
+    cipher: Option<Arc<Sealer>>,
+    /// Whether the last forward pass timed out.
+    stalled: AtomicBool,
+    /// Where a stuck device is reported to the supervisor.
+    faults: Option<Sender<String>>,
+    /// Tokens left to compute in the lanes under processing, as of the latest round.
+    backlog: AtomicUsize,
+    /// The fewest tokens any lane under processing has left to generate, as of the latest round.
+    owed: AtomicUsize,
+    /// How fast the recent forward passes went.
+    speed: Mutex<Speed>,
+    /// The highest priority of the requests handed back for want of a slot since the latest round.
+    demand: Mutex<Option<Priority>>,
+    /// Generations that gave their lanes up to ones of a higher priority, to be queued again.
+    preempted: Mutex<Vec<GenerateContext>>,
 }
 
 impl Runtime {
@@ -427,6 +866,12 @@ impl Runtime {
         let state = Arc::new(builder.state());
         let model = Arc::new(Model(builder.model()));
         let runtime = JobRuntime::new(builder).await;
+        let cipher = reload
+            .state_cache
+            .encrypt
+            .then(|| Arc::new(Sealer::generate()));
+        let governor = Governor::new(&reload.governor, reload.max_batch);
+        let payloads = vec![Payload::default(); state.num_batch()];
 
         Self {
             context,
@@ -438,10 +883,27 @@ impl Runtime {
             tokenizer: Arc::new(tokenizer),
             vocab: Arc::new(vocab),
             slots: Mutex::new(slots),
+            payloads: Mutex::new(payloads),
             caches: Mutex::new(caches),
+            owners: Default::default(),
+            buckets: Default::default(),
+            governor,
+            cipher,
+            stalled: AtomicBool::new(false),
+            faults: None,
+            backlog: AtomicUsize::new(0),
+            owed: AtomicUsize::new(0),
+            speed: Mutex::new(Speed::default()),
+            demand: Mutex::new(None),
+            preempted: Mutex::new(vec![]),
         }
     }
 
+    /// Report to `faults` if the device gets stuck.
+    pub(crate) fn report_faults(&mut self, faults: Sender<String>) {
+        self.faults = Some(faults);
+    }
+
     #[inline]
     pub fn context(&self) -> &Context {
         &self.context
@@ -483,6 +945,78 @@ impl Runtime {
         states
     }
 
+    pub async fn slot_stats(&self) -> SlotStats {
+        let slots = self.slots.lock().await;
+        let mut stats = SlotStats::default();
+        for slot in slots.iter() {
+            match slot {
+                SlotState::Idle(_, _) => stats.idle += 1,
+                SlotState::Wait(_) => stats.waiting += 1,
+                SlotState::Busy => stats.busy += 1,
+            }
+        }
+        stats
+    }
+
+    /// Take the generations preempted since the last call, to be queued again.
+    pub async fn preempted(&self) -> Vec<GenerateContext> {
+        std::mem::take(&mut *self.preempted.lock().await)
+    }
+
+    /// Tokens left to compute in the slots, both the ones under processing and the ones about to be.
+    pub async fn backlog(&self) -> usize {
+        let slots = self.slots.lock().await;
+        let waiting = slots
+            .iter()
+            .map(|slot| match slot {
+                SlotState::Wait(context) => context.suffix.len(),
+                _ => 0,
+            })
+            .sum::<usize>();
+        waiting + self.backlog.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Prompt tokens read per second recently, if any prompt has been read since the model was last idle.
+    pub async fn prefill_rate(&self) -> Option<f32> {
+        self.speed.lock().await.prefill
+    }
+
+    /// Time until a slot frees up: zero if one is idle already,
+    /// or else the tokens the closest lane still owes at the recent decoding speed.
+    pub async fn slot_wait(&self) -> Option<Duration> {
+        let slots = self.slots.lock().await;
+        if slots.iter().any(|slot| matches!(slot, SlotState::Idle(..))) {
+            return Some(Duration::ZERO);
+        }
+        drop(slots);
+        let step = self.speed.lock().await.step?;
+        let owed = self.owed.load(AtomicOrdering::Relaxed);
+        Some(Duration::from_secs_f32(step * owed as f32))
+    }
+
+    /// Account a forward pass that read `prefill` prompt tokens and took `elapsed`.
+    async fn measure(&self, prefill: usize, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f32();
+        if seconds <= 0.0 {
+            return;
+        }
+        let mut speed = self.speed.lock().await;
+        match prefill {
+            0 => Speed::smooth(&mut speed.step, seconds),
+            tokens => Speed::smooth(&mut speed.prefill, tokens as f32 / seconds),
+        }
+    }
+
+    pub async fn num_cached_states(&self) -> usize {
+        let caches = self.caches.lock().await;
+        caches.default.cache.count()
+            + caches
+                .backed
+                .values()
+                .map(|x| x.cache.count())
+                .sum::<usize>()
+    }
+
     pub async fn load_init_state(&self, state: InitState) {
         let mut caches = self.caches.lock().await;
         caches.backed.insert(
@@ -499,6 +1033,145 @@ impl Runtime {
         caches.backed.remove(&id);
     }
 
+    /// Drop the cached states along the token sequences, i.e., the ones backed while reading them.
+    /// Returns the number of states dropped, or that would be dropped on a dry run.
+    pub async fn forget(&self, sequences: &[Vec<u16>], dry_run: bool) -> usize {
+        let mut caches = self.caches.lock().await;
+        let caches = &mut *caches;
+        let mut count = 0;
+        for cache in std::iter::once(&mut caches.default).chain(caches.backed.values_mut()) {
+            let keys = cache
+                .cache
+                .iter()
+                .map(|(tokens, _)| tokens)
+                .filter(|tokens| !tokens.0.is_empty())
+                .filter(|tokens| sequences.iter().any(|x| x.starts_with(&tokens.0)))
+                .cloned()
+                .collect_vec();
+            count += keys.len();
+            if !dry_run {
+                for tokens in keys {
+                    cache.cache.remove(&tokens);
+                }
+            }
+        }
+        count
+    }
+
+    /// Take apart the runtime. Returns the prompt cache, which lives in the host memory or on the disk,
+    /// and the requests in the slots, which are still to be finished.
+    pub(crate) fn salvage(self) -> (Salvage, Vec<GenerateContext>) {
+        let Self {
+            reload,
+            slots,
+            payloads,
+            caches,
+            preempted,
+            ..
+        } = self;
+        let waiting = slots
+            .into_inner()
+            .into_iter()
+            .filter_map(|slot| match slot {
+                SlotState::Wait(context) => Some(*context),
+                _ => None,
+            });
+        let busy = payloads
+            .into_inner()
+            .into_iter()
+            .filter_map(|payload| match payload {
+                Payload::Busy(context) => Some(context),
+                _ => None,
+            });
+        let contexts = waiting.chain(busy).chain(preempted.into_inner()).collect();
+        let salvage = Salvage {
+            reload,
+            caches: caches.into_inner(),
+        };
+        (salvage, contexts)
+    }
+
+    /// Drop the runtime, finishing the requests in its slots as canceled.
+    pub(crate) fn cancel(self) {
+        let (_, contexts) = self.salvage();
+        for context in contexts {
+            context.fail(RuntimeError::Canceled);
+        }
+    }
+
+    /// Take over the prompt cache of a runtime lost to its device, if it runs the same model.
+    pub(crate) async fn restore(&self, salvage: Salvage) {
+        let Salvage {
+            reload,
+            caches: mut salvaged,
+        } = salvage;
+        if reload != self.reload {
+            log::warn!("prompt cache of the lost runtime dropped: the model is changed");
+            return;
+        }
+
+        let mut caches = self.caches.lock().await;
+        caches.default.cache = salvaged.default.cache;
+        for (id, item) in caches.backed.iter_mut() {
+            if let Some(cache) = salvaged.backed.remove(id) {
+                item.cache = cache.cache;
+            }
+        }
+        drop(caches);
+
+        let count = self.num_cached_states().await;
+        log::info!("restored {count} cached states of the lost runtime");
+    }
+
+    /// Swap initial states without touching the model weights.
+    /// Caches of states that are kept are preserved.
+    pub fn update_init_states(
+        &mut self,
+        reload: ReloadRequest,
+        removed: &[StateId],
+        added: Vec<InitState>,
+    ) {
+        let caches = self.caches.get_mut();
+        for id in removed {
+            caches.backed.remove(id);
+        }
+        for state in added {
+            let id = state.id;
+            let item = Cache {
+                state: Some(state),
+                cache: Trie::new(),
+            };
+            caches.backed.insert(id, item);
+        }
+
+        // states listed in the config take precedence over the ones loaded otherwise
+        let default = reload
+            .state
+            .iter()
+            .filter(|state| state.default)
+            .find_map(|state| caches.backed.get(&state.id))
+            .or_else(|| {
+                caches
+                    .backed
+                    .values()
+                    .find(|item| item.state.as_ref().is_some_and(|state| state.default))
+            })
+            .and_then(|item| item.state.clone());
+        let changed = match (&caches.default.state, &default) {
+            (Some(lhs), Some(rhs)) => lhs.id != rhs.id,
+            (None, None) => false,
+            _ => true,
+        };
+        if changed {
+            caches.default = Cache {
+                state: default,
+                cache: Trie::new(),
+            };
+        }
+
+        self.reload = reload;
+    }
+
     pub async fn serialize_model(&self, path: PathBuf) -> Result<()> {
         let model = self.model.clone();
         let handle = tokio::task::spawn_blocking(move || {
@@ -508,6 +1181,21 @@ impl Runtime {
         handle.await?
     }
 
+    /// The cached state right after the `tokens`, which start from the initial state `id`.
+    pub async fn cached_state(&self, id: StateId, tokens: &[u16]) -> Result<TensorCpu<f32>> {
+        let item = {
+            let mut caches = self.caches.lock().await;
+            let cache = &caches.fetch(id).cache;
+            cache
+                .get(tokens.as_token_slice())
+                .map(|item| item.item.clone())
+        };
+        match item {
+            Some(item) => item.restore().await,
+            None => anyhow::bail!("no state is cached after the {} tokens", tokens.len()),
+        }
+    }
+
     /// Search for the longest common prefix in the memory cache and checkout the state from that point.
     /// Should there be a cache miss, an initial state is returned.
     async fn checkout(
@@ -515,7 +1203,7 @@ impl Runtime {
         id: StateId,
         tokens: &[u16],
         batch: usize,
-    ) -> (Vec<u16>, Arc<TensorCpu<f32>>) {
+    ) -> (Vec<u16>, TensorCpu<f32>) {
         let mut caches = self.caches.lock().await;
 
         let Cache { state, cache } = caches.fetch(id);
@@ -527,16 +1215,50 @@ impl Runtime {
         log::info!("slot {} checks out backed cache of length {}", batch, len);
 
         let prefix = prefix[0..len].to_vec();
-        let state = state.clone().map(|state| state.data);
+        let init = state.clone().map(|state| state.data);
         let reload = match cache.remove(prefix[..].as_token_slice()) {
             Some(reload) => CachedItem::update(reload),
-            None => CachedItem::new(state.unwrap_or_else(|| self.state.init())),
+            None => return (vec![], init.unwrap_or_else(|| self.state.init())),
         };
-        if len > 0 {
-            let key = Tokens(prefix.clone());
-            cache.insert(key, reload.clone());
+        let state = match reload.item.restore().await {
+            Ok(state) => state,
+            Err(err) => {
+                log::error!("failed to restore cached state: {err}");
+                return (vec![], init.unwrap_or_else(|| self.state.init()));
+            }
+        };
+
+        // the state is likely to be checked out again soon, so it is kept in memory as is
+        let item = match reload.item.as_ref() {
+            BackedState::Full(_) => reload,
+            _ => CachedItem::new(BackedState::Full(state.clone())),
+        };
+        cache.insert(Tokens(prefix.clone()), item);
+        (prefix, state)
+    }
+
+    /// Start reading the offloaded state that a waiting request is going to check out, so that it is ready by then.
+    async fn prefetch(&self, id: StateId, tokens: &[u16]) {
+        let mut caches = self.caches.lock().await;
+        let cache = &caches.fetch(id).cache;
+        let prefix = cache.longest_common_prefix(tokens.as_token_slice());
+        let Some(item) = (1..=prefix.len())
+            .rev()
+            .find_map(|len| cache.get(prefix[0..len].as_token_slice()))
+        else {
+            return;
+        };
+        let BackedState::Disk { prefetching, .. } = item.item.as_ref() else {
+            return;
+        };
+        if !prefetching.swap(true, AtomicOrdering::Relaxed) {
+            let state = item.item.clone();
+            tokio::spawn(async move {
+                if let Err(err) = state.prefetch().await {
+                    log::warn!("failed to prefetch cached state: {err}");
+                }
+            });
         }
-        (prefix, reload.item)
     }
 
     /// Compile and cache the given schema into a BNF sampler.
@@ -553,6 +1275,57 @@ impl Runtime {
         Ok(BnfSampler::new(sampler))
     }
 
+    /// Build the logits transformers a request asks for.
+    async fn transformers(
+        &self,
+        request: &GenerateRequest,
+        prompt_tokens: &[u16],
+    ) -> Result<Vec<Arc<RwLock<dyn Transformer + Send + Sync>>>, String> {
+        // compile the BNF schema.
+        let mut transformers = Vec::<Arc<RwLock<dyn Transformer + Send + Sync>>>::new();
+        if let Some(schema) = request.bnf_schema.clone() {
+            match self.compile_bnf_schema(schema).await {
+                Ok(bnf) => transformers.push(Arc::new(RwLock::new(bnf))),
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+        if request.dry.is_enabled() {
+            match DryPenalty::new(&request.dry, prompt_tokens, &self.tokenizer) {
+                Ok(dry) => transformers.push(Arc::new(RwLock::new(dry))),
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+        if request.logit_noise > 0.0 {
+            let noise = GumbelNoise::new(request.logit_noise);
+            transformers.push(Arc::new(RwLock::new(noise)));
+        }
+        if request.top_n_sigma > 0.0 {
+            let sigma = TopNSigma(request.top_n_sigma);
+            transformers.push(Arc::new(RwLock::new(sigma)));
+        }
+        if request.xtc.is_enabled() {
+            let xtc = ExcludeTopChoices(request.xtc.clone());
+            transformers.push(Arc::new(RwLock::new(xtc)));
+        }
+        if let Some(budget) = &request.reasoning_budget {
+            match ReasoningLimiter::new(budget, self.tokenizer.clone()) {
+                Ok(limiter) => transformers.push(Arc::new(RwLock::new(limiter))),
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+        for ProcessorRequest { name, options } in &request.processors {
+            let Some(processor) = processor::find(name) else {
+                return Err(format!("unknown logits processor {name}"));
+            };
+            match processor.create(options, prompt_tokens) {
+                Ok(processor) => transformers.push(Arc::new(RwLock::new(processor))),
+                Err(err) => return Err(format!("{name}: {err}")),
+            }
+        }
+
+        Ok(transformers)
+    }
+
     /// Queue an inference task.
     pub async fn queue(&self, context: GenerateContext) -> Result<SlotResult> {
         let mut slots = self.slots.lock().await;
@@ -563,15 +1336,37 @@ impl Runtime {
             None => return Ok(SlotResult::Error("empty task is not queued".into())),
         };
 
-        // compile the BNF schema.
-        let mut transformers = Vec::<Arc<RwLock<dyn Transformer + Send + Sync>>>::new();
-        if let Some(schema) = context.request.bnf_schema.clone() {
-            match self.compile_bnf_schema(schema).await {
-                Ok(bnf) => transformers.push(Arc::new(RwLock::new(bnf))),
-                Err(err) => return Ok(SlotResult::Error(err.to_string())),
+        // hand the request back if its owner already holds too many slots, so that others go first
+        if let Some(quota) = &context.request.quota {
+            let owners = self.owners.lock().await;
+            let held = owners.get(&quota.owner).copied().unwrap_or_default();
+            drop(owners);
+            if quota.max_slots > 0 && held >= quota.max_slots {
+                self.prefetch(context.request.state, &tokens).await;
+                return Ok(SlotResult::Failure(
+                    GenerateContext {
+                        prefix: Default::default(),
+                        suffix: Tokens([tokens, vec![last]].concat()),
+                        ..context
+                    }
+                    .into(),
+                ));
             }
         }
 
+        // a preempted generation goes on with its transformers as they are, e.g., where its grammar is at
+        let transformers = match &context.transformers {
+            Some(transformers) => transformers.clone(),
+            None => match self
+                .transformers(&context.request, &context.prompt_tokens)
+                .await
+            {
+                Ok(transformers) => transformers,
+                Err(err) => return Ok(SlotResult::Error(err)),
+            },
+        };
+        let transformers = Some(transformers);
+
         // find the best idle slot by:
         // 1. find the slot that matches the context (continue)
         // 2. find an empty slot
@@ -596,20 +1391,28 @@ impl Runtime {
         match choice {
             // we cannot find a slot because all slots are occupied
             // in this case, we hand the request back to the caller
-            None => Ok(SlotResult::Failure(
-                GenerateContext {
-                    prefix: Default::default(),
-                    suffix: Tokens([tokens, vec![last]].concat()),
-                    transformers,
-                    ..context
-                }
-                .into(),
-            )),
+            None => {
+                let priority = context.request.priority;
+                let mut demand = self.demand.lock().await;
+                *demand = (*demand).max(Some(priority));
+                drop(demand);
+                self.prefetch(context.request.state, &tokens).await;
+                Ok(SlotResult::Failure(
+                    GenerateContext {
+                        prefix: Default::default(),
+                        suffix: Tokens([tokens, vec![last]].concat()),
+                        transformers,
+                        ..context
+                    }
+                    .into(),
+                ))
+            }
             // back a non-relative and non-empty slot and use it for our new context
             Some(SlotChoice::Back(batch)) => {
                 log::info!("start at non-empty slot {}", batch);
                 let (prefix, reload) = self.checkout(context.request.state, &tokens, batch).await;
-                self.state.load(batch, reload.as_ref().clone())?;
+                self.state.load(batch, reload)?;
+                self.hold(&context.request).await;
 
                 let tokens = [tokens, vec![last]].concat();
                 let len = prefix.len();
@@ -630,7 +1433,8 @@ impl Runtime {
             Some(SlotChoice::Empty(batch)) => {
                 log::info!("start at empty slot {}", batch);
                 let (prefix, reload) = self.checkout(context.request.state, &tokens, batch).await;
-                self.state.load(batch, reload.as_ref().clone())?;
+                self.state.load(batch, reload)?;
+                self.hold(&context.request).await;
 
                 let tokens = [tokens, vec![last]].concat();
                 let len = prefix.len();
@@ -649,6 +1453,7 @@ impl Runtime {
             // continue from an existing slot; no need backing as well
             Some(SlotChoice::Continue(batch, len)) => {
                 log::info!("continue at slot {}", batch);
+                self.hold(&context.request).await;
                 let tokens = [tokens, vec![last]].concat();
                 let state = SlotState::Wait(
                     GenerateContext {
@@ -665,6 +1470,102 @@ impl Runtime {
         }
     }
 
+    /// Handle a forward pass that timed out. The lane with the most tokens in the pass is failed, and the states
+    /// of the other lanes in it, which are lost along with the pass, are rebuilt from their tokens.
+    /// If the device does not finish the pass within the timeout either, or the pass after also times out,
+    /// the device is taken as stuck and reported.
+    async fn stall(&self, payloads: &mut [Payload], loads: &[usize]) -> Result<()> {
+        let timeout = self.reload.infer_timeout;
+        // the pass is dropped, but the device may still be running it; states are only loaded once it is idle
+        let context = self.context.clone();
+        let idle = tokio::task::spawn_blocking(move || context.device.poll(Maintain::Wait));
+        let idle = tokio::time::timeout(Duration::from_secs(timeout), idle)
+            .await
+            .is_ok();
+        if !idle || self.stalled.swap(true, AtomicOrdering::Relaxed) {
+            let error = format!("device stuck: forward passes take over {timeout}s");
+            match &self.faults {
+                // the supervisor aborts the loop here and salvages the lanes as they are
+                Some(faults) => {
+                    let _ = faults.send(error);
+                    return std::future::pending().await;
+                }
+                None => anyhow::bail!(error),
+            }
+        }
+
+        let culprit = loads
+            .iter()
+            .enumerate()
+            .filter(|(_, &load)| load > 0)
+            .max_by_key(|(_, &load)| load)
+            .map(|(batch, _)| batch);
+        let mut slots = self.slots.lock().await;
+        for (batch, payload) in payloads.iter_mut().enumerate() {
+            if loads.get(batch).map_or(true, |&load| load == 0) {
+                continue;
+            }
+            match std::mem::take(payload) {
+                Payload::Busy(context) if Some(batch) == culprit => {
+                    let error = format!("forward pass timed out after {timeout}s");
+                    context.scope(|| log::error!("slot {batch} failed: {error}"));
+                    self.release(&context.request).await;
+                    slots[batch] = SlotState::default();
+                    context.fail(RuntimeError::BackendLost(error));
+                }
+                Payload::Busy(mut context) => {
+                    self.rewind(batch, &mut context).await?;
+                    *payload = Payload::Busy(context);
+                }
+                other => *payload = other,
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuild the state of a lane from the longest cached prefix of its tokens.
+    async fn rewind(&self, batch: usize, context: &mut GenerateContext) -> Result<()> {
+        let prefix = std::mem::take(&mut context.prefix);
+        let suffix = std::mem::take(&mut context.suffix);
+        let tokens = [prefix.0, suffix.0].concat();
+        // as in queueing, at least one token is left as the suffix
+        let Some((&last, tokens)) = tokens.split_last() else {
+            return Ok(());
+        };
+        let (prefix, reload) = self.checkout(context.request.state, tokens, batch).await;
+        self.state.load(batch, reload)?;
+        log::info!("slot {batch} rewinds to length {}", prefix.len());
+
+        let tokens = [tokens, &[last]].concat();
+        let len = prefix.len();
+        context.prefix = Tokens(tokens[..len].to_vec());
+        context.suffix = Tokens(tokens[len..].to_vec());
+        Ok(())
+    }
+
+    /// Count the slot taken by a request against its owner. Only once the slot is surely taken,
+    /// so that every count is given back by [`Self::release`].
+    async fn hold(&self, request: &GenerateRequest) {
+        if let Some(quota) = &request.quota {
+            let mut owners = self.owners.lock().await;
+            *owners.entry(quota.owner.clone()).or_default() += 1;
+        }
+    }
+
+    /// Give back the slot held by the owner of a finished request.
+    async fn release(&self, request: &GenerateRequest) {
+        let Some(quota) = &request.quota else {
+            return;
+        };
+        let mut owners = self.owners.lock().await;
+        if let Some(held) = owners.get_mut(&quota.owner) {
+            *held = held.saturating_sub(1);
+            if *held == 0 {
+                owners.remove(&quota.owner);
+            }
+        }
+    }
+
     /// This critical section synchronizes `slots` and fills `payloads`.
     async fn prepare(&self, payloads: &mut [Payload]) -> Result<()> {
         let mut slots = self.slots.lock().await;
@@ -673,7 +1574,9 @@ impl Runtime {
         for (slot, payload) in slots.iter().zip_eq(payloads.iter_mut()) {
             if !(payload.is_empty() || matches!(slot, SlotState::Busy)) {
                 log::warn!("payload should either be empty or slot should be busy");
-                *payload = Payload::Empty;
+                if let Payload::Busy(context) | Payload::Done(context) = std::mem::take(payload) {
+                    self.release(&context.request).await;
+                }
             }
         }
 
@@ -682,6 +1585,7 @@ impl Runtime {
             let Some(context) = payload.take() else {
                 continue;
             };
+            self.release(&context.request).await;
 
             let backed = self.state.back(batch).await?;
             if context.request.embed {
@@ -696,23 +1600,51 @@ impl Runtime {
 
             let mut caches = self.caches.lock().await;
             let cache = &mut caches.fetch(context.request.state).cache;
-            cache.insert(context.prefix.clone(), CachedItem::new(backed));
-            log::info!(
-                "backed completed slot {} of length {}",
-                batch,
-                context.prefix.len()
+            cache.insert(
+                context.prefix.clone(),
+                CachedItem::new(BackedState::Full(backed)),
             );
+            context.scope(|| {
+                log::info!(
+                    "backed completed slot {} of length {}",
+                    batch,
+                    context.prefix.len()
+                )
+            });
 
             assert!(matches!(slots[batch], SlotState::Busy));
             slots[batch] = SlotState::Idle(context.prefix, Instant::now());
         }
 
+        // free a lane for a request that waits for one, if it is more urgent than a generation running
+        if let Some(priority) = self.demand.lock().await.take() {
+            let victim = payloads
+                .iter()
+                .enumerate()
+                .filter_map(|(batch, payload)| match payload {
+                    Payload::Busy(context) if context.request.priority < priority => {
+                        Some((batch, context.request.priority))
+                    }
+                    _ => None,
+                })
+                .min_by_key(|(_, priority)| *priority)
+                .map(|(batch, _)| batch);
+            if let Some(batch) = victim {
+                self.preempt(batch, &mut payloads[batch], &mut slots[batch])
+                    .await?;
+            }
+        }
+
         // take data from some waiting slots
         let occupancy = payloads
             .iter()
             .filter(|x| matches!(x, Payload::Busy(_)))
             .count();
-        let remain = self.reload.max_batch - self.reload.max_batch.min(occupancy);
+        let lanes = match &self.governor {
+            Some(governor) => governor.lanes().await,
+            None => self.reload.max_batch,
+        };
+        let remain = lanes - lanes.min(occupancy);
         let batches = slots
             .iter()
             .enumerate()
@@ -724,8 +1656,11 @@ impl Runtime {
             let mut slot = SlotState::Busy;
             std::mem::swap(&mut slots[batch], &mut slot);
             match slot {
-                SlotState::Wait(context) => {
-                    let _ = context.sender.send(Token::Start);
+                SlotState::Wait(mut context) => {
+                    if !context.started {
+                        let _ = context.sender.send(Token::Start);
+                        context.started = true;
+                    }
                     assert!(matches!(payloads[batch], Payload::Empty));
                     payloads[batch] = Payload::Busy(*context);
                 }
@@ -736,13 +1671,141 @@ impl Runtime {
         Ok(())
     }
 
+    /// Checkpoint the generation in the lane and hand the lane back, so that the generation can be queued again
+    /// and resume from the checkpoint.
+    async fn preempt(
+        &self,
+        batch: usize,
+        payload: &mut Payload,
+        slot: &mut SlotState,
+    ) -> Result<()> {
+        let Payload::Busy(context) = std::mem::take(payload) else {
+            return Ok(());
+        };
+        self.release(&context.request).await;
+
+        // the state waits out the generations that took over, so it is compressed as the idle ones are,
+        // and offloaded along with them past the memory capacity
+        let backed = BackedState::Full(self.state.back(batch).await?);
+        let option = &self.reload.state_cache;
+        let backed = match option.compress_after {
+            0 => backed,
+            _ => backed.compress(option.compression).unwrap_or(backed),
+        };
+        let mut caches = self.caches.lock().await;
+        let cache = &mut caches.fetch(context.request.state).cache;
+        cache.insert(context.prefix.clone(), CachedItem::new(backed));
+        drop(caches);
+        context.scope(|| {
+            log::info!(
+                "preempted slot {} at length {}",
+                batch,
+                context.prefix.len()
+            )
+        });
+
+        *slot = SlotState::Idle(context.prefix.clone(), Instant::now());
+        self.preempted.lock().await.push(context);
+        Ok(())
+    }
+
+    /// Decoding lanes whose owners are over their output rate.
+    /// Soft quotas only hold while lanes of others have work to do.
+    async fn throttled(&self, payloads: &[Payload], congested: &[bool]) -> Vec<bool> {
+        let mut buckets = self.buckets.lock().await;
+        buckets.retain(|_, bucket| bucket.instant.elapsed() < BUCKET_TTL);
+
+        let over = payloads
+            .iter()
+            .map(|payload| match payload {
+                // prompts are read at full speed; only the tokens generated count
+                Payload::Busy(context) if context.suffix.len() <= 1 => {
+                    match &context.request.rate {
+                        Some(quota) if quota.tokens_per_sec > 0.0 => !buckets
+                            .entry(quota.owner.clone())
+                            .or_insert_with(|| TokenBucket::new(quota))
+                            .refill(quota),
+                        _ => false,
+                    }
+                }
+                _ => false,
+            })
+            .collect_vec();
+        let others = itertools::multizip((payloads, &over, congested))
+            .any(|(payload, &over, &congested)| payload.is_busy() && !over && !congested);
+
+        payloads
+            .iter()
+            .zip_eq(over)
+            .map(|(payload, over)| match payload {
+                Payload::Busy(context) if over => {
+                    others
+                        || context
+                            .request
+                            .rate
+                            .as_ref()
+                            .is_some_and(|quota| quota.hard)
+                }
+                _ => false,
+            })
+            .collect()
+    }
+
+    /// Account a generated token to the rate quota of its owner, if any.
+    async fn charge(&self, request: &GenerateRequest) {
+        let Some(quota) = &request.rate else {
+            return;
+        };
+        if let Some(bucket) = self.buckets.lock().await.get_mut(&quota.owner) {
+            bucket.take(quota);
+        }
+    }
+
     async fn process(&self, payloads: &mut [Payload]) -> Result<()> {
         self.prepare(payloads).await?;
+        let backlog = payloads
+            .iter()
+            .map(|payload| match payload {
+                Payload::Busy(context) => context.suffix.len(),
+                _ => 0,
+            })
+            .sum();
+        self.backlog.store(backlog, AtomicOrdering::Relaxed);
+        let owed = payloads
+            .iter()
+            .filter_map(|payload| match payload {
+                Payload::Busy(context) => Some(
+                    context
+                        .request
+                        .max_tokens
+                        .saturating_sub(context.model_tokens.len()),
+                ),
+                _ => None,
+            })
+            .min()
+            .unwrap_or_default();
+        self.owed.store(owed, AtomicOrdering::Relaxed);
 
-        let batches = payloads
+        // lanes whose receivers lag behind, or whose owners generate too fast, are skipped in this round
+        let congested = payloads
             .iter()
             .map(|payload| match payload {
-                Payload::Busy(context) => context.suffix.0.clone(),
+                Payload::Busy(context) => context.is_congested(),
+                _ => false,
+            })
+            .collect_vec();
+        let throttled = self.throttled(payloads, &congested).await;
+        let paused = congested
+            .iter()
+            .zip_eq(throttled)
+            .map(|(&congested, throttled)| congested || throttled)
+            .collect_vec();
+
+        let batches = payloads
+            .iter()
+            .zip_eq(paused.iter())
+            .map(|(payload, paused)| match payload {
+                Payload::Busy(context) if !paused => context.suffix.0.clone(),
                 _ => vec![],
             })
             .map(|tokens| InferInputBatch {
@@ -752,14 +1815,44 @@ impl Runtime {
             .collect();
         let inference = InferInput::new(batches, self.reload.token_chunk_size);
         if inference.num_token() == 0 {
+            if paused.contains(&true) {
+                tokio::time::sleep(PAUSE_INTERVAL).await;
+            }
             return Ok(());
         }
+        if let Some(governor) = &self.governor {
+            if let Some(pause) = governor.pause().await {
+                tokio::time::sleep(pause).await;
+            }
+        }
         let mut inference = Some(inference);
 
         // run the model until there is at least one slot finished
         let outputs = loop {
             let input = inference.take().unwrap();
-            let (input, output) = self.runtime.infer(input).await;
+            // tokens left for each lane in this pass, which tell the one likely to hang it
+            let loads = input
+                .batches
+                .iter()
+                .map(|batch| batch.tokens.len())
+                .collect_vec();
+            let timeout = Duration::from_secs(self.reload.infer_timeout);
+            let instant = Instant::now();
+            let infer = self.runtime.infer(input);
+            let (input, output) = match timeout.is_zero() {
+                true => infer.await,
+                false => match tokio::time::timeout(timeout, infer).await {
+                    Ok(result) => result,
+                    Err(_) => return self.stall(payloads, &loads).await,
+                },
+            };
+            self.stalled.store(false, AtomicOrdering::Relaxed);
+            // only the lanes still reading their prompts tell the prefill speed
+            let prefill = itertools::zip_eq(&loads, &input.batches)
+                .filter(|(&load, _)| load > 1)
+                .map(|(load, batch)| load - batch.tokens.len())
+                .sum();
+            self.measure(prefill, instant.elapsed()).await;
             inference = Some(input);
 
             if output.iter().any(|batch| batch.size() > 0) {
@@ -774,7 +1867,7 @@ impl Runtime {
                 (Payload::Busy(context), output) if output.size() > 0 => {
                     let num_vocab = self.info.num_vocab;
                     let output = output.0.clone();
-                    let transformers = context.transformers.clone();
+                    let transformers = context.transformers.clone().unwrap_or_default();
                     let sampler = context.request.sampler.clone();
                     let bias = context.request.bias.clone();
                     set.spawn(async move {
@@ -812,17 +1905,32 @@ impl Runtime {
 
         // sample tokens
         let mut set = tokio::task::JoinSet::new();
-        for (batch, (payload, output)) in
-            payloads.iter_mut().zip_eq(outputs.into_iter()).enumerate()
-        {
+        for (batch, (payload, output)) in payloads.iter_mut().zip_eq(outputs).enumerate() {
             match (payload, output) {
                 (Payload::Busy(context), output) if output.size() > 0 => {
                     let num_vocab = self.info.num_vocab;
                     let sampler = context.request.sampler.clone();
+                    let trace = context.request.trace.clone();
                     set.spawn(async move {
                         let data = output.to_vec();
                         assert_eq!(data.len(), num_vocab);
                         let token = sampler.write().await.sample(&data);
+                        if let Some(trace) = trace {
+                            let candidates = data
+                                .iter()
+                                .enumerate()
+                                .map(|(token, &prob)| (token as u16, prob))
+                                .sorted_unstable_by(|x, y| y.1.total_cmp(&x.1))
+                                .take(TRACE_CANDIDATES)
+                                .collect();
+                            let prob = data[token as usize];
+                            let step = TraceStep {
+                                token,
+                                prob,
+                                candidates,
+                            };
+                            trace.lock().unwrap().push(step);
+                        }
                         (batch, token)
                     });
                 }
@@ -841,10 +1949,14 @@ impl Runtime {
             let Payload::Busy(context) = payload else {
                 continue;
             };
+            if paused[batch] {
+                continue;
+            }
 
             let instant = context.instant.get_or_insert(Instant::now());
             let prefix = std::mem::take(&mut context.prefix);
             let suffix = std::mem::take(&mut context.suffix);
+            let last = prefix.len();
             let model_tokens = [prefix.0, suffix.0].concat();
 
             // compute new prefix and suffix using the current remaining tokens
@@ -853,9 +1965,25 @@ impl Runtime {
             context.prefix = Tokens(model_tokens[..len].to_vec());
             context.suffix = Tokens(model_tokens[len..].to_vec());
 
+            // checkpoint the state every time the prefix crosses the interval,
+            // so that regenerating from the middle of a conversation only replays from there.
+            let interval = self.reload.checkpoint_interval;
+            if interval > 0 && len / interval > last / interval {
+                let mut caches = self.caches.lock().await;
+                let cache = &mut caches.fetch(context.request.state).cache;
+                let backed = self.state.back(batch).await?;
+
+                cache.insert(
+                    context.prefix.clone(),
+                    CachedItem::new(BackedState::Full(backed)),
+                );
+                log::info!("checkpointed slot {} at length {}", batch, len);
+            }
+
             let Some(&token) = tokens.get(&batch) else {
                 continue;
             };
+            self.charge(&context.request).await;
 
             // cache the prompt if it is too long.
             if !context.prompt_cached && context.prompt_tokens.len() > PROMPT_CACHE_TOKENS {
@@ -863,7 +1991,10 @@ impl Runtime {
                 let cache = &mut caches.fetch(context.request.state).cache;
                 let backed = self.state.back(batch).await?;
 
-                cache.insert(context.prefix.clone(), CachedItem::new(backed));
+                cache.insert(
+                    context.prefix.clone(),
+                    CachedItem::new(BackedState::Full(backed)),
+                );
                 context.prompt_cached = true;
 
                 log::info!(
@@ -888,7 +2019,7 @@ impl Runtime {
             context.model_tokens.push(token);
 
             let mut done = false;
-            let mut finish = |reason| {
+            let mut finish = |reason, stop| {
                 let counter = {
                     let prompt = context.prompt_tokens.len();
                     let completion = context.model_tokens.len();
@@ -902,25 +2033,25 @@ impl Runtime {
                     }
                 };
 
-                let _ = context.sender.send(Token::Stop(reason, counter));
+                let _ = context.sender.send(Token::Stop(reason, stop, counter));
                 let _ = context.sender.send(Token::Done);
                 done = true;
             };
 
             // update the transformer (BNF) state
             let mut exhausted = false;
-            for transformer in context.transformers.iter() {
+            for transformer in context.transformers.iter().flatten() {
                 let mut transformer = transformer.write().await;
                 exhausted |= transformer.update(token);
             }
 
-            // here we detect if there is a stop word in our buffer
+            // here we detect if there is a stop word in our buffer; of overlapping ones, the earliest wins
             let ((head, tail), stop_matched) = context
                 .request
                 .stop
                 .iter()
-                .map(|stop| {
-                    let stop = stop.as_bytes();
+                .map(|text| {
+                    let stop = text.as_bytes();
                     let mut index_safe = 0;
                     let mut index_unsafe = 0;
                     while index_unsafe < context.buffer.len() {
@@ -928,7 +2059,7 @@ impl Runtime {
                         let index_stop = index_unsafe - index_safe;
                         if index_stop >= stop.len() {
                             // we have a total match
-                            return (index_safe, true);
+                            return (index_safe, Some(text));
                         }
 
                         let output = context.buffer[index_unsafe];
@@ -939,27 +2070,36 @@ impl Runtime {
                             index_safe = index_unsafe;
                         }
                     }
-                    (index_safe, index_unsafe - index_safe >= stop.len())
+                    let matched = index_unsafe - index_safe >= stop.len();
+                    (index_safe, matched.then_some(text))
                 })
-                .min_by(|x, y| match (x.1, y.1) {
-                    (true, false) => Ordering::Less,
-                    (false, true) => Ordering::Greater,
+                .min_by(|x, y| match (&x.1, &y.1) {
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
                     _ => x.0.cmp(&y.0),
                 })
-                .map(|(mid, matched)| (context.buffer.split_at(mid), matched))
-                .unwrap_or(((&context.buffer[..], &[]), false));
+                .map(|(mid, matched)| (context.buffer.split_at(mid), matched.cloned()))
+                .unwrap_or(((&context.buffer[..], &[]), None));
 
             if context.sender.is_disconnected() {
                 done = true;
-            } else if exhausted || stop_matched {
+            } else if exhausted || stop_matched.is_some() {
                 let output = String::from_utf8_lossy(head);
                 let _ = context.sender.send(Token::Content(output.into()));
-                finish(FinishReason::Stop);
+                finish(FinishReason::Stop, stop_matched);
             } else if context.model_tokens.len() >= context.request.max_tokens {
-                finish(FinishReason::Length);
-            } else if let Ok(word) = String::from_utf8(head.to_vec()) {
-                let _ = context.sender.send(Token::Content(word));
-                context.buffer = tail.to_vec();
+                // the text held back for a possible stop word or an unfinished character is final now
+                if !context.buffer.is_empty() {
+                    let output = String::from_utf8_lossy(&context.buffer);
+                    let _ = context.sender.send(Token::Content(output.into()));
+                }
+                finish(FinishReason::Length, None);
+            } else {
+                let (word, rest) = split_utf8(head);
+                if !word.is_empty() {
+                    let _ = context.sender.send(Token::Content(word));
+                }
+                context.buffer = [rest, tail].concat();
             }
 
             done.then(|| payload.finalize());
@@ -968,40 +2108,174 @@ impl Runtime {
         Ok(())
     }
 
-    /// Keep the items in the cache less then [`MAX_CACHE_ITEMS`].
+    /// Read the temperature and the power draw of the GPU, and adjust the load to them.
+    async fn govern(&self) {
+        if let Some(governor) = &self.governor {
+            governor.update().await;
+        }
+    }
+
+    /// Compress the idle items in the cache, keep their number within the capacity, and offload the ones
+    /// beyond the memory capacity.
     async fn maintain_cache(&self) {
+        let option = &self.reload.state_cache;
+        let spills = {
+            let mut caches = self.caches.lock().await;
+            caches.default.maintain(option);
+            caches
+                .backed
+                .iter_mut()
+                .for_each(|(_, x)| x.maintain(option));
+            caches.spills(option.memory_capacity)
+        };
+        for (id, tokens, item) in spills {
+            // a prefetched state is on the disk already, and only dropped from memory
+            if matches!(item.item.as_ref(), BackedState::Disk { .. }) {
+                if item.item.unload() {
+                    log::info!("dropped prefetched state of length {}", tokens.len());
+                }
+                continue;
+            }
+            if let Err(err) = self.offload(id, tokens, item).await {
+                log::error!("failed to offload cached state: {err}");
+            }
+        }
+    }
+
+    /// Write a cached state to disk, and replace it with the file unless it is checked out meanwhile.
+    async fn offload(
+        &self,
+        id: Option<StateId>,
+        tokens: Tokens,
+        item: CachedItem<BackedState>,
+    ) -> Result<()> {
+        let dir = self.reload.state_cache.offload_path.clone();
+        let path = dir.join(format!("{}.state", uuid::Uuid::new_v4()));
+        let state = item.item.clone();
+        let file = path.clone();
+        let cipher = self.cipher.clone();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let Some(bytes) = state.write() else {
+                return Ok(());
+            };
+            let bytes = match &cipher {
+                Some(cipher) => cipher.seal(&bytes),
+                None => bytes,
+            };
+            std::fs::create_dir_all(dir)?;
+            std::fs::write(file, bytes)?;
+            Ok(())
+        })
+        .await??;
+        // removes the file when dropped, if it is not put into the cache
+        let disk = BackedState::Disk {
+            path,
+            loaded: Default::default(),
+            prefetching: Default::default(),
+            cipher: self.cipher.clone(),
+        };
+
         let mut caches = self.caches.lock().await;
-        caches.default.maintain();
-        caches.backed.iter_mut().for_each(|(_, x)| x.maintain());
+        let Some(cache) = caches.get_mut(id) else {
+            return Ok(());
+        };
+        let unchanged = cache.cache.get(&tokens).is_some_and(|current| {
+            current.instant == item.instant && Arc::ptr_eq(&current.item, &item.item)
+        });
+        if unchanged {
+            log::info!("offloaded cached state of length {}", tokens.len());
+            let item = CachedItem {
+                item: Arc::new(disk),
+                instant: item.instant,
+            };
+            cache.cache.insert(tokens, item);
+        }
+        Ok(())
     }
 }
 
-pub async fn run(receiver: Receiver<()>, env: Arc<RwLock<Environment>>) {
-    {
-        // this task constantly runs, cleaning up state cache
-        let env = env.clone();
-        tokio::spawn(async move {
-            loop {
-                if let Environment::Loaded(runtime) = &*env.read().await {
-                    runtime.maintain_cache().await;
+/// Remove the states left in the offload directory by earlier runs, which no cache refers to.
+pub(crate) fn clean_offload(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.extension().is_some_and(|ext| ext == "state") {
+            if let Err(err) = std::fs::remove_file(&path) {
+                log::warn!("failed to remove offloaded state {}: {err}", path.display());
+            }
+        }
+    }
+}
+
+/// Split off the longest prefix of the bytes that can be sent as text.
+/// Bytes that can never be valid are replaced, but an unfinished character at the end is held back,
+/// since the next token may complete it.
+fn split_utf8(bytes: &[u8]) -> (String, &[u8]) {
+    let mut text = String::new();
+    let mut rest = bytes;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                text.push_str(valid);
+                return (text, &[]);
+            }
+            Err(err) => {
+                let (valid, invalid) = rest.split_at(err.valid_up_to());
+                text.push_str(&String::from_utf8_lossy(valid));
+                match err.error_len() {
+                    Some(len) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        rest = &invalid[len..];
+                    }
+                    None => return (text, invalid),
                 }
-                tokio::time::sleep(Duration::from_secs(1)).await;
             }
-        });
+        }
+    }
+}
+
+/// Constantly runs, cleaning up the state cache and adjusting the load to the GPU.
+pub async fn maintain(env: Arc<RwLock<Environment>>) {
+    loop {
+        if let Environment::Loaded(runtime) = &*env.read().await {
+            runtime.maintain_cache().await;
+            runtime.govern().await;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
     }
+}
 
+pub async fn run(receiver: Receiver<()>, env: Arc<RwLock<Environment>>) {
     while let Ok(()) = receiver.recv_async().await {
         if let Environment::Loaded(runtime) = &*env.read().await {
-            let mut payloads = vec![Payload::default(); runtime.num_batch()];
+            let mut payloads = runtime.payloads.lock().await;
             'run: loop {
                 if let Err(err) = runtime.process(&mut payloads).await {
                     log::error!("{}", err);
+                    // the lanes are given up, as if the loop started afresh
+                    let mut slots = runtime.slots.lock().await;
+                    for (slot, payload) in slots.iter_mut().zip_eq(payloads.iter_mut()) {
+                        if let Payload::Busy(context) | Payload::Done(context) =
+                            std::mem::take(payload)
+                        {
+                            runtime.release(&context.request).await;
+                            context.fail(RuntimeError::BackendLost(err.to_string()));
+                        }
+                        if matches!(slot, SlotState::Busy) {
+                            *slot = SlotState::default();
+                        }
+                    }
                     break 'run;
                 }
                 if payloads.iter().all(Payload::is_empty) {
                     break 'run;
                 }
             }
+            // the model has run dry: forget the speeds, which would only tell how busy it was
+            runtime.backlog.store(0, AtomicOrdering::Relaxed);
+            runtime.owed.store(0, AtomicOrdering::Relaxed);
+            *runtime.speed.lock().await = Speed::default();
         }
     }
 }
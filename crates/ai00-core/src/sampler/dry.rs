@@ -0,0 +1,115 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use derivative::Derivative;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+use web_rwkv::tokenizer::Tokenizer;
+
+use super::Transformer;
+
+/// Longest repetition looked back for, so that a step stays cheap on degenerate loops.
+const MAX_MATCH: usize = 64;
+
+/// Options of the DRY ("don't repeat yourself") penalty, which penalizes the tokens
+/// that would extend a sequence already seen in the context.
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct DryParams {
+    /// Strength of the penalty. `0` disables it.
+    pub dry_multiplier: f32,
+    /// Growth of the penalty with each token the repetition is longer.
+    #[derivative(Default(value = "1.75"))]
+    pub dry_base: f32,
+    /// Length of repetitions left unpenalized.
+    #[derivative(Default(value = "2"))]
+    pub dry_allowed_length: usize,
+    /// Number of recent tokens searched for repetitions. `0` searches the whole context.
+    pub dry_penalty_last_n: usize,
+    /// Texts whose tokens end repetitions, so that, e.g., lines are matched one by one.
+    #[derivative(Default(value = r#"vec!["\n".into(), ":".into(), "\"".into(), "*".into()]"#))]
+    pub dry_sequence_breakers: Vec<String>,
+}
+
+impl DryParams {
+    pub fn is_enabled(&self) -> bool {
+        self.dry_multiplier > 0.0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DryPenalty {
+    params: DryParams,
+    breakers: HashSet<u16>,
+    /// The prompt and the tokens generated so far.
+    history: Vec<u16>,
+}
+
+impl DryPenalty {
+    pub fn new(params: &DryParams, prompt: &[u16], tokenizer: &Tokenizer) -> Result<Self> {
+        let mut breakers = HashSet::new();
+        for breaker in &params.dry_sequence_breakers {
+            breakers.extend(tokenizer.encode(breaker.as_bytes())?);
+        }
+        Ok(Self {
+            params: params.clone(),
+            breakers,
+            history: prompt.to_vec(),
+        })
+    }
+
+    /// For each token that would repeat a sequence, the length of the longest such sequence.
+    fn repetitions(&self) -> HashMap<u16, usize> {
+        let history = match self.params.dry_penalty_last_n {
+            0 => &self.history[..],
+            n => &self.history[self.history.len().saturating_sub(n)..],
+        };
+        let mut repetitions = HashMap::new();
+        let Some((&last, _)) = history.split_last() else {
+            return repetitions;
+        };
+        if self.breakers.contains(&last) {
+            return repetitions;
+        }
+
+        let end = history.len() - 1;
+        for index in (0..end).filter(|&index| history[index] == last) {
+            let next = history[index + 1];
+            if self.breakers.contains(&next) {
+                continue;
+            }
+            let len = (0..=index.min(MAX_MATCH - 1))
+                .take_while(|&offset| {
+                    let token = history[index - offset];
+                    token == history[end - offset] && !self.breakers.contains(&token)
+                })
+                .count();
+            let entry = repetitions.entry(next).or_insert(0);
+            *entry = len.max(*entry);
+        }
+        repetitions
+    }
+}
+
+impl Transformer for DryPenalty {
+    fn transform(&self, output: &mut [f32]) {
+        let DryParams {
+            dry_multiplier,
+            dry_base,
+            dry_allowed_length,
+            ..
+        } = self.params;
+        for (token, len) in self.repetitions() {
+            if len >= dry_allowed_length {
+                let exponent = (len - dry_allowed_length) as f32;
+                output[token as usize] -= dry_multiplier * dry_base.powf(exponent);
+            }
+        }
+    }
+
+    fn update(&mut self, token: u16) -> bool {
+        self.history.push(token);
+        false
+    }
+}
@@ -1,7 +1,13 @@
 pub mod bnf;
+pub mod dry;
 pub mod mirostat;
+pub mod noise;
 pub mod nucleus;
+pub mod processor;
+pub mod reasoning;
+pub mod sigma;
 pub mod typical;
+pub mod xtc;
 
 pub trait Sampler {
     /// Initialize the sampler state.
@@ -18,3 +24,13 @@ pub trait Transformer {
     /// Update the internal state after a token is chosen. Return if the state machine is halt.
     fn update(&mut self, token: u16) -> bool;
 }
+
+impl<T: Transformer + ?Sized> Transformer for Box<T> {
+    fn transform(&self, output: &mut [f32]) {
+        (**self).transform(output)
+    }
+
+    fn update(&mut self, token: u16) -> bool {
+        (**self).update(token)
+    }
+}
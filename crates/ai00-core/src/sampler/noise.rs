@@ -0,0 +1,35 @@
+use super::Transformer;
+
+/// Adds Gumbel noise to the logits, so that generations of the same prompt part ways
+/// even when the sampler is close to greedy.
+#[derive(Debug, Clone)]
+pub struct GumbelNoise {
+    scale: f32,
+    seed: u64,
+    step: u64,
+}
+
+impl GumbelNoise {
+    pub fn new(scale: f32) -> Self {
+        Self {
+            scale,
+            seed: fastrand::u64(..),
+            step: 0,
+        }
+    }
+}
+
+impl Transformer for GumbelNoise {
+    fn transform(&self, output: &mut [f32]) {
+        let mut rng = fastrand::Rng::with_seed(self.seed ^ self.step);
+        for logits in output.iter_mut() {
+            let u = rng.f32().max(f32::MIN_POSITIVE);
+            *logits += -self.scale * (-u.ln()).ln();
+        }
+    }
+
+    fn update(&mut self, _token: u16) -> bool {
+        self.step += 1;
+        false
+    }
+}
@@ -7,6 +7,9 @@ use serde::{Deserialize, Serialize};
 
 use super::Sampler;
 
+/// Lowest temperature of a dynamic step, since the probabilities are raised to its inverse.
+const MIN_TEMPERATURE: f32 = 0.01;
+
 #[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
 #[derivative(Default)]
 #[serde(default)]
@@ -23,6 +26,36 @@ pub struct NucleusParams {
     pub frequency_penalty: f32,
     #[derivative(Default(value = "0.99654026"))]
     pub penalty_decay: f32,
+    /// Spread of the temperature around `temperature` with the entropy of each step:
+    /// from `temperature - dynatemp_range`, but no lower than `0.01`, when the model is sure, to `temperature + dynatemp_range`
+    /// when it is not. `0` keeps the temperature fixed.
+    pub dynatemp_range: f32,
+    /// Shape of the mapping from the entropy to the temperature.
+    #[derivative(Default(value = "1.0"))]
+    pub dynatemp_exponent: f32,
+}
+
+impl NucleusParams {
+    /// The temperature of a step, given the probabilities before truncation.
+    fn step_temperature(&self, probs: &[f32]) -> f32 {
+        if self.dynatemp_range <= 0.0 {
+            return self.temperature;
+        }
+        let (count, entropy) = probs
+            .iter()
+            .filter(|&&x| x > 0.0)
+            .fold((0usize, 0.0f32), |(count, entropy), &x| {
+                (count + 1, entropy - x * x.ln())
+            });
+        let max_entropy = (count as f32).ln();
+        let normalized = match max_entropy > 0.0 {
+            true => (entropy / max_entropy).clamp(0.0, 1.0),
+            false => 0.0,
+        };
+        let min = (self.temperature - self.dynatemp_range).max(MIN_TEMPERATURE);
+        let max = self.temperature + self.dynatemp_range;
+        min + (max - min) * normalized.powf(self.dynatemp_exponent)
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -68,6 +101,7 @@ impl Sampler for NucleusSampler {
 
     fn sample(&mut self, probs: &[f32]) -> u16 {
         let NucleusSampler { params, state } = self;
+        let temperature = params.step_temperature(probs);
 
         let sorted = probs
             .iter()
@@ -82,7 +116,7 @@ impl Sampler for NucleusSampler {
                     Some((id, *cum, *x))
                 }
             })
-            .map(|(id, _, x)| (id, x.powf(1.0 / params.temperature)))
+            .map(|(id, _, x)| (id, x.powf(1.0 / temperature)))
             .collect_vec();
 
         let sum: f32 = sorted.iter().map(|(_, x)| x).sum();
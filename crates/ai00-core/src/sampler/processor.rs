@@ -0,0 +1,98 @@
+//! User-defined transformations of the logits, registered by name and picked by requests,
+//! e.g., repetition penalties or exclusions the built-in samplers lack.
+//!
+//! Applications that embed the engine call [`register`]. With the `plugins` feature,
+//! processors can also come from dynamic libraries, see [`load_plugin`].
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use anyhow::Result;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+
+use super::Transformer;
+
+/// A factory of logits transformations, one for each generation that asks for it.
+pub trait LogitsProcessor: Send + Sync {
+    /// The name requests refer to the processor by.
+    fn name(&self) -> &str;
+    /// Create the transformation of one generation from the options in the request
+    /// and the tokens of the prompt.
+    fn create(
+        &self,
+        options: &serde_json::Value,
+        prompt: &[u16],
+    ) -> Result<Box<dyn Transformer + Send + Sync>>;
+}
+
+/// A processor asked by a request, applied after the sampler and the bias.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ProcessorRequest {
+    pub name: String,
+    /// Options given to the processor as they are.
+    #[serde(default)]
+    #[salvo(schema(value_type = Object))]
+    pub options: serde_json::Value,
+}
+
+type Registry = RwLock<HashMap<String, Arc<dyn LogitsProcessor>>>;
+
+fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+/// Register a processor, replacing the one of the same name if any.
+pub fn register(processor: Arc<dyn LogitsProcessor>) {
+    let name = processor.name().to_owned();
+    log::info!("registered logits processor {name}");
+    registry().write().unwrap().insert(name, processor);
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn LogitsProcessor>> {
+    registry().read().unwrap().get(name).cloned()
+}
+
+/// Names of the processors registered.
+pub fn names() -> Vec<String> {
+    registry().read().unwrap().keys().cloned().collect()
+}
+
+/// The symbol a plugin exports to register its processors.
+#[cfg(feature = "plugins")]
+pub const PLUGIN_ENTRY: &[u8] = b"ai00_register_processors";
+
+/// Function behind [`PLUGIN_ENTRY`]: calls `register` with each processor of the plugin, e.g.,
+///
+/// ```ignore
+/// #[no_mangle]
+/// pub fn ai00_register_processors(register: &mut dyn FnMut(Arc<dyn LogitsProcessor>)) {
+///     register(Arc::new(MyProcessor));
+/// }
+/// ```
+#[cfg(feature = "plugins")]
+pub type PluginEntry = fn(&mut dyn FnMut(Arc<dyn LogitsProcessor>));
+
+/// Load the processors of a plugin, a dynamic library exporting [`PLUGIN_ENTRY`].
+/// Returns the names of the processors registered. The library is kept loaded until exit.
+///
+/// # Safety
+/// Traits are passed across the library with the Rust ABI, so the plugin must be built
+/// with the same compiler and the same version of this crate.
+#[cfg(feature = "plugins")]
+pub unsafe fn load_plugin(path: impl AsRef<std::path::Path>) -> Result<Vec<String>> {
+    let library = libloading::Library::new(path.as_ref())?;
+    let entry = library.get::<PluginEntry>(PLUGIN_ENTRY)?;
+
+    let mut names = vec![];
+    entry(&mut |processor| {
+        names.push(processor.name().to_owned());
+        register(processor);
+    });
+    // the processors run code of the library
+    std::mem::forget(library);
+    Ok(names)
+}
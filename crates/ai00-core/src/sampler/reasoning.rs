@@ -0,0 +1,100 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use anyhow::Result;
+use web_rwkv::tokenizer::Tokenizer;
+
+use super::Transformer;
+
+/// Caps the tokens generated inside a reasoning block.
+#[derive(Debug, Clone)]
+pub struct ReasoningBudget {
+    /// Tag that opens a reasoning block.
+    pub open: String,
+    /// Tag that closes a reasoning block.
+    pub close: String,
+    /// Maximum tokens generated inside a reasoning block before it is closed.
+    pub max_tokens: usize,
+}
+
+/// Watches the output for reasoning blocks, and forces the closing tag once the budget runs out.
+#[derive(Debug)]
+pub struct ReasoningLimiter {
+    tokenizer: Arc<Tokenizer>,
+    open: Vec<u8>,
+    close: Vec<u8>,
+    close_tokens: Vec<u16>,
+    max_tokens: usize,
+    /// Recent output, long enough to hold either tag.
+    window: Vec<u8>,
+    /// Number of tokens generated in the current block, if inside one.
+    count: Option<usize>,
+    /// Tokens of the closing tag yet to be forced.
+    forced: VecDeque<u16>,
+}
+
+impl ReasoningLimiter {
+    pub fn new(budget: &ReasoningBudget, tokenizer: Arc<Tokenizer>) -> Result<Self> {
+        let close_tokens = tokenizer.encode(budget.close.as_bytes())?;
+        Ok(Self {
+            tokenizer,
+            open: budget.open.as_bytes().to_vec(),
+            close: budget.close.as_bytes().to_vec(),
+            close_tokens,
+            max_tokens: budget.max_tokens,
+            window: vec![],
+            count: None,
+            forced: VecDeque::new(),
+        })
+    }
+
+    fn contains(window: &[u8], tag: &[u8]) -> bool {
+        !tag.is_empty() && window.windows(tag.len()).any(|x| x == tag)
+    }
+}
+
+impl Transformer for ReasoningLimiter {
+    fn transform(&self, output: &mut [f32]) {
+        if let Some(&forced) = self.forced.front() {
+            output
+                .iter_mut()
+                .enumerate()
+                .filter(|&(token, _)| token != forced as usize)
+                .for_each(|(_, logits)| *logits = f32::MIN)
+        }
+    }
+
+    fn update(&mut self, token: u16) -> bool {
+        if self.forced.front() == Some(&token) {
+            self.forced.pop_front();
+        }
+
+        let word = self.tokenizer.decode(&[token]).unwrap_or_default();
+        self.window.extend(word);
+        let len = self.open.len().max(self.close.len()) * 2;
+        if self.window.len() > len {
+            self.window.drain(..self.window.len() - len);
+        }
+
+        match self.count {
+            None if Self::contains(&self.window, &self.open) => {
+                self.window.clear();
+                self.count = Some(0);
+            }
+            None => {}
+            Some(_) if Self::contains(&self.window, &self.close) => {
+                self.window.clear();
+                self.count = None;
+                self.forced.clear();
+            }
+            Some(count) => {
+                let count = count + 1;
+                self.count = Some(count);
+                if count >= self.max_tokens && self.forced.is_empty() {
+                    self.forced = self.close_tokens.iter().copied().collect();
+                    log::info!("reasoning budget of {} tokens exhausted", self.max_tokens);
+                }
+            }
+        }
+        false
+    }
+}
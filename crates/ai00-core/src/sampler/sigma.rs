@@ -0,0 +1,29 @@
+use super::Transformer;
+
+/// Keeps the tokens whose logits are within `n` standard deviations of the top one.
+/// Unlike `top_p`, the cut does not move with the temperature.
+#[derive(Debug, Clone)]
+pub struct TopNSigma(pub f32);
+
+impl Transformer for TopNSigma {
+    fn transform(&self, output: &mut [f32]) {
+        // tokens masked out before, e.g., by a BNF schema, are left out of the statistics
+        let valid = || output.iter().copied().filter(|&x| x > f32::MIN);
+        let count = valid().count() as f32;
+        if count == 0.0 {
+            return;
+        }
+        let max = valid().fold(f32::MIN, f32::max);
+        let mean = valid().sum::<f32>() / count;
+        let variance = valid().map(|x| (x - mean).powi(2)).sum::<f32>() / count;
+        let min = max - self.0 * variance.sqrt();
+        output
+            .iter_mut()
+            .filter(|x| **x < min)
+            .for_each(|x| *x = f32::MIN);
+    }
+
+    fn update(&mut self, _token: u16) -> bool {
+        false
+    }
+}
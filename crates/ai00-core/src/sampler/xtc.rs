@@ -0,0 +1,56 @@
+use derivative::Derivative;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+
+use super::Transformer;
+
+/// Options of XTC ("exclude top choices"), which removes the most likely tokens but the least of them,
+/// so that the output strays from the obvious while staying sensible.
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct XtcParams {
+    /// Chance that a step excludes the top choices. `0` disables XTC.
+    pub xtc_probability: f32,
+    /// Minimum probability of a token to count as a top choice.
+    #[derivative(Default(value = "0.1"))]
+    pub xtc_threshold: f32,
+}
+
+impl XtcParams {
+    pub fn is_enabled(&self) -> bool {
+        self.xtc_probability > 0.0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExcludeTopChoices(pub XtcParams);
+
+impl Transformer for ExcludeTopChoices {
+    fn transform(&self, output: &mut [f32]) {
+        let XtcParams {
+            xtc_probability,
+            xtc_threshold,
+        } = self.0;
+        if fastrand::f32() >= xtc_probability {
+            return;
+        }
+
+        let max = output.iter().copied().fold(f32::MIN, f32::max);
+        let sum: f32 = output.iter().map(|x| (x - max).exp()).sum();
+        // a token is a top choice if its probability reaches the threshold
+        let min = max + (xtc_threshold * sum).ln();
+        let mut choices: Vec<_> = (0..output.len()).filter(|&x| output[x] >= min).collect();
+        if choices.len() < 2 {
+            return;
+        }
+        choices.sort_unstable_by(|&x, &y| output[x].total_cmp(&output[y]));
+        for &token in &choices[1..] {
+            output[token] = f32::MIN;
+        }
+    }
+
+    fn update(&mut self, _token: u16) -> bool {
+        false
+    }
+}
@@ -0,0 +1,68 @@
+//! Encryption of the data written to disk, such as the outputs of scheduled jobs and the offloaded states.
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{anyhow, bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+/// Length of the nonce in front of every sealed record.
+const NONCE_LEN: usize = 12;
+
+/// Seals records with AES-256-GCM. Every record carries its own random nonce.
+pub struct Sealer(Aes256Gcm);
+
+impl std::fmt::Debug for Sealer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Sealer")
+    }
+}
+
+impl Sealer {
+    /// Create from a base64 encoded key of 32 bytes, e.g., generated by `openssl rand -base64 32`.
+    pub fn new(key: &str) -> Result<Self> {
+        let key = STANDARD
+            .decode(key.trim())
+            .map_err(|err| anyhow!("storage key is not base64: {err}"))?;
+        if key.len() != 32 {
+            bail!("storage key has {} bytes, expect 32", key.len());
+        }
+        let key = Key::<Aes256Gcm>::from_slice(&key);
+        Ok(Self(Aes256Gcm::new(key)))
+    }
+
+    /// Create with a random key, which only lives in memory.
+    pub fn generate() -> Self {
+        Self(Aes256Gcm::new(&Aes256Gcm::generate_key(OsRng)))
+    }
+
+    pub fn seal(&self, data: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let sealed = self
+            .0
+            .encrypt(&nonce, data)
+            .expect("encryption into a vector does not fail");
+        [nonce.as_slice(), &sealed].concat()
+    }
+
+    pub fn open(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < NONCE_LEN {
+            bail!("sealed record is truncated");
+        }
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+        self.0
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .map_err(|_| anyhow!("failed to open sealed record: wrong key or corrupted data"))
+    }
+
+    /// Seal a line of text into a line of base64, so that sealed records still go one per line.
+    pub fn seal_line(&self, line: &str) -> String {
+        STANDARD.encode(self.seal(line.as_bytes()))
+    }
+
+    pub fn open_line(&self, line: &str) -> Result<String> {
+        let data = STANDARD.decode(line.trim())?;
+        Ok(String::from_utf8(self.open(&data)?)?)
+    }
+}
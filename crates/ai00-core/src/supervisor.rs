@@ -0,0 +1,209 @@
+//! Keeps the model loop running. When it panics or the device is lost, the runtime is dropped, failing
+//! the generations it holds with the error of the crash, and the model is loaded again, at most `max_restarts`
+//! times within [`RESTART_WINDOW`].
+//!
+//! A lost device is no fault of the requests, so the work is saved as far as possible: the prompt cache,
+//! which lives in the host memory or on the disk, is taken over by the new runtime, and the requests that
+//! have not got any token yet are queued again, to resume from their latest checkpoints in the cache.
+
+use std::{
+    any::Any,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+
+use flume::{Receiver, Sender};
+use tokio::sync::{Mutex, RwLock};
+use web_rwkv::{context::Context, wgpu::DeviceLostReason};
+
+use crate::{
+    run::{GenerateContext, Salvage},
+    Environment, ReloadRequest, RuntimeError, RuntimeEvent, Subscribers,
+};
+
+/// Span over which crashes count against `max_restarts`.
+const RESTART_WINDOW: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Default)]
+struct HealthState {
+    /// The last crash, until a model is loaded again.
+    fault: Option<String>,
+    /// Automatic reloads so far.
+    restarts: usize,
+    /// Whether the model crashed too often to be reloaded.
+    down: bool,
+    crashes: Vec<Instant>,
+}
+
+/// Crashes of the model loop, shared with the stats.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Health(Arc<StdMutex<HealthState>>);
+
+impl Health {
+    pub fn fault(&self) -> Option<String> {
+        self.0.lock().unwrap().fault.clone()
+    }
+
+    pub fn restarts(&self) -> usize {
+        self.0.lock().unwrap().restarts
+    }
+
+    /// Whether the model is left unloaded after crashing too often, until one is loaded by hand.
+    pub fn is_down(&self) -> bool {
+        self.0.lock().unwrap().down
+    }
+
+    /// A model is loaded again.
+    pub fn recover(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.fault = None;
+        state.down = false;
+    }
+
+    /// Record a crash. Returns `true` if the model may be reloaded once more.
+    fn crash(&self, error: String, max_restarts: usize) -> bool {
+        let mut state = self.0.lock().unwrap();
+        state.fault = Some(error);
+        state
+            .crashes
+            .retain(|instant| instant.elapsed() < RESTART_WINDOW);
+        state.crashes.push(Instant::now());
+        let restart = state.crashes.len() <= max_restarts;
+        match restart {
+            true => state.restarts += 1,
+            false => state.down = true,
+        }
+        restart
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".into(),
+        },
+    }
+}
+
+/// Report to the supervisor if the device of the context is lost.
+pub(crate) fn watch_device(context: &Context, faults: Sender<String>) {
+    context
+        .device
+        .set_device_lost_callback(move |reason, message| {
+            // also called when the device is dropped on unload
+            if matches!(
+                reason,
+                DeviceLostReason::Unknown | DeviceLostReason::DeviceInvalid
+            ) {
+                let _ = faults.send(format!("device lost: {message}"));
+            }
+        });
+}
+
+/// A reload requested after a crash.
+pub(crate) struct Restart {
+    pub request: ReloadRequest,
+    /// The prompt cache to take over if the device was lost.
+    pub salvage: Option<Salvage>,
+}
+
+pub(crate) struct Supervisor {
+    pub env: Arc<RwLock<Environment>>,
+    pub queue: Arc<Mutex<Vec<GenerateContext>>>,
+    pub health: Health,
+    pub subscribers: Subscribers,
+    /// Device losses reported by [`watch_device`].
+    pub faults: Receiver<String>,
+    /// Where the reloads after crashes are requested.
+    pub restarts: Sender<Restart>,
+}
+
+impl Supervisor {
+    /// Run the model loop until the engine exits, restarting it after each crash.
+    pub async fn run(self, receiver: Receiver<()>) {
+        loop {
+            let mut handle = tokio::spawn(crate::run::run(receiver.clone(), self.env.clone()));
+            let (error, lost) = tokio::select! {
+                result = &mut handle => match result {
+                    // a lost device may also bring the loop down with a panic
+                    Err(err) if err.is_panic() => match self.faults.drain().last() {
+                        Some(error) => (error, true),
+                        None => (panic_message(err.into_panic()), false),
+                    },
+                    _ => break,
+                },
+                Ok(error) = self.faults.recv_async() => {
+                    handle.abort();
+                    (error, true)
+                }
+            };
+            log::error!("model crashed: {error}");
+            self.subscribers.send(RuntimeEvent::Crashed {
+                error: error.clone(),
+            });
+            self.recover(error, lost).await;
+        }
+    }
+
+    /// Drop the crashed runtime and request a reload. `lost` tells if the device is lost.
+    async fn recover(&self, error: String, lost: bool) {
+        let env = std::mem::take(&mut *self.env.write().await);
+        // reports coming late are about the device just dropped
+        self.faults.drain();
+        let failure = RuntimeError::from_crash(error.clone());
+        let Environment::Loaded(runtime) = env else {
+            // nothing to reload
+            self.fail_queue(failure).await;
+            self.health.0.lock().unwrap().fault = Some(error);
+            return;
+        };
+        let reload = runtime.reload().clone();
+        let (salvage, contexts) = runtime.salvage();
+        let salvage = match lost {
+            true => {
+                let (replays, failed): (Vec<_>, Vec<_>) = contexts
+                    .into_iter()
+                    .partition(GenerateContext::is_prefilling);
+                for context in failed {
+                    context.fail(failure.clone());
+                }
+                log::info!("{} requests to be replayed after the reload", replays.len());
+                // they are older than the ones still in the queue
+                let replays = replays.into_iter().map(GenerateContext::replay);
+                self.queue.lock().await.splice(0..0, replays);
+                Some(salvage)
+            }
+            false => {
+                for context in contexts {
+                    context.fail(failure.clone());
+                }
+                self.fail_queue(failure).await;
+                None
+            }
+        };
+
+        match self.health.crash(error, reload.max_restarts) {
+            true => {
+                log::info!("reloading the model after the crash");
+                let request = reload.clone();
+                let _ = self.restarts.send(Restart { request, salvage });
+            }
+            false => {
+                self.fail_queue(RuntimeError::ModelNotLoaded).await;
+                log::error!(
+                    "model crashed more than {} times in {} minutes, left unloaded",
+                    reload.max_restarts,
+                    RESTART_WINDOW.as_secs() / 60
+                )
+            }
+        }
+    }
+
+    async fn fail_queue(&self, error: RuntimeError) {
+        for context in self.queue.lock().await.drain(..) {
+            context.fail(error.clone());
+        }
+    }
+}
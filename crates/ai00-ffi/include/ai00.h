@@ -0,0 +1,38 @@
+/* C bindings of the ai00 inference engine. Configs and requests are JSON strings. */
+
+#ifndef AI00_H
+#define AI00_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct Ai00Engine Ai00Engine;
+
+/* Called with each piece of text generated. Return non-zero to stop the generation. */
+typedef int (*Ai00Callback)(const char *text, void *user_data);
+
+/* The message of the error of the last call on this thread, or NULL if it succeeded. Valid until the next call on this thread. */
+const char *ai00_last_error(void);
+
+/* Start an engine and load the model described by a reload request. NULL on failure. */
+Ai00Engine *ai00_engine_load(const char *config);
+
+/* Unload the model and stop the engine. */
+void ai00_engine_free(Ai00Engine *engine);
+
+/* Generate, calling `callback` with each piece of text. 0 on success, -1 on failure.
+ * The request has `prompt`, `max_tokens`, `stop`, `state` and the nucleus sampler options. */
+int ai00_generate(const Ai00Engine *engine, const char *request, Ai00Callback callback, void *user_data);
+
+/* Generate and return the whole text, to be freed with `ai00_string_free`. NULL on failure. */
+char *ai00_generate_text(const Ai00Engine *engine, const char *request);
+
+/* Free a string returned by this library. */
+void ai00_string_free(char *text);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,188 @@
+//! C ABI of the inference engine, for applications that embed it in-process, e.g., through ctypes,
+//! P/Invoke or N-API. The declarations are in `include/ai00.h`.
+//!
+//! Configs and requests are passed as JSON strings. Functions that fail return `NULL` or `-1`,
+//! and [`ai00_last_error`] tells why until the next call.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr,
+};
+
+use ai00_core::{
+    engine::{Engine, GenerateOption},
+    ReloadRequest, Token,
+};
+use anyhow::{anyhow, bail, Result};
+use futures_util::StreamExt;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Called with each piece of text generated, and the `user_data` given along.
+/// Returning non-zero stops the generation.
+pub type Ai00Callback = extern "C" fn(text: *const c_char, user_data: *mut c_void) -> c_int;
+
+/// An engine with the runtime that drives it.
+pub struct Ai00Engine {
+    runtime: tokio::runtime::Runtime,
+    engine: Engine,
+}
+
+/// Forget the error of the previous call, so that it is not taken for one of this call.
+fn clear_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+fn set_error(err: anyhow::Error) {
+    let message = CString::new(err.to_string().replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Run `f`, turning errors and panics into the last error and `fallback`.
+fn guard<T>(fallback: T, f: impl FnOnce() -> Result<T>) -> T {
+    clear_error();
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(err)) => {
+            set_error(err);
+            fallback
+        }
+        Err(_) => {
+            set_error(anyhow!("panicked"));
+            fallback
+        }
+    }
+}
+
+/// # Safety
+/// `text` must be `NULL` or a valid NUL-terminated string.
+unsafe fn read_str<'a>(text: *const c_char) -> Result<&'a str> {
+    if text.is_null() {
+        return Err(anyhow!("null string"));
+    }
+    Ok(CStr::from_ptr(text).to_str()?)
+}
+
+/// # Safety
+/// `engine` must be `NULL` or returned by [`ai00_engine_load`] and not yet freed.
+unsafe fn read_engine<'a>(engine: *const Ai00Engine) -> Result<&'a Ai00Engine> {
+    engine.as_ref().ok_or_else(|| anyhow!("null engine"))
+}
+
+fn to_c_string(text: String) -> CString {
+    CString::new(text.replace('\0', "")).unwrap_or_default()
+}
+
+/// The message of the error of the last call on this thread, or `NULL` if it succeeded.
+/// Valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn ai00_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Start an engine and load the model described by `config`, a reload request in JSON.
+/// Blocks until the model is loaded. Returns `NULL` on failure.
+///
+/// # Safety
+/// `config` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ai00_engine_load(config: *const c_char) -> *mut Ai00Engine {
+    guard(ptr::null_mut(), || {
+        let request: ReloadRequest = serde_json::from_str(read_str(config)?)?;
+        let runtime = tokio::runtime::Runtime::new()?;
+        let engine = runtime.block_on(Engine::load(request))?;
+        Ok(Box::into_raw(Box::new(Ai00Engine { runtime, engine })))
+    })
+}
+
+/// Unload the model and stop the engine.
+///
+/// # Safety
+/// `engine` must be `NULL` or returned by [`ai00_engine_load`] and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn ai00_engine_free(engine: *mut Ai00Engine) {
+    clear_error();
+    if engine.is_null() {
+        return;
+    }
+    let Ai00Engine { runtime, engine } = *Box::from_raw(engine);
+    let _ = engine.unload();
+    drop(engine);
+    runtime.shutdown_background();
+}
+
+/// Generate from `request`, a JSON object with `prompt`, `max_tokens`, `stop`, `state`
+/// and the nucleus sampler options. Blocks until the generation ends, calling `callback`
+/// with each piece of text. Returns `0` on success and `-1` on failure.
+///
+/// # Safety
+/// `engine` must be returned by [`ai00_engine_load`] and not yet freed; `request` must be a valid
+/// NUL-terminated string. `callback` must be safe to call with `user_data` from this thread.
+#[no_mangle]
+pub unsafe extern "C" fn ai00_generate(
+    engine: *const Ai00Engine,
+    request: *const c_char,
+    callback: Ai00Callback,
+    user_data: *mut c_void,
+) -> c_int {
+    guard(-1, || {
+        let Ai00Engine { runtime, engine } = read_engine(engine)?;
+        let request: GenerateOption = serde_json::from_str(read_str(request)?)?;
+        runtime.block_on(async {
+            // dropping the stream stops the generation
+            let mut stream = engine.generate_stream(request.into()).await?;
+            while let Some(token) = stream.next().await {
+                match token {
+                    Token::Content(content) => {
+                        let content = to_c_string(content);
+                        if callback(content.as_ptr(), user_data) != 0 {
+                            break;
+                        }
+                    }
+                    Token::Done => break,
+                    Token::Error(error) => bail!(error),
+                    _ => {}
+                }
+            }
+            Ok(0)
+        })
+    })
+}
+
+/// Generate from `request` as [`ai00_generate`] does, returning the whole text.
+/// Free the text with [`ai00_string_free`]. Returns `NULL` on failure.
+///
+/// # Safety
+/// `engine` must be returned by [`ai00_engine_load`] and not yet freed; `request` must be a valid
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ai00_generate_text(
+    engine: *const Ai00Engine,
+    request: *const c_char,
+) -> *mut c_char {
+    guard(ptr::null_mut(), || {
+        let Ai00Engine { runtime, engine } = read_engine(engine)?;
+        let request: GenerateOption = serde_json::from_str(read_str(request)?)?;
+        let generation = runtime.block_on(engine.generate(request.into()))?;
+        Ok(to_c_string(generation.text).into_raw())
+    })
+}
+
+/// Free a string returned by this library.
+///
+/// # Safety
+/// `text` must be `NULL` or returned by this library and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn ai00_string_free(text: *mut c_char) {
+    clear_error();
+    if !text.is_null() {
+        drop(CString::from_raw(text));
+    }
+}
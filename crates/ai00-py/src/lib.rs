@@ -0,0 +1,233 @@
+//! Python bindings of the inference engine, running the same code path as the server.
+//!
+//! ```python
+//! import ai00_py
+//!
+//! engine = ai00_py.Engine({"model_path": "model.st", "tokenizer_path": "vocab.json"})
+//! print(engine.generate({"prompt": "User: Hi!\n\nAssistant:", "stop": ["\n\n"]}))
+//!
+//! async for piece in engine.stream({"prompt": "User: Hi!\n\nAssistant:"}):
+//!     print(piece, end="")
+//! ```
+//!
+//! Configs and requests are objects `json.dumps` accepts, or JSON strings.
+//!
+//! The bindings are built with the `python` feature, which maturin enables.
+#![cfg(feature = "python")]
+// the code pyo3 generates for the methods converts the errors into themselves
+#![allow(clippy::useless_conversion)]
+
+use std::{path::PathBuf, sync::Arc};
+
+use ai00_core::{
+    engine::{Engine as CoreEngine, GenerateOption},
+    reload,
+    run::StateId,
+    ReloadRequest, Token,
+};
+use flume::r#async::RecvStream;
+use futures_util::StreamExt;
+use pyo3::{
+    exceptions::{PyRuntimeError, PyStopAsyncIteration, PyStopIteration, PyValueError},
+    prelude::*,
+    types::PyString,
+};
+use pyo3_async_runtimes::tokio::{future_into_py, get_runtime};
+use serde::de::DeserializeOwned;
+use tokio::sync::Mutex;
+
+/// Errors of the engine, raised as `RuntimeError` with the message only.
+fn error(err: anyhow::Error) -> PyErr {
+    PyRuntimeError::new_err(err.to_string())
+}
+
+fn read_json<T: DeserializeOwned>(py: Python<'_>, value: &Bound<'_, PyAny>) -> PyResult<T> {
+    let text: String = match value.downcast::<PyString>() {
+        Ok(text) => text.to_str()?.to_owned(),
+        Err(_) => py
+            .import_bound("json")?
+            .call_method1("dumps", (value,))?
+            .extract()?,
+    };
+    serde_json::from_str(&text).map_err(|err| PyValueError::new_err(err.to_string()))
+}
+
+fn state_id(id: StateId) -> String {
+    serde_json::to_value(id)
+        .ok()
+        .and_then(|id| id.as_str().map(ToOwned::to_owned))
+        .unwrap_or_default()
+}
+
+fn parse_state_id(id: &str) -> PyResult<StateId> {
+    serde_json::from_value(id.into()).map_err(|err| PyValueError::new_err(err.to_string()))
+}
+
+/// An engine with a model loaded.
+#[pyclass(module = "ai00_py")]
+struct Engine {
+    engine: CoreEngine,
+}
+
+#[pymethods]
+impl Engine {
+    /// Start an engine and load the model described by `config`, a reload request.
+    /// Blocks until the model is loaded.
+    #[new]
+    fn new(py: Python<'_>, config: &Bound<'_, PyAny>) -> PyResult<Self> {
+        let request: ReloadRequest = read_json(py, config)?;
+        let engine = py
+            .allow_threads(|| get_runtime().block_on(CoreEngine::load(request)))
+            .map_err(error)?;
+        Ok(Self { engine })
+    }
+
+    /// Generate the whole text of a request with `prompt`, `max_tokens`, `stop`, `state`
+    /// and the nucleus sampler options.
+    fn generate(&self, py: Python<'_>, request: &Bound<'_, PyAny>) -> PyResult<String> {
+        let request: GenerateOption = read_json(py, request)?;
+        let engine = self.engine.clone();
+        let generation = py
+            .allow_threads(|| get_runtime().block_on(engine.generate(request.into())))
+            .map_err(error)?;
+        Ok(generation.text)
+    }
+
+    /// Awaitable version of `generate`.
+    fn generate_async<'py>(
+        &self,
+        py: Python<'py>,
+        request: &Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let request: GenerateOption = read_json(py, request)?;
+        let engine = self.engine.clone();
+        future_into_py(py, async move {
+            let generation = engine.generate(request.into()).await.map_err(error)?;
+            Ok(generation.text)
+        })
+    }
+
+    /// Stream the pieces of text of a request, with either `for` or `async for`.
+    /// Dropping the stream stops the generation.
+    fn stream(&self, py: Python<'_>, request: &Bound<'_, PyAny>) -> PyResult<Stream> {
+        let request: GenerateOption = read_json(py, request)?;
+        let engine = self.engine.clone();
+        let stream = py
+            .allow_threads(|| get_runtime().block_on(engine.generate_stream(request.into())))
+            .map_err(error)?;
+        Ok(Stream(Arc::new(Mutex::new(stream))))
+    }
+
+    /// Embed a text with the output of the `layer`-th layer counting from the last.
+    #[pyo3(signature = (text, layer = 0))]
+    fn embed(&self, py: Python<'_>, text: String, layer: usize) -> PyResult<Vec<f32>> {
+        let engine = self.engine.clone();
+        py.allow_threads(|| get_runtime().block_on(engine.embed(text, layer)))
+            .map_err(error)
+    }
+
+    /// Load an initial state from a file. Returns its id, to be given as `state` in requests.
+    #[pyo3(signature = (path, name = None, default = false))]
+    fn load_state(
+        &self,
+        py: Python<'_>,
+        path: PathBuf,
+        name: Option<String>,
+        default: bool,
+    ) -> PyResult<String> {
+        let state = reload::State {
+            path,
+            name,
+            id: StateId::new(),
+            default,
+        };
+        let engine = self.engine.clone();
+        let id = py
+            .allow_threads(|| get_runtime().block_on(engine.load_state(state)))
+            .map_err(error)?;
+        Ok(state_id(id))
+    }
+
+    /// Read `prompt` from the initial state `state`, or from scratch, and save the state it ends up in
+    /// to a file `load_state` reads.
+    #[pyo3(signature = (path, prompt, state = None))]
+    fn save_state(
+        &self,
+        py: Python<'_>,
+        path: PathBuf,
+        prompt: String,
+        state: Option<&str>,
+    ) -> PyResult<()> {
+        let state = state.map(parse_state_id).transpose()?.unwrap_or_default();
+        let engine = self.engine.clone();
+        py.allow_threads(|| get_runtime().block_on(engine.save_state(prompt, state, path)))
+            .map_err(error)
+    }
+
+    fn unload_state(&self, id: &str) -> PyResult<()> {
+        self.engine.unload_state(parse_state_id(id)?).map_err(error)
+    }
+
+    /// The ids and names of the initial states loaded.
+    fn states(&self, py: Python<'_>) -> PyResult<Vec<(String, String)>> {
+        let engine = self.engine.clone();
+        let info = py
+            .allow_threads(|| get_runtime().block_on(engine.info()))
+            .map_err(error)?;
+        let states = info
+            .states
+            .into_iter()
+            .map(|(id, state)| (state_id(id), state.name))
+            .collect();
+        Ok(states)
+    }
+}
+
+/// The pieces of text of a generation.
+#[pyclass(module = "ai00_py")]
+struct Stream(Arc<Mutex<RecvStream<'static, Token>>>);
+
+async fn next(stream: Arc<Mutex<RecvStream<'static, Token>>>) -> Option<String> {
+    let mut stream = stream.lock().await;
+    while let Some(token) = stream.next().await {
+        match token {
+            Token::Content(content) => return Some(content),
+            Token::Done => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+#[pymethods]
+impl Stream {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python<'_>) -> PyResult<String> {
+        let stream = self.0.clone();
+        py.allow_threads(|| get_runtime().block_on(next(stream)))
+            .ok_or_else(|| PyStopIteration::new_err(()))
+    }
+
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let stream = self.0.clone();
+        future_into_py(py, async move {
+            next(stream)
+                .await
+                .ok_or_else(|| PyStopAsyncIteration::new_err(()))
+        })
+    }
+}
+
+#[pymodule]
+fn ai00_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Engine>()?;
+    m.add_class::<Stream>()?;
+    Ok(())
+}
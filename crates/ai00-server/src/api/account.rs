@@ -0,0 +1,389 @@
+//! Accounts of the users of a shared instance, who log in with a password instead of an app key
+//! and keep their own default sampler and model.
+//!
+//! The token of an account names the account as the caller, so that caches, conversations
+//! and limits are kept apart per user. Accounts are kept in a JSON file with salted PBKDF2 hashes.
+//!
+//! With the `ldap` feature, users of a directory log in with their directory password too.
+//! Their accounts are added on the first login, without a hash, to keep their settings.
+//! The directory then owns the names, so no one registers locally.
+//!
+//! Names are compared in lower case, as directories do.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use aes_gcm::aead::OsRng;
+use anyhow::{anyhow, Result};
+use pbkdf2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Params, Pbkdf2,
+};
+use salvo::{oapi::extract::JsonBody, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use super::{auth::caller, oai::SamplerParams};
+use crate::config::{AccountOption, ListenerOption};
+
+/// Preferences of a user, applied to the requests made with the token of the account.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct AccountSettings {
+    /// Sampler of the chats and completions that give no `sampler_override`.
+    pub sampler: Option<SamplerParams>,
+    /// Model the WebUI loads for the user, as a path under the models directory.
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Account {
+    /// PHC string of the salted password hash. Empty for accounts of the directory.
+    #[serde(default)]
+    hash: String,
+    #[serde(default)]
+    settings: AccountSettings,
+}
+
+#[derive(Debug)]
+pub struct AccountStore {
+    option: AccountOption,
+    accounts: Mutex<HashMap<String, Account>>,
+}
+
+/// Rounds of PBKDF2-SHA256 on new hashes, as OWASP recommends. Older hashes keep the rounds they are made with.
+const PBKDF2_ROUNDS: u32 = 600_000;
+
+fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let params = Params {
+        rounds: PBKDF2_ROUNDS,
+        ..Default::default()
+    };
+    let hash = Pbkdf2
+        .hash_password_customized(password.as_bytes(), None, None, params, &salt)
+        .map_err(|err| anyhow!("failed to hash password: {err}"))?;
+    Ok(hash.to_string())
+}
+
+/// Check a name and a password by binding to the directory as the user.
+#[cfg(feature = "ldap")]
+async fn bind(option: &crate::config::LdapOption, name: &str, password: &str) -> Result<bool> {
+    use ldap3::{LdapConnAsync, LdapConnSettings};
+
+    /// Result code of wrong credentials.
+    const INVALID_CREDENTIALS: u32 = 49;
+
+    // servers take a bind without a password as anonymous, which always succeeds
+    if password.is_empty() {
+        return Ok(false);
+    }
+    let settings = LdapConnSettings::new()
+        .set_conn_timeout(std::time::Duration::from_secs(option.timeout))
+        .set_starttls(option.starttls);
+    let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &option.url).await?;
+    ldap3::drive!(conn);
+    let dn = option.bind_dn.replace("{name}", &ldap3::dn_escape(name));
+    let result = ldap.simple_bind(&dn, password).await?;
+    let _ = ldap.unbind().await;
+    match result.rc {
+        0 => Ok(true),
+        INVALID_CREDENTIALS => Ok(false),
+        _ => anyhow::bail!("failed to bind to the directory: {result}"),
+    }
+}
+
+impl AccountStore {
+    /// Read the accounts from the file of the option. No file means no accounts yet.
+    pub fn load(option: AccountOption) -> Result<Self> {
+        #[cfg(not(feature = "ldap"))]
+        if option.ldap.is_some() {
+            log::warn!("ldap skipped, the server is built without the `ldap` feature");
+        }
+        if option.register && option.ldap.is_some() {
+            log::warn!("registration closed, the names belong to the directory");
+        }
+        let accounts = match option.path.exists() {
+            true => serde_json::from_slice(&std::fs::read(&option.path)?)?,
+            false => HashMap::new(),
+        };
+        Ok(Self {
+            option,
+            accounts: Mutex::new(accounts),
+        })
+    }
+
+    pub fn obtain(depot: &Depot) -> Option<Arc<Self>> {
+        depot.get::<Arc<Self>>("accounts").ok().cloned()
+    }
+
+    /// Whether anyone may register. A local account would otherwise take the name of a directory user
+    /// before the first login, and lock them out.
+    fn open(&self) -> bool {
+        self.option.register && self.option.ldap.is_none()
+    }
+
+    /// The name an account is kept under.
+    fn normalize(name: &str) -> String {
+        name.trim().to_lowercase()
+    }
+
+    /// Write the accounts through a temporary file, so that a crash never leaves the file half written.
+    fn save(path: &Path, accounts: &HashMap<String, Account>) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, serde_json::to_vec_pretty(accounts)?)?;
+        std::fs::rename(temp, path)?;
+        Ok(())
+    }
+
+    /// Add an account. Returns `false` if the name is taken.
+    fn register(&self, name: &str, password: &str) -> Result<bool> {
+        let hash = hash_password(password)?;
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(name) {
+            return Ok(false);
+        }
+        let account = Account {
+            hash,
+            settings: Default::default(),
+        };
+        accounts.insert(name.to_owned(), account);
+        if let Err(err) = Self::save(&self.option.path, &accounts) {
+            accounts.remove(name);
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    fn verify(&self, name: &str, password: &str) -> bool {
+        // hashing takes a while; other logins need not wait for it
+        let hash = {
+            let accounts = self.accounts.lock().unwrap();
+            let Some(account) = accounts.get(name) else {
+                return false;
+            };
+            account.hash.clone()
+        };
+        PasswordHash::new(&hash)
+            .is_ok_and(|hash| Pbkdf2.verify_password(password.as_bytes(), &hash).is_ok())
+    }
+
+    /// Check a name and a password against the directory, if any.
+    /// Local accounts are never taken over by users of the directory of the same name.
+    async fn verify_directory(&self, name: &str, password: &str) -> Result<bool> {
+        #[cfg(feature = "ldap")]
+        if let Some(option) = &self.option.ldap {
+            let local = |account: &Account| !account.hash.is_empty();
+            if self.accounts.lock().unwrap().get(name).is_some_and(local) {
+                return Ok(false);
+            }
+            if !bind(option, name, password).await? {
+                return Ok(false);
+            }
+            let mut accounts = self.accounts.lock().unwrap();
+            if !accounts.contains_key(name) {
+                let account = Account {
+                    hash: String::new(),
+                    settings: Default::default(),
+                };
+                accounts.insert(name.to_owned(), account);
+                Self::save(&self.option.path, &accounts)?;
+                log::info!("added account {name} of the directory");
+            }
+            return Ok(true);
+        }
+        let _ = (name, password);
+        Ok(false)
+    }
+
+    pub fn settings(&self, name: &str) -> Option<AccountSettings> {
+        let accounts = self.accounts.lock().unwrap();
+        accounts.get(name).map(|account| account.settings.clone())
+    }
+
+    /// Replace the settings of an account. Returns `false` if there is no such account.
+    fn update(&self, name: &str, settings: AccountSettings) -> Result<bool> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let Some(account) = accounts.get_mut(name) else {
+            return Ok(false);
+        };
+        let settings = std::mem::replace(&mut account.settings, settings);
+        if let Err(err) = Self::save(&self.option.path, &accounts) {
+            if let Some(account) = accounts.get_mut(name) {
+                account.settings = settings;
+            }
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    /// Fill in the defaults of the caller into the sampler options of a request.
+    pub fn apply(depot: &Depot, sampler_override: &mut Option<SamplerParams>) {
+        let settings = Self::obtain(depot)
+            .zip(caller(depot))
+            .and_then(|(store, name)| store.settings(&name));
+        if let Some(sampler) = settings.and_then(|settings| settings.sampler) {
+            sampler_override.get_or_insert(sampler);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct AccountRequest {
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct LoginResponse {
+    /// Token to call the APIs with as the account.
+    pub token: String,
+}
+
+/// Sign a token for the account, or render the error.
+fn login_response(depot: &Depot, name: String, res: &mut Response) {
+    let listen_option = depot.get::<ListenerOption>("listen").unwrap();
+    match super::auth::issue_token(listen_option, name, false) {
+        Ok(token) => res.render(Json(LoginResponse { token })),
+        Err(err) => {
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+            res.render(Text::Plain(err.to_string()));
+        }
+    }
+}
+
+/// `/api/accounts/register`: add an account and log in with it.
+#[endpoint(
+    responses(
+        (status_code = 200, body = LoginResponse),
+        (status_code = 400, description = "The name is empty or the password is too short.", body = String),
+        (status_code = 403, description = "Registration is closed, or the accounts come from a directory."),
+        (status_code = 404, description = "Accounts are not enabled."),
+        (status_code = 409, description = "The name is taken."),
+        (status_code = 500, description = "Failed to write the accounts.", body = String),
+    )
+)]
+pub fn register(depot: &mut Depot, req: JsonBody<AccountRequest>, res: &mut Response) {
+    let Some(store) = AccountStore::obtain(depot) else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    if !store.open() {
+        res.status_code(StatusCode::FORBIDDEN);
+        return;
+    }
+    let AccountRequest { name, password } = req.0;
+    let name = AccountStore::normalize(&name);
+    let min_password = store.option.min_password;
+    if name.is_empty() || password.chars().count() < min_password {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain(format!(
+            "name must not be empty, and password must have at least {min_password} characters"
+        )));
+        return;
+    }
+    // app ids are callers too, so an account of the same name would share their data
+    let listen_option = depot.get::<ListenerOption>("listen").unwrap();
+    if listen_option
+        .app_keys
+        .iter()
+        .any(|key| key.app_id.to_lowercase() == name)
+    {
+        res.status_code(StatusCode::CONFLICT);
+        return;
+    }
+    match store.register(&name, &password) {
+        Ok(true) => {
+            log::info!("registered account {name}");
+            login_response(depot, name, res);
+        }
+        Ok(false) => {
+            res.status_code(StatusCode::CONFLICT);
+        }
+        Err(err) => {
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+            res.render(Text::Plain(err.to_string()));
+        }
+    }
+}
+
+/// `/api/accounts/login`: exchange the name and password of an account with a token.
+#[endpoint(
+    responses(
+        (status_code = 200, body = LoginResponse),
+        (status_code = 403, description = "Wrong name or password."),
+        (status_code = 404, description = "Accounts are not enabled."),
+        (status_code = 502, description = "The directory server cannot be reached."),
+    )
+)]
+pub async fn login(depot: &mut Depot, req: JsonBody<AccountRequest>, res: &mut Response) {
+    let Some(store) = AccountStore::obtain(depot) else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    let AccountRequest { name, password } = req.0;
+    let name = AccountStore::normalize(&name);
+    if store.verify(&name, &password) {
+        login_response(depot, name, res);
+        return;
+    }
+    match store.verify_directory(&name, &password).await {
+        Ok(true) => login_response(depot, name, res),
+        Ok(false) => {
+            res.status_code(StatusCode::FORBIDDEN);
+        }
+        Err(err) => {
+            log::warn!("failed to check account against the directory: {err}");
+            res.status_code(StatusCode::BAD_GATEWAY);
+        }
+    }
+}
+
+/// `/api/accounts/settings`: the settings of the account of the caller.
+#[endpoint(
+    responses(
+        (status_code = 200, body = AccountSettings),
+        (status_code = 404, description = "The caller has no account."),
+    )
+)]
+pub fn get_settings(depot: &mut Depot, res: &mut Response) {
+    let settings = AccountStore::obtain(depot)
+        .zip(caller(depot))
+        .and_then(|(store, name)| store.settings(&name));
+    match settings {
+        Some(settings) => res.render(Json(settings)),
+        None => {
+            res.status_code(StatusCode::NOT_FOUND);
+        }
+    }
+}
+
+/// `/api/accounts/settings`: replace the settings of the account of the caller.
+#[endpoint(
+    responses(
+        (status_code = 200, description = "The settings are saved."),
+        (status_code = 404, description = "The caller has no account."),
+        (status_code = 500, description = "Failed to write the accounts.", body = String),
+    )
+)]
+pub fn save_settings(depot: &mut Depot, req: JsonBody<AccountSettings>, res: &mut Response) {
+    let Some((store, name)) = AccountStore::obtain(depot).zip(caller(depot)) else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    match store.update(&name, req.0) {
+        Ok(true) => {}
+        Ok(false) => {
+            res.status_code(StatusCode::NOT_FOUND);
+        }
+        Err(err) => {
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+            res.render(Text::Plain(err.to_string()));
+        }
+    }
+}
@@ -1,14 +1,72 @@
-use ai00_core::{AdapterList, ThreadRequest};
-use salvo::prelude::*;
+use ai00_core::{reload::AdapterOption, AdapterInfo, AdapterList, RuntimeStats, ThreadRequest};
+use salvo::{oapi::extract::JsonBody, prelude::*};
+use serde::Deserialize;
 
+use super::try_request_info;
 use crate::types::ThreadState;
 
-/// `/api/adapters`.
-#[endpoint]
-pub async fn adapters(depot: &mut Depot) -> Json<Vec<String>> {
+async fn list_adapters(depot: &mut Depot) -> Vec<AdapterInfo> {
     let (list_sender, list_receiver) = flume::unbounded();
     let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
     let _ = sender.send(ThreadRequest::Adapter(list_sender));
     let AdapterList(list) = list_receiver.recv_async().await.unwrap_or_default();
-    Json(list)
+    list
+}
+
+/// `/api/adapters`.
+#[endpoint]
+pub async fn adapters(depot: &mut Depot) -> Json<Vec<String>> {
+    let list = list_adapters(depot).await;
+    Json(list.iter().map(ToString::to_string).collect())
+}
+
+/// Detailed information of all adapters.
+/// Note that wgpu does not expose the memory of an adapter.
+#[endpoint]
+pub async fn adapters_info(depot: &mut Depot) -> Json<Vec<AdapterInfo>> {
+    Json(list_adapters(depot).await)
+}
+
+/// Scheduling and GPU resource statistics of the runtime.
+#[endpoint]
+pub async fn adapters_stats(depot: &mut Depot) -> Json<RuntimeStats> {
+    let (stats_sender, stats_receiver) = flume::unbounded();
+    let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
+    let _ = sender.send(ThreadRequest::Stats(stats_sender));
+    Json(stats_receiver.recv_async().await.unwrap_or_default())
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct AdapterSelectRequest {
+    adapter: AdapterOption,
+}
+
+/// Move the loaded model onto another adapter by reloading it there.
+#[endpoint(
+    status_codes(200, 404, 500),
+    responses(
+        (status_code = 200, description = "The model is reloaded on the selected adapter."),
+        (status_code = 404, description = "There is no model loaded."),
+        (status_code = 500, description = "Failed to load the model on the selected adapter."),
+    )
+)]
+pub async fn select_adapter(depot: &mut Depot, req: JsonBody<AdapterSelectRequest>) -> StatusCode {
+    let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
+    let Ok(info) = try_request_info(sender.clone()).await else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    let request = Box::new(ai00_core::ReloadRequest {
+        adapter: req.0.adapter,
+        ..info.reload
+    });
+    let (result_sender, result_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Reload {
+        request,
+        sender: Some(result_sender),
+    });
+    match result_receiver.recv_async().await.unwrap_or_default() {
+        true => StatusCode::OK,
+        false => StatusCode::INTERNAL_SERVER_ERROR,
+    }
 }
@@ -0,0 +1,257 @@
+//! Append-only trail of the calls to the admin APIs: who called what, when, with which parameters,
+//! and how it went. Calls refused for lack of rights are recorded too.
+//!
+//! Records go one JSON object per line, sealed if a storage key is given, and are read back at
+//! `/api/admin/audit`. Secrets among the parameters, e.g., the keys in a saved config, are masked.
+
+use std::sync::Arc;
+
+use ai00_core::storage::Sealer;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use salvo::{http::header, prelude::*};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+use super::auth::caller;
+use crate::config::AuditOption;
+
+/// Records returned by a query if it gives no `limit`.
+const DEFAULT_LIMIT: usize = 100;
+/// What masked parameters read as.
+const MASK: &str = "***";
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AuditRecord {
+    #[salvo(schema(value_type = String))]
+    pub time: DateTime<Local>,
+    /// The caller and its address.
+    pub actor: String,
+    pub method: String,
+    pub path: String,
+    /// The query and, if it is small JSON, the body of the request.
+    #[salvo(schema(value_type = Object))]
+    pub params: Value,
+    pub status: u16,
+    /// Id of the call, as in the `X-Request-Id` header.
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+pub struct AuditLog {
+    option: AuditOption,
+    sealer: Option<Sealer>,
+    /// Keeps the lines of concurrent calls apart.
+    lock: Mutex<()>,
+}
+
+/// Whether a parameter holds a secret, by its name.
+fn is_secret(key: &str) -> bool {
+    let key = key.to_lowercase();
+    ["secret", "password", "token"]
+        .iter()
+        .any(|word| key.contains(word))
+        || key == "key"
+        || key == "slot"
+}
+
+fn mask(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                match is_secret(key) {
+                    true => *value = MASK.into(),
+                    false => mask(value),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(mask),
+        _ => {}
+    }
+}
+
+impl AuditLog {
+    pub fn new(option: AuditOption, sealer: Option<Sealer>) -> Self {
+        Self {
+            option,
+            sealer,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// The query and the JSON body of a request, leaving the body for the handler to read again.
+    async fn params(&self, req: &mut Request) -> Value {
+        let mut params: Map<String, Value> = req
+            .queries()
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone().into()))
+            .collect();
+
+        let json = req
+            .content_type()
+            .is_some_and(|mime| mime.essence_str() == "application/json");
+        let max_body = self.option.max_body;
+        // bodies of unknown length may be streams, left to the handler
+        let small = req
+            .header::<usize>(header::CONTENT_LENGTH)
+            .is_some_and(|size| size <= max_body);
+        if json && small {
+            if let Ok(body) = req.payload_with_max_size(max_body).await {
+                let body = body.clone();
+                if let Ok(value) = serde_json::from_slice(&body) {
+                    params.insert("body".into(), value);
+                }
+                req.replace_body(body.into());
+            }
+        }
+
+        let mut params = Value::Object(params);
+        mask(&mut params);
+        params
+    }
+
+    async fn append(&self, record: &AuditRecord) -> Result<()> {
+        let Some(path) = &self.option.path else {
+            return Ok(());
+        };
+        let mut line = serde_json::to_string(record)?;
+        if let Some(sealer) = &self.sealer {
+            line = sealer.seal_line(&line);
+        }
+        line.push('\n');
+
+        let _lock = self.lock.lock().await;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|err| anyhow!("failed to open {}: {err}", path.to_string_lossy()))?
+            .write_all(line.as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    /// All records in the order they are written. Lines that cannot be read are skipped.
+    async fn read(&self) -> Result<Vec<AuditRecord>> {
+        let Some(path) = &self.option.path else {
+            return Ok(vec![]);
+        };
+        let text = match tokio::fs::read_to_string(path).await {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let records = text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| {
+                let line = match &self.sealer {
+                    Some(sealer) => sealer.open_line(line).ok()?,
+                    None => line.to_owned(),
+                };
+                serde_json::from_str(&line).ok()
+            })
+            .collect();
+        Ok(records)
+    }
+}
+
+fn audit_log(depot: &Depot) -> Option<Arc<AuditLog>> {
+    let audit = depot.get::<Arc<AuditLog>>("audit").ok()?;
+    audit.option.path.is_some().then(|| audit.clone())
+}
+
+/// Guard of the admin APIs that records every call with its outcome.
+#[handler]
+pub async fn audit_trail(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    let Some(audit) = audit_log(depot) else {
+        return;
+    };
+    let time = Local::now();
+    let id = caller(depot).unwrap_or_else(|| "anonymous".into());
+    let actor = format!("{id}@{}", req.remote_addr());
+    let method = req.method().to_string();
+    let path = req.uri().path().to_owned();
+    let params = audit.params(req).await;
+
+    ctrl.call_next(req, depot, res).await;
+
+    let record = AuditRecord {
+        time,
+        actor,
+        method,
+        path,
+        params,
+        status: res.status_code.unwrap_or(StatusCode::OK).as_u16(),
+        request_id: ai00_core::request_id(),
+    };
+    if let Err(err) = audit.append(&record).await {
+        log::error!("failed to record admin call {}: {err}", record.path);
+    }
+}
+
+/// `/api/admin/audit`: the latest records of the admin calls, oldest first.
+///
+/// Filters by `actor` and `path`, which match parts of the recorded ones, and by `since`, an RFC 3339 time.
+#[endpoint(
+    parameters(
+        ("actor" = Option<String>, Query, description = "Part of the actor."),
+        ("path" = Option<String>, Query, description = "Part of the path called."),
+        ("since" = Option<String>, Query, description = "Earliest time, in RFC 3339."),
+        ("limit" = Option<usize>, Query, description = "Maximum records returned, 100 if not given."),
+    ),
+    responses(
+        (status_code = 200, body = Vec<AuditRecord>),
+        (status_code = 400, description = "`since` is not a time.", body = String),
+        (status_code = 404, description = "No audit log is configured."),
+        (status_code = 500, description = "Failed to read the audit log.", body = String),
+    )
+)]
+pub async fn audit_records(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let Some(audit) = audit_log(depot) else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    let actor = req.query::<String>("actor");
+    let path = req.query::<String>("path");
+    let since = match req.query::<String>("since") {
+        Some(since) => match DateTime::parse_from_rfc3339(&since) {
+            Ok(since) => Some(since),
+            Err(err) => {
+                res.status_code(StatusCode::BAD_REQUEST);
+                res.render(Text::Plain(format!("invalid since: {err}")));
+                return;
+            }
+        },
+        None => None,
+    };
+    let limit = req.query::<usize>("limit").unwrap_or(DEFAULT_LIMIT);
+
+    let records = match audit.read().await {
+        Ok(records) => records,
+        Err(err) => {
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+            res.render(Text::Plain(err.to_string()));
+            return;
+        }
+    };
+    let mut records: Vec<_> = records
+        .into_iter()
+        .filter(|record| actor.as_ref().map_or(true, |x| record.actor.contains(x)))
+        .filter(|record| path.as_ref().map_or(true, |x| record.path.contains(x)))
+        .filter(|record| since.map_or(true, |since| record.time >= since))
+        .collect();
+    let skip = records.len().saturating_sub(limit);
+    records.drain(..skip);
+    res.render(Json(records));
+}
@@ -2,12 +2,17 @@ use anyhow::Result;
 use jsonwebtoken::{EncodingKey, Header};
 use salvo::{
     http::cookie::time::{Duration, OffsetDateTime},
+    jwt_auth::JwtAuthDepotExt,
     oapi::extract::JsonBody,
     prelude::*,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::{config::ListenerOption, types::JwtClaims};
+use super::{security::client_ip, signature::signed_caller};
+use crate::{
+    config::ListenerOption,
+    types::{JwtClaims, OidcClaims},
+};
 
 #[derive(Serialize, Deserialize, Debug, ToParameters, ToSchema)]
 #[salvo(extract(
@@ -27,6 +32,27 @@ struct AuthResponse {
     message: Option<String>,
 }
 
+/// Sign a token of the `slot` secret for the caller `sid`, expiring after `expire_sec`.
+/// Only tokens with `admin` set may call the admin APIs.
+pub fn issue_token(
+    listen_option: &ListenerOption,
+    sid: String,
+    admin: bool,
+) -> jsonwebtoken::errors::Result<String> {
+    let exp = OffsetDateTime::now_utc()
+        + Duration::seconds(listen_option.expire_sec.unwrap_or(86400u32) as i64);
+    let claim = JwtClaims {
+        sid,
+        exp: exp.unix_timestamp(),
+        admin,
+    };
+    jsonwebtoken::encode(
+        &Header::default(),
+        &claim,
+        &EncodingKey::from_secret(listen_option.slot.as_bytes()),
+    )
+}
+
 /// Exchange `appkey` and `app_secret` with the authorization token.
 #[endpoint(
     responses(
@@ -38,23 +64,22 @@ struct AuthResponse {
 pub fn exchange(depot: &mut Depot, req: JsonBody<AppKeyRequest>, res: &mut Response) {
     let listen_option = depot.get::<ListenerOption>("listen").unwrap();
     let auth = req.0;
+    if listen_option.oidc.is_some() {
+        res.status_code(StatusCode::BAD_REQUEST)
+            .render(Json(AuthResponse {
+                token: None,
+                code: StatusCode::BAD_REQUEST.as_u16(),
+                message: Some("tokens are issued by the OIDC provider".to_string()),
+            }));
+        return;
+    }
     if listen_option
         .app_keys
         .clone()
         .into_iter()
         .any(|p| p.app_id == auth.app_id.clone() && p.secret_key == auth.app_secret.clone())
     {
-        let exp = OffsetDateTime::now_utc()
-            + Duration::seconds(listen_option.expire_sec.unwrap_or(86400u32) as i64);
-        let claim = JwtClaims {
-            sid: auth.app_id,
-            exp: exp.unix_timestamp(),
-        };
-        match jsonwebtoken::encode(
-            &Header::default(),
-            &claim,
-            &EncodingKey::from_secret(listen_option.slot.clone().as_bytes()),
-        ) {
+        match issue_token(listen_option, auth.app_id, true) {
             Ok(token) => {
                 res.render(Json(AuthResponse {
                     token: Some(token),
@@ -81,3 +106,98 @@ pub fn exchange(depot: &mut Depot, req: JsonBody<AppKeyRequest>, res: &mut Respo
             }));
     }
 }
+
+/// Identity of the caller: the app id of a shared-secret token or of a signed request, or the subject of an OIDC token.
+pub fn caller(depot: &Depot) -> Option<String> {
+    depot
+        .jwt_auth_data::<JwtClaims>()
+        .map(|data| data.claims.sid.clone())
+        .or_else(|| {
+            depot
+                .jwt_auth_data::<OidcClaims>()
+                .map(|data| data.claims.sub.clone())
+        })
+        .or_else(|| signed_caller(depot))
+}
+
+/// Check the role claims of the caller against the scope when OIDC is enabled.
+fn authorize(depot: &Depot, admin: bool) -> Result<(), StatusCode> {
+    let listen_option = depot
+        .get::<ListenerOption>("listen")
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if signed_caller(depot).is_some() {
+        let signing = listen_option.signing.as_ref();
+        return match !admin || signing.is_some_and(|option| option.admin) {
+            true => Ok(()),
+            false => Err(StatusCode::FORBIDDEN),
+        };
+    }
+    let Some(oidc) = &listen_option.oidc else {
+        // tokens of accounts are signed with the same secret, but never carry the admin claim
+        let token = depot.jwt_auth_data::<JwtClaims>();
+        return match admin && token.is_some_and(|data| !data.claims.admin) {
+            true => Err(StatusCode::FORBIDDEN),
+            false => Ok(()),
+        };
+    };
+    let Some(data) = depot.jwt_auth_data::<OidcClaims>() else {
+        // `force_pass` only relaxes the inference scope; admin calls always need a valid token
+        return match !admin && listen_option.force_pass.unwrap_or_default() {
+            true => Ok(()),
+            false => Err(StatusCode::UNAUTHORIZED),
+        };
+    };
+
+    let required = match admin {
+        true => &oidc.admin_roles,
+        false if oidc.inference_roles.is_empty() => return Ok(()),
+        false => &oidc.inference_roles,
+    };
+    let roles = data.claims.roles(&oidc.roles_claim);
+    match required.iter().any(|role| roles.contains(role)) {
+        true => Ok(()),
+        false => Err(StatusCode::FORBIDDEN),
+    }
+}
+
+/// Guard of the admin APIs: callers must hold one of `admin_roles`.
+#[handler]
+pub async fn admin_scope(depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+    if let Err(status) = authorize(depot, true) {
+        res.status_code(status);
+        ctrl.skip_rest();
+    }
+}
+
+/// Guard of the inference APIs: callers must hold one of `inference_roles` if any is set.
+/// Also records the client the request is accounted to, see [`client`].
+#[handler]
+pub async fn inference_scope(
+    depot: &mut Depot,
+    req: &mut Request,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    if let Err(status) = authorize(depot, false) {
+        res.status_code(status);
+        ctrl.skip_rest();
+        return;
+    }
+
+    let ip = client_ip(depot);
+    let client = caller(depot)
+        .or(ip.map(|ip| ip.to_string()))
+        .unwrap_or_else(|| {
+            let addr = req.remote_addr();
+            addr.as_ipv4()
+                .map(|addr| addr.ip().to_string())
+                .or_else(|| addr.as_ipv6().map(|addr| addr.ip().to_string()))
+                .unwrap_or_else(|| addr.to_string())
+        });
+    depot.insert("client", client);
+}
+
+/// The client an inference request is accounted to: the caller if authenticated, or else the client address.
+pub fn client(depot: &Depot) -> Option<String> {
+    depot.get::<String>("client").ok().cloned()
+}
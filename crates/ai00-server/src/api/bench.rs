@@ -0,0 +1,205 @@
+use std::time::{Duration, Instant};
+
+use ai00_core::{GenerateRequest, ReloadRequest, RuntimeInfo, ThreadRequest, Token};
+use anyhow::{bail, Result};
+use derivative::Derivative;
+use flume::Sender;
+use futures_util::future::join_all;
+use itertools::Itertools;
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+use web_rwkv::runtime::model::ModelInfo;
+
+use super::try_request_info;
+use crate::types::ThreadState;
+
+/// Text the benchmark prompts are cut from. The content does not affect the speed.
+const CORPUS: &str = "The quick brown fox jumps over the lazy dog. \
+    A journey of a thousand miles begins with a single step. \
+    All that glitters is not gold, and not all those who wander are lost. \
+    In the middle of difficulty lies opportunity, and the best way out is always through. \
+    ";
+
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema, clap::Args)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct BenchOption {
+    /// Numbers of requests run in parallel, each capped by `max_batch`.
+    #[arg(long, value_delimiter = ',', default_values_t = [1, 4])]
+    #[derivative(Default(value = "vec![1, 4]"))]
+    pub batch: Vec<usize>,
+    /// Prompt lengths in tokens.
+    #[arg(long, value_delimiter = ',', default_values_t = [128, 1024])]
+    #[derivative(Default(value = "vec![128, 1024]"))]
+    pub context: Vec<usize>,
+    /// Number of tokens generated by each request.
+    #[arg(long, default_value_t = 128)]
+    #[derivative(Default(value = "128"))]
+    pub decode: usize,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BenchResult {
+    pub batch: usize,
+    pub context: usize,
+    /// Prompt tokens processed per second, summed over the batch.
+    pub prefill: f64,
+    /// Tokens generated per second, summed over the batch.
+    pub decode: f64,
+    /// Mean time until the first token of a request arrives.
+    pub first_token: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BenchReport {
+    pub reload: ReloadRequest,
+    /// Version, layers, embedding size and the other dimensions of the model.
+    #[salvo(schema(value_type = Object))]
+    pub model: ModelInfo,
+    pub results: Vec<BenchResult>,
+}
+
+/// Run one round of `batch` parallel requests with prompts of `context` tokens.
+async fn bench_round(
+    sender: &Sender<ThreadRequest>,
+    info: &RuntimeInfo,
+    corpus: &[u16],
+    batch: usize,
+    context: usize,
+    decode: usize,
+) -> Result<BenchResult> {
+    let start = Instant::now();
+    let tasks = (0..batch).map(|_| {
+        // a unique head defeats the prompt cache, so that every round runs a full prefill
+        let head = format!("{}\n", fastrand::u64(..));
+        let mut tokens = info.tokenizer.encode(head.as_bytes()).unwrap_or_default();
+        tokens.extend(corpus.iter().take(context.saturating_sub(tokens.len())));
+
+        let request = Box::new(GenerateRequest {
+            prompt_tokens: Some(tokens),
+            max_tokens: decode,
+            ..Default::default()
+        });
+        let (token_sender, token_receiver) = flume::unbounded();
+        let _ = sender.send(ThreadRequest::Generate {
+            request,
+            tokenizer: info.tokenizer.clone(),
+            sender: token_sender,
+        });
+
+        async move {
+            let mut first_token = None;
+            let mut completion = 0;
+            while let Ok(token) = token_receiver.recv_async().await {
+                match token {
+                    Token::Content(_) => {
+                        first_token.get_or_insert_with(|| start.elapsed());
+                    }
+                    Token::Stop(_, _, counter) => completion = counter.completion,
+                    Token::Done => break,
+                    _ => {}
+                }
+            }
+            (first_token, completion, start.elapsed())
+        }
+    });
+    let outputs = join_all(tasks).await;
+
+    let Some(first_tokens) = outputs
+        .iter()
+        .map(|(first_token, _, _)| *first_token)
+        .collect::<Option<Vec<_>>>()
+    else {
+        bail!("benchmark request generated no tokens");
+    };
+    let prefill_end = first_tokens.iter().max().copied().unwrap_or_default();
+    let first_token = first_tokens.iter().sum::<Duration>() / batch.max(1) as u32;
+    let decode_start = first_tokens.iter().min().copied().unwrap_or_default();
+    let decode_end = outputs
+        .iter()
+        .map(|(_, _, end)| *end)
+        .max()
+        .unwrap_or_default();
+    let decoded: usize = outputs
+        .iter()
+        .map(|(_, completion, _)| completion.saturating_sub(1))
+        .sum();
+
+    Ok(BenchResult {
+        batch,
+        context,
+        prefill: (batch * context) as f64 / prefill_end.as_secs_f64(),
+        decode: decoded as f64 / (decode_end - decode_start).as_secs_f64(),
+        first_token,
+    })
+}
+
+/// Run the prefill and decode benchmarks on the loaded model.
+pub async fn run_bench(sender: Sender<ThreadRequest>, option: BenchOption) -> Result<BenchReport> {
+    let info = try_request_info(sender.clone()).await?;
+
+    let max_context = option.context.iter().max().copied().unwrap_or_default();
+    let mut corpus = vec![];
+    let tokens = info.tokenizer.encode(CORPUS.as_bytes())?;
+    while corpus.len() < max_context {
+        corpus.extend_from_slice(&tokens);
+    }
+
+    let max_batch = info.reload.max_batch;
+    let rounds = option
+        .batch
+        .iter()
+        .map(|&batch| batch.clamp(1, max_batch.max(1)))
+        .dedup()
+        .cartesian_product(option.context.iter().copied())
+        .collect_vec();
+
+    let mut results = vec![];
+    for (batch, context) in rounds {
+        log::info!("benchmarking batch {batch}, context {context}");
+        let result = bench_round(&sender, &info, &corpus, batch, context, option.decode).await?;
+        log::info!(
+            "prefill {:.2} tokens/s, decode {:.2} tokens/s",
+            result.prefill,
+            result.decode
+        );
+        results.push(result);
+    }
+
+    Ok(BenchReport {
+        reload: info.reload,
+        model: info.model,
+        results,
+    })
+}
+
+/// `/api/admin/bench`.
+///
+/// Requests of the benchmark compete with others for the slots, so run it on an idle server.
+#[endpoint(
+    request_body = BenchOption,
+    responses(
+        (status_code = 200, description = "Speeds of every combination of batch and context.", body = BenchReport),
+        (status_code = 400, description = "The options cannot be parsed.", body = String),
+        (status_code = 404, description = "No model is loaded, or a round failed."),
+    )
+)]
+pub async fn bench(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let option = match req.parse_json::<BenchOption>().await {
+        Ok(option) => option,
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(err.to_string()));
+            return;
+        }
+    };
+    let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
+    match run_bench(sender.clone(), option).await {
+        Ok(report) => res.render(Json(report)),
+        Err(err) => {
+            log::error!("benchmark failed: {err}");
+            res.status_code(StatusCode::NOT_FOUND);
+            res.render("ERROR");
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Request ids, which tie together everything a call leaves behind: the `X-Request-Id` header of the
+//! response, the log lines, the audit record and the error body. A caller may give its own id in the
+//! header of the request, e.g., the one its gateway assigned; otherwise one is generated.
+
+use ai00_core::{request_id, REQUEST_ID};
+use log::{Log, Metadata, Record};
+use salvo::{http::HeaderValue, prelude::*};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Longest id taken from a caller.
+const MAX_ID_LEN: usize = 128;
+
+/// The id given by the caller, if it is printable and not too long.
+fn given_id(req: &Request) -> Option<String> {
+    let id = req.header::<String>(REQUEST_ID_HEADER)?;
+    let valid = (1..=MAX_ID_LEN).contains(&id.len()) && id.chars().all(|x| x.is_ascii_graphic());
+    valid.then_some(id)
+}
+
+/// Assign the id to the call and return it in the response. The rest of the call runs with the id in scope.
+#[handler]
+pub async fn correlate(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    let id = given_id(req).unwrap_or_else(|| format!("{:032x}", fastrand::u128(..)));
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    REQUEST_ID.scope(id, ctrl.call_next(req, depot, res)).await;
+}
+
+/// Logger that tags the lines logged within a call with its id.
+pub struct CorrelatedLogger<L>(pub L);
+
+impl<L: Log> Log for CorrelatedLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let Some(id) = request_id() else {
+            return self.0.log(record);
+        };
+        self.0.log(
+            &Record::builder()
+                .args(format_args!("[{id}] {}", record.args()))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use ai00_core::{GenerateRequest, Trace};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Local};
+use salvo::prelude::*;
+use serde::Serialize;
+use web_rwkv::tokenizer::Tokenizer;
+
+use super::{auth::client, oai::SamplerParams};
+
+/// Number of debug records kept; the oldest ones are dropped first.
+const MAX_DEBUG_RECORDS: usize = 64;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DebugCandidate {
+    pub token: u16,
+    pub text: String,
+    pub prob: f32,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DebugStep {
+    /// The chosen token.
+    pub token: u16,
+    pub text: String,
+    /// Probability of the chosen token after bias, grammar and other transforms.
+    pub prob: f32,
+    /// The most likely tokens at this step.
+    pub candidates: Vec<DebugCandidate>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DebugChoice {
+    pub text: String,
+    pub steps: Vec<DebugStep>,
+}
+
+/// Everything that went into a `debug` request and how the model answered it.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DebugRecord {
+    pub id: String,
+    #[salvo(schema(value_type = String))]
+    pub time: DateTime<Local>,
+    pub model: String,
+    /// The prompt exactly as the model sees it; for chat completions, the messages as rendered.
+    pub prompt: String,
+    pub prompt_tokens: Vec<u16>,
+    /// Sampler the request is run with.
+    pub sampler: SamplerParams,
+    pub choices: Vec<DebugChoice>,
+    #[serde(skip)]
+    owner: Option<String>,
+}
+
+/// Keeps the records of recent `debug` requests for `/api/debug/<id>`.
+#[derive(Debug, Default)]
+pub struct DebugStore {
+    records: Mutex<VecDeque<DebugRecord>>,
+}
+
+impl DebugStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(&self, record: DebugRecord) {
+        let mut records = self.records.lock().unwrap();
+        while records.len() >= MAX_DEBUG_RECORDS {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    fn get(&self, id: &str, owner: Option<&str>) -> Option<DebugRecord> {
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|record| record.id == id && record.owner.as_deref() == owner)
+            .cloned()
+    }
+
+    /// Remove the records of the client. Returns the number of them.
+    pub fn purge(&self, owner: &str, dry_run: bool) -> usize {
+        let mut records = self.records.lock().unwrap();
+        let owned = |record: &DebugRecord| record.owner.as_deref() == Some(owner);
+        let count = records.iter().filter(|record| owned(record)).count();
+        if !dry_run {
+            records.retain(|record| !owned(record));
+        }
+        count
+    }
+}
+
+/// A `debug` request in flight. Traces the generations and records them when finished.
+pub struct DebugSession {
+    store: Arc<DebugStore>,
+    tokenizer: Arc<Tokenizer>,
+    record: DebugRecord,
+    traces: Vec<Trace>,
+}
+
+impl DebugSession {
+    /// Start tracing the generations of a request.
+    pub fn begin(
+        depot: &Depot,
+        model: String,
+        sampler: SamplerParams,
+        tokenizer: Arc<Tokenizer>,
+        requests: &mut [GenerateRequest],
+    ) -> Result<Self> {
+        let store = depot
+            .get::<Arc<DebugStore>>("debug")
+            .cloned()
+            .map_err(|_| anyhow!("debug store is not available"))?;
+        let Some(request) = requests.first() else {
+            bail!("no generation to debug");
+        };
+        let prompt_tokens = match &request.prompt_tokens {
+            Some(tokens) => tokens.clone(),
+            None => tokenizer.encode(request.prompt.as_bytes())?,
+        };
+        let prompt = match &request.prompt_tokens {
+            Some(tokens) => String::from_utf8_lossy(&tokenizer.decode(tokens)?).into(),
+            None => request.prompt.clone(),
+        };
+
+        let traces = requests
+            .iter_mut()
+            .map(|request| request.trace.insert(Default::default()).clone())
+            .collect();
+        let record = DebugRecord {
+            id: format!("{:016x}", fastrand::u64(..)),
+            time: Local::now(),
+            model,
+            prompt,
+            prompt_tokens,
+            sampler,
+            choices: vec![],
+            owner: client(depot),
+        };
+        Ok(Self {
+            store,
+            tokenizer,
+            record,
+            traces,
+        })
+    }
+
+    fn decode(&self, token: u16) -> String {
+        let bytes = self.tokenizer.decode(&[token]).unwrap_or_default();
+        String::from_utf8_lossy(&bytes).into()
+    }
+
+    /// Record the texts of the generations along with their traces. Returns the id of the record.
+    pub fn finish(mut self, texts: impl IntoIterator<Item = String>) -> String {
+        let traces = std::mem::take(&mut self.traces);
+        let choices = texts
+            .into_iter()
+            .zip(traces)
+            .map(|(text, trace)| {
+                let steps = trace
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|step| DebugStep {
+                        token: step.token,
+                        text: self.decode(step.token),
+                        prob: step.prob,
+                        candidates: step
+                            .candidates
+                            .iter()
+                            .map(|&(token, prob)| DebugCandidate {
+                                token,
+                                text: self.decode(token),
+                                prob,
+                            })
+                            .collect(),
+                    })
+                    .collect();
+                DebugChoice { text, steps }
+            })
+            .collect();
+        self.record.choices = choices;
+
+        let id = self.record.id.clone();
+        self.store.insert(self.record);
+        id
+    }
+}
+
+/// `/api/debug/<id>`: the record of a `debug` request, only to the client that made it.
+#[endpoint(
+    parameters(("id" = String, Path, description = "Id of the record, returned as `debug_id`.")),
+    status_codes(200, 400, 404, 500),
+    responses(
+        (status_code = 200, body = DebugRecord),
+        (status_code = 404, description = "No such record of the client; it may have been dropped."),
+    )
+)]
+pub async fn debug_record(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let Some(id) = req.param::<String>("id") else {
+        res.status_code(StatusCode::BAD_REQUEST);
+        return;
+    };
+    let Ok(store) = depot.get::<Arc<DebugStore>>("debug") else {
+        res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+        return;
+    };
+    match store.get(&id, client(depot).as_deref()) {
+        Some(record) => res.render(Json(record)),
+        None => {
+            res.status_code(StatusCode::NOT_FOUND);
+        }
+    }
+}
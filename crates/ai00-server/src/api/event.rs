@@ -0,0 +1,95 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use ai00_core::{RuntimeEvent, ThreadRequest};
+use flume::Sender;
+use futures_util::stream;
+use salvo::{
+    prelude::*,
+    sse::{SseEvent, SseKeepAlive},
+};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Number of events buffered for a slow client before it starts missing them.
+const EVENT_CAPACITY: usize = 64;
+/// Interval of the keep-alive comments on an idle event stream.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerNotice {
+    /// The server stops accepting requests, and closes the remaining connections after the grace period.
+    ShutdownImminent { grace_period: u64 },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum ServerEvent {
+    Runtime(RuntimeEvent),
+    Server(ServerNotice),
+}
+
+/// Broadcasts the server events to the clients of `/api/events`.
+pub struct EventBus {
+    sender: broadcast::Sender<ServerEvent>,
+}
+
+impl EventBus {
+    /// Create the bus and forward the events of the runtime to it.
+    pub fn new(runtime: &Sender<ThreadRequest>) -> Arc<Self> {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        let (event_sender, event_receiver) = flume::unbounded();
+        let _ = runtime.send(ThreadRequest::Subscribe(event_sender));
+
+        let bus = Arc::new(Self { sender });
+        let forward = {
+            let bus = bus.clone();
+            async move {
+                while let Ok(event) = event_receiver.recv_async().await {
+                    bus.send(ServerEvent::Runtime(event));
+                }
+            }
+        };
+        tokio::spawn(forward);
+        bus
+    }
+
+    pub fn send(&self, event: ServerEvent) {
+        // no one is listening
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// `/api/events`: a stream of the server events, such as model reloads and shutdown.
+#[endpoint(responses((
+    status_code = 200,
+    description = "Server-sent events, each a JSON object of the event.",
+    content_type = "text/event-stream",
+    body = String
+)))]
+pub async fn events(depot: &mut Depot, res: &mut Response) {
+    let Ok(bus) = depot.get::<Arc<EventBus>>("events") else {
+        res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+        return;
+    };
+    let receiver = bus.subscribe();
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let event = SseEvent::default().json(event).unwrap_or_default();
+                    break Some((Ok::<_, Infallible>(event), receiver));
+                }
+                Err(RecvError::Lagged(count)) => log::warn!("event client missed {count} events"),
+                Err(RecvError::Closed) => break None,
+            }
+        }
+    });
+    SseKeepAlive::new(stream)
+        .max_interval(KEEP_ALIVE)
+        .stream(res);
+}
@@ -0,0 +1,193 @@
+use ai00_core::{sampler::nucleus::NucleusParams, FinishReason, GenerateRequest, TokenCounter};
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use salvo::{oapi::extract::JsonBody, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    oai::{
+        check_choices,
+        completion::CompletionRequest,
+        error::{render_error, render_failure},
+        fit_context, generate, SamplerParams,
+    },
+    request_info,
+};
+use crate::{
+    config::{LimitOption, SamplerLimits},
+    types::ThreadState,
+    SLEEP,
+};
+
+/// Maximum number of generations a sweep may run, over all combinations and choices.
+const MAX_SWEEP_SIZE: usize = 64;
+
+/// Values of the sampler parameters to sweep. Parameters left empty keep the value of the request.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct SweepGrid {
+    pub temperature: Vec<f32>,
+    pub top_p: Vec<f32>,
+    pub top_k: Vec<usize>,
+    pub presence_penalty: Vec<f32>,
+    pub frequency_penalty: Vec<f32>,
+}
+
+impl SweepGrid {
+    /// All combinations of the grid, based on the parameters of the request, each to run `n` times.
+    fn combinations(&self, base: &NucleusParams, n: usize) -> Result<Vec<NucleusParams>> {
+        fn or<T: Copy>(values: &[T], base: T) -> Vec<T> {
+            match values.is_empty() {
+                true => vec![base],
+                false => values.to_vec(),
+            }
+        }
+
+        let temperature = or(&self.temperature, base.temperature);
+        let top_p = or(&self.top_p, base.top_p);
+        let top_k = or(&self.top_k, base.top_k);
+        let presence_penalty = or(&self.presence_penalty, base.presence_penalty);
+        let frequency_penalty = or(&self.frequency_penalty, base.frequency_penalty);
+
+        let size = temperature.len()
+            * top_p.len()
+            * top_k.len()
+            * presence_penalty.len()
+            * frequency_penalty.len()
+            * n;
+        if size > MAX_SWEEP_SIZE {
+            bail!("sweep has {size} generations, but at most {MAX_SWEEP_SIZE} are allowed");
+        }
+
+        let combinations = itertools::iproduct!(
+            temperature,
+            top_p,
+            top_k,
+            presence_penalty,
+            frequency_penalty
+        )
+        .map(
+            |(temperature, top_p, top_k, presence_penalty, frequency_penalty)| NucleusParams {
+                temperature,
+                top_p,
+                top_k,
+                presence_penalty,
+                frequency_penalty,
+                ..base.clone()
+            },
+        )
+        .collect_vec();
+        Ok(combinations)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct SweepRequest {
+    /// The completion request to run. Its sampler parameters, or its `sampler_override` if of the nucleus sampler,
+    /// are the base of the grid; each combination runs `n` times.
+    #[serde(flatten)]
+    pub request: CompletionRequest,
+    #[serde(default)]
+    pub grid: SweepGrid,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SweepResult {
+    pub params: NucleusParams,
+    /// Which of the `n` choices of the combination it is.
+    pub index: usize,
+    pub text: String,
+    pub finish_reason: FinishReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequence: Option<String>,
+    #[serde(rename = "usage")]
+    pub counter: TokenCounter,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SweepResponse {
+    pub model: String,
+    pub results: Vec<SweepResult>,
+}
+
+/// Run the same prompt across a grid of sampler parameters, and return the results of all combinations.
+/// The combinations share the prompt state and run in parallel lanes.
+#[endpoint(responses((status_code = 200, description = "Results of all combinations.", body = SweepResponse)))]
+pub async fn sweep(depot: &mut Depot, req: JsonBody<SweepRequest>, res: &mut Response) {
+    let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
+    let info = request_info(sender.clone(), SLEEP).await;
+    let model_name = info.reload.model_path.to_string_lossy().into_owned();
+
+    let SweepRequest { request, grid } = req.0;
+    let limits = SamplerLimits::obtain(depot);
+    let combinations = || -> Result<_> {
+        let base = match request.sampler_override() {
+            None => request.sampler(),
+            Some(SamplerParams::Nucleus(params)) => params,
+            Some(_) => bail!("the grid only sweeps the nucleus sampler"),
+        };
+        let n = check_choices(request.n(), false, &info)?;
+        let mut combinations = grid.combinations(base, n)?;
+        for params in &mut combinations {
+            limits.bound_nucleus(params)?;
+        }
+        Ok((combinations, n))
+    };
+    let (combinations, n) = match combinations() {
+        Ok(combinations) => combinations,
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(err.to_string()));
+            return;
+        }
+    };
+
+    let max_tokens = request.max_tokens();
+    let mut request = match limits.generate(request) {
+        Ok(request) => request,
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(err.to_string()));
+            return;
+        }
+    };
+    if let Err(err) = fit_context(&mut request, max_tokens, &info) {
+        render_failure(res, StatusCode::BAD_REQUEST, &err);
+        return;
+    }
+    LimitOption::obtain(depot).apply(depot, &mut request);
+
+    let requests = combinations
+        .iter()
+        .flat_map(|params| std::iter::repeat(params).take(n))
+        .map(|params| GenerateRequest {
+            sampler: SamplerParams::Nucleus(params.clone()).into(),
+            ..request.clone()
+        })
+        .collect();
+    let generations = match generate(sender, info.tokenizer, requests, None).await {
+        Ok(generations) => generations,
+        Err(err) => {
+            render_error(res, &err);
+            return;
+        }
+    };
+
+    let results = combinations
+        .iter()
+        .flat_map(|params| (0..n).map(move |index| (params.clone(), index)))
+        .zip(generations)
+        .map(|((params, index), generation)| SweepResult {
+            params,
+            index,
+            text: generation.text,
+            finish_reason: generation.finish_reason,
+            stop_sequence: generation.stop_sequence,
+            counter: generation.counter,
+        })
+        .collect();
+    res.render(Json(SweepResponse {
+        model: model_name,
+        results,
+    }));
+}
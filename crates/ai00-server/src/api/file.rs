@@ -1,30 +1,183 @@
 use std::{
-    fs::{File, Metadata},
+    collections::HashMap,
+    fs::{File, Metadata, OpenOptions},
     io::{BufReader, Cursor, Read, Seek, SeekFrom, Write},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use itertools::Itertools;
 use memmap2::Mmap;
 use safetensors::SafeTensors;
-use salvo::{macros::Extractible, prelude::*};
+use salvo::{
+    http::{form::FilePart, header::CONTENT_LENGTH},
+    prelude::*,
+};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use tokio::sync::OwnedMutexGuard;
 use web_rwkv::runtime::{loader::Loader, model::ModelInfo};
+use zip::ZipArchive;
+
+use super::auth::caller;
+use crate::{
+    config::{Config, WorkspaceOption},
+    types::ThreadState,
+    validate::{validate, ConfigIssue, ConfigReport},
+};
+
+/// Room left in an upload request for the form fields besides the chunk.
+const FORM_OVERHEAD: u64 = 64 << 10;
+
+/// The sandbox of the file APIs: only paths under the configured roots are reachable,
+/// and every call is recorded in the audit log.
+struct Workspace {
+    option: WorkspaceOption,
+    caller: String,
+}
+
+impl Workspace {
+    fn new(depot: &Depot, req: &Request) -> Self {
+        let option = depot
+            .get::<WorkspaceOption>("workspace")
+            .cloned()
+            .unwrap_or_default();
+        let id = caller(depot).unwrap_or_else(|| "anonymous".into());
+        let caller = format!("{id}@{}", req.remote_addr());
+        Self { option, caller }
+    }
+
+    /// Check that `path` stays inside one of `roots` after resolving symlinks.
+    /// The path itself may not exist yet, but its parent must.
+    fn check(&self, path: impl AsRef<Path>, roots: &[PathBuf]) -> Result<()> {
+        let path = path.as_ref();
+        if path.components().any(|x| x == Component::ParentDir) {
+            bail!("cannot have \"..\" in paths");
+        }
+        let path = match (path.canonicalize(), path.parent(), path.file_name()) {
+            (Ok(path), _, _) => path,
+            (Err(_), Some(parent), Some(name)) => parent.canonicalize()?.join(name),
+            (Err(err), _, _) => return Err(err.into()),
+        };
+        match roots
+            .iter()
+            .filter_map(|root| root.canonicalize().ok())
+            .any(|root| path.starts_with(root))
+        {
+            true => Ok(()),
+            false => bail!("path not permitted"),
+        }
+    }
+
+    /// Check that the files `path` includes stay inside the permitted roots, and so on for the files they include.
+    /// Files that cannot be read or parsed are left for the config loader to report.
+    fn check_includes(&self, path: &Path, includes: &[PathBuf], depth: usize) -> Result<()> {
+        if depth > crate::MAX_INCLUDE_DEPTH {
+            bail!("includes nested too deep at {}", path.to_string_lossy());
+        }
+        let parent = path.parent().unwrap_or(Path::new(""));
+        for include in includes {
+            let path = parent.join(include);
+            self.check(&path, &self.option.permitted)?;
+            let Ok(contents) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+            let Ok(config) = toml::from_str::<Config>(&contents) else {
+                continue;
+            };
+            self.check_includes(&path, &config.include, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    fn audit(&self, action: &str, path: impl AsRef<Path>, status: StatusCode) {
+        if self.option.audit {
+            log::info!(
+                target: "ai00_server::audit",
+                "{} {} {}: {}",
+                self.caller,
+                action,
+                path.as_ref().to_string_lossy(),
+                status
+            );
+        }
+    }
+}
+
+/// Uncompressed size of all entries in the archive, as recorded in its central directory.
+fn unzip_size(data: &[u8]) -> Result<u64> {
+    let mut archive = ZipArchive::new(Cursor::new(data))?;
+    let mut size = 0;
+    for index in 0..archive.len() {
+        size += archive.by_index_raw(index)?.size();
+    }
+    Ok(size)
+}
+
+/// Upload sessions with a chunk being written, so that the chunks of a session are taken one at a time.
+#[derive(Debug, Default)]
+pub struct Uploads(Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>);
+
+/// Holds an upload session until dropped.
+struct UploadGuard<'a> {
+    uploads: &'a Uploads,
+    staging: PathBuf,
+    _guard: OwnedMutexGuard<()>,
+}
 
-use crate::{check_path_permitted, config::Config, types::ThreadState};
+impl Drop for UploadGuard<'_> {
+    fn drop(&mut self) {
+        let mut sessions = self.uploads.0.lock().unwrap();
+        // one count is held by the map and one by this guard; any other is a chunk waiting
+        if sessions
+            .get(&self.staging)
+            .is_some_and(|lock| Arc::strong_count(lock) <= 2)
+        {
+            sessions.remove(&self.staging);
+        }
+    }
+}
 
-const PERMITTED_PATHS: [&str; 4] = [
-    "assets/models",
-    "assets/tokenizer",
-    "assets/configs",
-    "assets/www",
-];
-const UNZIP_PATHS: [&str; 2] = ["assets/unzip", "assets/temp"];
+impl Uploads {
+    /// Wait for the chunk in progress of the same session, if any.
+    async fn lock(&self, path: &Path) -> UploadGuard<'_> {
+        let staging = staging_path(path);
+        let staging = match (staging.parent(), staging.file_name()) {
+            (Some(parent), Some(name)) => parent
+                .canonicalize()
+                .map(|parent| parent.join(name))
+                .unwrap_or(staging.clone()),
+            _ => staging,
+        };
+        let lock = self
+            .0
+            .lock()
+            .unwrap()
+            .entry(staging.clone())
+            .or_default()
+            .clone();
+        let _guard = lock.lock_owned().await;
+        UploadGuard {
+            uploads: self,
+            staging,
+            _guard,
+        }
+    }
+}
 
-fn check_path(path: impl AsRef<Path>) -> Result<()> {
-    check_path_permitted(path, &PERMITTED_PATHS)
+/// Chunks of an upload session are staged next to the target until all bytes arrive.
+fn staging_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+fn compute_full_sha(path: impl AsRef<Path>) -> Result<String> {
+    let mut file = File::open(path.as_ref())?;
+    let mut sha = Sha256::new();
+    std::io::copy(&mut file, &mut sha)?;
+    Ok(format!("{:x}", sha.finalize()))
 }
 
 fn compute_sha(path: impl AsRef<Path>, meta: &Metadata) -> Result<String> {
@@ -51,40 +204,61 @@ fn compute_sha(path: impl AsRef<Path>, meta: &Metadata) -> Result<String> {
     Ok(format!("{:x}", result))
 }
 
-#[derive(Debug, Clone, Deserialize, Extractible)]
-#[salvo(extract(default_source(from = "body")))]
+#[derive(Debug, Clone, Deserialize, ToSchema)]
 pub struct FileInfoRequest {
+    #[salvo(schema(value_type = String))]
     path: PathBuf,
+    /// Compute the SHA-256 of the files; of 10 segments of 1MB for files over 10MB.
     #[serde(default)]
     is_sha: bool,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct FileInfo {
+    #[salvo(schema(value_type = String))]
     path: PathBuf,
     name: String,
     size: u64,
     sha: String,
+    /// Version, layers, embedding size and the other dimensions, if the file is a model.
+    #[salvo(schema(value_type = Option<Object>))]
     info: Option<ModelInfo>,
 }
 
-#[derive(Debug, Clone, Deserialize, Extractible)]
-#[salvo(extract(default_source(from = "body")))]
+#[derive(Debug, Clone, Deserialize, ToSchema)]
 pub struct UnzipRequest {
     #[serde(alias = "zip_path")]
+    #[salvo(schema(value_type = String))]
     path: PathBuf,
+    /// Directory the archive is extracted into, replacing what it holds.
     #[serde(alias = "target_dir")]
+    #[salvo(schema(value_type = String))]
     output: PathBuf,
 }
 
-#[derive(Debug, Clone, Deserialize, Extractible)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct UploadStatus {
+    #[salvo(schema(value_type = String))]
+    path: PathBuf,
+    /// Bytes received so far; the next chunk must start at this offset.
+    offset: u64,
+    complete: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
 pub struct LoadRequest {
+    #[salvo(schema(value_type = String))]
     path: PathBuf,
 }
 
-#[derive(Debug, Clone, Deserialize, Extractible)]
+#[derive(Debug, Clone, Deserialize, ToSchema)]
 pub struct SaveRequest {
+    #[salvo(schema(value_type = String))]
     path: PathBuf,
+    /// The config, in the layout of the config file.
+    #[salvo(schema(value_type = Object))]
     config: Config,
 }
 
@@ -133,8 +307,18 @@ async fn dir_inner(
     }
 }
 
-#[handler]
+/// `/api/files/dir`: the files in a directory, with the info of the models among them.
+#[endpoint(
+    request_body = FileInfoRequest,
+    responses(
+        (status_code = 200, description = "The files.", body = Vec<FileInfo>),
+        (status_code = 403, description = "The directory is outside the permitted ones."),
+        (status_code = 404, description = "The directory cannot be read."),
+        (status_code = 500, description = "The request cannot be parsed.", body = String),
+    )
+)]
 pub async fn dir(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let workspace = Workspace::new(depot, req);
     let request = match req.parse_json::<FileInfoRequest>().await {
         Ok(t) => t,
         Err(err) => {
@@ -143,8 +327,10 @@ pub async fn dir(depot: &mut Depot, req: &mut Request, res: &mut Response) {
             return;
         }
     };
-    if let Err(err) = check_path(&request.path) {
+    let path = request.path.clone();
+    if let Err(err) = workspace.check(&path, &workspace.option.permitted) {
         log::error!("check path failed: {}", err);
+        workspace.audit("dir", &path, StatusCode::FORBIDDEN);
         res.status_code(StatusCode::FORBIDDEN);
         res.render("ERROR");
         return;
@@ -152,18 +338,25 @@ pub async fn dir(depot: &mut Depot, req: &mut Request, res: &mut Response) {
 
     match dir_inner(depot, Json(request)).await {
         Ok((status, files)) => {
+            workspace.audit("dir", &path, status);
             res.status_code(status);
             res.render(Json(files));
         }
         Err(status) => {
+            workspace.audit("dir", &path, status);
             res.status_code(status);
             res.render("ERROR");
         }
     }
 }
 
-/// `/api/models/list`.
-#[handler]
+/// `/api/models/list`: the models in the model directory, with their info and hashes.
+#[endpoint(
+    responses(
+        (status_code = 200, description = "The models.", body = Vec<FileInfo>),
+        (status_code = 404, description = "The model directory cannot be read."),
+    )
+)]
 pub async fn models(depot: &mut Depot, res: &mut Response) {
     let ThreadState { path, .. } = depot.obtain::<ThreadState>().unwrap();
     let request = FileInfoRequest {
@@ -182,32 +375,61 @@ pub async fn models(depot: &mut Depot, res: &mut Response) {
     }
 }
 
-#[handler]
-pub async fn unzip(_depot: &mut Depot, request: UnzipRequest) -> StatusCode {
-    if let Err(err) = check_path(&request.path) {
+/// `/api/files/unzip`: extract an archive into a directory.
+#[endpoint(
+    request_body = UnzipRequest,
+    responses(
+        (status_code = 200, description = "Done."),
+        (status_code = 400, description = "The request cannot be parsed."),
+        (status_code = 403, description = "The archive or the directory is outside the permitted ones."),
+        (status_code = 404, description = "The archive cannot be read or extracted."),
+        (status_code = 413, description = "The uncompressed size exceeds `workspace.max_unzip_size`."),
+    )
+)]
+pub async fn unzip(depot: &mut Depot, req: &mut Request) -> StatusCode {
+    let Ok(request) = req.parse_body::<UnzipRequest>().await else {
+        return StatusCode::BAD_REQUEST;
+    };
+    let workspace = Workspace::new(depot, req);
+    let status = unzip_inner(&workspace, &request);
+    workspace.audit("unzip", &request.path, status);
+    status
+}
+
+fn unzip_inner(workspace: &Workspace, request: &UnzipRequest) -> StatusCode {
+    if let Err(err) = workspace.check(&request.path, &workspace.option.permitted) {
         log::error!("check path failed: {}", err);
         return StatusCode::FORBIDDEN;
     }
-    if let Err(err) = check_path_permitted(&request.output, &UNZIP_PATHS) {
+    if let Err(err) = workspace.check(&request.output, &workspace.option.unzip) {
         log::error!("check path failed: {}", err);
         return StatusCode::FORBIDDEN;
     }
 
-    let unzip_inner = move || -> Result<()> {
+    let extract = || -> Result<StatusCode> {
+        let file = File::open(&request.path)?;
+        let map = unsafe { Mmap::map(&file)? };
+
+        let size = unzip_size(&map)?;
+        if size > workspace.option.max_unzip_size {
+            log::error!(
+                "failed to unzip: uncompressed size {size} exceeds the limit of {}",
+                workspace.option.max_unzip_size
+            );
+            return Ok(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+
         if Path::new(&request.output).exists() {
             std::fs::remove_dir_all(&request.output)?;
         }
         std::fs::create_dir_all(&request.output)?;
-
-        let file = File::open(&request.path)?;
-        let map = unsafe { Mmap::map(&file)? };
         zip_extract::extract(Cursor::new(&map), &request.output, false)?;
 
-        Ok(())
+        Ok(StatusCode::OK)
     };
 
-    match unzip_inner() {
-        Ok(_) => StatusCode::OK,
+    match extract() {
+        Ok(status) => status,
         Err(err) => {
             log::error!("failed to unzip: {}", err);
             StatusCode::NOT_FOUND
@@ -215,21 +437,257 @@ pub async fn unzip(_depot: &mut Depot, request: UnzipRequest) -> StatusCode {
     }
 }
 
-/// `/api/files/config/load`.
-#[handler]
-pub async fn load_config(_depot: &mut Depot, request: LoadRequest, response: &mut Response) {
-    if let Err(err) = check_path(&request.path) {
+/// `/api/files/upload`.
+///
+/// Append a chunk to the upload session of a file. The multipart form carries the target `path`,
+/// the `offset` and the `chunk` itself, the `total` size of the file and optionally its `sha256`.
+/// Once all bytes are received the hash is verified and the file is moved into place.
+/// An existing file is only replaced if `overwrite` is `true`.
+/// Chunks of the same session are appended one at a time.
+#[endpoint(
+    responses(
+        (status_code = 200, description = "The chunk is appended, or the file is complete.", body = UploadStatus),
+        (status_code = 400, description = "A field is missing, or the chunk exceeds `total`.", body = String),
+        (status_code = 403, description = "The path is outside the permitted directories."),
+        (status_code = 409, description = "The file exists, or the offset is not where the session is at.", body = UploadStatus),
+        (status_code = 413, description = "The chunk exceeds `workspace.max_chunk_size`."),
+        (status_code = 422, description = "The hash of the file does not match `sha256`; the session is reset.", body = UploadStatus),
+        (status_code = 500, description = "The chunk cannot be written."),
+    )
+)]
+pub async fn upload(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let workspace = Workspace::new(depot, req);
+    let max_size = workspace.option.max_chunk_size;
+    let length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    if length.is_some_and(|length| length > max_size + FORM_OVERHEAD) {
+        res.status_code(StatusCode::PAYLOAD_TOO_LARGE);
+        res.render("PAYLOAD_TOO_LARGE");
+        return;
+    }
+
+    let form = match req.form_data().await {
+        Ok(form) => form,
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(err.to_string()));
+            return;
+        }
+    };
+    let (Some(path), Some(Ok(offset)), Some(Ok(total)), Some(chunk)) = (
+        form.fields.get("path").map(PathBuf::from),
+        form.fields.get("offset").map(|x| x.parse::<u64>()),
+        form.fields.get("total").map(|x| x.parse::<u64>()),
+        form.files.get("chunk"),
+    ) else {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render("expect `path`, `offset`, `total` and `chunk` in the form");
+        return;
+    };
+    let sha = form.fields.get("sha256").map(|x| x.to_lowercase());
+    let overwrite = form.fields.get("overwrite").is_some_and(|x| x == "true");
+
+    let uploads = depot.get::<Arc<Uploads>>("uploads").ok().cloned();
+    let _guard = match &uploads {
+        Some(uploads) => Some(uploads.lock(&path).await),
+        None => None,
+    };
+    let (status, session) = upload_inner(&workspace, &path, offset, total, sha, overwrite, chunk);
+    workspace.audit("upload", &path, status);
+    res.status_code(status);
+    match session {
+        Some(session) => res.render(Json(session)),
+        None => res.render("ERROR"),
+    }
+}
+
+fn upload_inner(
+    workspace: &Workspace,
+    path: &Path,
+    offset: u64,
+    total: u64,
+    sha: Option<String>,
+    overwrite: bool,
+    chunk: &FilePart,
+) -> (StatusCode, Option<UploadStatus>) {
+    if let Err(err) = workspace.check(path, &workspace.option.permitted) {
         log::error!("check path failed: {}", err);
+        return (StatusCode::FORBIDDEN, None);
+    }
+    if chunk.size() > workspace.option.max_chunk_size {
+        log::error!("failed to upload: chunk size {} too large", chunk.size());
+        return (StatusCode::PAYLOAD_TOO_LARGE, None);
+    }
+
+    let staging = staging_path(path);
+    let received = std::fs::metadata(&staging).map_or(0, |meta| meta.len());
+    let status = |offset, complete, sha| UploadStatus {
+        path: path.into(),
+        offset,
+        complete,
+        sha,
+    };
+    if !overwrite && path.exists() {
+        log::error!("failed to upload: {} exists", path.to_string_lossy());
+        return (StatusCode::CONFLICT, Some(status(received, false, None)));
+    }
+    if offset != received {
+        log::error!("failed to upload: expect offset {received}, got {offset}");
+        return (StatusCode::CONFLICT, Some(status(received, false, None)));
+    }
+    if received + chunk.size() > total {
+        log::error!("failed to upload: chunk exceeds the total size {total}");
+        return (StatusCode::BAD_REQUEST, Some(status(received, false, None)));
+    }
+
+    let append = || -> Result<u64> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&staging)?;
+        let mut reader = File::open(chunk.path())?;
+        Ok(received + std::io::copy(&mut reader, &mut file)?)
+    };
+    let offset = match append() {
+        Ok(offset) if offset < total => return (StatusCode::OK, Some(status(offset, false, None))),
+        Ok(offset) => offset,
+        Err(err) => {
+            log::error!("failed to upload: {err}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, None);
+        }
+    };
+
+    let finish = || -> Result<String> {
+        let digest = compute_full_sha(&staging)?;
+        if sha.as_ref().is_some_and(|sha| sha != &digest) {
+            std::fs::remove_file(&staging)?;
+            bail!("sha256 mismatch, got {digest}");
+        }
+        std::fs::rename(&staging, path)?;
+        Ok(digest)
+    };
+    match finish() {
+        Ok(digest) => (StatusCode::OK, Some(status(offset, true, Some(digest)))),
+        Err(err) => {
+            log::error!("failed to upload: {err}");
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Some(status(0, false, None)),
+            )
+        }
+    }
+}
+
+/// `/api/files/upload?path=...`: query the upload session of a file, in order to resume it.
+#[endpoint(
+    parameters(("path" = String, Query, description = "Path of the file uploaded.")),
+    responses(
+        (status_code = 200, description = "The session.", body = UploadStatus),
+        (status_code = 400, description = "No path is given."),
+        (status_code = 403, description = "The path is outside the permitted directories."),
+    )
+)]
+pub async fn upload_status(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let workspace = Workspace::new(depot, req);
+    let Some(path) = req.query::<PathBuf>("path") else {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render("ERROR");
+        return;
+    };
+    if let Err(err) = workspace.check(&path, &workspace.option.permitted) {
+        log::error!("check path failed: {}", err);
+        res.status_code(StatusCode::FORBIDDEN);
+        res.render("FORBIDDEN");
+        return;
+    }
+
+    let staging = staging_path(&path);
+    let (offset, complete) = match std::fs::metadata(&staging) {
+        Ok(meta) => (meta.len(), false),
+        Err(_) => (0, path.is_file()),
+    };
+    res.render(Json(UploadStatus {
+        path,
+        offset,
+        complete,
+        sha: None,
+    }));
+}
+
+/// `/api/files/upload?path=...`: discard the received chunks of an upload session.
+#[endpoint(
+    parameters(("path" = String, Query, description = "Path of the file uploaded.")),
+    status_codes(200, 400, 403, 404),
+)]
+pub async fn abort_upload(depot: &mut Depot, req: &mut Request) -> StatusCode {
+    let workspace = Workspace::new(depot, req);
+    let Some(path) = req.query::<PathBuf>("path") else {
+        return StatusCode::BAD_REQUEST;
+    };
+    let uploads = depot.get::<Arc<Uploads>>("uploads").ok().cloned();
+    let _guard = match &uploads {
+        Some(uploads) => Some(uploads.lock(&path).await),
+        None => None,
+    };
+    let status = match workspace.check(&path, &workspace.option.permitted) {
+        Ok(_) => match std::fs::remove_file(staging_path(&path)) {
+            Ok(_) => StatusCode::OK,
+            Err(_) => StatusCode::NOT_FOUND,
+        },
+        Err(err) => {
+            log::error!("check path failed: {}", err);
+            StatusCode::FORBIDDEN
+        }
+    };
+    workspace.audit("abort upload", &path, status);
+    status
+}
+
+/// `/api/files/config/load`: read a config file as it is, without merging the files it includes.
+#[endpoint(
+    request_body = LoadRequest,
+    responses(
+        (status_code = 200, description = "The config, in the layout of the config file."),
+        (status_code = 400, description = "The request cannot be parsed."),
+        (status_code = 403, description = "The file is outside the permitted directories."),
+        (status_code = 404, description = "The file cannot be read or parsed."),
+        (status_code = 413, description = "The file exceeds `workspace.max_config_size`."),
+    )
+)]
+pub async fn load_config(depot: &mut Depot, req: &mut Request, response: &mut Response) {
+    let Ok(request) = req.parse_body::<LoadRequest>().await else {
+        response.status_code(StatusCode::BAD_REQUEST);
+        return;
+    };
+    let workspace = Workspace::new(depot, req);
+    if let Err(err) = workspace.check(&request.path, &workspace.option.permitted) {
+        log::error!("check path failed: {}", err);
+        workspace.audit("load config", &request.path, StatusCode::FORBIDDEN);
         response.status_code(StatusCode::FORBIDDEN);
         response.render("FORBIDDEN");
+        return;
+    }
+    if let Ok(meta) = std::fs::metadata(&request.path) {
+        if meta.len() > workspace.option.max_config_size {
+            log::error!("failed to load config: file size {} too large", meta.len());
+            workspace.audit("load config", &request.path, StatusCode::PAYLOAD_TOO_LARGE);
+            response.status_code(StatusCode::PAYLOAD_TOO_LARGE);
+            response.render("PAYLOAD_TOO_LARGE");
+            return;
+        }
     }
-    match crate::load_config(request.path).await {
+    match crate::load_config(&request.path).await {
         Ok(config) => {
+            workspace.audit("load config", &request.path, StatusCode::OK);
             response.status_code(StatusCode::OK);
             response.render(Json(config));
         }
         Err(err) => {
             log::error!("failed to load config: {}", err);
+            workspace.audit("load config", &request.path, StatusCode::NOT_FOUND);
             // Err(StatusCode::NOT_FOUND)
             response.status_code(StatusCode::NOT_FOUND);
             response.render("NOT_FOUND");
@@ -237,24 +695,94 @@ pub async fn load_config(_depot: &mut Depot, request: LoadRequest, response: &mu
     }
 }
 
-/// `/api/files/config/save`.
-#[handler]
-pub async fn save_config(_depot: &mut Depot, request: SaveRequest) -> StatusCode {
-    if let Err(err) = check_path(&request.path) {
+/// `/api/files/config/validate`.
+///
+/// Check a config file with its includes, and report its issues: unknown keys, invalid values and missing files.
+#[endpoint(
+    request_body = LoadRequest,
+    responses(
+        (status_code = 200, description = "The issues found, if any.", body = ConfigReport),
+        (status_code = 400, description = "The request cannot be parsed."),
+        (status_code = 403, description = "The file or one it includes is outside the permitted directories."),
+    )
+)]
+pub async fn validate_config(depot: &mut Depot, req: &mut Request, response: &mut Response) {
+    let Ok(request) = req.parse_body::<LoadRequest>().await else {
+        response.status_code(StatusCode::BAD_REQUEST);
+        return;
+    };
+    let workspace = Workspace::new(depot, req);
+    if let Err(err) = workspace.check(&request.path, &workspace.option.permitted) {
         log::error!("check path failed: {}", err);
+        workspace.audit("validate config", &request.path, StatusCode::FORBIDDEN);
+        response.status_code(StatusCode::FORBIDDEN);
+        response.render("FORBIDDEN");
+        return;
+    }
+    let includes = crate::load_config(&request.path)
+        .await
+        .map(|config| config.include)
+        .unwrap_or_default();
+    if let Err(err) = workspace.check_includes(&request.path, &includes, 1) {
+        log::error!("check include failed: {}", err);
+        workspace.audit("validate config", &request.path, StatusCode::FORBIDDEN);
+        response.status_code(StatusCode::FORBIDDEN);
+        response.render("FORBIDDEN");
+        return;
+    }
+    let issues = match crate::read_layered(&request.path, &Default::default()) {
+        Ok(table) => validate(&table).await,
+        Err(err) => vec![ConfigIssue::new("", err.to_string())],
+    };
+    workspace.audit("validate config", &request.path, StatusCode::OK);
+    response.render(Json(ConfigReport::from(issues)));
+}
+
+/// `/api/files/config/save`: write a config file.
+#[endpoint(
+    request_body = SaveRequest,
+    responses(
+        (status_code = 200, description = "Done."),
+        (status_code = 400, description = "The request cannot be parsed."),
+        (status_code = 403, description = "The file or one it includes is outside the permitted directories, or it is not `.toml`."),
+        (status_code = 413, description = "The config exceeds `workspace.max_config_size`."),
+        (status_code = 500, description = "The file cannot be written."),
+    )
+)]
+pub async fn save_config(depot: &mut Depot, req: &mut Request) -> StatusCode {
+    let Ok(request) = req.parse_body::<SaveRequest>().await else {
+        return StatusCode::BAD_REQUEST;
+    };
+    let workspace = Workspace::new(depot, req);
+    let status = save_config_inner(&workspace, &request);
+    workspace.audit("save config", &request.path, status);
+    status
+}
+
+fn save_config_inner(workspace: &Workspace, request: &SaveRequest) -> StatusCode {
+    if let Err(err) = workspace.check(&request.path, &workspace.option.permitted) {
+        log::error!("check path failed: {}", err);
+        return StatusCode::FORBIDDEN;
+    }
+    if let Err(err) = workspace.check_includes(&request.path, &request.config.include, 1) {
+        log::error!("check include failed: {}", err);
         return StatusCode::FORBIDDEN;
     }
 
-    let write = || -> Result<()> {
-        let mut file = File::create(&request.path)?;
+    let write = || -> Result<StatusCode> {
         let buf = toml::to_string(&request.config)?.into_bytes();
+        if buf.len() as u64 > workspace.option.max_config_size {
+            log::error!("failed to save config: config size {} too large", buf.len());
+            return Ok(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        let mut file = File::create(&request.path)?;
         file.write_all(&buf)?;
-        Ok(())
+        Ok(StatusCode::OK)
     };
 
     match request.path.extension() {
         Some(ext) if ext == "toml" => match write() {
-            Ok(_) => StatusCode::OK,
+            Ok(status) => status,
             Err(err) => {
                 log::error!("failed to save config: {err}");
                 StatusCode::INTERNAL_SERVER_ERROR
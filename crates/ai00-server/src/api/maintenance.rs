@@ -0,0 +1,411 @@
+//! Maintenance windows: at the times in the config, the server stops taking generations, waits for the
+//! running ones to finish, unloads the model, runs the tasks of the window and loads the model back.
+//!
+//! Throughout, `/health/ready` reports the server as not ready, so that load balancers route around it.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use ai00_core::{ReloadRequest, RuntimeStats, ThreadRequest};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local};
+use flume::Sender;
+use salvo::{http::header, prelude::*};
+use serde::Serialize;
+
+use super::{
+    oai::cache::{ConversationCache, ResponseCache, SemanticCache},
+    schedule::{Cron, Scheduler},
+    try_request_info,
+};
+use crate::{
+    config::{Config, MaintenanceOption, MaintenanceTask},
+    SLEEP,
+};
+
+/// Seconds clients turned away during a window are told to wait.
+const RETRY_AFTER: u64 = 60;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenancePhase {
+    /// No window is open.
+    #[default]
+    Serving,
+    /// New generations are refused while the running ones finish.
+    Draining,
+    /// The model is unloaded and the tasks are running.
+    Running,
+    /// The model is being loaded back.
+    Reloading,
+}
+
+#[derive(Debug, Default, Clone, Serialize, ToSchema)]
+pub struct WindowStatus {
+    pub name: String,
+    /// When the window opens next.
+    #[salvo(schema(value_type = Option<String>))]
+    pub next: Option<DateTime<Local>>,
+    /// When the window last closed.
+    #[salvo(schema(value_type = Option<String>))]
+    pub last: Option<DateTime<Local>>,
+    /// Errors of the last run, one for each step that failed.
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, ToSchema)]
+pub struct MaintenanceStatus {
+    pub phase: MaintenancePhase,
+    /// The window that is open, if any.
+    pub window: Option<String>,
+    /// When the current phase began.
+    #[salvo(schema(value_type = Option<String>))]
+    pub since: Option<DateTime<Local>>,
+    pub windows: Vec<WindowStatus>,
+}
+
+/// Runs the maintenance windows and tells whether the server is serving.
+pub struct Maintainer {
+    sender: Sender<ThreadRequest>,
+    scheduler: Arc<Scheduler>,
+    cache: Arc<ResponseCache>,
+    semantic_cache: Arc<SemanticCache>,
+    conversation_cache: Arc<ConversationCache>,
+    audit_path: Option<PathBuf>,
+    /// Where the state cache offloads to, if no model is loaded to tell.
+    offload_path: PathBuf,
+    options: HashMap<String, MaintenanceOption>,
+    /// The phase, the open window and when the phase began.
+    state: Mutex<(MaintenancePhase, Option<String>, Option<DateTime<Local>>)>,
+    windows: Mutex<HashMap<String, WindowStatus>>,
+    /// Keeps windows from overlapping.
+    lock: tokio::sync::Mutex<()>,
+}
+
+/// Rename a log aside with the time, and remove the oldest rotated ones beyond `keep`.
+/// Returns `false` if the log is empty and left as it is.
+fn rotate(path: &Path, keep: usize) -> Result<bool> {
+    let Some(name) = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+    else {
+        bail!("{} is not a file", path.to_string_lossy());
+    };
+    if !std::fs::metadata(path).is_ok_and(|meta| meta.len() > 0) {
+        return Ok(false);
+    }
+    let stamp = Local::now().format("%Y%m%d-%H%M%S");
+    std::fs::rename(path, path.with_file_name(format!("{name}.{stamp}")))?;
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let prefix = format!("{name}.");
+    let mut rotated: Vec<_> = std::fs::read_dir(dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|x| x.to_string_lossy().starts_with(&prefix))
+        })
+        .collect();
+    // the stamps sort by time
+    rotated.sort();
+    let excess = rotated.len().saturating_sub(keep);
+    for path in &rotated[..excess] {
+        std::fs::remove_file(path)?;
+    }
+    Ok(true)
+}
+
+impl Maintainer {
+    pub fn new(
+        sender: Sender<ThreadRequest>,
+        config: &Config,
+        scheduler: Arc<Scheduler>,
+        cache: Arc<ResponseCache>,
+        semantic_cache: Arc<SemanticCache>,
+        conversation_cache: Arc<ConversationCache>,
+    ) -> Arc<Self> {
+        let mut options = HashMap::new();
+        let mut crons = vec![];
+        for option in &config.maintenance {
+            let cron: Cron = match option.cron.parse() {
+                Ok(cron) => cron,
+                Err(err) => {
+                    log::error!("invalid maintenance window {}: {err}", option.name);
+                    continue;
+                }
+            };
+            if option.name.is_empty() || options.contains_key(&option.name) {
+                log::error!(
+                    "maintenance window name {:?} is empty or taken",
+                    option.name
+                );
+                continue;
+            }
+            options.insert(option.name.clone(), option.clone());
+            crons.push((option.name.clone(), cron));
+        }
+
+        let maintainer = Arc::new(Self {
+            sender,
+            scheduler,
+            cache,
+            semantic_cache,
+            conversation_cache,
+            audit_path: config.audit.path.clone(),
+            offload_path: config.state_cache.offload_path.clone(),
+            options,
+            state: Default::default(),
+            windows: Default::default(),
+            lock: Default::default(),
+        });
+        for (name, cron) in crons {
+            let maintainer = maintainer.clone();
+            log::info!("scheduled maintenance window {name}");
+            tokio::spawn(async move {
+                while let Some(next) = cron.next(Local::now()) {
+                    maintainer.status_mut(&name, |status| status.next = Some(next));
+                    let duration = (next - Local::now()).to_std().unwrap_or_default();
+                    tokio::time::sleep(duration).await;
+                    maintainer.run(&name).await;
+                }
+                maintainer.status_mut(&name, |status| status.next = None);
+            });
+        }
+        maintainer
+    }
+
+    fn status_mut(&self, name: &str, f: impl FnOnce(&mut WindowStatus)) {
+        let mut windows = self.windows.lock().unwrap();
+        let status = windows.entry(name.into()).or_insert_with(|| WindowStatus {
+            name: name.into(),
+            ..Default::default()
+        });
+        f(status);
+    }
+
+    fn set_phase(&self, phase: MaintenancePhase, window: Option<&str>) {
+        log::info!("maintenance: {phase:?}");
+        *self.state.lock().unwrap() = (phase, window.map(Into::into), Some(Local::now()));
+    }
+
+    pub fn phase(&self) -> MaintenancePhase {
+        self.state.lock().unwrap().0
+    }
+
+    pub fn status(&self) -> MaintenanceStatus {
+        let (phase, window, since) = self.state.lock().unwrap().clone();
+        let mut windows: Vec<_> = self.windows.lock().unwrap().values().cloned().collect();
+        windows.sort_by(|x, y| x.name.cmp(&y.name));
+        MaintenanceStatus {
+            phase,
+            window,
+            since,
+            windows,
+        }
+    }
+
+    async fn stats(&self) -> RuntimeStats {
+        let (sender, receiver) = flume::unbounded();
+        let _ = self.sender.send(ThreadRequest::Stats(sender));
+        receiver.recv_async().await.unwrap_or_default()
+    }
+
+    /// Open a window at once, apart from its schedule. Returns `false` if there is no such window.
+    pub fn trigger(self: &Arc<Self>, name: &str) -> bool {
+        if !self.options.contains_key(name) {
+            return false;
+        }
+        let maintainer = self.clone();
+        let name = name.to_owned();
+        tokio::spawn(async move { maintainer.run(&name).await });
+        true
+    }
+
+    async fn run(&self, name: &str) {
+        let Some(option) = self.options.get(name) else {
+            return;
+        };
+        let _lock = self.lock.lock().await;
+        log::info!("maintenance window {name} opens");
+
+        let mut errors = vec![];
+        let reload = self.drain(option).await;
+        self.set_phase(MaintenancePhase::Running, Some(name));
+        let offload_path = match &reload {
+            Some(reload) => reload.state_cache.offload_path.clone(),
+            None => self.offload_path.clone(),
+        };
+        for task in &option.tasks {
+            if let Err(err) = self.run_task(*task, option, &offload_path).await {
+                log::error!("maintenance task {task:?} failed: {err}");
+                errors.push(format!("{task:?}: {err}"));
+            }
+        }
+
+        if let Some(reload) = reload {
+            self.set_phase(MaintenancePhase::Reloading, Some(name));
+            let (sender, receiver) = flume::unbounded();
+            let _ = self.sender.send(ThreadRequest::Reload {
+                request: Box::new(reload),
+                sender: Some(sender),
+            });
+            if !receiver.recv_async().await.unwrap_or_default() {
+                errors.push("failed to load the model back".into());
+            }
+        }
+
+        self.set_phase(MaintenancePhase::Serving, None);
+        self.status_mut(name, |status| {
+            status.last = Some(Local::now());
+            status.errors = errors;
+        });
+        log::info!("maintenance window {name} closes");
+    }
+
+    /// Refuse new generations, wait for the running ones, and unload the model.
+    /// Returns how to load the model back, if one is loaded.
+    async fn drain(&self, option: &MaintenanceOption) -> Option<ReloadRequest> {
+        self.set_phase(MaintenancePhase::Draining, Some(&option.name));
+        let reload = try_request_info(self.sender.clone()).await.ok()?.reload;
+
+        let timeout = Duration::from_secs(option.drain_timeout);
+        let drained = tokio::time::timeout(timeout, async {
+            loop {
+                let RuntimeStats { queued, slots, .. } = self.stats().await;
+                if queued + slots.waiting + slots.busy == 0 {
+                    break;
+                }
+                tokio::time::sleep(SLEEP).await;
+            }
+        });
+        if drained.await.is_err() {
+            log::warn!("maintenance: generations still running after the drain timeout");
+        }
+
+        let _ = self.sender.send(ThreadRequest::Unload);
+        while self.stats().await.loaded {
+            tokio::time::sleep(SLEEP).await;
+        }
+        Some(reload)
+    }
+
+    async fn run_task(
+        &self,
+        task: MaintenanceTask,
+        option: &MaintenanceOption,
+        offload_path: &Path,
+    ) -> Result<()> {
+        match task {
+            MaintenanceTask::CompactCaches => {
+                let count = self.cache.compact()
+                    + self.semantic_cache.compact()
+                    + self.conversation_cache.compact();
+                let mut files = 0;
+                // with no model loaded, no state file is in use
+                if let Ok(entries) = std::fs::read_dir(offload_path) {
+                    for path in entries.flatten().map(|entry| entry.path()) {
+                        if path.extension().is_some_and(|x| x == "state") {
+                            std::fs::remove_file(path)?;
+                            files += 1;
+                        }
+                    }
+                }
+                log::info!("maintenance: dropped {count} cache entries and {files} state files");
+            }
+            MaintenanceTask::RotateLogs => {
+                let outputs = self
+                    .scheduler
+                    .list()
+                    .into_iter()
+                    .filter_map(|job| job.option.output);
+                for path in self.audit_path.clone().into_iter().chain(outputs) {
+                    if rotate(&path, option.keep_logs)? {
+                        log::info!("maintenance: rotated {}", path.to_string_lossy());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn maintainer(depot: &Depot) -> Arc<Maintainer> {
+    depot
+        .get::<Arc<Maintainer>>("maintainer")
+        .cloned()
+        .expect("maintainer is injected")
+}
+
+/// Guard of the generation APIs that turns requests away while a maintenance window is open.
+#[handler]
+pub async fn maintenance_gate(depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+    if maintainer(depot).phase() != MaintenancePhase::Serving {
+        res.status_code(StatusCode::SERVICE_UNAVAILABLE);
+        res.add_header(header::RETRY_AFTER, RETRY_AFTER, true).ok();
+        res.render(Text::Plain("the server is under maintenance"));
+        ctrl.skip_rest();
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Readiness {
+    /// Whether the server takes generations: a model is loaded and no maintenance window is open.
+    pub ready: bool,
+    pub loaded: bool,
+    /// Whether the model crashed and is not loaded back yet.
+    pub degraded: bool,
+    /// The error the model crashed with.
+    pub fault: Option<String>,
+    /// Automatic reloads after crashes so far.
+    pub restarts: usize,
+    pub maintenance: MaintenanceStatus,
+}
+
+/// `/health/ready`: whether the server takes generations, for load balancers and orchestrators.
+#[endpoint(responses(
+    (status_code = 200, description = "The server is ready.", body = Readiness),
+    (status_code = 503, description = "No model is loaded, the model crashed, or a maintenance window is open.", body = Readiness),
+))]
+pub async fn readiness(depot: &mut Depot, res: &mut Response) {
+    let maintainer = maintainer(depot);
+    let RuntimeStats {
+        loaded,
+        fault,
+        restarts,
+        ..
+    } = maintainer.stats().await;
+    let maintenance = maintainer.status();
+    let degraded = fault.is_some();
+    let ready = loaded && !degraded && maintenance.phase == MaintenancePhase::Serving;
+    if !ready {
+        res.status_code(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    res.render(Json(Readiness {
+        ready,
+        loaded,
+        degraded,
+        fault,
+        restarts,
+        maintenance,
+    }));
+}
+
+/// `/api/admin/maintenance/run?name=...`: open a maintenance window now.
+#[endpoint(
+    parameters(("name" = String, Query, description = "Name of the window.")),
+    status_codes(202, 400, 404),
+)]
+pub async fn run_maintenance(depot: &mut Depot, req: &mut Request) -> StatusCode {
+    let Some(name) = req.query::<String>("name") else {
+        return StatusCode::BAD_REQUEST;
+    };
+    match maintainer(depot).trigger(&name) {
+        true => StatusCode::ACCEPTED,
+        false => StatusCode::NOT_FOUND,
+    }
+}
@@ -4,14 +4,32 @@ use ai00_core::{RuntimeInfo, ThreadRequest};
 use anyhow::Result;
 use flume::Sender;
 
+pub mod account;
 pub mod adapter;
+pub mod audit;
 pub mod auth;
+pub mod bench;
+pub mod correlation;
+pub mod debug;
+pub mod event;
+pub mod experiment;
 pub mod file;
+pub mod maintenance;
 pub mod model;
 pub mod oai;
+pub mod plugin;
+pub mod privacy;
+pub mod schedule;
+pub mod security;
+pub mod setup;
+pub mod signature;
+pub mod web;
 
-pub use adapter::adapters;
-pub use file::{dir, load_config, models, save_config, unzip};
+pub use adapter::{adapters, adapters_info, adapters_stats, select_adapter};
+pub use file::{
+    abort_upload, dir, load_config, models, save_config, unzip, upload, upload_status,
+    validate_config,
+};
 pub use model::{info, load, load_state, save, state, unload};
 
 pub async fn try_request_info(sender: Sender<ThreadRequest>) -> Result<RuntimeInfo> {
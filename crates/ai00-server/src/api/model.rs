@@ -10,20 +10,24 @@ use web_rwkv::runtime::model::ModelInfo;
 use super::*;
 use crate::{build_path, types::ThreadState, SLEEP};
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct InfoResponse {
     reload: ReloadRequest,
+    /// Version, layers, embedding size and the other dimensions of the model.
+    #[salvo(schema(value_type = Object))]
     model: ModelInfo,
     states: Vec<InitStateInfo>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 struct InitStateInfo {
     id: StateId,
     name: String,
 }
 
-#[handler]
+/// The config of the loaded model, its dimensions and its initial states.
+/// Waits until a model is loaded.
+#[endpoint]
 pub async fn info(depot: &mut Depot) -> Json<InfoResponse> {
     let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
     let RuntimeInfo {
@@ -43,8 +47,12 @@ pub async fn info(depot: &mut Depot) -> Json<InfoResponse> {
     })
 }
 
-/// `/api/models/state`.
-#[handler]
+/// An SSE stream of the info of the loaded model, sent periodically.
+#[endpoint(
+    responses(
+        (status_code = 200, description = "Events of model info.", body = InfoResponse, content_type = "text/event-stream"),
+    )
+)]
 pub async fn state(depot: &mut Depot, res: &mut Response) {
     let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
     let (info_sender, info_receiver) = flume::unbounded();
@@ -76,12 +84,24 @@ pub async fn state(depot: &mut Depot, res: &mut Response) {
     salvo::sse::stream(res, stream);
 }
 
-/// `/api/models/load`.
-#[handler]
+/// Load a model with the given config, replacing the current one.
+/// Paths are relative to the model directory.
+#[endpoint(
+    request_body = ReloadRequest,
+    status_codes(200, 400, 404, 500),
+    responses(
+        (status_code = 200, description = "Done."),
+        (status_code = 400, description = "The request cannot be parsed."),
+        (status_code = 404, description = "A path is outside the model directory."),
+        (status_code = 500, description = "Failed to load the model."),
+    )
+)]
 pub async fn load(depot: &mut Depot, req: &mut Request) -> StatusCode {
     let ThreadState { sender, path } = depot.obtain::<ThreadState>().unwrap();
     let (result_sender, result_receiver) = flume::unbounded();
-    let mut request: ReloadRequest = req.parse_body().await.unwrap();
+    let Ok(mut request) = req.parse_body::<ReloadRequest>().await else {
+        return StatusCode::BAD_REQUEST;
+    };
 
     // make sure that we are not visiting un-permitted path.
     request.model_path = match build_path(path, request.model_path) {
@@ -111,8 +131,8 @@ pub async fn load(depot: &mut Depot, req: &mut Request) -> StatusCode {
     }
 }
 
-/// `/api/models/unload`.
-#[handler]
+/// Unload the model. Returns once it is unloaded.
+#[endpoint(status_codes(200))]
 pub async fn unload(depot: &mut Depot) -> StatusCode {
     let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
     let _ = sender.send(ThreadRequest::Unload);
@@ -120,12 +140,23 @@ pub async fn unload(depot: &mut Depot) -> StatusCode {
     StatusCode::OK
 }
 
-/// `/api/models/state/load`.
-#[handler]
+/// Load an additional initial state.
+#[endpoint(
+    request_body = ai00_core::reload::State,
+    status_codes(200, 400, 404, 500),
+    responses(
+        (status_code = 200, description = "Done."),
+        (status_code = 400, description = "The request cannot be parsed."),
+        (status_code = 404, description = "A path is outside the model directory."),
+        (status_code = 500, description = "Failed to load the state."),
+    )
+)]
 pub async fn load_state(depot: &mut Depot, req: &mut Request) -> StatusCode {
     let ThreadState { sender, path } = depot.obtain::<ThreadState>().unwrap();
     let (result_sender, result_receiver) = flume::unbounded();
-    let mut request: ai00_core::reload::State = req.parse_body().await.unwrap();
+    let Ok(mut request) = req.parse_body::<ai00_core::reload::State>().await else {
+        return StatusCode::BAD_REQUEST;
+    };
 
     request.path = match build_path(path, &request.path) {
         Ok(path) => path,
@@ -142,12 +173,23 @@ pub async fn load_state(depot: &mut Depot, req: &mut Request) -> StatusCode {
     }
 }
 
-/// `/api/models/save`.
-#[handler]
+/// Save the loaded model with its quantization as a prefab.
+#[endpoint(
+    request_body = SaveRequest,
+    status_codes(200, 400, 404, 500),
+    responses(
+        (status_code = 200, description = "Done."),
+        (status_code = 400, description = "The request cannot be parsed."),
+        (status_code = 404, description = "A path is outside the model directory."),
+        (status_code = 500, description = "Failed to save the model."),
+    )
+)]
 pub async fn save(depot: &mut Depot, req: &mut Request) -> StatusCode {
     let ThreadState { sender, path } = depot.obtain::<ThreadState>().unwrap();
     let (result_sender, result_receiver) = flume::unbounded();
-    let mut request: SaveRequest = req.parse_body().await.unwrap();
+    let Ok(mut request) = req.parse_body::<SaveRequest>().await else {
+        return StatusCode::BAD_REQUEST;
+    };
 
     // make sure that we are not visiting un-permitted path.
     request.path = match build_path(path, request.path) {
@@ -0,0 +1,413 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ai00_core::{run::StateId, GenerateRequest, ThreadRequest, Token};
+use flume::Sender;
+use salvo::Depot;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use web_rwkv::tokenizer::Tokenizer;
+
+use crate::{api::auth::caller, config::CacheOption};
+
+/// The time of insertion, the response, and the client it is served to.
+type ResponseEntry = (Instant, Value, Option<String>);
+
+/// Exact-match cache of non-streaming responses, keyed by the hash of the model and the request.
+#[derive(Debug, Default)]
+pub struct ResponseCache {
+    option: CacheOption,
+    entries: Mutex<HashMap<String, ResponseEntry>>,
+}
+
+impl ResponseCache {
+    pub fn new(option: CacheOption) -> Self {
+        Self {
+            option,
+            entries: Default::default(),
+        }
+    }
+
+    /// Find the cache in the depot and hash the request if it is eligible for caching.
+    pub fn obtain(
+        depot: &Depot,
+        model: &str,
+        request: &impl Serialize,
+        deterministic: bool,
+    ) -> Option<(Arc<Self>, String)> {
+        let cache = depot.get::<Arc<Self>>("cache").ok()?;
+        if !cache.option.enable || (cache.option.deterministic_only && !deterministic) {
+            return None;
+        }
+
+        // `Value` keeps map keys sorted, so equal requests always hash the same;
+        // timings only shape the response, a hit never carries any
+        let mut value = serde_json::to_value(request).ok()?;
+        if let Value::Object(map) = &mut value {
+            map.remove("timings");
+        }
+        let mut sha = Sha256::new();
+        sha.update(model.as_bytes());
+        sha.update(value.to_string().as_bytes());
+        let key = format!("{:x}", sha.finalize());
+        Some((cache.clone(), key))
+    }
+
+    fn ttl(&self) -> Duration {
+        Duration::from_secs(self.option.ttl)
+    }
+
+    /// Returns the cached response with `cached` set, if it is not expired.
+    pub fn get(&self, key: &str) -> Option<Value> {
+        let ttl = self.ttl();
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((instant, value, _)) if instant.elapsed() < ttl => {
+                let mut value = value.clone();
+                value["cached"] = true.into();
+                Some(value)
+            }
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Store a response, evicting expired entries and then the oldest ones if the cache is full.
+    /// Timings are stripped, since they would be stale when the response is replayed.
+    pub fn insert(&self, key: String, owner: Option<String>, response: &impl Serialize) {
+        if self.option.capacity == 0 {
+            return;
+        }
+        let Ok(mut value) = serde_json::to_value(response) else {
+            return;
+        };
+        if let Value::Object(map) = &mut value {
+            map.remove("timings");
+        }
+
+        let ttl = self.ttl();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (instant, _, _)| instant.elapsed() < ttl);
+        while entries.len() >= self.option.capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (instant, _, _))| *instant)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => entries.remove(&oldest),
+                None => break,
+            };
+        }
+        entries.insert(key, (Instant::now(), value, owner));
+    }
+
+    /// Drop the expired responses. Returns the number of them.
+    pub fn compact(&self) -> usize {
+        let ttl = self.ttl();
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.len();
+        entries.retain(|_, (instant, _, _)| instant.elapsed() < ttl);
+        len - entries.len()
+    }
+
+    /// Remove the responses served to the client. Returns the number of them.
+    pub fn purge(&self, owner: &str, dry_run: bool) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let owned = |x: &Option<String>| x.as_deref() == Some(owner);
+        let count = entries.values().filter(|(_, _, x)| owned(x)).count();
+        if !dry_run {
+            entries.retain(|_, (_, _, x)| !owned(x));
+        }
+        count
+    }
+}
+
+#[derive(Debug)]
+struct SemanticEntry {
+    instant: Instant,
+    /// Hash of the model and the parameters of the request besides the prompt.
+    scope: String,
+    embedding: Vec<f32>,
+    response: Value,
+    owner: Option<String>,
+}
+
+/// Cache of non-streaming responses, matched by the cosine similarity of prompt embeddings.
+#[derive(Debug, Default)]
+pub struct SemanticCache {
+    option: CacheOption,
+    entries: Mutex<Vec<SemanticEntry>>,
+}
+
+impl SemanticCache {
+    pub fn new(option: CacheOption) -> Self {
+        Self {
+            option,
+            entries: Default::default(),
+        }
+    }
+
+    /// Find the cache in the depot if it applies to the caller.
+    pub fn obtain(depot: &Depot) -> Option<Arc<Self>> {
+        let cache = depot.get::<Arc<Self>>("semantic_cache").ok()?;
+        let option = &cache.option.semantic;
+        let excluded = caller(depot).is_some_and(|caller| option.exclude.contains(&caller));
+        (option.enable && !excluded).then(|| cache.clone())
+    }
+
+    /// Hash of the model and of the request without its `prompt_fields`, which only prompts alike within share responses.
+    /// Unless the cache is `shared`, the caller is hashed too, so a client is never served a response made for another.
+    pub fn scope(
+        depot: &Depot,
+        model: &str,
+        request: &impl Serialize,
+        prompt_fields: &[&str],
+    ) -> Option<String> {
+        let shared = depot
+            .get::<Arc<Self>>("semantic_cache")
+            .is_ok_and(|cache| cache.option.semantic.shared);
+        let mut value = serde_json::to_value(request).ok()?;
+        if let Value::Object(map) = &mut value {
+            for field in prompt_fields {
+                map.remove(*field);
+            }
+        }
+        let mut sha = Sha256::new();
+        sha.update(model.as_bytes());
+        sha.update(value.to_string().as_bytes());
+        if !shared {
+            sha.update(caller(depot).unwrap_or_default().as_bytes());
+        }
+        Some(format!("{:x}", sha.finalize()))
+    }
+
+    /// Embed the prompt of the request with the loaded model. The embedding is normalized.
+    pub async fn embed(
+        &self,
+        sender: &Sender<ThreadRequest>,
+        tokenizer: Arc<Tokenizer>,
+        request: &GenerateRequest,
+    ) -> Option<Vec<f32>> {
+        let request = Box::new(GenerateRequest {
+            prompt: request.prompt.clone(),
+            prompt_tokens: request.prompt_tokens.clone(),
+            max_tokens: 1,
+            request_id: ai00_core::request_id(),
+            embed: true,
+            embed_layer: self.option.semantic.embed_layer,
+            ..Default::default()
+        });
+        let (token_sender, token_receiver) = flume::unbounded();
+        let _ = sender.send(ThreadRequest::Generate {
+            request,
+            tokenizer,
+            sender: token_sender,
+        });
+
+        // the embedding comes after `Done`, once the slot is backed
+        let mut embedding = loop {
+            match token_receiver.recv_async().await.ok()? {
+                Token::Embed(embedding) => break embedding,
+                Token::Error(_) => return None,
+                _ => {}
+            }
+        };
+        let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            embedding.iter_mut().for_each(|x| *x /= norm);
+        }
+        Some(embedding)
+    }
+
+    /// Returns the cached response of the most similar prompt with `cached` set,
+    /// if the similarity exceeds the threshold.
+    pub fn get(&self, scope: &str, embedding: &[f32]) -> Option<Value> {
+        let ttl = Duration::from_secs(self.option.ttl);
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| entry.instant.elapsed() < ttl);
+
+        let (similarity, entry) = entries
+            .iter()
+            .filter(|entry| entry.scope == scope && entry.embedding.len() == embedding.len())
+            .map(|entry| {
+                let similarity: f32 = entry
+                    .embedding
+                    .iter()
+                    .zip(embedding)
+                    .map(|(x, y)| x * y)
+                    .sum();
+                (similarity, entry)
+            })
+            .max_by(|(x, _), (y, _)| x.total_cmp(y))?;
+        (similarity >= self.option.semantic.threshold).then(|| {
+            let mut value = entry.response.clone();
+            value["cached"] = true.into();
+            value
+        })
+    }
+
+    /// Store a response, evicting the oldest ones if the cache is full.
+    pub fn insert(
+        &self,
+        scope: String,
+        embedding: Vec<f32>,
+        owner: Option<String>,
+        response: &impl Serialize,
+    ) {
+        let capacity = self.option.semantic.capacity;
+        if capacity == 0 {
+            return;
+        }
+        let Ok(response) = serde_json::to_value(response) else {
+            return;
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= capacity {
+            // entries are pushed in order, so the oldest ones come first
+            let len = entries.len() + 1 - capacity;
+            entries.drain(..len);
+        }
+        entries.push(SemanticEntry {
+            instant: Instant::now(),
+            scope,
+            embedding,
+            response,
+            owner,
+        });
+    }
+
+    /// Drop the expired responses. Returns the number of them.
+    pub fn compact(&self) -> usize {
+        let ttl = Duration::from_secs(self.option.ttl);
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.len();
+        entries.retain(|entry| entry.instant.elapsed() < ttl);
+        len - entries.len()
+    }
+
+    /// Remove the responses served to the client. Returns the number of them.
+    pub fn purge(&self, owner: &str, dry_run: bool) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let owned = |entry: &SemanticEntry| entry.owner.as_deref() == Some(owner);
+        let count = entries.iter().filter(|entry| owned(entry)).count();
+        if !dry_run {
+            entries.retain(|entry| !owned(entry));
+        }
+        count
+    }
+}
+
+/// The time of last use, the tokens, and the client the conversation is with.
+type ConversationEntry = (Instant, Vec<u16>, Option<String>);
+
+/// Tokens of the conversations served before, keyed by the hash of their rendered text.
+///
+/// A stateless client sends the whole conversation every turn. Feeding the runtime exactly the tokens
+/// it saw last turn makes it find the states it backed then, instead of re-reading the conversation.
+#[derive(Debug, Default)]
+pub struct ConversationCache {
+    option: CacheOption,
+    entries: Mutex<HashMap<String, ConversationEntry>>,
+}
+
+impl ConversationCache {
+    pub fn new(option: CacheOption) -> Self {
+        Self {
+            option,
+            entries: Default::default(),
+        }
+    }
+
+    /// Find the cache in the depot, along with the scope of the conversations of the caller.
+    pub fn obtain(depot: &Depot, model: &str, state: StateId) -> Option<(Arc<Self>, String)> {
+        let cache = depot.get::<Arc<Self>>("conversation_cache").ok()?;
+        if !cache.option.conversation.enable {
+            return None;
+        }
+        let caller = caller(depot).unwrap_or_default();
+        let scope = format!("{model}\n{state:?}\n{caller}");
+        Some((cache.clone(), scope))
+    }
+
+    fn key(scope: &str, text: &str) -> String {
+        let mut sha = Sha256::new();
+        sha.update(scope.as_bytes());
+        sha.update([0]);
+        sha.update(text.as_bytes());
+        format!("{:x}", sha.finalize())
+    }
+
+    /// Returns the tokens the conversation of `text` was run with, if it is not expired.
+    pub fn get(&self, scope: &str, text: &str) -> Option<Vec<u16>> {
+        let ttl = Duration::from_secs(self.option.ttl);
+        let key = Self::key(scope, text);
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(&key) {
+            Some((instant, tokens, _)) if instant.elapsed() < ttl => {
+                *instant = Instant::now();
+                Some(tokens.clone())
+            }
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Remember the tokens of a conversation, evicting expired entries and then the oldest ones if the cache is full.
+    pub fn insert(&self, scope: &str, text: &str, tokens: Vec<u16>, owner: Option<String>) {
+        let capacity = self.option.conversation.capacity;
+        if capacity == 0 {
+            return;
+        }
+
+        let ttl = Duration::from_secs(self.option.ttl);
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (instant, _, _)| instant.elapsed() < ttl);
+        while entries.len() >= capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (instant, _, _))| *instant)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => entries.remove(&oldest),
+                None => break,
+            };
+        }
+        entries.insert(Self::key(scope, text), (Instant::now(), tokens, owner));
+    }
+
+    /// Drop the expired conversations. Returns the number of them.
+    pub fn compact(&self) -> usize {
+        let ttl = Duration::from_secs(self.option.ttl);
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.len();
+        entries.retain(|_, (instant, _, _)| instant.elapsed() < ttl);
+        len - entries.len()
+    }
+
+    /// Remove the conversations with the client. Returns their tokens, so that the states backed
+    /// along them can be dropped too.
+    pub fn purge(&self, owner: &str, dry_run: bool) -> Vec<Vec<u16>> {
+        let mut entries = self.entries.lock().unwrap();
+        let owned = |x: &Option<String>| x.as_deref() == Some(owner);
+        let tokens = entries
+            .values()
+            .filter(|(_, _, x)| owned(x))
+            .map(|(_, tokens, _)| tokens.clone())
+            .collect();
+        if !dry_run {
+            entries.retain(|_, (_, _, x)| !owned(x));
+        }
+        tokens
+    }
+}
@@ -1,17 +1,28 @@
 use std::{collections::HashMap, sync::Arc};
 
 use ai00_core::{
-    run::StateId, FinishReason, GenerateRequest, ThreadRequest, Token, TokenCounter, MAX_TOKENS,
+    run::StateId,
+    sampler::{dry::DryParams, processor::ProcessorRequest, xtc::XtcParams},
+    FinishReason, GenerateRequest, Priority, ThreadRequest, Token, TokenCounter, MAX_TOKENS,
 };
 use futures_util::StreamExt;
 use itertools::Itertools;
 use regex::Regex;
 use salvo::{oapi::extract::JsonBody, prelude::*, sse::SseEvent, Depot, Writer};
 use serde::{Deserialize, Serialize};
+use web_rwkv::tokenizer::Tokenizer;
 
-use super::*;
+use super::{
+    cache::{ConversationCache, ResponseCache, SemanticCache},
+    cjk::CjkOptions,
+    error::{render_error, render_failure, ErrorResponse},
+    reasoning::{ReasoningParams, ReasoningSplitter, Segment},
+    tool::{self, Tool, ToolCall, ToolCallDelta, ToolPiece, ToolStream, TOOL_CALL_END},
+    *,
+};
 use crate::{
-    api::request_info,
+    api::{account::AccountStore, auth::client, debug::DebugSession, request_info},
+    config::{LimitOption, ReasoningFormat, ReasoningOption, SamplerLimits, StreamOption},
     types::{Array, ThreadState},
     SLEEP,
 };
@@ -25,6 +36,9 @@ pub enum Role {
     User,
     #[serde(alias = "assistant")]
     Assistant,
+    /// The result of a tool call, in a message that follows the call.
+    #[serde(alias = "tool")]
+    Tool,
 }
 
 impl std::fmt::Display for Role {
@@ -33,6 +47,7 @@ impl std::fmt::Display for Role {
             Role::System => write!(f, "System"),
             Role::User => write!(f, "User"),
             Role::Assistant => write!(f, "Assistant"),
+            Role::Tool => write!(f, "Tool"),
         }
     }
 }
@@ -41,31 +56,106 @@ impl std::fmt::Display for Role {
 pub struct ChatRecord {
     role: Role,
     content: String,
+    /// Reasoning of the model, delivered apart from the content with the `Separate` reasoning format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reasoning_content: Option<String>,
+    /// On the last message of the assistant: the model continues this text instead of starting a new reply.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    prefix: bool,
+    /// On messages of the assistant: the tools it called.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
 }
 
-#[derive(Debug, Deserialize, ToSchema)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[salvo(schema(example = json!({
+    "messages": [
+        {"role": "user", "content": "Hi!"},
+        {"role": "assistant", "content": "Hello, I am your AI assistant. If you have any questions or instructions, please let me know!"},
+        {"role": "user", "content": "Tell me about water."},
+    ],
+    "max_tokens": 512,
+    "temperature": 1.0,
+    "top_p": 0.5,
+    "stop": ["\n\nUser:"],
+    "stream": false,
+})))]
 pub struct ChatRequest {
     #[serde(default)]
     messages: Array<ChatRecord>,
     #[serde(default)]
     names: HashMap<Role, String>,
+    /// Start of the reply, which the model continues. Same as a last assistant message with `prefix`.
+    #[serde(default)]
+    prefill: Option<String>,
     #[serde(default)]
     state: StateId,
-    #[serde(default = "default_max_tokens")]
-    max_tokens: usize,
+    /// Output token limit. 256 if not given, cut down to the room the prompt leaves in the context.
+    #[serde(default)]
+    max_tokens: Option<usize>,
     #[serde(default = "default_stop")]
     stop: Array<String>,
     #[serde(default)]
     stream: bool,
+    /// Minimum milliseconds between the chunks of a stream; tokens generated in between are merged. Overrides the config.
+    #[serde(default)]
+    stream_pace_ms: Option<u64>,
+    /// Number of choices to generate.
+    #[serde(default)]
+    n: Option<usize>,
+    /// Scale of the noise added to the logits, so that choices differ even at low temperature.
+    #[serde(default)]
+    diversity: f32,
     #[serde(default)]
     #[serde(alias = "logit_bias")]
     bias: HashMap<u16, f32>,
     #[serde(default)]
     bnf_schema: Option<String>,
+    /// Logits processors registered on the server, applied in order after the sampler.
+    #[serde(default)]
+    processors: Vec<ProcessorRequest>,
     #[serde(flatten)]
     sampler: NucleusParams,
+    #[serde(flatten)]
+    dry: DryParams,
+    #[serde(flatten)]
+    xtc: XtcParams,
+    /// Keep only the tokens within this many standard deviations of the top logit. `0` disables it.
+    #[serde(default)]
+    top_n_sigma: f32,
     #[serde(default)]
     sampler_override: Option<SamplerParams>,
+    /// How urgent the request is: `low` for background work that yields its lane to others, `normal` or `high`.
+    /// `high` is only granted to the callers of `limits.high_priority`, and taken as `normal` for others.
+    #[serde(default)]
+    priority: Priority,
+    /// Include latency measurements in chunks and the response.
+    #[serde(default)]
+    timings: bool,
+    /// Record the rendered prompt, its tokens and every sampling step, retrievable at `/api/debug/<id>`.
+    /// Bypasses the response caches. Not available with `stream`.
+    #[serde(default)]
+    debug: bool,
+    /// How to deliver reasoning blocks. Overrides the format in the config.
+    #[serde(default)]
+    reasoning_format: Option<ReasoningFormat>,
+    /// Budget of reasoning blocks.
+    #[serde(default)]
+    reasoning: Option<ReasoningParams>,
+    /// Normalization of the output, e.g., full width forms to half width, or simplified chinese to traditional.
+    #[serde(default)]
+    cjk: CjkOptions,
+    /// After the reply, generate questions the user may ask next, returned in `suggestions`.
+    /// With `stream`, they come in a last chunk without choices.
+    #[serde(default)]
+    suggestions: bool,
+    /// Tools the model may call. A reply that calls them ends with `tool_calls`, and the calls in `tool_calls`.
+    /// With `stream`, each call comes with its id and name first, then its arguments in pieces as they are generated.
+    #[serde(default)]
+    tools: Vec<Tool>,
+    /// Whether the model may call several tools in one reply. Otherwise generation stops at the end of the first call.
+    #[serde(default = "default_parallel_tool_calls")]
+    parallel_tool_calls: bool,
 }
 
 impl Default for ChatRequest {
@@ -73,30 +163,129 @@ impl Default for ChatRequest {
         Self {
             messages: Array::default(),
             names: HashMap::new(),
+            prefill: None,
             state: Default::default(),
-            max_tokens: 256,
+            max_tokens: None,
             stop: Array::Item("\n\n".into()),
             stream: false,
+            stream_pace_ms: None,
+            n: None,
+            diversity: 0.0,
             bias: HashMap::new(),
             bnf_schema: Default::default(),
+            processors: vec![],
             sampler: Default::default(),
+            dry: Default::default(),
+            xtc: Default::default(),
+            top_n_sigma: 0.0,
             sampler_override: Default::default(),
+            priority: Default::default(),
+            timings: false,
+            debug: false,
+            reasoning_format: None,
+            reasoning: None,
+            cjk: Default::default(),
+            suggestions: false,
+            tools: vec![],
+            parallel_tool_calls: true,
         }
     }
 }
 
-fn default_max_tokens() -> usize {
-    ChatRequest::default().max_tokens
+impl ChatRequest {
+    /// The last message if the model is to continue it.
+    fn prefix(&self) -> Option<&ChatRecord> {
+        let record = match &self.messages {
+            Array::None => None,
+            Array::Item(record) => Some(record),
+            Array::Vec(records) => records.last(),
+        };
+        record.filter(|record| record.role == Role::Assistant && record.prefix)
+    }
+
+    /// Start of the reply the model continues, if any; the leading whitespace of the reply is kept then.
+    fn prefill(&self) -> Option<&str> {
+        self.prefix()
+            .map(|record| record.content.as_str())
+            .or(self.prefill.as_deref())
+            .filter(|text| !text.trim().is_empty())
+    }
+
+    /// The messages rendered into the prompt: all but the one the model continues.
+    fn records(&self) -> Vec<ChatRecord> {
+        let mut records = Vec::from(self.messages.clone());
+        if self.prefix().is_some() {
+            records.pop();
+        }
+        records
+    }
+}
+
+/// Render the messages into the prompt, without the turn of the assistant that follows.
+fn render(records: &[ChatRecord], names: &HashMap<Role, String>) -> String {
+    let re = Regex::new(r"\n(\s*\n)+").unwrap();
+    records
+        .iter()
+        .map(
+            |ChatRecord {
+                 role,
+                 content,
+                 tool_calls,
+                 ..
+             }| {
+                let role = names.get(role).cloned().unwrap_or(role.to_string());
+                let content = re.replace_all(content, "\n");
+                let content = [content.trim(), &tool::render(tool_calls)]
+                    .into_iter()
+                    .filter(|text| !text.is_empty())
+                    .join("\n");
+                format!("{role}: {content}")
+            },
+        )
+        .join("\n\n")
 }
 
 fn default_stop() -> Array<String> {
     ChatRequest::default().stop
 }
 
+fn default_parallel_tool_calls() -> bool {
+    ChatRequest::default().parallel_tool_calls
+}
+
+impl Bounded for ChatRequest {
+    fn bounds(
+        &mut self,
+    ) -> (
+        &mut NucleusParams,
+        &mut Option<SamplerParams>,
+        &mut DryParams,
+        &mut Option<usize>,
+    ) {
+        (
+            &mut self.sampler,
+            &mut self.sampler_override,
+            &mut self.dry,
+            &mut self.max_tokens,
+        )
+    }
+}
+
 impl From<ChatRequest> for GenerateRequest {
     fn from(value: ChatRequest) -> Self {
+        let prefill = value.prefill().map(|text| text.trim_start().to_owned());
+        let mut records = value.records();
+        if !value.tools.is_empty() {
+            records.insert(
+                0,
+                ChatRecord {
+                    role: Role::System,
+                    content: tool::describe(&value.tools, value.parallel_tool_calls),
+                    ..Default::default()
+                },
+            );
+        }
         let ChatRequest {
-            messages,
             names,
             state,
             max_tokens,
@@ -105,20 +294,20 @@ impl From<ChatRequest> for GenerateRequest {
             sampler_override,
             bias,
             bnf_schema,
+            processors,
+            dry,
+            xtc,
+            top_n_sigma,
+            diversity,
+            priority,
+            cjk,
+            tools,
+            parallel_tool_calls,
             ..
         } = value;
 
-        let re = Regex::new(r"\n(\s*\n)+").unwrap();
-        let prompt = Vec::from(messages.clone())
-            .into_iter()
-            .map(|ChatRecord { role, content }| {
-                let role = names.get(&role).cloned().unwrap_or(role.to_string());
-                let content = re.replace_all(&content, "\n");
-                let content = content.trim();
-                format!("{role}: {content}")
-            })
-            .join("\n\n");
-        let model_text = Vec::from(messages)
+        let prompt = render(&records, &names);
+        let model_text = records
             .into_iter()
             .filter(|record| record.role == Role::Assistant)
             .map(|record| record.content)
@@ -129,10 +318,18 @@ impl From<ChatRequest> for GenerateRequest {
             .get(&assistant)
             .cloned()
             .unwrap_or(assistant.to_string());
-        let prompt = prompt + &format!("\n\n{assistant}:");
+        let mut prompt = prompt + &format!("\n\n{assistant}:");
+        if let Some(prefill) = prefill {
+            prompt.push(' ');
+            prompt.push_str(&prefill);
+        }
 
-        let max_tokens = max_tokens.min(MAX_TOKENS);
-        let stop = stop.into();
+        let max_tokens = max_tokens.unwrap_or(DEFAULT_MAX_TOKENS).min(MAX_TOKENS);
+        let mut stop: Vec<String> = stop.into();
+        if !tools.is_empty() && !parallel_tool_calls {
+            stop.push(TOOL_CALL_END.into());
+        }
+        cjk.expand_stops(&mut stop);
         let bias = Arc::new(bias);
         let sampler = match sampler_override {
             Some(sampler) => sampler.into(),
@@ -147,7 +344,13 @@ impl From<ChatRequest> for GenerateRequest {
             sampler,
             bias,
             bnf_schema,
+            processors,
+            dry,
+            xtc,
+            top_n_sigma: top_n_sigma.max(0.0),
             state,
+            logit_noise: diversity.max(0.0),
+            priority,
             ..Default::default()
         }
     }
@@ -158,6 +361,9 @@ struct ChatChoice {
     message: ChatRecord,
     index: usize,
     finish_reason: FinishReason,
+    /// The stop sequence that ended the generation, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequence: Option<String>,
 }
 
 #[derive(Debug, Serialize, ToSchema, ToResponse)]
@@ -167,6 +373,17 @@ struct ChatResponse {
     choices: Vec<ChatChoice>,
     #[serde(rename = "usage")]
     counter: TokenCounter,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<Timings>,
+    /// Whether the response is served from the cache.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    cached: bool,
+    /// Id of the debug record of the request, if asked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debug_id: Option<String>,
+    /// Questions the user may ask next, if asked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suggestions: Option<Vec<String>>,
 }
 
 #[derive(Debug, Default, Serialize, ToSchema, ToResponse)]
@@ -177,6 +394,8 @@ enum PartialChatRecord {
     None,
     Role(Role),
     Content(String),
+    ReasoningContent(String),
+    ToolCalls(Vec<ToolCallDelta>),
 }
 
 #[derive(Debug, Default, Serialize, ToSchema, ToResponse)]
@@ -184,6 +403,9 @@ struct PartialChatChoice {
     delta: PartialChatRecord,
     index: usize,
     finish_reason: FinishReason,
+    /// The stop sequence that ended the generation, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequence: Option<String>,
 }
 
 #[derive(Debug, Serialize, ToSchema, ToResponse)]
@@ -191,55 +413,354 @@ struct PartialChatResponse {
     object: String,
     model: String,
     choices: Vec<PartialChatChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<Timings>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suggestions: Option<Vec<String>>,
+}
+
+fn reasoning_option(depot: &Depot) -> ReasoningOption {
+    depot
+        .get::<ReasoningOption>("reasoning")
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// A chat that may extend a conversation served before.
+struct Conversation {
+    cache: Arc<ConversationCache>,
+    scope: String,
+    owner: Option<String>,
+    records: Vec<ChatRecord>,
+    names: HashMap<Role, String>,
+}
+
+impl Conversation {
+    fn new(depot: &Depot, model: &str, request: &ChatRequest) -> Option<Self> {
+        let (cache, scope) = ConversationCache::obtain(depot, model, request.state)?;
+        Some(Self {
+            cache,
+            scope,
+            owner: client(depot),
+            records: request.records(),
+            names: request.names.clone(),
+        })
+    }
+
+    /// Feed the runtime the tokens of the longest conversation served before that this one extends.
+    fn resume(&self, request: &mut GenerateRequest, tokenizer: &Tokenizer) {
+        for len in (1..self.records.len()).rev() {
+            let text = render(&self.records[..len], &self.names);
+            let Some(rest) = request.prompt.strip_prefix(&text) else {
+                continue;
+            };
+            let Some(tokens) = self.cache.get(&self.scope, &text) else {
+                continue;
+            };
+            match tokenizer.encode(rest.as_bytes()) {
+                Ok(rest) => {
+                    log::info!("chat resumes a conversation of {} tokens", tokens.len());
+                    request.prompt_tokens = Some([tokens, rest].concat());
+                }
+                Err(err) => log::warn!("failed to resume conversation: {err}"),
+            }
+            return;
+        }
+    }
+
+    /// Remember the tokens of the conversation with the reply appended, for the next turn to resume from.
+    fn remember(self, request: &GenerateRequest, reply: &str, tokenizer: &Tokenizer) {
+        let mut records = self.records;
+        records.push(ChatRecord {
+            role: Role::Assistant,
+            content: reply.into(),
+            ..Default::default()
+        });
+        let text = render(&records, &self.names);
+        let Some(rest) = text.strip_prefix(&request.prompt) else {
+            return;
+        };
+        let prompt = match &request.prompt_tokens {
+            Some(tokens) => Ok(tokens.clone()),
+            None => tokenizer.encode(request.prompt.as_bytes()),
+        };
+        if let (Ok(prompt), Ok(rest)) = (prompt, tokenizer.encode(rest.as_bytes())) {
+            let tokens = [prompt, rest].concat();
+            self.cache.insert(&self.scope, &text, tokens, self.owner);
+        }
+    }
+}
+
+/// Number of follow-up questions suggested.
+const SUGGESTIONS: usize = 3;
+/// Output token limit of the generation of suggestions.
+const SUGGESTION_MAX_TOKENS: usize = 128;
+
+/// Follow-up questions of a chat, generated from the conversation once the reply is done.
+struct Suggester {
+    records: Vec<ChatRecord>,
+    names: HashMap<Role, String>,
 }
 
-async fn respond_one(depot: &mut Depot, request: ChatRequest, res: &mut Response) {
+impl Suggester {
+    fn new(request: &ChatRequest) -> Option<Self> {
+        request.suggestions.then(|| Self {
+            records: request.records(),
+            names: request.names.clone(),
+        })
+    }
+
+    /// Ask for the questions after the reply, as a turn of the user the model answers with a list.
+    fn request(&self, reply: &str, request: &GenerateRequest) -> GenerateRequest {
+        let mut records = self.records.clone();
+        records.push(ChatRecord {
+            role: Role::Assistant,
+            content: reply.into(),
+            ..Default::default()
+        });
+        records.push(ChatRecord {
+            role: Role::User,
+            content: format!(
+                "Suggest {SUGGESTIONS} short questions I may ask next about this, one per line."
+            ),
+            ..Default::default()
+        });
+        let name = |role: Role| self.names.get(&role).cloned().unwrap_or(role.to_string());
+        let prompt = render(&records, &self.names) + &format!("\n\n{}:", name(Role::Assistant));
+        GenerateRequest {
+            prompt,
+            max_tokens: SUGGESTION_MAX_TOKENS,
+            stop: vec!["\n\n".into(), format!("\n{}:", name(Role::User))],
+            state: request.state,
+            quota: request.quota.clone(),
+            rate: request.rate.clone(),
+            priority: request.priority,
+            ..Default::default()
+        }
+    }
+
+    /// The questions in a list the model output, without their numbers or bullets.
+    fn parse(text: &str) -> Vec<String> {
+        text.lines()
+            .map(|line| {
+                line.trim()
+                    .trim_start_matches(|c: char| c.is_ascii_digit() || ".)-*•".contains(c))
+                    .trim()
+            })
+            .filter(|line| !line.is_empty())
+            .take(SUGGESTIONS)
+            .map(Into::into)
+            .collect()
+    }
+
+    async fn suggest(
+        &self,
+        sender: &Sender<ThreadRequest>,
+        tokenizer: Arc<Tokenizer>,
+        reply: &str,
+        request: &GenerateRequest,
+    ) -> Vec<String> {
+        let request = self.request(reply, request);
+        let generations = generate(sender, tokenizer, vec![request], None).await;
+        generations
+            .ok()
+            .as_ref()
+            .and_then(|generations| generations.first())
+            .map(|generation| Self::parse(&generation.text))
+            .unwrap_or_default()
+    }
+}
+
+async fn respond_one(depot: &mut Depot, mut request: ChatRequest, res: &mut Response) {
     let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
     let info = request_info(sender.clone(), SLEEP).await;
     let model_name = info.reload.model_path.to_string_lossy().into_owned();
 
-    let (token_sender, token_receiver) = flume::unbounded();
-    let request = Box::new(request.into());
-    let _ = sender.send(ThreadRequest::Generate {
-        request,
-        tokenizer: info.tokenizer,
-        sender: token_sender,
-    });
+    // the choices and the debug session take the sampler from here, so it is bounded first
+    if let Err(err) = SamplerLimits::obtain(depot).bound_request(&mut request) {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain(err.to_string()));
+        return;
+    }
+    let sampler = request
+        .sampler_override
+        .clone()
+        .unwrap_or_else(|| SamplerParams::Nucleus(request.sampler.clone()));
+    let deterministic =
+        sampler.is_deterministic(&request.xtc, request.diversity, request.top_n_sigma);
+    let cache = match request.debug {
+        true => None,
+        false => ResponseCache::obtain(depot, &model_name, &request, deterministic),
+    };
+    if let Some(value) = cache.as_ref().and_then(|(cache, key)| cache.get(key)) {
+        res.render(Json(value));
+        return;
+    }
+    let scope = SemanticCache::scope(depot, &model_name, &request, &["messages"]);
+
+    let n = match check_choices(request.n, false, &info) {
+        Ok(n) => n,
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(err.to_string()));
+            return;
+        }
+    };
+    let cjk = request.cjk;
+    if let Err(err) = cjk.check() {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain(err.to_string()));
+        return;
+    }
+    let prefilled = request.prefill().is_some();
+    let conversation = Conversation::new(depot, &model_name, &request);
+    let suggester = Suggester::new(&request);
+    let mut timings = request.timings.then(TimingTracker::new);
+    let debug = request.debug;
+    let tools = request.tools.clone();
+    let reasoning = reasoning_option(depot);
+    let splitter = ReasoningSplitter::new(&reasoning, request.reasoning_format);
+    let budget = request
+        .reasoning
+        .as_ref()
+        .map(|params| params.budget(&reasoning));
+    let max_tokens = request.max_tokens;
+    let mut request = match SamplerLimits::obtain(depot).generate(request) {
+        Ok(request) => request,
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(err.to_string()));
+            return;
+        }
+    };
+    request.reasoning_budget = budget;
+    if let Some(conversation) = &conversation {
+        conversation.resume(&mut request, &info.tokenizer);
+    }
+    if let Err(err) = fit_context(&mut request, max_tokens, &info) {
+        render_failure(res, StatusCode::BAD_REQUEST, &err);
+        return;
+    }
+    let limits = LimitOption::obtain(depot);
+    limits.apply(depot, &mut request);
 
-    let mut token_counter = TokenCounter::default();
-    let mut finish_reason = FinishReason::Null;
-    let mut text = String::new();
-    let mut stream = token_receiver.into_stream();
+    let semantic = match SemanticCache::obtain(depot).filter(|_| !debug).zip(scope) {
+        Some((semantic, scope)) => semantic
+            .embed(sender, info.tokenizer.clone(), &request)
+            .await
+            .map(|embedding| (semantic, scope, embedding)),
+        None => None,
+    };
+    let cached = semantic
+        .as_ref()
+        .and_then(|(semantic, scope, embedding)| semantic.get(scope, embedding));
+    if let Some(value) = cached {
+        res.render(Json(value));
+        return;
+    }
 
-    while let Some(token) = stream.next().await {
-        match token {
-            Token::Start => {}
-            Token::Content(token) => {
-                text += &token;
+    if let Err(err) = limits.shed(sender, &mut request, &info).await {
+        render_error(res, &err);
+        return;
+    }
+
+    let mut requests: Vec<_> = (0..n)
+        .map(|_| GenerateRequest {
+            sampler: sampler.clone().into(),
+            ..request.clone()
+        })
+        .collect();
+    let session = match debug {
+        true => match DebugSession::begin(
+            depot,
+            model_name.clone(),
+            sampler.clone(),
+            info.tokenizer.clone(),
+            &mut requests,
+        ) {
+            Ok(session) => Some(session),
+            Err(err) => {
+                res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+                res.render(Text::Plain(err.to_string()));
+                return;
             }
-            Token::Stop(reason, counter) => {
-                finish_reason = reason;
-                token_counter = counter;
-                break;
+        },
+        false => None,
+    };
+    let generations =
+        match generate(sender, info.tokenizer.clone(), requests, timings.as_mut()).await {
+            Ok(generations) => generations,
+            Err(err) => {
+                render_error(res, &err);
+                return;
             }
-            _ => unreachable!(),
-        }
-    }
+        };
+    let debug_id = session.map(|session| {
+        let texts = generations.iter().map(|generation| generation.text.clone());
+        session.finish(texts)
+    });
 
-    let json = Json(ChatResponse {
+    let mut response = ChatResponse {
         object: "chat.completion".into(),
-        model: model_name,
-        choices: vec![ChatChoice {
-            message: ChatRecord {
-                role: Role::Assistant,
-                content: text.trim().into(),
-            },
-            index: 0,
-            finish_reason,
-        }],
-        counter: token_counter,
-    });
-    res.render(json);
+        model: model_name.clone(),
+        counter: Generation::usage(&generations),
+        choices: generations
+            .into_iter()
+            .enumerate()
+            .map(|(index, generation)| {
+                let (content, reasoning_content) = splitter.clone().split(&generation.text);
+                let (content, tool_calls, finish_reason, stop_sequence) =
+                    match tool::parse(&tools, &content) {
+                        Some((content, calls)) => (content, calls, FinishReason::ToolCalls, None),
+                        None => (
+                            content,
+                            vec![],
+                            generation.finish_reason,
+                            generation.stop_sequence,
+                        ),
+                    };
+                let content = match prefilled {
+                    true => content.trim_end(),
+                    false => content.trim(),
+                };
+                ChatChoice {
+                    message: ChatRecord {
+                        role: Role::Assistant,
+                        content: cjk.normalize(content),
+                        reasoning_content: reasoning_content.map(|text| cjk.normalize(text.trim())),
+                        prefix: false,
+                        tool_calls,
+                    },
+                    index,
+                    finish_reason,
+                    stop_sequence,
+                }
+            })
+            .collect(),
+        timings: timings.as_ref().map(TimingTracker::timings),
+        cached: false,
+        debug_id,
+        suggestions: None,
+    };
+    if let (Some(suggester), Some(choice)) = (&suggester, response.choices.first()) {
+        let reply = &choice.message.content;
+        let suggestions = suggester
+            .suggest(sender, info.tokenizer.clone(), reply, &request)
+            .await;
+        response.suggestions = Some(suggestions);
+    }
+    if let (Some(conversation), [choice]) = (conversation, &response.choices[..]) {
+        if !prefilled {
+            conversation.remember(&request, &choice.message.content, &info.tokenizer);
+        }
+    }
+    if let Some((cache, key)) = cache {
+        cache.insert(key, client(depot), &response);
+    }
+    if let Some((semantic, scope, embedding)) = semantic {
+        semantic.insert(scope, embedding, client(depot), &response);
+    }
+    res.render(Json(response));
 }
 
 async fn respond_stream(depot: &mut Depot, request: ChatRequest, res: &mut Response) {
@@ -247,61 +768,234 @@ async fn respond_stream(depot: &mut Depot, request: ChatRequest, res: &mut Respo
     let info = request_info(sender.clone(), SLEEP).await;
     let model_name = info.reload.model_path.to_string_lossy().into_owned();
 
+    let mut stream_option = depot
+        .get::<StreamOption>("stream")
+        .cloned()
+        .unwrap_or_default();
+    if let Some(pace) = request.stream_pace_ms {
+        stream_option.pace = pace;
+    }
+
+    if request.debug {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain("debug is not supported with stream"));
+        return;
+    }
+    if let Err(err) = check_choices(request.n, true, &info) {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain(err.to_string()));
+        return;
+    }
+    let cjk = request.cjk;
+    if let Err(err) = cjk.check() {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain(err.to_string()));
+        return;
+    }
+    let prefilled = request.prefill().is_some();
+    let conversation = Conversation::new(depot, &model_name, &request);
+    let suggester = Suggester::new(&request);
+    let mut timings = request.timings.then(TimingTracker::new);
+    let reasoning = reasoning_option(depot);
+    let mut splitter = ReasoningSplitter::new(&reasoning, request.reasoning_format);
+    let mut tools = ToolStream::new(request.tools.clone());
+    let budget = request
+        .reasoning
+        .as_ref()
+        .map(|params| params.budget(&reasoning));
     let (token_sender, token_receiver) = flume::unbounded();
-    let request = Box::new(request.into());
+    let max_tokens = request.max_tokens;
+    let mut request = match SamplerLimits::obtain(depot).generate(request) {
+        Ok(request) => request,
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(err.to_string()));
+            return;
+        }
+    };
+    request.reasoning_budget = budget;
+    if let Some(conversation) = &conversation {
+        conversation.resume(&mut request, &info.tokenizer);
+    }
+    if let Err(err) = fit_context(&mut request, max_tokens, &info) {
+        render_failure(res, StatusCode::BAD_REQUEST, &err);
+        return;
+    }
+    let limits = LimitOption::obtain(depot);
+    limits.apply(depot, &mut request);
+    if let Err(err) = limits.shed(sender, &mut request, &info).await {
+        render_error(res, &err);
+        return;
+    }
+    stream_option.apply(&mut request);
+    let mut remember = conversation
+        .filter(|_| !prefilled)
+        .map(|conversation| (conversation, request.clone(), info.tokenizer.clone()));
+    let mut reply = String::new();
+    let (reply_sender, reply_receiver) = flume::bounded(1);
+    let suggestions = suggester.map(|suggester| {
+        let sender = sender.clone();
+        let tokenizer = info.tokenizer.clone();
+        let request = request.clone();
+        let model_name = model_name.clone();
+        async move {
+            // the reply is sent once the generation stops; none if it is aborted or the stream is dropped before
+            let reply: String = reply_receiver.recv_async().await.ok().flatten()?;
+            let suggestions = suggester
+                .suggest(&sender, tokenizer, &reply, &request)
+                .await;
+            serde_json::to_string(&PartialChatResponse {
+                object: "chat.completion.chunk".into(),
+                model: model_name,
+                choices: vec![],
+                timings: None,
+                suggestions: Some(suggestions),
+            })
+            .ok()
+        }
+    });
+    let suggesting = suggestions.is_some();
+    request.request_id = ai00_core::request_id();
+    let request = Box::new(request);
     let _ = sender.send(ThreadRequest::Generate {
         request,
         tokenizer: info.tokenizer,
         sender: token_sender,
     });
 
-    let mut start_token = true;
-    let stream = token_receiver.into_stream().map(move |token| {
-        let choice = match token {
-            Token::Start => PartialChatChoice {
-                delta: PartialChatRecord::Role(Role::Assistant),
-                ..Default::default()
-            },
+    let mut start_token = !prefilled;
+    let mut delta = move |piece| match piece {
+        ToolPiece::Segment(Segment::Content(token)) => {
+            let token = match start_token {
+                true => token.trim_start(),
+                false => &token,
+            };
+            start_token = false;
+            PartialChatRecord::Content(cjk.normalize(token))
+        }
+        ToolPiece::Segment(Segment::Reasoning(token)) => {
+            PartialChatRecord::ReasoningContent(cjk.normalize(&token))
+        }
+        ToolPiece::Call(call) => PartialChatRecord::ToolCalls(vec![call]),
+    };
+    let stream = stream_option.stream(token_receiver).flat_map(move |token| {
+        let stopped = matches!(token, Token::Stop(..));
+        let aborted = matches!(token, Token::Stop(FinishReason::Abort, ..));
+        let choices = match token {
+            Token::Start => {
+                if let Some(timings) = &mut timings {
+                    timings.start();
+                }
+                vec![PartialChatChoice {
+                    delta: PartialChatRecord::Role(Role::Assistant),
+                    ..Default::default()
+                }]
+            }
             Token::Content(token) => {
-                let token = match start_token {
-                    true => token.trim_start().into(),
-                    false => token,
+                if let Some(timings) = &mut timings {
+                    timings.token();
+                }
+                splitter
+                    .push(&token)
+                    .into_iter()
+                    .flat_map(|segment| tools.push(segment))
+                    .map(|piece| PartialChatChoice {
+                        delta: delta(piece),
+                        ..Default::default()
+                    })
+                    .collect()
+            }
+            Token::Stop(finish_reason, stop_sequence, _) => {
+                let mut pieces = splitter
+                    .finish()
+                    .into_iter()
+                    .flat_map(|segment| tools.push(segment))
+                    .collect_vec();
+                // a call held back whole is sent once the output ends
+                pieces.append(&mut tools.finish());
+                let mut choices = pieces
+                    .into_iter()
+                    .map(|piece| PartialChatChoice {
+                        delta: delta(piece),
+                        ..Default::default()
+                    })
+                    .collect_vec();
+                let (finish_reason, stop_sequence) = match tools.called() {
+                    true => (FinishReason::ToolCalls, None),
+                    false => (finish_reason, stop_sequence),
                 };
-                start_token = false;
-                PartialChatChoice {
-                    delta: PartialChatRecord::Content(token),
+                choices.push(PartialChatChoice {
+                    finish_reason,
+                    stop_sequence,
                     ..Default::default()
-                }
+                });
+                choices
+            }
+            Token::Done if suggesting => return futures_util::stream::iter(vec![]),
+            Token::Done => {
+                let event = Ok(SseEvent::default().text("[DONE]"));
+                return futures_util::stream::iter(vec![event]);
             }
-            Token::Stop(finish_reason, _) => PartialChatChoice {
-                finish_reason,
-                ..Default::default()
-            },
-            Token::Done => return Ok(SseEvent::default().text("[DONE]")),
             _ => unreachable!(),
         };
 
-        match serde_json::to_string(&PartialChatResponse {
-            object: "chat.completion.chunk".into(),
-            model: model_name.clone(),
-            choices: vec![choice],
-        }) {
-            Ok(json_text) => Ok(SseEvent::default().text(json_text)),
-            Err(err) => Err(err),
+        for choice in &choices {
+            if let PartialChatRecord::Content(text) = &choice.delta {
+                reply.push_str(text);
+            }
         }
+        if stopped {
+            if let Some((conversation, request, tokenizer)) = remember.take() {
+                conversation.remember(&request, &reply, &tokenizer);
+            }
+            let _ = reply_sender.try_send((!aborted).then(|| reply.clone()));
+        }
+
+        let events = choices
+            .into_iter()
+            .map(|choice| {
+                serde_json::to_string(&PartialChatResponse {
+                    object: "chat.completion.chunk".into(),
+                    model: model_name.clone(),
+                    choices: vec![choice],
+                    timings: timings.as_ref().map(TimingTracker::timings),
+                    suggestions: None,
+                })
+                .map(|json_text| SseEvent::default().text(json_text))
+            })
+            .collect_vec();
+        futures_util::stream::iter(events)
     });
-    salvo::sse::stream(res, stream);
+    // the stream ends with `[DONE]` whether there are suggestions or not
+    let suggestions = futures_util::stream::iter(suggestions)
+        .then(|suggestions| suggestions)
+        .flat_map(|json_text| {
+            let events = json_text
+                .into_iter()
+                .chain(["[DONE]".into()])
+                .map(|text| Ok(SseEvent::default().text(text)))
+                .collect_vec();
+            futures_util::stream::iter(events)
+        });
+    salvo::sse::stream(res, stream.chain(suggestions));
 }
 
 /// Generate chat completions with context.
 #[endpoint(
         responses(
-            (status_code = 200, description = "Generate one response if `stream` is false.", body = ChatResponse),
-            (status_code = 201, description = "Generate SSE response if `stream` is true. `StatusCode` should be 200.", body = PartialChatResponse)
+            (status_code = 200, description = "One response if `stream` is false, or else an SSE stream of chunks.", content(
+                ("application/json" = ChatResponse),
+                ("text/event-stream" = PartialChatResponse),
+            )),
+            (status_code = 400, description = "Invalid options, e.g., a stop sequence, a grammar or a template that cannot be used. An error object if the prompt exceeds the context.", body = String),
+            (status_code = 401, description = "No valid token or signature."),
+            (status_code = 500, description = "Failed to trace a `debug` request. An error object if the backend is lost, e.g., a forward pass timed out.", body = String),
+            (status_code = 503, description = "The device ran out of memory, the model is unloaded or crashed, or the first token would take longer than `max_first_token`.", body = ErrorResponse),
         )
     )]
 pub async fn chat_completions(depot: &mut Depot, req: JsonBody<ChatRequest>, res: &mut Response) {
-    let request = req.0;
+    let mut request = req.0;
+    AccountStore::apply(depot, &mut request.sampler_override);
     match request.stream {
         true => respond_stream(depot, request, res).await,
         false => respond_one(depot, request, res).await,
@@ -0,0 +1,122 @@
+//! Normalization of CJK output: full and half width forms, and simplified and traditional scripts.
+
+use anyhow::Result;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+
+/// Offset between the full width forms `U+FF01..=U+FF5E` and the printable ASCII `!..=~`.
+const WIDTH_OFFSET: u32 = 0xFF01 - 0x21;
+/// The ideographic space, the full width form of the ASCII space.
+const IDEOGRAPHIC_SPACE: char = '\u{3000}';
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Width {
+    #[default]
+    Keep,
+    /// Full width ASCII forms and the ideographic space to ASCII.
+    Half,
+    /// Printable ASCII and the space to their full width forms.
+    Full,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Script {
+    #[default]
+    Keep,
+    Simplified,
+    Traditional,
+}
+
+/// How CJK output is normalized. Needs the `zhconv` feature to convert scripts.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct CjkOptions {
+    pub width: Width,
+    pub script: Script,
+}
+
+fn half_width(c: char) -> char {
+    match c {
+        IDEOGRAPHIC_SPACE => ' ',
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - WIDTH_OFFSET).unwrap_or(c),
+        c => c,
+    }
+}
+
+fn full_width(c: char) -> char {
+    match c {
+        ' ' => IDEOGRAPHIC_SPACE,
+        '!'..='~' => char::from_u32(c as u32 + WIDTH_OFFSET).unwrap_or(c),
+        c => c,
+    }
+}
+
+#[cfg(feature = "zhconv")]
+fn convert(text: &str, script: Script) -> String {
+    use zhconv::{zhconv, Variant};
+    match script {
+        Script::Keep => text.into(),
+        Script::Simplified => zhconv(text, Variant::ZhHans),
+        Script::Traditional => zhconv(text, Variant::ZhHant),
+    }
+}
+
+#[cfg(not(feature = "zhconv"))]
+fn convert(text: &str, _script: Script) -> String {
+    text.into()
+}
+
+impl CjkOptions {
+    pub fn is_keep(&self) -> bool {
+        self.width == Width::Keep && self.script == Script::Keep
+    }
+
+    /// Fails if the options need a feature the server is built without.
+    pub fn check(&self) -> Result<()> {
+        if cfg!(not(feature = "zhconv")) && self.script != Script::Keep {
+            anyhow::bail!(
+                "script conversion is not available: the server is built without `zhconv`"
+            );
+        }
+        Ok(())
+    }
+
+    /// Normalize a piece of output.
+    ///
+    /// Scripts are converted by phrases, so a phrase split across the chunks of a stream
+    /// may be converted character by character instead.
+    pub fn normalize(&self, text: &str) -> String {
+        let text = match self.width {
+            Width::Keep => text.into(),
+            Width::Half => text.chars().map(half_width).collect(),
+            Width::Full => text.chars().map(full_width).collect(),
+        };
+        match self.script {
+            Script::Keep => text,
+            script => convert(&text, script),
+        }
+    }
+
+    /// Add the other forms of the stop sequences, so that they match the raw output whichever form the model writes.
+    pub fn expand_stops(&self, stop: &mut Vec<String>) {
+        if self.is_keep() {
+            return;
+        }
+        let mut variants = vec![];
+        for text in stop.iter() {
+            variants.push(text.chars().map(half_width).collect::<String>());
+            variants.push(text.chars().map(full_width).collect());
+            if self.script != Script::Keep {
+                variants.push(convert(text, Script::Simplified));
+                variants.push(convert(text, Script::Traditional));
+            }
+        }
+        for variant in variants {
+            if !variant.is_empty() && !stop.contains(&variant) {
+                stop.push(variant);
+            }
+        }
+    }
+}
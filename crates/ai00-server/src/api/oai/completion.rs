@@ -1,8 +1,11 @@
 use std::{collections::HashMap, sync::Arc};
 
 use ai00_core::{
-    run::StateId, FinishReason, GenerateRequest, ThreadRequest, Token, TokenCounter, MAX_TOKENS,
+    run::StateId,
+    sampler::{dry::DryParams, processor::ProcessorRequest, xtc::XtcParams},
+    FinishReason, GenerateRequest, Priority, ThreadRequest, Token, TokenCounter, MAX_TOKENS,
 };
+use flume::Sender;
 use futures_util::StreamExt;
 use salvo::{
     oapi::{extract::JsonBody, ToResponse, ToSchema},
@@ -11,74 +14,168 @@ use salvo::{
     Depot, Writer,
 };
 use serde::{Deserialize, Serialize};
+use web_rwkv::tokenizer::Tokenizer;
 
-use super::*;
+use super::{
+    cache::{ResponseCache, SemanticCache},
+    cjk::CjkOptions,
+    error::{render_error, render_failure, ErrorResponse},
+    *,
+};
 use crate::{
-    api::request_info,
+    api::{account::AccountStore, auth::client, debug::DebugSession, request_info},
+    config::{LimitOption, SamplerLimits, StreamOption},
     types::{Array, ThreadState},
     SLEEP,
 };
 
-#[derive(Debug, Deserialize, ToSchema)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+#[salvo(schema(example = json!({
+    "prompt": ["The Eiffel Tower is located in the city of"],
+    "max_tokens": 64,
+    "temperature": 1.0,
+    "top_p": 0.5,
+    "stop": ["\n\n", "."],
+    "stream": false,
+})))]
 pub struct CompletionRequest {
     #[serde(default)]
     prompt: Array<String>,
+    /// Pre-tokenized prompt which skips tokenization. Overrides `prompt` if given.
+    #[serde(default)]
+    input_ids: Option<Vec<u16>>,
     #[serde(default)]
     state: StateId,
-    #[serde(default = "default_max_tokens")]
-    max_tokens: usize,
+    /// Output token limit. 256 if not given, cut down to the room the prompt leaves in the context.
+    #[serde(default)]
+    max_tokens: Option<usize>,
     #[serde(default)]
     stop: Array<String>,
+    /// Number of choices to generate.
+    #[serde(default)]
+    n: Option<usize>,
+    /// Scale of the noise added to the logits, so that choices differ even at low temperature.
+    #[serde(default)]
+    diversity: f32,
+    /// Echo back the prompt in addition to the completion.
+    #[serde(default)]
+    echo: bool,
+    /// Text that comes after the completion. Generation stops once the model reaches it.
+    #[serde(default)]
+    suffix: Option<String>,
     #[serde(default)]
     stream: bool,
+    /// Minimum milliseconds between the chunks of a stream; tokens generated in between are merged. Overrides the config.
+    #[serde(default)]
+    stream_pace_ms: Option<u64>,
     #[serde(default)]
     #[serde(alias = "logit_bias")]
     bias: HashMap<u16, f32>,
     #[serde(default)]
     bnf_schema: Option<String>,
+    /// Logits processors registered on the server, applied in order after the sampler.
+    #[serde(default)]
+    processors: Vec<ProcessorRequest>,
     #[serde(flatten)]
     sampler: NucleusParams,
+    #[serde(flatten)]
+    dry: DryParams,
+    #[serde(flatten)]
+    xtc: XtcParams,
+    /// Keep only the tokens within this many standard deviations of the top logit. `0` disables it.
+    #[serde(default)]
+    top_n_sigma: f32,
     #[serde(default)]
     sampler_override: Option<SamplerParams>,
+    /// How urgent the request is: `low` for background work that yields its lane to others, `normal` or `high`.
+    /// `high` is only granted to the callers of `limits.high_priority`, and taken as `normal` for others.
+    #[serde(default)]
+    priority: Priority,
+    /// Include latency measurements in chunks and the response.
+    #[serde(default)]
+    timings: bool,
+    /// Record the rendered prompt, its tokens and every sampling step, retrievable at `/api/debug/<id>`.
+    /// Bypasses the response caches. Not available with `stream`.
+    #[serde(default)]
+    debug: bool,
+    /// Normalization of the output, e.g., full width forms to half width, or simplified chinese to traditional.
+    #[serde(default)]
+    cjk: CjkOptions,
 }
 
-impl Default for CompletionRequest {
-    fn default() -> Self {
-        Self {
-            prompt: Array::default(),
-            state: Default::default(),
-            max_tokens: 256,
-            stop: Array::default(),
-            stream: false,
-            bias: HashMap::new(),
-            bnf_schema: Default::default(),
-            sampler: Default::default(),
-            sampler_override: Default::default(),
-        }
+impl CompletionRequest {
+    pub fn max_tokens(&self) -> Option<usize> {
+        self.max_tokens
+    }
+
+    pub fn echo(&self) -> bool {
+        self.echo
+    }
+
+    pub fn sampler(&self) -> &NucleusParams {
+        &self.sampler
+    }
+
+    pub fn sampler_override(&self) -> Option<&SamplerParams> {
+        self.sampler_override.as_ref()
+    }
+
+    pub fn n(&self) -> Option<usize> {
+        self.n
+    }
+
+    pub fn cjk(&self) -> &CjkOptions {
+        &self.cjk
     }
 }
 
-fn default_max_tokens() -> usize {
-    CompletionRequest::default().max_tokens
+impl Bounded for CompletionRequest {
+    fn bounds(
+        &mut self,
+    ) -> (
+        &mut NucleusParams,
+        &mut Option<SamplerParams>,
+        &mut DryParams,
+        &mut Option<usize>,
+    ) {
+        (
+            &mut self.sampler,
+            &mut self.sampler_override,
+            &mut self.dry,
+            &mut self.max_tokens,
+        )
+    }
 }
 
 impl From<CompletionRequest> for GenerateRequest {
     fn from(value: CompletionRequest) -> Self {
         let CompletionRequest {
             prompt,
+            input_ids,
             state,
             max_tokens,
             stop,
+            suffix,
             sampler,
             sampler_override,
             bias,
             bnf_schema,
+            processors,
+            dry,
+            xtc,
+            top_n_sigma,
+            diversity,
+            priority,
+            cjk,
             ..
         } = value;
 
         let prompt = Vec::from(prompt).join("");
-        let max_tokens = max_tokens.min(MAX_TOKENS);
-        let stop = stop.into();
+        let max_tokens = max_tokens.unwrap_or(DEFAULT_MAX_TOKENS).min(MAX_TOKENS);
+        let mut stop: Vec<_> = stop.into();
+        // without infilling, the best the model can do is to write until it runs into the suffix
+        stop.extend(suffix.filter(|suffix| !suffix.is_empty()));
+        cjk.expand_stops(&mut stop);
         let bias = Arc::new(bias);
         let sampler = match sampler_override {
             Some(sampler) => sampler.into(),
@@ -87,12 +184,19 @@ impl From<CompletionRequest> for GenerateRequest {
 
         Self {
             prompt,
+            prompt_tokens: input_ids,
             max_tokens,
             stop,
             sampler,
             bias,
             bnf_schema,
+            processors,
+            dry,
+            xtc,
+            top_n_sigma: top_n_sigma.max(0.0),
             state,
+            logit_noise: diversity.max(0.0),
+            priority,
             ..Default::default()
         }
     }
@@ -103,6 +207,9 @@ pub struct CompletionChoice {
     text: String,
     index: usize,
     finish_reason: FinishReason,
+    /// The stop sequence that ended the generation, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequence: Option<String>,
 }
 
 #[derive(Debug, Serialize, ToSchema, ToResponse)]
@@ -112,6 +219,14 @@ pub struct CompletionResponse {
     choices: Vec<CompletionChoice>,
     #[serde(rename = "usage")]
     counter: TokenCounter,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<Timings>,
+    /// Whether the response is served from the cache.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    cached: bool,
+    /// Id of the debug record of the request, if asked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debug_id: Option<String>,
 }
 
 #[derive(Debug, Default, Serialize, ToSchema, ToResponse)]
@@ -128,6 +243,9 @@ pub struct PartialCompletionChoice {
     delta: PartialCompletionRecord,
     index: usize,
     finish_reason: FinishReason,
+    /// The stop sequence that ended the generation, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequence: Option<String>,
 }
 
 #[derive(Debug, Serialize, ToSchema, ToResponse)]
@@ -135,52 +253,193 @@ pub struct PartialCompletionResponse {
     object: String,
     model: String,
     choices: Vec<PartialCompletionChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<Timings>,
 }
 
-async fn respond_one(depot: &mut Depot, request: CompletionRequest, res: &mut Response) {
+/// The prompt of the request as text, which is echoed back if asked.
+fn echo_text(request: &GenerateRequest, tokenizer: &Tokenizer) -> String {
+    match &request.prompt_tokens {
+        Some(tokens) => {
+            let bytes = tokenizer.decode(tokens).unwrap_or_default();
+            String::from_utf8_lossy(&bytes).into()
+        }
+        None => request.prompt.clone(),
+    }
+}
+
+/// Run the completions of a request to the end and collect the response.
+/// Fails if any of the completions fails.
+pub async fn complete(
+    sender: &Sender<ThreadRequest>,
+    tokenizer: Arc<Tokenizer>,
+    model_name: String,
+    requests: Vec<GenerateRequest>,
+    echo: bool,
+    cjk: &CjkOptions,
+    mut timings: Option<TimingTracker>,
+) -> Result<CompletionResponse, RuntimeError> {
+    let echo = match (echo, requests.first()) {
+        (true, Some(request)) => echo_text(request, &tokenizer),
+        _ => String::new(),
+    };
+    let generations = generate(sender, tokenizer, requests, timings.as_mut()).await?;
+
+    Ok(CompletionResponse {
+        object: "text_completion".into(),
+        model: model_name,
+        counter: Generation::usage(&generations),
+        choices: generations
+            .into_iter()
+            .enumerate()
+            .map(|(index, generation)| CompletionChoice {
+                text: echo.clone() + &cjk.normalize(&generation.text),
+                index,
+                finish_reason: generation.finish_reason,
+                stop_sequence: generation.stop_sequence,
+            })
+            .collect(),
+        timings: timings.as_ref().map(TimingTracker::timings),
+        cached: false,
+        debug_id: None,
+    })
+}
+
+async fn respond_one(depot: &mut Depot, mut request: CompletionRequest, res: &mut Response) {
     let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
     let info = request_info(sender.clone(), SLEEP).await;
     let model_name = info.reload.model_path.to_string_lossy().into_owned();
 
-    let (token_sender, token_receiver) = flume::unbounded();
-    let request = Box::new(request.into());
-    let _ = sender.send(ThreadRequest::Generate {
-        request,
-        tokenizer: info.tokenizer,
-        sender: token_sender,
-    });
+    // the choices and the debug session take the sampler from here, so it is bounded first
+    if let Err(err) = SamplerLimits::obtain(depot).bound_request(&mut request) {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain(err.to_string()));
+        return;
+    }
+    let sampler = request
+        .sampler_override
+        .clone()
+        .unwrap_or_else(|| SamplerParams::Nucleus(request.sampler.clone()));
+    let deterministic =
+        sampler.is_deterministic(&request.xtc, request.diversity, request.top_n_sigma);
+    let cache = match request.debug {
+        true => None,
+        false => ResponseCache::obtain(depot, &model_name, &request, deterministic),
+    };
+    if let Some(value) = cache.as_ref().and_then(|(cache, key)| cache.get(key)) {
+        res.render(Json(value));
+        return;
+    }
+    let scope = SemanticCache::scope(depot, &model_name, &request, &["prompt", "input_ids"]);
 
-    let mut token_counter = TokenCounter::default();
-    let mut finish_reason = FinishReason::Null;
-    let mut text = String::new();
-    let mut stream = token_receiver.into_stream();
+    let n = match check_choices(request.n, false, &info) {
+        Ok(n) => n,
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(err.to_string()));
+            return;
+        }
+    };
+    let cjk = request.cjk;
+    if let Err(err) = cjk.check() {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain(err.to_string()));
+        return;
+    }
+    let timings = request.timings.then(TimingTracker::new);
+    let echo = request.echo;
+    let debug = request.debug;
+    let max_tokens = request.max_tokens;
+    let mut request = match SamplerLimits::obtain(depot).generate(request) {
+        Ok(request) => request,
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(err.to_string()));
+            return;
+        }
+    };
+    if let Err(err) = fit_context(&mut request, max_tokens, &info) {
+        render_failure(res, StatusCode::BAD_REQUEST, &err);
+        return;
+    }
+    let limits = LimitOption::obtain(depot);
+    limits.apply(depot, &mut request);
 
-    while let Some(token) = stream.next().await {
-        match token {
-            Token::Start => {}
-            Token::Content(token) => {
-                text += &token;
-            }
-            Token::Stop(reason, counter) => {
-                finish_reason = reason;
-                token_counter = counter;
-                break;
+    let semantic = match SemanticCache::obtain(depot).filter(|_| !debug).zip(scope) {
+        Some((semantic, scope)) => semantic
+            .embed(sender, info.tokenizer.clone(), &request)
+            .await
+            .map(|embedding| (semantic, scope, embedding)),
+        None => None,
+    };
+    let cached = semantic
+        .as_ref()
+        .and_then(|(semantic, scope, embedding)| semantic.get(scope, embedding));
+    if let Some(value) = cached {
+        res.render(Json(value));
+        return;
+    }
+
+    if let Err(err) = limits.shed(sender, &mut request, &info).await {
+        render_error(res, &err);
+        return;
+    }
+
+    let mut requests: Vec<_> = (0..n)
+        .map(|_| GenerateRequest {
+            sampler: sampler.clone().into(),
+            ..request.clone()
+        })
+        .collect();
+    let session = match debug {
+        true => match DebugSession::begin(
+            depot,
+            model_name.clone(),
+            sampler.clone(),
+            info.tokenizer.clone(),
+            &mut requests,
+        ) {
+            Ok(session) => Some(session),
+            Err(err) => {
+                res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+                res.render(Text::Plain(err.to_string()));
+                return;
             }
-            _ => unreachable!(),
+        },
+        false => None,
+    };
+    let mut response = match complete(
+        sender,
+        info.tokenizer,
+        model_name.clone(),
+        requests,
+        echo,
+        &cjk,
+        timings,
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(err) => {
+            render_error(res, &err);
+            return;
         }
+    };
+    if let Some(session) = session {
+        let texts: Vec<_> = response
+            .choices
+            .iter()
+            .map(|choice| choice.text.clone())
+            .collect();
+        response.debug_id = Some(session.finish(texts));
     }
-
-    let json = Json(CompletionResponse {
-        object: "text_completion".into(),
-        model: model_name,
-        choices: vec![CompletionChoice {
-            text,
-            index: 0,
-            finish_reason,
-        }],
-        counter: token_counter,
-    });
-    res.render(json);
+    if let Some((cache, key)) = cache {
+        cache.insert(key, client(depot), &response);
+    }
+    if let Some((semantic, scope, embedding)) = semantic {
+        semantic.insert(scope, embedding, client(depot), &response);
+    }
+    res.render(Json(response));
 }
 
 async fn respond_stream(depot: &mut Depot, request: CompletionRequest, res: &mut Response) {
@@ -188,49 +447,128 @@ async fn respond_stream(depot: &mut Depot, request: CompletionRequest, res: &mut
     let info = request_info(sender.clone(), SLEEP).await;
     let model_name = info.reload.model_path.to_string_lossy().into_owned();
 
+    let mut stream_option = depot
+        .get::<StreamOption>("stream")
+        .cloned()
+        .unwrap_or_default();
+    if let Some(pace) = request.stream_pace_ms {
+        stream_option.pace = pace;
+    }
+
+    if request.debug {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain("debug is not supported with stream"));
+        return;
+    }
+    if let Err(err) = check_choices(request.n, true, &info) {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain(err.to_string()));
+        return;
+    }
+    let cjk = request.cjk;
+    if let Err(err) = cjk.check() {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain(err.to_string()));
+        return;
+    }
+    let mut timings = request.timings.then(TimingTracker::new);
     let (token_sender, token_receiver) = flume::unbounded();
-    let request = Box::new(request.into());
+    let echo = request.echo;
+    let max_tokens = request.max_tokens;
+    let mut request = match SamplerLimits::obtain(depot).generate(request) {
+        Ok(request) => request,
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(err.to_string()));
+            return;
+        }
+    };
+    if let Err(err) = fit_context(&mut request, max_tokens, &info) {
+        render_failure(res, StatusCode::BAD_REQUEST, &err);
+        return;
+    }
+    let limits = LimitOption::obtain(depot);
+    limits.apply(depot, &mut request);
+    if let Err(err) = limits.shed(sender, &mut request, &info).await {
+        render_error(res, &err);
+        return;
+    }
+    let mut echo = echo.then(|| echo_text(&request, &info.tokenizer));
+    stream_option.apply(&mut request);
+    request.request_id = ai00_core::request_id();
+    let request = Box::new(request);
     let _ = sender.send(ThreadRequest::Generate {
         request,
         tokenizer: info.tokenizer,
         sender: token_sender,
     });
 
-    let stream = token_receiver.into_stream().skip(1).map(move |token| {
-        let choice = match token {
-            Token::Content(token) => PartialCompletionChoice {
-                delta: PartialCompletionRecord::Content(token),
-                ..Default::default()
-            },
-            Token::Stop(finish_reason, _) => PartialCompletionChoice {
-                finish_reason,
-                ..Default::default()
-            },
-            Token::Done => return Ok(SseEvent::default().text("[DONE]")),
-            _ => unreachable!(),
-        };
+    let stream = stream_option
+        .stream(token_receiver)
+        .filter_map(move |token| {
+            let choice = match token {
+                Token::Start => {
+                    if let Some(timings) = &mut timings {
+                        timings.start();
+                    }
+                    match echo.take() {
+                        Some(prompt) => PartialCompletionChoice {
+                            delta: PartialCompletionRecord::Content(prompt),
+                            ..Default::default()
+                        },
+                        None => return std::future::ready(None),
+                    }
+                }
+                Token::Content(token) => {
+                    if let Some(timings) = &mut timings {
+                        timings.token();
+                    }
+                    PartialCompletionChoice {
+                        delta: PartialCompletionRecord::Content(cjk.normalize(&token)),
+                        ..Default::default()
+                    }
+                }
+                Token::Stop(finish_reason, stop_sequence, _) => PartialCompletionChoice {
+                    finish_reason,
+                    stop_sequence,
+                    ..Default::default()
+                },
+                Token::Done => {
+                    return std::future::ready(Some(Ok(SseEvent::default().text("[DONE]"))))
+                }
+                _ => unreachable!(),
+            };
 
-        match serde_json::to_string(&PartialCompletionResponse {
-            object: "text_completion.chunk".into(),
-            model: model_name.clone(),
-            choices: vec![choice],
-        }) {
-            Ok(json_text) => Ok(SseEvent::default().text(json_text)),
-            Err(err) => Err(err),
-        }
-    });
+            let event = match serde_json::to_string(&PartialCompletionResponse {
+                object: "text_completion.chunk".into(),
+                model: model_name.clone(),
+                choices: vec![choice],
+                timings: timings.as_ref().map(TimingTracker::timings),
+            }) {
+                Ok(json_text) => Ok(SseEvent::default().text(json_text)),
+                Err(err) => Err(err),
+            };
+            std::future::ready(Some(event))
+        });
     salvo::sse::stream(res, stream);
 }
 
 /// Generate completions for the given text.
 #[endpoint(
         responses(
-            (status_code = 200, description = "Generate one response if `stream` is false.", body = CompletionResponse),
-            (status_code = 201, description = "Generate SSE response if `stream` is true. `StatusCode` should be 200.", body = PartialCompletionResponse)
+            (status_code = 200, description = "One response if `stream` is false, or else an SSE stream of chunks.", content(
+                ("application/json" = CompletionResponse),
+                ("text/event-stream" = PartialCompletionResponse),
+            )),
+            (status_code = 400, description = "Invalid options, e.g., a stop sequence or a grammar that cannot be used. An error object if the prompt exceeds the context.", body = String),
+            (status_code = 401, description = "No valid token or signature."),
+            (status_code = 500, description = "Failed to trace a `debug` request. An error object if the backend is lost, e.g., a forward pass timed out.", body = String),
+            (status_code = 503, description = "The device ran out of memory, the model is unloaded or crashed, or the first token would take longer than `max_first_token`.", body = ErrorResponse),
         )
     )]
 pub async fn completions(depot: &mut Depot, req: JsonBody<CompletionRequest>, res: &mut Response) {
-    let request = req.0;
+    let mut request = req.0;
+    AccountStore::apply(depot, &mut request.sampler_override);
     match request.stream {
         true => respond_stream(depot, request, res).await,
         false => respond_one(depot, request, res).await,
@@ -6,22 +6,34 @@ use salvo::{
 };
 use serde::{Deserialize, Serialize};
 
+use super::error::{render_error, ErrorResponse};
 use crate::{
     api::request_info,
+    config::EmbeddingOption,
     types::{Array, ThreadState},
     SLEEP,
 };
 
 #[derive(Debug, Default, Clone, Deserialize, ToSchema, ToParameters)]
+#[salvo(schema(example = json!({
+    "input": "Water is a compound of hydrogen and oxygen.",
+    "dimensions": 256,
+})))]
 #[serde(default)]
 pub struct EmbeddingRequest {
     input: Array<String>,
     embed_layer: usize,
+    /// Length the embedding is truncated to. Truncated embeddings are always normalized.
+    dimensions: Option<usize>,
+    /// Scale the embedding to unit length. Overrides `normalize` in the config.
+    normalize: Option<bool>,
 }
 
 impl From<EmbeddingRequest> for GenerateRequest {
     fn from(value: EmbeddingRequest) -> Self {
-        let EmbeddingRequest { input, embed_layer } = value;
+        let EmbeddingRequest {
+            input, embed_layer, ..
+        } = value;
         Self {
             prompt: Vec::from(input).join(""),
             max_tokens: 1,
@@ -48,20 +60,58 @@ pub struct EmbeddingResponse {
     counter: TokenCounter,
 }
 
+/// Truncate the embedding to `dimensions` if given, and scale it to unit length if asked.
+fn reshape(mut embedding: Vec<f32>, dimensions: Option<usize>, normalize: bool) -> Vec<f32> {
+    if let Some(dimensions) = dimensions {
+        embedding.truncate(dimensions);
+    }
+    if normalize || dimensions.is_some() {
+        let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            embedding.iter_mut().for_each(|x| *x /= norm);
+        }
+    }
+    embedding
+}
+
 /// Generate a embedding vector for the given text, with layer number specified for producing the embedding.
-#[endpoint]
-pub async fn embeddings(
-    depot: &mut Depot,
-    req: JsonBody<EmbeddingRequest>,
-) -> Json<EmbeddingResponse> {
+#[endpoint(
+    responses(
+        (status_code = 200, body = EmbeddingResponse),
+        (status_code = 400, description = "`dimensions` is out of range.", body = String),
+        (status_code = 401, description = "No valid token or signature."),
+        (status_code = 500, description = "The backend is lost, e.g., the forward pass timed out.", body = ErrorResponse),
+        (status_code = 503, description = "The device ran out of memory, or the model is unloaded.", body = ErrorResponse),
+    )
+)]
+pub async fn embeddings(depot: &mut Depot, req: JsonBody<EmbeddingRequest>, res: &mut Response) {
     let request = req.to_owned(); // req.parse_json::<EmbeddingRequest>().await.unwrap();
     let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
     let info = request_info(sender.clone(), SLEEP).await;
     let model_name = info.reload.model_path.to_string_lossy().into_owned();
 
+    let num_emb = info.model.num_emb;
+    let dimensions = request.dimensions;
+    if let Some(dimensions) = dimensions.filter(|&x| x == 0 || x > num_emb) {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain(format!(
+            "dimensions is {dimensions}, but must be between 1 and {num_emb}"
+        )));
+        return;
+    }
+    let normalize = request.normalize.unwrap_or_else(|| {
+        depot
+            .get::<EmbeddingOption>("embedding")
+            .map(|option| option.normalize)
+            .unwrap_or_default()
+    });
+
     let (token_sender, token_receiver) = flume::unbounded();
     let _ = sender.send(ThreadRequest::Generate {
-        request: Box::new(request.into()),
+        request: Box::new(GenerateRequest {
+            request_id: ai00_core::request_id(),
+            ..request.into()
+        }),
         tokenizer: info.tokenizer,
         sender: token_sender,
     });
@@ -72,23 +122,27 @@ pub async fn embeddings(
 
     while let Some(token) = stream.next().await {
         match token {
-            Token::Stop(_, counter) => token_counter = counter,
+            Token::Stop(_, _, counter) => token_counter = counter,
             Token::Embed(emb) => {
                 embedding = emb;
                 break;
             }
+            Token::Error(error) => {
+                render_error(res, &error);
+                return;
+            }
             _ => {}
         }
     }
 
-    Json(EmbeddingResponse {
+    res.render(Json(EmbeddingResponse {
         object: "list".into(),
         model: model_name,
         data: vec![EmbeddingData {
             object: "embedding".into(),
             index: 0,
-            embedding,
+            embedding: reshape(embedding, dimensions, normalize),
         }],
         counter: token_counter,
-    })
+    }));
 }
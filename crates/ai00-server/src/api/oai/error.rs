@@ -0,0 +1,97 @@
+//! Failures of generations, answered in the shape the OpenAI APIs give errors, with a status for each kind.
+
+use ai00_core::RuntimeError;
+use salvo::{http::header, oapi::ToSchema, prelude::*};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ErrorBody {
+    pub message: String,
+    /// `invalid_request_error` if the request is at fault, or else `server_error`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub param: Option<String>,
+    /// The kind of the error, e.g., `context_length_exceeded`.
+    pub code: Option<String>,
+    /// Id of the call, as in the `X-Request-Id` header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Estimated wait for the first token in seconds, if the request is refused for it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_wait: Option<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+impl From<&RuntimeError> for ErrorResponse {
+    fn from(value: &RuntimeError) -> Self {
+        let (kind, code) = match value {
+            RuntimeError::OutOfMemory(_) => ("server_error", "out_of_memory"),
+            RuntimeError::ContextExceeded { .. } => {
+                ("invalid_request_error", "context_length_exceeded")
+            }
+            RuntimeError::InvalidInput(_) => ("invalid_request_error", "invalid_input"),
+            RuntimeError::ModelNotLoaded => ("server_error", "model_not_loaded"),
+            RuntimeError::Canceled => ("server_error", "canceled"),
+            RuntimeError::BackendLost(_) => ("server_error", "backend_lost"),
+            RuntimeError::Overloaded { .. } => ("server_error", "overloaded"),
+        };
+        let estimated_wait = match value {
+            RuntimeError::Overloaded { estimate, .. } => Some(estimate.as_secs_f32()),
+            _ => None,
+        };
+        Self {
+            error: ErrorBody {
+                message: value.to_string(),
+                kind: kind.into(),
+                param: None,
+                code: Some(code.into()),
+                request_id: ai00_core::request_id(),
+                estimated_wait,
+            },
+        }
+    }
+}
+
+/// The status of a response failed with the error. Those that may pass on a retry are `503`.
+pub fn status(error: &RuntimeError) -> StatusCode {
+    match error {
+        RuntimeError::ContextExceeded { .. } | RuntimeError::InvalidInput(_) => {
+            StatusCode::BAD_REQUEST
+        }
+        RuntimeError::OutOfMemory(_)
+        | RuntimeError::ModelNotLoaded
+        | RuntimeError::Canceled
+        | RuntimeError::Overloaded { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        RuntimeError::BackendLost(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub fn render_error(res: &mut Response, error: &RuntimeError) {
+    res.status_code(status(error));
+    if let RuntimeError::Overloaded { estimate, limit } = error {
+        // by then the work ahead is likely to be done
+        let retry = estimate
+            .saturating_sub(*limit)
+            .as_secs_f32()
+            .ceil()
+            .max(1.0);
+        res.add_header(header::RETRY_AFTER, retry.to_string(), true)
+            .ok();
+    }
+    res.render(Json(ErrorResponse::from(error)));
+}
+
+/// Render `err` as a [`RuntimeError`] if it is one, or else as plain text with `status`.
+pub fn render_failure(res: &mut Response, status: StatusCode, err: &anyhow::Error) {
+    match err.downcast_ref::<RuntimeError>() {
+        Some(error) => render_error(res, error),
+        None => {
+            res.status_code(status);
+            res.render(Text::Plain(err.to_string()));
+        }
+    }
+}
@@ -1,25 +1,91 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use ai00_core::sampler::{
-    mirostat::{MirostatParams, MirostatSampler},
-    nucleus::{NucleusParams, NucleusSampler},
-    typical::{TypicalParams, TypicalSampler},
-    Sampler,
+use ai00_core::{
+    sampler::{
+        dry::DryParams,
+        mirostat::{MirostatParams, MirostatSampler},
+        nucleus::{NucleusParams, NucleusSampler},
+        typical::{TypicalParams, TypicalSampler},
+        xtc::XtcParams,
+        Sampler,
+    },
+    FinishReason, GenerateRequest, Priority, RateQuota, RuntimeError, RuntimeInfo, SlotQuota,
+    ThreadRequest, Token, TokenCounter,
 };
-use salvo::oapi::ToSchema;
+use anyhow::{bail, Result};
+use flume::{Receiver, Sender};
+use futures_util::{future::join_all, Stream};
+use itertools::Itertools;
+use salvo::{oapi::ToSchema, Depot};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
+use web_rwkv::tokenizer::Tokenizer;
 
+pub mod cache;
 pub mod chat;
+pub mod cjk;
 pub mod completion;
 pub mod embedding;
+pub mod error;
 pub mod info;
+pub mod reasoning;
+pub mod tool;
 
 pub use chat::chat_completions;
 pub use completion::completions;
 pub use embedding::embeddings;
 pub use info::models;
 
+use crate::{
+    api::auth::{caller, client},
+    config::{LimitOption, LimitPolicy, OverflowPolicy, RatePolicy, SamplerLimits, StreamOption},
+};
+
+/// Output token limit of requests that give none.
+pub const DEFAULT_MAX_TOKENS: usize = 256;
+
+/// Fit the request into the context window of the model.
+///
+/// If the caller gives no `max_tokens`, the default limit is cut down to the room the prompt leaves.
+/// Fails with the exact counts if the prompt alone does not fit.
+pub fn fit_context(
+    request: &mut GenerateRequest,
+    max_tokens: Option<usize>,
+    info: &RuntimeInfo,
+) -> Result<()> {
+    let context = info.reload.context_length;
+    if context == 0 {
+        return Ok(());
+    }
+    let reserve = info.reload.context_reserve;
+    // the tokens are kept, so that the prompt is not tokenized again
+    let prompt = match &request.prompt_tokens {
+        Some(tokens) => tokens.len(),
+        None => {
+            let tokens = info.tokenizer.encode(request.prompt.as_bytes())?;
+            let len = tokens.len();
+            request.prompt_tokens = Some(tokens);
+            len
+        }
+    };
+    let available = context.saturating_sub(reserve);
+    if prompt >= available {
+        let error = RuntimeError::ContextExceeded {
+            prompt,
+            context,
+            reserve,
+        };
+        return Err(error.into());
+    }
+    if max_tokens.is_none() {
+        request.max_tokens = request.max_tokens.min(available - prompt);
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 #[serde(tag = "type")]
 pub enum SamplerParams {
@@ -34,6 +100,23 @@ impl Default for SamplerParams {
     }
 }
 
+impl SamplerParams {
+    /// Whether the sampler always picks the most likely token, so that outputs are reproducible.
+    /// Any of XTC, logit noise and top-nσ, when enabled, makes it not.
+    pub fn is_deterministic(&self, xtc: &XtcParams, logit_noise: f32, top_n_sigma: f32) -> bool {
+        if xtc.is_enabled() || logit_noise > 0.0 || top_n_sigma > 0.0 {
+            return false;
+        }
+        match self {
+            SamplerParams::Nucleus(params) => {
+                let greedy = params.temperature == 0.0 && params.dynatemp_range <= 0.0;
+                params.top_k == 1 || params.top_p == 0.0 || greedy
+            }
+            _ => false,
+        }
+    }
+}
+
 impl From<SamplerParams> for Arc<RwLock<dyn Sampler + Send + Sync>> {
     fn from(value: SamplerParams) -> Self {
         match value {
@@ -43,3 +126,442 @@ impl From<SamplerParams> for Arc<RwLock<dyn Sampler + Send + Sync>> {
         }
     }
 }
+
+/// Check the number of choices asked by a request against the slots of the model.
+pub fn check_choices(n: Option<usize>, stream: bool, info: &RuntimeInfo) -> Result<usize> {
+    let n = n.unwrap_or(1);
+    let max = info.reload.max_batch.max(1);
+    match n {
+        1 => Ok(1),
+        _ if stream => bail!("n > 1 is not supported with stream"),
+        n if (1..=max).contains(&n) => Ok(n),
+        n => bail!("n is {n}, but must be between 1 and max_batch ({max})"),
+    }
+}
+
+/// Output of one finished generation.
+#[derive(Debug, Clone)]
+pub struct Generation {
+    pub text: String,
+    pub finish_reason: FinishReason,
+    pub stop_sequence: Option<String>,
+    pub counter: TokenCounter,
+    /// Set if the generation failed.
+    pub error: Option<RuntimeError>,
+}
+
+impl Default for Generation {
+    fn default() -> Self {
+        Self {
+            text: String::new(),
+            finish_reason: FinishReason::Abort,
+            stop_sequence: None,
+            counter: Default::default(),
+            error: None,
+        }
+    }
+}
+
+impl Generation {
+    /// Receive tokens until the generation finishes, or only until the first token if `prefill` is set.
+    async fn receive(
+        &mut self,
+        receiver: &Receiver<Token>,
+        mut timings: Option<&mut TimingTracker>,
+        prefill: bool,
+    ) {
+        while let Ok(token) = receiver.recv_async().await {
+            match token {
+                Token::Start => {
+                    if let Some(timings) = &mut timings {
+                        timings.start();
+                    }
+                }
+                Token::Content(token) => {
+                    if let Some(timings) = &mut timings {
+                        timings.token();
+                    }
+                    self.text += &token;
+                    if prefill {
+                        return;
+                    }
+                }
+                Token::Stop(reason, stop, counter) => {
+                    self.finish_reason = reason;
+                    self.stop_sequence = stop;
+                    self.counter = counter;
+                    return;
+                }
+                Token::Error(error) => {
+                    self.error = Some(error);
+                    return;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Usage of all generations of a request, which share the prompt.
+    pub fn usage(generations: &[Generation]) -> TokenCounter {
+        let prompt = generations
+            .first()
+            .map(|x| x.counter.prompt)
+            .unwrap_or_default();
+        let completion = generations.iter().map(|x| x.counter.completion).sum();
+        let duration = generations.iter().map(|x| x.counter.duration).max();
+        TokenCounter {
+            prompt,
+            completion,
+            total: prompt + completion,
+            duration: duration.unwrap_or_default(),
+        }
+    }
+}
+
+/// Run the generations of a request to the end. Timings are tracked on the first one.
+/// Fails if any of them fails.
+///
+/// The first request runs alone until its prefill is done and its prompt state is cached,
+/// so that the rest pick the state up instead of prefilling again.
+pub async fn generate(
+    sender: &Sender<ThreadRequest>,
+    tokenizer: Arc<Tokenizer>,
+    requests: Vec<GenerateRequest>,
+    mut timings: Option<&mut TimingTracker>,
+) -> Result<Vec<Generation>, RuntimeError> {
+    let receivers = requests
+        .into_iter()
+        .map(|request| {
+            let (token_sender, token_receiver) = flume::unbounded();
+            let request = ThreadRequest::Generate {
+                request: Box::new(GenerateRequest {
+                    request_id: ai00_core::request_id(),
+                    ..request
+                }),
+                tokenizer: tokenizer.clone(),
+                sender: token_sender,
+            };
+            (request, token_receiver)
+        })
+        .collect_vec();
+    let mut generations = vec![Generation::default(); receivers.len()];
+
+    let mut receivers = receivers.into_iter();
+    let Some((request, first)) = receivers.next() else {
+        return Ok(generations);
+    };
+    let _ = sender.send(request);
+    generations[0]
+        .receive(&first, timings.as_deref_mut(), true)
+        .await;
+    if let Some(error) = generations[0].error.take() {
+        return Err(error);
+    }
+
+    let rest = receivers
+        .map(|(request, receiver)| {
+            let _ = sender.send(request);
+            receiver
+        })
+        .collect_vec();
+    let (head, tail) = generations.split_at_mut(1);
+    let head = head[0].receive(&first, timings, false);
+    let tail = tail
+        .iter_mut()
+        .zip(rest.iter())
+        .map(|(generation, receiver)| generation.receive(receiver, None, false));
+    futures_util::future::join(head, join_all(tail)).await;
+    match generations.iter_mut().find_map(|x| x.error.take()) {
+        Some(error) => Err(error),
+        None => Ok(generations),
+    }
+}
+
+/// Latency measurements of a request, observed from the API side.
+#[derive(Debug, Default, Clone, Copy, Serialize, ToSchema)]
+pub struct Timings {
+    /// Time spent in the queue before a slot picked up the request.
+    pub queue: Duration,
+    /// Time from receiving the request to the first generated token.
+    pub first_token: Option<Duration>,
+    /// Time from receiving the request to this point.
+    pub elapsed: Duration,
+}
+
+/// Tracks [`Timings`] of a request as tokens arrive.
+#[derive(Debug, Clone)]
+pub struct TimingTracker {
+    instant: Instant,
+    queue: Option<Duration>,
+    first_token: Option<Duration>,
+}
+
+impl Default for TimingTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimingTracker {
+    pub fn new() -> Self {
+        Self {
+            instant: Instant::now(),
+            queue: None,
+            first_token: None,
+        }
+    }
+
+    /// Record that the request left the queue and started processing.
+    pub fn start(&mut self) {
+        self.queue.get_or_insert(self.instant.elapsed());
+    }
+
+    /// Record that a token has been generated.
+    pub fn token(&mut self) {
+        self.start();
+        self.first_token.get_or_insert(self.instant.elapsed());
+    }
+
+    pub fn timings(&self) -> Timings {
+        Timings {
+            queue: self.queue.unwrap_or_default(),
+            first_token: self.first_token,
+            elapsed: self.instant.elapsed(),
+        }
+    }
+}
+
+impl LimitOption {
+    /// Account a generate request to its client, so that it waits while the client runs too many at once,
+    /// and is slowed down while the client generates too fast.
+    /// The `high` priority is only granted to the callers listed for it.
+    pub fn apply(&self, depot: &Depot, request: &mut GenerateRequest) {
+        let granted = caller(depot).is_some_and(|caller| self.high_priority.contains(&caller));
+        if request.priority > Priority::Normal && !granted {
+            request.priority = Priority::Normal;
+        }
+
+        let Some(owner) = client(depot) else {
+            return;
+        };
+        if self.max_concurrency > 0 {
+            request.quota = Some(SlotQuota {
+                owner: owner.clone(),
+                max_slots: self.max_concurrency,
+            });
+        }
+        let tokens_per_sec = self
+            .token_rates
+            .get(&owner)
+            .copied()
+            .unwrap_or(self.max_token_rate);
+        if tokens_per_sec > 0.0 {
+            request.rate = Some(RateQuota {
+                owner,
+                tokens_per_sec,
+                hard: self.rate_policy == RatePolicy::Hard,
+            });
+        }
+    }
+
+    /// Refuse a generate request whose first token is estimated later than `max_first_token`,
+    /// rather than leave it waiting in the queue.
+    ///
+    /// The prompt is usually tokenized by [`fit_context`] already; if not, the tokens are kept on the request.
+    pub async fn shed(
+        &self,
+        sender: &Sender<ThreadRequest>,
+        request: &mut GenerateRequest,
+        info: &RuntimeInfo,
+    ) -> Result<(), RuntimeError> {
+        if self.max_first_token <= 0.0 {
+            return Ok(());
+        }
+        if request.prompt_tokens.is_none() {
+            // an untokenizable prompt is left for the runtime to refuse
+            request.prompt_tokens = info.tokenizer.encode(request.prompt.as_bytes()).ok();
+        }
+        let prompt = request.prompt_tokens.as_ref().map_or(0, Vec::len);
+        let (stats_sender, stats_receiver) = flume::unbounded();
+        let _ = sender.send(ThreadRequest::Stats(stats_sender));
+        let Ok(stats) = stats_receiver.recv_async().await else {
+            return Ok(());
+        };
+        let limit = Duration::from_secs_f32(self.max_first_token);
+        match stats.first_token(prompt) {
+            Some(estimate) if estimate > limit => {
+                log::warn!(
+                    "refused generation: first token estimated in {:.1}s with {} tokens ahead",
+                    estimate.as_secs_f32(),
+                    stats.backlog
+                );
+                Err(RuntimeError::Overloaded { estimate, limit })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn obtain(depot: &Depot) -> Self {
+        depot.get::<Self>("limits").cloned().unwrap_or_default()
+    }
+}
+
+/// A chat or completion request, whose sampler options are bound by [`SamplerLimits`] on the way to a generation.
+pub trait Bounded: Into<GenerateRequest> {
+    /// The sampler, its override, the DRY options and the output token limit.
+    fn bounds(
+        &mut self,
+    ) -> (
+        &mut NucleusParams,
+        &mut Option<SamplerParams>,
+        &mut DryParams,
+        &mut Option<usize>,
+    );
+}
+
+impl SamplerLimits {
+    pub fn obtain(depot: &Depot) -> Self {
+        depot
+            .get::<Self>("sampler_limits")
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Bring a value within its bound, or fail if the policy rejects it.
+    fn bound<T>(&self, name: &str, value: &mut T, max: Option<T>) -> Result<()>
+    where
+        T: PartialOrd + Copy + std::fmt::Display,
+    {
+        match max {
+            Some(max) if *value > max => match self.policy {
+                LimitPolicy::Clamp => {
+                    *value = max;
+                    Ok(())
+                }
+                LimitPolicy::Reject => bail!("{name} is {value}, but must be at most {max}"),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn bound_penalties(&self, presence: &mut f32, frequency: &mut f32) -> Result<()> {
+        self.bound("presence_penalty", presence, self.max_penalty)?;
+        self.bound("frequency_penalty", frequency, self.max_penalty)
+    }
+
+    /// Bound the sampler options of a chat or completion request, and turn it into a generate request.
+    /// Every such request takes this way to a generation, so that none gets around the limits.
+    pub fn generate(&self, mut request: impl Bounded) -> Result<GenerateRequest> {
+        self.bound_request(&mut request)?;
+        Ok(request.into())
+    }
+
+    /// Bound the sampler options of a chat or completion request in place, for handlers that read them
+    /// before [`Self::generate`]. Bounding again there changes nothing.
+    pub fn bound_request(&self, request: &mut impl Bounded) -> Result<()> {
+        let (sampler, sampler_override, dry, max_tokens) = request.bounds();
+        self.enforce(sampler, sampler_override, dry, max_tokens)
+    }
+
+    /// Bound the sampler options and the output token limit of a request.
+    fn enforce(
+        &self,
+        sampler: &mut NucleusParams,
+        sampler_override: &mut Option<SamplerParams>,
+        dry: &mut DryParams,
+        max_tokens: &mut Option<usize>,
+    ) -> Result<()> {
+        match sampler_override {
+            Some(SamplerParams::Nucleus(params)) => self.bound_nucleus(params)?,
+            Some(SamplerParams::Typical(params)) => {
+                self.bound("temperature", &mut params.temperature, self.max_temperature)?;
+                self.bound_penalties(&mut params.presence_penalty, &mut params.frequency_penalty)?;
+            }
+            Some(SamplerParams::Mirostat(_)) => {}
+            None => self.bound_nucleus(sampler)?,
+        }
+        self.bound("dry_multiplier", &mut dry.dry_multiplier, self.max_penalty)?;
+
+        if let Some(max) = self.max_tokens {
+            let max_tokens = max_tokens.get_or_insert(max);
+            self.bound("max_tokens", max_tokens, Some(max))?;
+        }
+        Ok(())
+    }
+
+    /// Bound the options of a nucleus sampler.
+    pub fn bound_nucleus(&self, params: &mut NucleusParams) -> Result<()> {
+        self.bound("temperature", &mut params.temperature, self.max_temperature)?;
+        if let Some(max) = self.max_temperature {
+            // the dynamic temperature may rise up to the range above the temperature
+            let range = (max - params.temperature).max(0.0);
+            self.bound("dynatemp_range", &mut params.dynatemp_range, Some(range))?;
+        }
+        self.bound_penalties(&mut params.presence_penalty, &mut params.frequency_penalty)
+    }
+}
+
+impl StreamOption {
+    /// Apply the overflow policy to a generate request.
+    pub fn apply(&self, request: &mut GenerateRequest) {
+        if self.overflow == OverflowPolicy::Pause {
+            request.max_pending = Some(self.buffer.max(1));
+        }
+    }
+
+    /// Turn the token receiver into a stream.
+    /// With [`OverflowPolicy::Coalesce`], contents that pile up beyond the buffer are merged into one token.
+    /// With a pace, contents are sent at most once per pace, merging the ones generated in between.
+    /// If the generation ends without finishing or fails, the stream finishes with [`FinishReason::Abort`].
+    pub fn stream(&self, receiver: Receiver<Token>) -> impl Stream<Item = Token> {
+        let buffer = match self.overflow {
+            OverflowPolicy::Coalesce => self.buffer.max(1),
+            OverflowPolicy::Pause => usize::MAX,
+        };
+        let pace = Duration::from_millis(self.pace);
+        let state = (receiver, None, false, tokio::time::Instant::now());
+        futures_util::stream::unfold(state, move |(receiver, pending, done, next)| async move {
+            let token = match pending {
+                Some(token) => token,
+                None => match receiver.recv_async().await {
+                    Ok(token) => token,
+                    Err(_) if done => return None,
+                    Err(_) => {
+                        let token = Token::Stop(FinishReason::Abort, None, Default::default());
+                        return Some((token, (receiver, Some(Token::Done), true, next)));
+                    }
+                },
+            };
+            let done = done || matches!(token, Token::Done);
+            match token {
+                // the status is sent already, so the stream can only finish as aborted
+                Token::Error(_) => {
+                    let token = Token::Stop(FinishReason::Abort, None, Default::default());
+                    Some((token, (receiver, Some(Token::Done), true, next)))
+                }
+                Token::Content(content) if !pace.is_zero() => {
+                    tokio::time::sleep_until(next).await;
+                    let (content, pending) = coalesce(&receiver, content);
+                    let next = tokio::time::Instant::now() + pace;
+                    Some((Token::Content(content), (receiver, pending, done, next)))
+                }
+                Token::Content(content) if receiver.len() >= buffer => {
+                    let (content, pending) = coalesce(&receiver, content);
+                    Some((Token::Content(content), (receiver, pending, done, next)))
+                }
+                token => Some((token, (receiver, None, done, next))),
+            }
+        })
+    }
+}
+
+/// Merge the contents waiting in the receiver into `content`. Returns the first token that is not a content.
+fn coalesce(receiver: &Receiver<Token>, mut content: String) -> (String, Option<Token>) {
+    while let Ok(token) = receiver.try_recv() {
+        match token {
+            Token::Content(next) => content.push_str(&next),
+            token => return (content, Some(token)),
+        }
+    }
+    (content, None)
+}
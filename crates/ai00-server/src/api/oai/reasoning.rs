@@ -0,0 +1,123 @@
+use ai00_core::sampler::reasoning::ReasoningBudget;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ReasoningFormat, ReasoningOption};
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ReasoningParams {
+    /// Maximum tokens generated inside a reasoning block. The block is closed once it runs out.
+    pub max_tokens: usize,
+}
+
+impl ReasoningParams {
+    pub fn budget(&self, option: &ReasoningOption) -> ReasoningBudget {
+        ReasoningBudget {
+            open: option.open.clone(),
+            close: option.close.clone(),
+            max_tokens: self.max_tokens,
+        }
+    }
+}
+
+/// A piece of the model output, either in or out of a reasoning block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Content(String),
+    Reasoning(String),
+}
+
+/// Splits reasoning blocks (`<think>...</think>`) out of the model output as it streams in.
+/// Tags split across chunks are held back until they can be told apart from normal text.
+#[derive(Debug, Clone)]
+pub struct ReasoningSplitter {
+    format: ReasoningFormat,
+    open: String,
+    close: String,
+    inside: bool,
+    buffer: String,
+}
+
+impl ReasoningSplitter {
+    pub fn new(option: &ReasoningOption, format: Option<ReasoningFormat>) -> Self {
+        Self {
+            format: format.unwrap_or(option.format),
+            open: option.open.clone(),
+            close: option.close.clone(),
+            inside: false,
+            buffer: String::new(),
+        }
+    }
+
+    fn segment(&self, text: String) -> Option<Segment> {
+        if text.is_empty() {
+            return None;
+        }
+        match (self.inside, self.format) {
+            (false, _) => Some(Segment::Content(text)),
+            (true, ReasoningFormat::Strip) => None,
+            (true, _) => Some(Segment::Reasoning(text)),
+        }
+    }
+
+    /// Feed a chunk of output, returning the segments that are certain so far.
+    pub fn push(&mut self, text: &str) -> Vec<Segment> {
+        if self.format == ReasoningFormat::Keep || self.open.is_empty() || self.close.is_empty() {
+            return self.segment(text.into()).into_iter().collect();
+        }
+
+        self.buffer.push_str(text);
+        let mut segments = vec![];
+        loop {
+            let tag = match self.inside {
+                true => &self.close,
+                false => &self.open,
+            };
+            match self.buffer.find(tag.as_str()) {
+                Some(index) => {
+                    let end = index + tag.len();
+                    let rest = self.buffer.split_off(end);
+                    let mut text = std::mem::replace(&mut self.buffer, rest);
+                    text.truncate(index);
+                    segments.extend(self.segment(text));
+                    self.inside = !self.inside;
+                }
+                None => {
+                    // hold back the tail that may be the start of a tag
+                    let partial = (1..tag.len())
+                        .rev()
+                        .filter(|&len| tag.is_char_boundary(len))
+                        .find(|&len| self.buffer.ends_with(&tag[..len]))
+                        .unwrap_or(0);
+                    let rest = self.buffer.split_off(self.buffer.len() - partial);
+                    let text = std::mem::replace(&mut self.buffer, rest);
+                    segments.extend(self.segment(text));
+                    break;
+                }
+            }
+        }
+        segments
+    }
+
+    /// Flush whatever is held back at the end of the output.
+    pub fn finish(&mut self) -> Vec<Segment> {
+        let text = std::mem::take(&mut self.buffer);
+        self.segment(text).into_iter().collect()
+    }
+
+    /// Split a complete output into the content and the reasoning, if any.
+    pub fn split(mut self, text: &str) -> (String, Option<String>) {
+        let mut content = String::new();
+        let mut reasoning: Option<String> = None;
+        let segments = self.push(text).into_iter().chain(self.finish());
+        for segment in segments {
+            match segment {
+                Segment::Content(text) => content.push_str(&text),
+                Segment::Reasoning(text) => reasoning
+                    .get_or_insert_with(Default::default)
+                    .push_str(&text),
+            }
+        }
+        (content, reasoning)
+    }
+}
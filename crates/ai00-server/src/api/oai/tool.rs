@@ -0,0 +1,391 @@
+use itertools::Itertools;
+use regex::Regex;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::reasoning::Segment;
+
+/// Tags the model wraps a call in, around a JSON object with the `name` and the `arguments`.
+pub const TOOL_CALL_START: &str = "<tool_call>";
+pub const TOOL_CALL_END: &str = "</tool_call>";
+
+fn default_kind() -> String {
+    "function".into()
+}
+
+fn call_id() -> String {
+    format!("call_{:016x}", fastrand::u64(..))
+}
+
+/// A tool the model may call instead of replying.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Tool {
+    #[serde(rename = "type", default = "default_kind")]
+    kind: String,
+    function: Function,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Function {
+    name: String,
+    #[serde(default)]
+    description: String,
+    /// JSON schema of the arguments.
+    #[serde(default)]
+    parameters: Value,
+}
+
+/// A call of a tool parsed from the reply.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ToolCall {
+    id: String,
+    #[serde(rename = "type", default = "default_kind")]
+    kind: String,
+    function: FunctionCall,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct FunctionCall {
+    name: String,
+    /// The arguments as a JSON string.
+    arguments: String,
+}
+
+/// The system message that tells the model about the tools and how to call them, and whether several at once.
+pub fn describe(tools: &[Tool], parallel: bool) -> String {
+    let tools = tools
+        .iter()
+        .map(|tool| serde_json::to_string(&tool.function).unwrap_or_default())
+        .join("\n");
+    let several = match parallel {
+        true => " To call several, put each call on a line of its own.",
+        false => "",
+    };
+    format!(
+        "You may call these tools:\n{tools}\n\nTo call one, reply with {TOOL_CALL_START}{{\"name\": <name>, \"arguments\": <arguments>}}{TOOL_CALL_END}.{several}"
+    )
+}
+
+/// The calls of an earlier reply written back the way the model made them.
+pub fn render(calls: &[ToolCall]) -> String {
+    calls
+        .iter()
+        .map(|call| {
+            let arguments: Value =
+                serde_json::from_str(&call.function.arguments).unwrap_or_default();
+            let call = serde_json::json!({"name": call.function.name, "arguments": arguments});
+            format!("{TOOL_CALL_START}{call}{TOOL_CALL_END}")
+        })
+        .join("\n")
+}
+
+/// Split the calls of the given tools out of a reply, returning the text left and the calls, if there are any.
+/// The last call may miss its end tag, since generation stops at it unless the calls are parallel.
+pub fn parse(tools: &[Tool], text: &str) -> Option<(String, Vec<ToolCall>)> {
+    if tools.is_empty() {
+        return None;
+    }
+
+    #[derive(Deserialize)]
+    struct Call {
+        name: String,
+        #[serde(default)]
+        arguments: Value,
+    }
+
+    let mut content = String::new();
+    let mut calls = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find(TOOL_CALL_START) {
+        content.push_str(&rest[..start]);
+        let body = &rest[start + TOOL_CALL_START.len()..];
+        let (body, next) = match body.find(TOOL_CALL_END) {
+            Some(end) => (&body[..end], &body[end + TOOL_CALL_END.len()..]),
+            None => (body, ""),
+        };
+        match serde_json::from_str::<Call>(body.trim()) {
+            Ok(call) if tools.iter().any(|tool| tool.function.name == call.name) => {
+                calls.push(ToolCall {
+                    id: call_id(),
+                    kind: default_kind(),
+                    function: FunctionCall {
+                        name: call.name,
+                        arguments: call.arguments.to_string(),
+                    },
+                })
+            }
+            _ => content.push_str(&rest[start..rest.len() - next.len()]),
+        }
+        rest = next;
+    }
+    content.push_str(rest);
+
+    (!calls.is_empty()).then_some((content, calls))
+}
+
+/// The part of a call sent in one chunk of a stream: the id and the name first, then pieces of the arguments.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ToolCallDelta {
+    /// Which call of the reply it belongs to.
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    kind: Option<String>,
+    function: FunctionCallDelta,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FunctionCallDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// The next piece of the arguments, which add up to a JSON string.
+    arguments: String,
+}
+
+/// What a stream passes on: content or reasoning, or a piece of a call.
+#[derive(Debug, Clone)]
+pub enum ToolPiece {
+    Segment(Segment),
+    Call(ToolCallDelta),
+}
+
+/// Where a stream is in the output.
+#[derive(Debug, Default, Clone)]
+enum ToolState {
+    #[default]
+    Text,
+    /// In a call before its name and the start of its arguments are read; `header` is unset once they
+    /// turn out not to be in that order, or the tool is unknown, so that the call is parsed whole at its end.
+    Call { header: bool },
+    /// In the arguments of a call, which are passed on as they come.
+    Arguments(JsonScan),
+    /// Past the arguments, up to the end tag.
+    Tail,
+}
+
+/// Finds the end of a JSON value read in pieces.
+#[derive(Debug, Default, Clone)]
+struct JsonScan {
+    started: bool,
+    depth: usize,
+    string: bool,
+    escape: bool,
+}
+
+impl JsonScan {
+    /// Read on; returns the length of the text that belongs to the value, and whether the value ends there.
+    fn feed(&mut self, text: &str) -> (usize, bool) {
+        for (index, c) in text.char_indices() {
+            if self.string {
+                match (self.escape, c) {
+                    (true, _) => self.escape = false,
+                    (false, '\\') => self.escape = true,
+                    (false, '"') => self.string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match c {
+                '"' => self.string = true,
+                '{' | '[' => self.depth += 1,
+                // the end of the object the value is in
+                '}' | ']' | ',' if self.depth == 0 => return (index, true),
+                '}' | ']' => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        return (index + 1, true);
+                    }
+                }
+                _ => {}
+            }
+        }
+        (text.len(), false)
+    }
+}
+
+/// Passes the streamed content on, less the tool calls, which are sent as deltas: the name as soon as it is read,
+/// then the arguments as they are generated.
+#[derive(Debug, Clone)]
+pub struct ToolStream {
+    tools: Vec<Tool>,
+    /// Start of a call up to its arguments.
+    header: Regex,
+    buffer: String,
+    state: ToolState,
+    calls: usize,
+}
+
+impl ToolStream {
+    pub fn new(tools: Vec<Tool>) -> Self {
+        let header =
+            Regex::new(r#"^\s*\{\s*"name"\s*:\s*"((?:[^"\\]|\\.)*)"\s*,\s*"arguments"\s*:"#)
+                .expect("invalid tool call pattern");
+        Self {
+            tools,
+            header,
+            buffer: String::new(),
+            state: ToolState::Text,
+            calls: 0,
+        }
+    }
+
+    /// Whether any call is made so far.
+    pub fn called(&self) -> bool {
+        self.calls > 0
+    }
+
+    /// Pass a segment on, less the content that is or may be a tool call, and the pieces of the calls read.
+    pub fn push(&mut self, segment: Segment) -> Vec<ToolPiece> {
+        let text = match segment {
+            Segment::Content(text) if !self.tools.is_empty() => text,
+            segment => return vec![ToolPiece::Segment(segment)],
+        };
+
+        self.buffer.push_str(&text);
+        let mut pieces = vec![];
+        while self.step(&mut pieces) {}
+        pieces
+    }
+
+    /// Flush what is held back at the end of the output. The last call may miss its end tag,
+    /// since generation stops at it unless the calls are parallel.
+    pub fn finish(&mut self) -> Vec<ToolPiece> {
+        let text = std::mem::take(&mut self.buffer);
+        let mut pieces = vec![];
+        match std::mem::take(&mut self.state) {
+            ToolState::Text => Self::content(&mut pieces, text),
+            ToolState::Call { .. } => self.resolve(&mut pieces, &text, false),
+            ToolState::Arguments(_) | ToolState::Tail => {}
+        }
+        pieces
+    }
+
+    fn content(pieces: &mut Vec<ToolPiece>, text: String) {
+        if !text.is_empty() {
+            pieces.push(ToolPiece::Segment(Segment::Content(text)));
+        }
+    }
+
+    fn call(&mut self, id: String, name: String, arguments: String) -> ToolPiece {
+        let index = self.calls;
+        self.calls += 1;
+        ToolPiece::Call(ToolCallDelta {
+            index,
+            id: Some(id),
+            kind: Some(default_kind()),
+            function: FunctionCallDelta {
+                name: Some(name),
+                arguments,
+            },
+        })
+    }
+
+    /// Parse a call read whole, which is content again if it is not a call of the tools.
+    fn resolve(&mut self, pieces: &mut Vec<ToolPiece>, body: &str, closed: bool) {
+        let end = if closed { TOOL_CALL_END } else { "" };
+        let text = format!("{TOOL_CALL_START}{body}{end}");
+        match parse(&self.tools, &text) {
+            Some((content, calls)) => {
+                Self::content(pieces, content);
+                for ToolCall { id, function, .. } in calls {
+                    let piece = self.call(id, function.name, function.arguments);
+                    pieces.push(piece);
+                }
+            }
+            None => Self::content(pieces, text),
+        }
+    }
+
+    /// Take on what the buffer holds in the current state. Returns `true` if the state changes, and the rest is to be read in the next.
+    fn step(&mut self, pieces: &mut Vec<ToolPiece>) -> bool {
+        match std::mem::take(&mut self.state) {
+            ToolState::Text => match self.buffer.find(TOOL_CALL_START) {
+                Some(index) => {
+                    let rest = self.buffer[index + TOOL_CALL_START.len()..].to_owned();
+                    self.buffer.truncate(index);
+                    Self::content(pieces, std::mem::replace(&mut self.buffer, rest));
+                    self.state = ToolState::Call { header: true };
+                    true
+                }
+                None => {
+                    // hold back the tail that may be the start of a tag
+                    let held = (1..TOOL_CALL_START.len())
+                        .rev()
+                        .find(|&len| self.buffer.ends_with(&TOOL_CALL_START[..len]))
+                        .unwrap_or(0);
+                    let rest = self.buffer.split_off(self.buffer.len() - held);
+                    Self::content(pieces, std::mem::replace(&mut self.buffer, rest));
+                    false
+                }
+            },
+            ToolState::Call { header } => {
+                let captures = header
+                    .then(|| self.header.captures(&self.buffer))
+                    .flatten()
+                    .map(|captures| (captures[1].to_owned(), captures[0].len()));
+                // a call read up to its arguments, but of no tool, is left to be parsed whole
+                let header = header && captures.is_none();
+                if let Some((name, len)) = captures {
+                    let name: String =
+                        serde_json::from_str(&format!("\"{name}\"")).unwrap_or_default();
+                    if self.tools.iter().any(|tool| tool.function.name == name) {
+                        self.buffer.drain(..len);
+                        let piece = self.call(call_id(), name, String::new());
+                        pieces.push(piece);
+                        self.state = ToolState::Arguments(Default::default());
+                        return true;
+                    }
+                }
+                match self.buffer.find(TOOL_CALL_END) {
+                    Some(index) => {
+                        let rest = self.buffer[index + TOOL_CALL_END.len()..].to_owned();
+                        let body = std::mem::replace(&mut self.buffer, rest);
+                        self.resolve(pieces, &body[..index], true);
+                        true
+                    }
+                    None => {
+                        self.state = ToolState::Call { header };
+                        false
+                    }
+                }
+            }
+            ToolState::Arguments(mut scan) => {
+                if !scan.started {
+                    self.buffer = self.buffer.trim_start().to_owned();
+                    scan.started = !self.buffer.is_empty();
+                }
+                let (len, end) = scan.feed(&self.buffer);
+                let rest = self.buffer.split_off(len);
+                let arguments = std::mem::replace(&mut self.buffer, rest);
+                if !arguments.is_empty() {
+                    pieces.push(ToolPiece::Call(ToolCallDelta {
+                        index: self.calls - 1,
+                        id: None,
+                        kind: None,
+                        function: FunctionCallDelta {
+                            name: None,
+                            arguments,
+                        },
+                    }));
+                }
+                self.state = match end {
+                    true => ToolState::Tail,
+                    false => ToolState::Arguments(scan),
+                };
+                end
+            }
+            ToolState::Tail => match self.buffer.find(TOOL_CALL_END) {
+                Some(index) => {
+                    self.buffer.drain(..index + TOOL_CALL_END.len());
+                    true
+                }
+                None => {
+                    self.state = ToolState::Tail;
+                    false
+                }
+            },
+        }
+    }
+}
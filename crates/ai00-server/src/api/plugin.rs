@@ -0,0 +1,347 @@
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Result};
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Where the plugin archives are kept.
+pub const PLUGIN_PATH: &str = "assets/www/plugins";
+/// Suffix appended to the archive of a disabled plugin, so that it is skipped on start.
+const DISABLED_SUFFIX: &str = ".disabled";
+/// The manifest every uploaded plugin must carry at the root of its archive.
+const MANIFEST_NAME: &str = "manifest.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PluginManifest {
+    /// Name of the plugin, also the directory it is served under: `plugins/<name>`.
+    pub name: String,
+    pub version: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+}
+
+impl PluginManifest {
+    fn validate(&self) -> Result<()> {
+        validate_name(&self.name)?;
+        let valid = self
+            .version
+            .split(['-', '+'])
+            .next()
+            .is_some_and(|core| core.split('.').all(|x| x.parse::<u64>().is_ok()));
+        if !valid {
+            bail!("invalid version {} of plugin {}", self.version, self.name);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PluginInfo {
+    pub name: String,
+    pub enabled: bool,
+    /// Plugins installed before manifests were required have none.
+    pub manifest: Option<PluginManifest>,
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid || name == "api" {
+        bail!("invalid plugin name {name}");
+    }
+    Ok(())
+}
+
+fn read_manifest(path: &Path) -> Result<Option<PluginManifest>> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let mut file = match archive.by_name(MANIFEST_NAME) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    Ok(Some(serde_json::from_str(&contents)?))
+}
+
+/// Installs, enables and removes the WebUI plugins at runtime.
+/// Enabled plugins are extracted into the WebUI directory, which is served from disk on every request.
+pub struct PluginManager {
+    source: PathBuf,
+    target: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl PluginManager {
+    pub fn new(target: impl AsRef<Path>) -> Result<Arc<Self>> {
+        let source = PathBuf::from(PLUGIN_PATH);
+        if !source.exists() {
+            std::fs::create_dir_all(&source)?;
+        }
+        Ok(Arc::new(Self {
+            source,
+            target: target.as_ref().into(),
+            lock: Default::default(),
+        }))
+    }
+
+    fn archive(&self, name: &str, enabled: bool) -> PathBuf {
+        match enabled {
+            true => self.source.join(format!("{name}.zip")),
+            false => self.source.join(format!("{name}.zip{DISABLED_SUFFIX}")),
+        }
+    }
+
+    fn extracted(&self, name: &str) -> PathBuf {
+        self.target.join("plugins").join(name)
+    }
+
+    fn extract(&self, name: &str) -> Result<()> {
+        let dir = self.extracted(name);
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        let file = File::open(self.archive(name, true))?;
+        std::fs::create_dir_all(&dir)?;
+        zip_extract::extract(file, &dir, false)?;
+        Ok(())
+    }
+
+    fn unload(&self, name: &str) -> Result<()> {
+        let dir = self.extracted(name);
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        Ok(())
+    }
+
+    /// Extract all enabled plugins.
+    pub fn load_all(&self) -> Result<()> {
+        let _lock = self.lock.lock().unwrap();
+        for info in self.scan()?.into_iter().filter(|info| info.enabled) {
+            match self.extract(&info.name) {
+                Ok(_) => log::info!("loaded plugin {}", info.name),
+                Err(err) => log::error!("failed to load plugin {}, {}", info.name, err),
+            }
+        }
+        Ok(())
+    }
+
+    fn scan(&self) -> Result<Vec<PluginInfo>> {
+        let mut plugins = vec![];
+        for entry in std::fs::read_dir(&self.source)?.filter_map(|x| x.ok()) {
+            let path = entry.path();
+            if !path.is_file() {
+                continue;
+            }
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let (name, enabled) = match file_name.strip_suffix(DISABLED_SUFFIX) {
+                Some(name) => (name.to_owned(), false),
+                None => (file_name, true),
+            };
+            let Some(name) = name.strip_suffix(".zip") else {
+                continue;
+            };
+            if validate_name(name).is_err() {
+                continue;
+            }
+            let manifest = read_manifest(&path).unwrap_or_else(|err| {
+                log::warn!("failed to read manifest of plugin {name}: {err}");
+                None
+            });
+            plugins.push(PluginInfo {
+                name: name.into(),
+                enabled,
+                manifest,
+            });
+        }
+        plugins.sort_by(|x, y| x.name.cmp(&y.name));
+        Ok(plugins)
+    }
+
+    pub fn list(&self) -> Result<Vec<PluginInfo>> {
+        let _lock = self.lock.lock().unwrap();
+        self.scan()
+    }
+
+    /// Install a plugin archive, replacing the plugin of the same name. The plugin is enabled at once.
+    pub fn install(&self, path: &Path) -> Result<PluginInfo> {
+        let manifest =
+            read_manifest(path)?.ok_or_else(|| anyhow!("plugin has no {MANIFEST_NAME}"))?;
+        manifest.validate()?;
+
+        let _lock = self.lock.lock().unwrap();
+        let name = manifest.name.clone();
+        let disabled = self.archive(&name, false);
+        if disabled.exists() {
+            std::fs::remove_file(disabled)?;
+        }
+        std::fs::copy(path, self.archive(&name, true))?;
+        self.extract(&name)?;
+        Ok(PluginInfo {
+            name,
+            enabled: true,
+            manifest: Some(manifest),
+        })
+    }
+
+    /// Enable or disable a plugin. Returns `false` if there is no such plugin.
+    pub fn set_enabled(&self, name: &str, enabled: bool) -> Result<bool> {
+        validate_name(name)?;
+        let _lock = self.lock.lock().unwrap();
+        let (from, to) = (self.archive(name, !enabled), self.archive(name, enabled));
+        if !from.exists() {
+            return Ok(to.exists());
+        }
+        std::fs::rename(from, to)?;
+        match enabled {
+            true => self.extract(name)?,
+            false => self.unload(name)?,
+        }
+        Ok(true)
+    }
+
+    /// Remove a plugin along with its archive. Returns `false` if there is no such plugin.
+    pub fn remove(&self, name: &str) -> Result<bool> {
+        validate_name(name)?;
+        let _lock = self.lock.lock().unwrap();
+        let mut found = false;
+        for path in [self.archive(name, true), self.archive(name, false)] {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+                found = true;
+            }
+        }
+        self.unload(name)?;
+        Ok(found)
+    }
+}
+
+fn plugins(depot: &Depot) -> Option<Arc<PluginManager>> {
+    depot.get::<Arc<PluginManager>>("plugins").cloned().ok()
+}
+
+fn render_error(res: &mut Response, status: StatusCode, err: anyhow::Error) {
+    log::error!("plugin: {err}");
+    res.status_code(status);
+    res.render(Text::Plain(err.to_string()));
+}
+
+/// `/api/plugins`: list the installed plugins.
+#[endpoint(
+    responses(
+        (status_code = 200, description = "The plugins.", body = Vec<PluginInfo>),
+        (status_code = 404, description = "The WebUI is disabled."),
+        (status_code = 500, description = "The plugin directory cannot be read.", body = String),
+    )
+)]
+pub async fn list_plugins(depot: &mut Depot, res: &mut Response) {
+    let Some(plugins) = plugins(depot) else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    match plugins.list() {
+        Ok(list) => res.render(Json(list)),
+        Err(err) => render_error(res, StatusCode::INTERNAL_SERVER_ERROR, err),
+    }
+}
+
+/// `/api/plugins`: install the plugin archive in the `file` field of the multipart form.
+///
+/// The archive carries a `manifest.json` at its root with the `name` and the `version` of the plugin.
+#[endpoint(
+    responses(
+        (status_code = 200, description = "The plugin is installed and enabled.", body = PluginInfo),
+        (status_code = 400, description = "No file is given, or the archive or its manifest is invalid.", body = String),
+        (status_code = 404, description = "The WebUI is disabled."),
+    )
+)]
+pub async fn install_plugin(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let Some(plugins) = plugins(depot) else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    let Some(file) = req.file("file").await else {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render("expect `file` in the form");
+        return;
+    };
+    match plugins.install(file.path()) {
+        Ok(info) => {
+            log::info!("installed plugin {}", info.name);
+            res.render(Json(info));
+        }
+        Err(err) => render_error(res, StatusCode::BAD_REQUEST, err),
+    }
+}
+
+async fn set_enabled(depot: &Depot, req: &Request, res: &mut Response, enabled: bool) {
+    let Some(plugins) = plugins(depot) else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    let Some(name) = req.query::<String>("name") else {
+        res.status_code(StatusCode::BAD_REQUEST);
+        return;
+    };
+    match plugins.set_enabled(&name, enabled) {
+        Ok(true) => res.render("OK"),
+        Ok(false) => {
+            res.status_code(StatusCode::NOT_FOUND);
+        }
+        Err(err) => render_error(res, StatusCode::BAD_REQUEST, err),
+    }
+}
+
+/// `/api/plugins/enable?name=...`.
+#[endpoint(
+    parameters(("name" = String, Query, description = "Name of the plugin.")),
+    status_codes(200, 400, 404),
+)]
+pub async fn enable_plugin(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    set_enabled(depot, req, res, true).await
+}
+
+/// `/api/plugins/disable?name=...`.
+#[endpoint(
+    parameters(("name" = String, Query, description = "Name of the plugin.")),
+    status_codes(200, 400, 404),
+)]
+pub async fn disable_plugin(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    set_enabled(depot, req, res, false).await
+}
+
+/// `/api/plugins?name=...`: remove a plugin.
+#[endpoint(
+    parameters(("name" = String, Query, description = "Name of the plugin.")),
+    status_codes(200, 400, 404),
+)]
+pub async fn remove_plugin(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let Some(plugins) = plugins(depot) else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    let Some(name) = req.query::<String>("name") else {
+        res.status_code(StatusCode::BAD_REQUEST);
+        return;
+    };
+    match plugins.remove(&name) {
+        Ok(true) => {
+            log::info!("removed plugin {name}");
+            res.render("OK");
+        }
+        Ok(false) => {
+            res.status_code(StatusCode::NOT_FOUND);
+        }
+        Err(err) => render_error(res, StatusCode::BAD_REQUEST, err),
+    }
+}
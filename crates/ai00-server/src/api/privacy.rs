@@ -0,0 +1,104 @@
+//! Deletion of the data kept about a client, for operators who serve end users.
+//!
+//! Only in-memory data are tied to clients. The log and the outputs of scheduled jobs are not,
+//! and are left to the retention of the operator.
+
+use std::sync::Arc;
+
+use ai00_core::ThreadRequest;
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    debug::DebugStore,
+    oai::cache::{ConversationCache, ResponseCache, SemanticCache},
+};
+use crate::types::ThreadState;
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct PurgeRequest {
+    /// The client as requests are accounted to: the app id or OIDC subject, or else the address.
+    pub client: String,
+    /// Only count what would be deleted.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// What is deleted, or would be on a dry run.
+#[derive(Debug, Default, Clone, Serialize, ToSchema)]
+pub struct PurgeReport {
+    pub client: String,
+    pub dry_run: bool,
+    /// Responses in the exact-match cache.
+    pub responses: usize,
+    /// Responses in the semantic cache.
+    pub semantic_responses: usize,
+    /// Conversations kept for chats to resume from.
+    pub conversations: usize,
+    /// States the runtime backed while reading those conversations.
+    pub states: usize,
+    /// Records of `debug` requests.
+    pub debug_records: usize,
+}
+
+/// `/api/privacy/purge`: delete the cached responses, conversations, states and debug records of a client.
+#[endpoint(
+    request_body = PurgeRequest,
+    responses(
+        (status_code = 200, body = PurgeReport),
+        (status_code = 400, description = "The request cannot be parsed.", body = String),
+    )
+)]
+pub async fn purge(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let request = match req.parse_json::<PurgeRequest>().await {
+        Ok(request) => request,
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(err.to_string()));
+            return;
+        }
+    };
+    let PurgeRequest { client, dry_run } = request;
+    let mut report = PurgeReport {
+        client: client.clone(),
+        dry_run,
+        ..Default::default()
+    };
+
+    if let Ok(cache) = depot.get::<Arc<ResponseCache>>("cache") {
+        report.responses = cache.purge(&client, dry_run);
+    }
+    if let Ok(cache) = depot.get::<Arc<SemanticCache>>("semantic_cache") {
+        report.semantic_responses = cache.purge(&client, dry_run);
+    }
+    if let Ok(store) = depot.get::<Arc<DebugStore>>("debug") {
+        report.debug_records = store.purge(&client, dry_run);
+    }
+    let tokens = match depot.get::<Arc<ConversationCache>>("conversation_cache") {
+        Ok(cache) => cache.purge(&client, dry_run),
+        Err(_) => vec![],
+    };
+    report.conversations = tokens.len();
+
+    if !tokens.is_empty() {
+        let ThreadState { sender, .. } = depot.obtain::<ThreadState>().unwrap();
+        let (count_sender, count_receiver) = flume::bounded(1);
+        let _ = sender.send(ThreadRequest::Forget {
+            tokens,
+            dry_run,
+            sender: count_sender,
+        });
+        report.states = count_receiver.recv_async().await.unwrap_or_default();
+    }
+
+    // the client is left out of the log on purpose
+    if !dry_run {
+        let total = report.responses
+            + report.semantic_responses
+            + report.conversations
+            + report.states
+            + report.debug_records;
+        log::info!("purged {total} items of data of a client");
+    }
+    res.render(Json(report));
+}
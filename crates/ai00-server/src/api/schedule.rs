@@ -0,0 +1,413 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use ai00_core::{storage::Sealer, ThreadRequest};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone, Timelike};
+use flume::Sender;
+use salvo::prelude::*;
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, task::JoinHandle};
+
+use super::{
+    oai::{completion::complete, fit_context},
+    request_info,
+};
+use crate::{
+    build_path,
+    config::{JobOption, SamplerLimits},
+    SLEEP,
+};
+
+/// How far ahead to look for the next time a cron expression fires.
+const MAX_LOOKAHEAD_DAYS: usize = 366 * 5;
+
+/// A parsed cron expression. Each field is a bit set of the values it matches.
+#[derive(Debug, Clone, Copy)]
+pub struct Cron {
+    minute: u64,
+    hour: u64,
+    day: u64,
+    month: u64,
+    weekday: u64,
+    /// Whether both day fields are restricted, in which case a day matching either of them fires.
+    either_day: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>()?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            bail!("step of {part} cannot be 0");
+        }
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (start.parse()?, end.parse()?),
+                // `a/n` means from `a` to the end
+                None if step > 1 => (range.parse()?, max),
+                None => (range.parse()?, range.parse()?),
+            },
+        };
+        if start < min || end > max || start > end {
+            bail!("{part} is out of range {min}-{max}");
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for Cron {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields: Vec<_> = s.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            bail!("cron expression {s} must have 5 fields");
+        };
+        let weekday = parse_field(weekday, 0, 7)?;
+        Ok(Self {
+            minute: parse_field(minute, 0, 59)?,
+            hour: parse_field(hour, 0, 23)?,
+            day: parse_field(day, 1, 31)?,
+            month: parse_field(month, 1, 12)?,
+            // both 0 and 7 are sunday
+            weekday: (weekday | (weekday >> 7)) & 0x7f,
+            either_day: day != "*" && fields[4] != "*",
+        })
+    }
+}
+
+impl Cron {
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        let bit = |bits: u64, value: u32| bits & (1 << value) != 0;
+        if !bit(self.month, date.month()) {
+            return false;
+        }
+        let day = bit(self.day, date.day());
+        let weekday = bit(self.weekday, date.weekday().num_days_from_sunday());
+        match self.either_day {
+            true => day || weekday,
+            false => day && weekday,
+        }
+    }
+
+    /// The first time strictly after `after` that the expression fires.
+    pub fn next(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = after.naive_local() + Duration::minutes(1);
+        let start = start.date().and_hms_opt(start.hour(), start.minute(), 0)?;
+        for date in start.date().iter_days().take(MAX_LOOKAHEAD_DAYS) {
+            if !self.matches_date(date) {
+                continue;
+            }
+            for (hour, minute) in (0..24)
+                .filter(|hour| self.hour & (1 << hour) != 0)
+                .flat_map(|hour| (0..60).map(move |minute| (hour, minute)))
+                .filter(|(_, minute)| self.minute & (1 << minute) != 0)
+            {
+                let time = date.and_hms_opt(hour, minute, 0)?;
+                if time < start {
+                    continue;
+                }
+                // skip times that do not exist due to daylight saving
+                if let Some(time) = Local.from_local_datetime(&time).earliest() {
+                    return Some(time);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, ToSchema)]
+pub struct JobStatus {
+    /// When the job fires next.
+    #[salvo(schema(value_type = Option<String>))]
+    pub next: Option<DateTime<Local>>,
+    /// When the job last finished.
+    #[salvo(schema(value_type = Option<String>))]
+    pub last: Option<DateTime<Local>>,
+    /// Error of the last run, if it failed.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct JobInfo {
+    #[serde(flatten)]
+    pub option: JobOption,
+    pub status: JobStatus,
+}
+
+#[derive(Debug, Serialize)]
+struct JobRecord<'a, T> {
+    job: &'a str,
+    time: DateTime<Local>,
+    response: T,
+}
+
+struct Job {
+    option: JobOption,
+    status: Arc<Mutex<JobStatus>>,
+    handle: JoinHandle<()>,
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Runs the scheduled prompt jobs and delivers their results.
+pub struct Scheduler {
+    sender: Sender<ThreadRequest>,
+    client: reqwest::Client,
+    /// Seals the lines written to the outputs, if a storage key is given.
+    sealer: Option<Sealer>,
+    /// Bounds of the sampler options of the jobs, as of the requests from the callers.
+    limits: SamplerLimits,
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+impl Scheduler {
+    pub fn new(
+        sender: Sender<ThreadRequest>,
+        jobs: Vec<JobOption>,
+        sealer: Option<Sealer>,
+        limits: SamplerLimits,
+    ) -> Arc<Self> {
+        let scheduler = Arc::new(Self {
+            sender,
+            client: reqwest::Client::new(),
+            sealer,
+            limits,
+            jobs: Default::default(),
+        });
+        for option in jobs {
+            let name = option.name.clone();
+            match scheduler.add(option) {
+                Ok(_) => log::info!("scheduled job {name}"),
+                Err(err) => log::error!("failed to schedule job {name}: {err}"),
+            }
+        }
+        scheduler
+    }
+
+    /// Add a job, replacing the one of the same name.
+    pub fn add(self: &Arc<Self>, mut option: JobOption) -> Result<()> {
+        if option.name.is_empty() {
+            bail!("job name cannot be empty");
+        }
+        let cron: Cron = option.cron.parse()?;
+        if let Some(output) = &option.output {
+            option.output = Some(build_path("assets", output)?);
+        }
+
+        let status = Arc::new(Mutex::new(JobStatus::default()));
+        let handle = {
+            let scheduler = self.clone();
+            let option = option.clone();
+            let status = status.clone();
+            tokio::spawn(async move {
+                while let Some(next) = cron.next(Local::now()) {
+                    status.lock().unwrap().next = Some(next);
+                    let duration = (next - Local::now()).to_std().unwrap_or_default();
+                    tokio::time::sleep(duration).await;
+                    scheduler.run(&option, &status).await;
+                }
+                status.lock().unwrap().next = None;
+            })
+        };
+
+        let job = Job {
+            option: option.clone(),
+            status,
+            handle,
+        };
+        self.jobs.lock().unwrap().insert(option.name, job);
+        Ok(())
+    }
+
+    /// Remove a job. Returns `false` if there is no such job.
+    pub fn remove(&self, name: &str) -> bool {
+        self.jobs.lock().unwrap().remove(name).is_some()
+    }
+
+    pub fn list(&self) -> Vec<JobInfo> {
+        let mut jobs: Vec<_> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .map(|job| JobInfo {
+                option: job.option.clone(),
+                status: job.status.lock().unwrap().clone(),
+            })
+            .collect();
+        jobs.sort_by(|x, y| x.option.name.cmp(&y.option.name));
+        jobs
+    }
+
+    /// Run a job at once, apart from its schedule. Returns `false` if there is no such job.
+    pub fn trigger(self: &Arc<Self>, name: &str) -> bool {
+        let Some((option, status)) = self
+            .jobs
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|job| (job.option.clone(), job.status.clone()))
+        else {
+            return false;
+        };
+        let scheduler = self.clone();
+        tokio::spawn(async move { scheduler.run(&option, &status).await });
+        true
+    }
+
+    async fn run(&self, option: &JobOption, status: &Mutex<JobStatus>) {
+        log::info!("running job {}", option.name);
+        let result = self.run_once(option).await;
+        if let Err(err) = &result {
+            log::error!("job {} failed: {err}", option.name);
+        }
+
+        let mut status = status.lock().unwrap();
+        status.last = Some(Local::now());
+        status.error = result.err().map(|err| err.to_string());
+    }
+
+    async fn run_once(&self, option: &JobOption) -> Result<()> {
+        let info = request_info(self.sender.clone(), SLEEP).await;
+        let model_name = info.reload.model_path.to_string_lossy().into_owned();
+        let max_tokens = option.request.max_tokens();
+        let mut request = self.limits.generate(option.request.clone())?;
+        fit_context(&mut request, max_tokens, &info)?;
+        let echo = option.request.echo();
+        let cjk = option.request.cjk();
+        cjk.check()?;
+        let response = complete(
+            &self.sender,
+            info.tokenizer,
+            model_name,
+            vec![request],
+            echo,
+            cjk,
+            None,
+        )
+        .await?;
+
+        let record = JobRecord {
+            job: &option.name,
+            time: Local::now(),
+            response,
+        };
+        if let Some(url) = &option.webhook {
+            self.client
+                .post(url)
+                .json(&record)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        if let Some(path) = &option.output {
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let mut line = serde_json::to_string(&record)?;
+            if let Some(sealer) = &self.sealer {
+                line = sealer.seal_line(&line);
+            }
+            line.push('\n');
+            tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await
+                .map_err(|err| anyhow!("failed to open {}: {err}", path.to_string_lossy()))?
+                .write_all(line.as_bytes())
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+fn scheduler(depot: &Depot) -> Arc<Scheduler> {
+    depot
+        .get::<Arc<Scheduler>>("scheduler")
+        .cloned()
+        .expect("scheduler is injected")
+}
+
+/// `/api/admin/jobs`: list the scheduled jobs with their status.
+#[endpoint(responses((status_code = 200, body = Vec<JobInfo>)))]
+pub async fn list_jobs(depot: &mut Depot, res: &mut Response) {
+    res.render(Json(scheduler(depot).list()));
+}
+
+/// `/api/admin/jobs`: add a job, or replace the one of the same name.
+#[endpoint(
+    request_body = JobOption,
+    responses(
+        (status_code = 200, description = "The job is scheduled.", body = String),
+        (status_code = 400, description = "The job cannot be parsed, or its cron is invalid.", body = String),
+    )
+)]
+pub async fn add_job(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let option = match req.parse_json::<JobOption>().await {
+        Ok(option) => option,
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(err.to_string()));
+            return;
+        }
+    };
+    let name = option.name.clone();
+    match scheduler(depot).add(option) {
+        Ok(_) => {
+            log::info!("scheduled job {name}");
+            res.render("OK");
+        }
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(err.to_string()));
+        }
+    }
+}
+
+/// `/api/admin/jobs?name=...`: remove a job.
+#[endpoint(
+    parameters(("name" = String, Query, description = "Name of the job.")),
+    status_codes(200, 400, 404),
+)]
+pub async fn remove_job(depot: &mut Depot, req: &mut Request) -> StatusCode {
+    let Some(name) = req.query::<String>("name") else {
+        return StatusCode::BAD_REQUEST;
+    };
+    match scheduler(depot).remove(&name) {
+        true => StatusCode::OK,
+        false => StatusCode::NOT_FOUND,
+    }
+}
+
+/// `/api/admin/jobs/run?name=...`: run a job now. The result is delivered as usual.
+#[endpoint(
+    parameters(("name" = String, Query, description = "Name of the job.")),
+    status_codes(202, 400, 404),
+)]
+pub async fn run_job(depot: &mut Depot, req: &mut Request) -> StatusCode {
+    let Some(name) = req.query::<String>("name") else {
+        return StatusCode::BAD_REQUEST;
+    };
+    match scheduler(depot).trigger(&name) {
+        true => StatusCode::ACCEPTED,
+        false => StatusCode::NOT_FOUND,
+    }
+}
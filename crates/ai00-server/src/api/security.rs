@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use anyhow::{anyhow, Result};
+use futures_util::Stream;
+use ipnet::IpNet;
+use salvo::{
+    http::body::{BytesFrame, ResBody},
+    prelude::*,
+};
+
+use crate::config::SecurityOption;
+
+fn parse_nets(nets: &[String]) -> Result<Vec<IpNet>> {
+    nets.iter()
+        .map(|net| {
+            net.parse::<IpNet>()
+                .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| anyhow!("invalid network {net}"))
+        })
+        .collect()
+}
+
+/// Decrements the in-flight count of a client when its request finishes.
+struct Connection {
+    ip: IpAddr,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// A streamed response body, e.g., of SSE, that holds the connection of the client until it is sent through.
+struct HeldBody {
+    body: ResBody,
+    _connection: Connection,
+}
+
+impl Stream for HeldBody {
+    type Item = std::io::Result<BytesFrame>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.body)
+            .poll_next(cx)
+            .map(|frame| frame.map(|frame| frame.map(BytesFrame)))
+    }
+}
+
+/// Applies the allow and deny lists and the per-address request limit, before any authentication.
+/// Also records the client address in the depot, see [`client_ip`].
+pub struct Firewall {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    trusted_proxies: Vec<IpNet>,
+    forwarded_header: String,
+    max_connections: usize,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Firewall {
+    pub fn new(option: &SecurityOption) -> Result<Self> {
+        Ok(Self {
+            allow: parse_nets(&option.allow)?,
+            deny: parse_nets(&option.deny)?,
+            trusted_proxies: parse_nets(&option.trusted_proxies)?,
+            forwarded_header: option.forwarded_header.clone(),
+            max_connections: option.max_connections,
+            connections: Default::default(),
+        })
+    }
+
+    fn trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    /// The client address: the peer, or the nearest untrusted hop reported by trusted proxies.
+    fn resolve(&self, req: &Request) -> Option<IpAddr> {
+        let peer = req.remote_addr().clone().into_std()?.ip();
+        if !self.trusted(&peer) {
+            return Some(peer);
+        }
+        let hops = req
+            .headers()
+            .get_all(self.forwarded_header.as_str())
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|hop| hop.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>();
+        let mut ip = peer;
+        for hop in hops.into_iter().rev() {
+            ip = hop;
+            if !self.trusted(&ip) {
+                break;
+            }
+        }
+        Some(ip)
+    }
+
+    fn permitted(&self, ip: &IpAddr) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip));
+        allowed && !self.deny.iter().any(|net| net.contains(ip))
+    }
+
+    fn connect(&self, ip: IpAddr) -> Option<Connection> {
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.entry(ip).or_default();
+        if self.max_connections > 0 && *count >= self.max_connections {
+            return None;
+        }
+        *count += 1;
+        Some(Connection {
+            ip,
+            connections: self.connections.clone(),
+        })
+    }
+}
+
+#[handler]
+impl Firewall {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        // requests from a unix socket have no address and are always local
+        let Some(ip) = self.resolve(req) else {
+            return;
+        };
+        if !self.permitted(&ip) {
+            log::warn!("refused request from {ip}");
+            res.status_code(StatusCode::FORBIDDEN);
+            ctrl.skip_rest();
+            return;
+        }
+        let Some(connection) = self.connect(ip) else {
+            res.status_code(StatusCode::TOO_MANY_REQUESTS);
+            ctrl.skip_rest();
+            return;
+        };
+
+        depot.insert("client_ip", ip);
+        ctrl.call_next(req, depot, res).await;
+
+        // a streamed body is sent after the handlers return, and the connection is held until then
+        match res.take_body() {
+            body @ (ResBody::Stream(_)
+            | ResBody::Channel(_)
+            | ResBody::Boxed(_)
+            | ResBody::Hyper(_)) => res.stream(HeldBody {
+                body,
+                _connection: connection,
+            }),
+            body => {
+                res.replace_body(body);
+            }
+        }
+    }
+}
+
+/// The client address recorded by the [`Firewall`], if it is enabled.
+pub fn client_ip(depot: &Depot) -> Option<IpAddr> {
+    depot.get::<IpAddr>("client_ip").ok().copied()
+}
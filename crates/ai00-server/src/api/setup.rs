@@ -0,0 +1,185 @@
+use std::{
+    fs::File,
+    path::{Component, PathBuf},
+};
+
+use ai00_core::reload::{AdapterOption, Model, Precision};
+use anyhow::Result;
+use flume::Sender;
+use memmap2::Mmap;
+use safetensors::SafeTensors;
+use salvo::{oapi::ToSchema, prelude::*};
+use serde::{Deserialize, Serialize};
+use web_rwkv::runtime::{
+    loader::Loader,
+    model::{ModelInfo, Quant},
+};
+
+use crate::config::{Config, WebOption};
+
+/// Extensions of the model files offered by the setup.
+const MODEL_EXTENSIONS: &[&str] = &["st", "prefab"];
+
+/// What the setup API needs: where to write the config, and whom to tell once it is written.
+#[derive(Debug, Clone)]
+pub struct SetupState {
+    pub config_path: PathBuf,
+    pub finish: Sender<()>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SetupModel {
+    pub name: String,
+    pub size: u64,
+    /// Read from the header of safetensors models; prefabs are not inspected.
+    #[salvo(schema(value_type = Option<Object>))]
+    pub info: Option<ModelInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct SetupRequest {
+    /// File name of the model under `assets/models`.
+    #[salvo(schema(value_type = String))]
+    pub model: PathBuf,
+    pub adapter: AdapterOption,
+    pub quant: usize,
+    #[salvo(schema(value_type = String))]
+    pub quant_type: Quant,
+    pub precision: Precision,
+    pub context_length: usize,
+    pub max_batch: usize,
+}
+
+impl Default for SetupRequest {
+    fn default() -> Self {
+        let model = Model::default();
+        Self {
+            model: model.name,
+            adapter: Default::default(),
+            quant: model.quant,
+            quant_type: model.quant_type,
+            precision: model.precision,
+            context_length: model.context_length,
+            max_batch: model.max_batch,
+        }
+    }
+}
+
+fn model_info(path: &std::path::Path) -> Result<ModelInfo> {
+    let file = File::open(path)?;
+    let data = unsafe { Mmap::map(&file)? };
+    let model = SafeTensors::deserialize(&data)?;
+    Loader::info(&model)
+}
+
+fn scan_models() -> Result<Vec<SetupModel>> {
+    let root = Model::default().path;
+    let mut models = vec![];
+    for entry in std::fs::read_dir(&root)?.filter_map(|x| x.ok()) {
+        let path = entry.path();
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        if !path.is_file() || !extension.is_some_and(|ext| MODEL_EXTENSIONS.contains(&ext)) {
+            continue;
+        }
+        let info = match extension {
+            Some("st") => model_info(&path)
+                .inspect_err(|err| log::warn!("failed to read model {}: {err}", path.display()))
+                .ok(),
+            _ => None,
+        };
+        models.push(SetupModel {
+            name: entry.file_name().to_string_lossy().into(),
+            size: entry.metadata()?.len(),
+            info,
+        });
+    }
+    models.sort_by(|x, y| x.name.cmp(&y.name));
+    Ok(models)
+}
+
+/// `/api/setup/models`: the models found under `assets/models`.
+#[endpoint(responses((status_code = 200, description = "The models found.", body = Vec<SetupModel>)))]
+pub async fn setup_models(res: &mut Response) {
+    match scan_models() {
+        Ok(models) => res.render(Json(models)),
+        Err(err) => {
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+            res.render(Text::Plain(err.to_string()));
+        }
+    }
+}
+
+/// `/api/setup/config`: write the initial config with the choices made, and start the server with it.
+#[endpoint(
+    request_body = SetupRequest,
+    status_codes(200, 400, 404, 500),
+    responses((status_code = 200, description = "The config is written and the server starts."))
+)]
+pub async fn setup_config(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let state = depot.obtain::<SetupState>().unwrap().clone();
+    let request = match req.parse_json::<SetupRequest>().await {
+        Ok(request) => request,
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(err.to_string()));
+            return;
+        }
+    };
+
+    // the model must be under the models directory
+    let relative = request
+        .model
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !relative {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain(format!(
+            "model {} is not a path under the models directory",
+            request.model.to_string_lossy()
+        )));
+        return;
+    }
+    let mut config = Config::default();
+    if !config.model.path.join(&request.model).is_file() {
+        res.status_code(StatusCode::NOT_FOUND);
+        res.render(Text::Plain(format!(
+            "model {} not found",
+            request.model.to_string_lossy()
+        )));
+        return;
+    }
+    config.model = Model {
+        name: request.model,
+        quant: request.quant,
+        quant_type: request.quant_type,
+        precision: request.precision,
+        context_length: request.context_length,
+        max_batch: request.max_batch,
+        ..config.model
+    };
+    config.adapter = request.adapter;
+    let web = WebOption::default();
+    if web.path.exists() {
+        config.web = Some(web);
+    }
+
+    let write = || -> Result<()> {
+        if let Some(parent) = state.config_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&state.config_path, toml::to_string(&config)?)?;
+        Ok(())
+    };
+    match write() {
+        Ok(_) => {
+            log::info!("config written to {}", state.config_path.to_string_lossy());
+            let _ = state.finish.send(());
+            res.render("OK");
+        }
+        Err(err) => {
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+            res.render(Text::Plain(err.to_string()));
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Authentication of machine callers by signing requests with the secret of an app key.
+//! See [`SigningOption`] for how requests are signed.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use hmac::{Hmac, Mac};
+use salvo::{
+    jwt_auth::{JwtAuthDepotExt, JwtAuthState},
+    prelude::*,
+};
+use sha2::{Digest, Sha256};
+
+use crate::config::{ListenerOption, SigningOption};
+
+const APP_ID_HEADER: &str = "X-Ai00-App-Id";
+const TIMESTAMP_HEADER: &str = "X-Ai00-Timestamp";
+const NONCE_HEADER: &str = "X-Ai00-Nonce";
+const SIGNATURE_HEADER: &str = "X-Ai00-Signature";
+
+/// The app id of a request whose signature is verified.
+pub fn signed_caller(depot: &Depot) -> Option<String> {
+    depot.get::<String>("signed_caller").ok().cloned()
+}
+
+/// Verifies signed requests and refuses replayed ones. Runs after the token authentication, which it
+/// relaxes: a request passes with either a valid token or a valid signature.
+pub struct Signature {
+    option: SigningOption,
+    /// Secret keys by app id.
+    keys: HashMap<String, String>,
+    /// Whether requests carrying neither pass anyway. The OIDC scopes decide on their own.
+    pass: bool,
+    /// Nonces seen by app id, with the server time they were seen at.
+    /// Kept for twice the skew, the span of timestamps a request could be replayed with.
+    nonces: Mutex<HashMap<(String, String), i64>>,
+}
+
+impl Signature {
+    pub fn new(listen: &ListenerOption, option: &SigningOption) -> Self {
+        let keys = listen
+            .app_keys
+            .iter()
+            .map(|key| (key.app_id.clone(), key.secret_key.clone()))
+            .collect();
+        let pass = listen.oidc.is_some() || listen.force_pass.unwrap_or_default();
+        Self {
+            option: option.clone(),
+            keys,
+            pass,
+            nonces: Default::default(),
+        }
+    }
+
+    fn header<'a>(req: &'a Request, name: &str) -> Result<&'a str> {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| anyhow!("missing header {name}"))
+    }
+
+    /// Check the headers that need no body. Returns the secret key of the caller and the timestamp.
+    fn check_headers(&self, req: &Request) -> Result<(String, i64)> {
+        let app_id = Self::header(req, APP_ID_HEADER)?;
+        let Some(key) = self.keys.get(app_id) else {
+            bail!("unknown app id {app_id}");
+        };
+        let timestamp: i64 = Self::header(req, TIMESTAMP_HEADER)?.parse()?;
+        let now = chrono::Utc::now().timestamp();
+        if now.abs_diff(timestamp) > self.option.max_skew {
+            bail!("timestamp is {}s off", now - timestamp);
+        }
+        Ok((key.clone(), timestamp))
+    }
+
+    fn verify(&self, req: &Request, key: &str, timestamp: i64, body: &[u8]) -> Result<()> {
+        let nonce = Self::header(req, NONCE_HEADER)?;
+        let signature = hex::decode(Self::header(req, SIGNATURE_HEADER)?)?;
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+        let digest = format!("{:x}", Sha256::digest(body));
+        let message = format!(
+            "{}\n{path}\n{timestamp}\n{nonce}\n{digest}",
+            req.method().as_str()
+        );
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes())?;
+        mac.update(message.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| anyhow!("signature mismatch"))?;
+
+        // only remember the nonces of valid signatures, so that they cannot be flooded
+        let app_id = Self::header(req, APP_ID_HEADER)?;
+        let ttl = 2 * self.option.max_skew as i64;
+        let now = chrono::Utc::now().timestamp();
+        let mut nonces = self.nonces.lock().unwrap();
+        nonces.retain(|_, seen| now - *seen <= ttl);
+        if nonces.insert((app_id.into(), nonce.into()), now).is_some() {
+            bail!("nonce {nonce} is replayed");
+        }
+        Ok(())
+    }
+}
+
+#[handler]
+impl Signature {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        if !req.headers().contains_key(SIGNATURE_HEADER) {
+            if !self.pass && depot.jwt_auth_state() != JwtAuthState::Authorized {
+                res.status_code(StatusCode::UNAUTHORIZED);
+                ctrl.skip_rest();
+            }
+            return;
+        }
+
+        let mut refuse = |err: anyhow::Error| {
+            log::warn!("refused signed request: {err}");
+            res.status_code(StatusCode::UNAUTHORIZED);
+            ctrl.skip_rest();
+        };
+        let (key, timestamp) = match self.check_headers(req) {
+            Ok(checked) => checked,
+            Err(err) => return refuse(err),
+        };
+        let body = match req.payload_with_max_size(self.option.max_body).await {
+            Ok(body) => body.clone(),
+            Err(err) => return refuse(err.into()),
+        };
+        // put the body back for the handlers that read it as a stream, e.g., forms
+        req.replace_body(body.clone().into());
+        if let Err(err) = self.verify(req, &key, timestamp, &body) {
+            return refuse(err);
+        }
+
+        let app_id = Self::header(req, APP_ID_HEADER)
+            .unwrap_or_default()
+            .to_owned();
+        depot.insert("signed_caller", app_id);
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use futures_util::StreamExt;
+use salvo::{
+    http::{
+        header::{CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, ETAG, LAST_MODIFIED},
+        HeaderValue,
+    },
+    prelude::*,
+};
+
+/// Path the pages of the WebUI poll in dev mode, under the base path.
+pub const WEB_VERSION_PATH: &str = "webui/version";
+/// Milliseconds between the polls of a page.
+const POLL_INTERVAL: u64 = 1000;
+
+fn modified(path: &Path) -> u128 {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |time| time.as_millis())
+}
+
+/// The latest modification under the directory in milliseconds since the epoch. Symlinked directories are not followed.
+fn latest_change(dir: &Path) -> u128 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return modified(dir);
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(kind) if kind.is_dir() => latest_change(&entry.path()),
+            _ => modified(&entry.path()),
+        })
+        .fold(modified(dir), u128::max)
+}
+
+/// Tells the pages of the WebUI when its files last changed.
+pub struct WebVersion {
+    roots: Vec<PathBuf>,
+}
+
+impl WebVersion {
+    pub fn new(roots: Vec<PathBuf>) -> Self {
+        Self { roots }
+    }
+}
+
+#[handler]
+impl WebVersion {
+    async fn handle(&self, res: &mut Response) {
+        let roots = self.roots.clone();
+        let version = tokio::task::spawn_blocking(move || {
+            roots
+                .iter()
+                .map(|root| latest_change(root))
+                .max()
+                .unwrap_or_default()
+        })
+        .await
+        .unwrap_or_default();
+        res.headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        res.render(version.to_string());
+    }
+}
+
+/// Dev mode of the WebUI: keeps browsers from caching it, and has its pages reload once its files change.
+pub struct LiveReload {
+    script: String,
+}
+
+impl LiveReload {
+    /// `url` is where [`WebVersion`] is served.
+    pub fn new(url: &str) -> Self {
+        let script = format!(
+            "<script>(() => {{ let version; setInterval(async () => {{ try {{ \
+             const next = await (await fetch(\"{url}\", {{ cache: \"no-store\" }})).text(); \
+             if (version !== undefined && next !== version) location.reload(); version = next; \
+             }} catch (_) {{}} }}, {POLL_INTERVAL}); }})();</script>"
+        );
+        Self { script }
+    }
+}
+
+#[handler]
+impl LiveReload {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        ctrl.call_next(req, depot, res).await;
+        res.headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+
+        let html = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|kind| kind.to_str().ok())
+            .is_some_and(|kind| kind.starts_with("text/html"));
+        if !html
+            || res
+                .status_code
+                .is_some_and(|status| status != StatusCode::OK)
+        {
+            return;
+        }
+
+        let mut body = res.take_body();
+        let mut data = vec![];
+        while let Some(frame) = body.next().await {
+            match frame {
+                Ok(frame) => {
+                    if let Ok(bytes) = frame.into_data() {
+                        data.extend_from_slice(&bytes);
+                    }
+                }
+                Err(err) => {
+                    log::error!("failed to read webui page: {err}");
+                    res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+                    return;
+                }
+            }
+        }
+        let mut page = String::from_utf8_lossy(&data).into_owned();
+        let index = page.rfind("</body>").unwrap_or(page.len());
+        page.insert_str(index, &self.script);
+
+        // the page is no longer the file on disk
+        for header in [CONTENT_LENGTH, ETAG, LAST_MODIFIED] {
+            res.headers_mut().remove(header);
+        }
+        res.render(Text::Html(page));
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use ai00_core::{engine::Engine, ReloadRequest, SaveRequest, ThreadRequest};
+use anyhow::{bail, Result};
+use clap::Subcommand;
+use flume::Sender;
+use web_rwkv::{runtime::model::Quant, tokenizer::Tokenizer};
+
+use crate::{
+    api::bench::{run_bench, BenchOption},
+    validate::{validate, ConfigReport},
+    ConfigLayers,
+};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Start the server. This is the default.
+    Serve,
+    /// Convert a PyTorch model (`.pth`) into safetensors (`.st`).
+    Convert {
+        #[arg(short, long, value_name = "FILE")]
+        input: PathBuf,
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Load the model in the config with quantization and save it as a prefab.
+    Quantize {
+        /// Number of layers to quantize. Overrides `model.quant`.
+        #[arg(long)]
+        quant: Option<usize>,
+        /// Quantization type (`Int8` or `NF4`). Overrides `model.quant_type`.
+        #[arg(long, value_parser = parse_quant)]
+        quant_type: Option<Quant>,
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+    },
+    /// Print the token ids of a text with the tokenizer in the config. Reads stdin if no text is given.
+    Tokenize { text: Option<String> },
+    /// Load the model in the config, run prefill and decode benchmarks on it and print the report.
+    Bench(BenchOption),
+    /// Check the config and the files it refers to.
+    ValidateConfig,
+    /// Print the lines of a file encrypted with the storage key in the config, such as the output of a job.
+    Decrypt {
+        #[arg(value_name = "FILE")]
+        input: PathBuf,
+    },
+}
+
+fn parse_quant(value: &str) -> Result<Quant, String> {
+    serde_json::from_value(serde_json::Value::String(value.into())).map_err(|err| err.to_string())
+}
+
+async fn load_model(request: ReloadRequest) -> Result<Sender<ThreadRequest>> {
+    Ok(Engine::load(request).await?.sender())
+}
+
+async fn load_tokenizer(path: impl AsRef<Path>) -> Result<Tokenizer> {
+    let contents = tokio::fs::read_to_string(path).await?;
+    Ok(Tokenizer::new(&contents)?)
+}
+
+async fn quantize(
+    mut request: ReloadRequest,
+    quant: Option<usize>,
+    quant_type: Option<Quant>,
+    output: PathBuf,
+) -> Result<()> {
+    request.quant = quant.unwrap_or(request.quant);
+    request.quant_type = quant_type.unwrap_or(request.quant_type);
+    let sender = load_model(request).await?;
+
+    let (result_sender, result_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Save {
+        request: SaveRequest {
+            path: output.clone(),
+        },
+        sender: result_sender,
+    });
+    match result_receiver.recv_async().await? {
+        true => {
+            log::info!("quantized model saved to {}", output.to_string_lossy());
+            Ok(())
+        }
+        false => bail!("failed to save the model"),
+    }
+}
+
+async fn tokenize(request: ReloadRequest, text: Option<String>) -> Result<()> {
+    let tokenizer = load_tokenizer(&request.tokenizer_path).await?;
+    let text = match text {
+        Some(text) => text,
+        None => {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            text
+        }
+    };
+    let tokens = tokenizer.encode(text.as_bytes())?;
+    println!("{}", serde_json::to_string(&tokens)?);
+    Ok(())
+}
+
+async fn bench(request: ReloadRequest, option: BenchOption) -> Result<()> {
+    let sender = load_model(request).await?;
+    let report = run_bench(sender, option).await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+async fn validate_config(path: &Path, layers: &ConfigLayers) -> Result<()> {
+    let table = crate::read_layered(path, layers)?;
+    let report = ConfigReport::from(validate(&table).await);
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    for issue in &report.issues {
+        log::error!("{issue}");
+    }
+    match report.issues.len() {
+        0 => {
+            log::info!("config {} is valid", path.to_string_lossy());
+            Ok(())
+        }
+        len => bail!("config {} has {len} issue(s)", path.to_string_lossy()),
+    }
+}
+
+async fn decrypt(path: &Path, layers: &ConfigLayers, input: PathBuf) -> Result<()> {
+    let config = crate::load_layered(path, layers).await?;
+    let Some(sealer) = config.storage.sealer()? else {
+        bail!("no storage key in the config");
+    };
+    let contents = tokio::fs::read_to_string(&input).await?;
+    for (index, line) in contents.lines().enumerate() {
+        match sealer.open_line(line) {
+            Ok(line) => println!("{line}"),
+            Err(err) => bail!("line {} of {}: {err}", index + 1, input.to_string_lossy()),
+        }
+    }
+    Ok(())
+}
+
+/// Run an offline command. Commands that need the model load it without starting the server.
+pub async fn run(command: Command, path: &Path, layers: &ConfigLayers) -> Result<()> {
+    let request = || async {
+        let config = crate::load_layered(path, layers).await?;
+        ReloadRequest::try_from(config)
+    };
+    match command {
+        Command::Serve => Ok(()),
+        Command::Convert { input, output } => {
+            let output = converter::convert(input, output)?;
+            log::info!("converted model saved to {}", output.to_string_lossy());
+            Ok(())
+        }
+        Command::Quantize {
+            quant,
+            quant_type,
+            output,
+        } => quantize(request().await?, quant, quant_type, output).await,
+        Command::Tokenize { text } => tokenize(request().await?, text).await,
+        Command::Bench(option) => bench(request().await?, option).await,
+        Command::ValidateConfig => validate_config(path, layers).await,
+        Command::Decrypt { input } => decrypt(path, layers, input).await,
+    }
+}
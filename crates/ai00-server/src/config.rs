@@ -1,16 +1,21 @@
 use std::{
+    collections::HashMap,
     net::{IpAddr, Ipv4Addr},
     path::PathBuf,
 };
 
 use ai00_core::{
-    reload::{AdapterOption, BnfOption, Lora, Model, State, Tokenizer},
+    reload::{
+        AdapterOption, BnfOption, GovernorOption, Lora, Model, State, StateCacheOption, Tokenizer,
+    },
+    storage::Sealer,
     ReloadRequest,
 };
 use derivative::Derivative;
+use salvo::oapi::ToSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::build_path;
+use crate::{api::oai::completion::CompletionRequest, build_path};
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(default)]
@@ -20,9 +25,29 @@ pub struct Config {
     pub state: Vec<State>,
     pub tokenizer: Tokenizer,
     pub bnf: BnfOption,
+    pub state_cache: StateCacheOption,
+    pub governor: GovernorOption,
     pub adapter: AdapterOption,
     pub listen: ListenerOption,
+    pub cors: CorsOption,
+    pub stream: StreamOption,
+    pub reasoning: ReasoningOption,
+    pub workspace: WorkspaceOption,
+    pub cache: CacheOption,
+    pub limits: LimitOption,
+    pub embedding: EmbeddingOption,
+    pub sampler: SamplerOption,
+    pub schedule: Vec<JobOption>,
+    pub maintenance: Vec<MaintenanceOption>,
+    pub storage: StorageOption,
+    pub audit: AuditOption,
     pub web: Option<WebOption>,
+    /// Config files merged beneath this one, relative to it.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<PathBuf>,
+    /// Named overlays of the config, picked with `--profile`.
+    #[serde(skip_serializing_if = "toml::Table::is_empty")]
+    pub profiles: toml::Table,
 }
 
 impl TryFrom<Config> for ReloadRequest {
@@ -36,10 +61,16 @@ impl TryFrom<Config> for ReloadRequest {
                     path,
                     quant,
                     quant_type,
+                    quant_ranges,
                     precision,
                     token_chunk_size,
                     max_batch,
                     embed_device,
+                    checkpoint_interval,
+                    context_length,
+                    context_reserve,
+                    max_restarts,
+                    infer_timeout,
                 },
             mut lora,
             mut state,
@@ -47,10 +78,16 @@ impl TryFrom<Config> for ReloadRequest {
                 path: tokenizer_path,
             },
             bnf,
+            mut state_cache,
+            governor,
             adapter,
+            storage,
             ..
         } = value;
 
+        // with a storage key, nothing of the conversations goes to disk in the clear
+        state_cache.encrypt |= storage.key.is_some();
+
         let model_path = build_path(&path, name)?;
         for lora in lora.iter_mut() {
             lora.path = build_path(&path, &lora.path)?;
@@ -65,12 +102,20 @@ impl TryFrom<Config> for ReloadRequest {
             state,
             quant,
             quant_type,
+            quant_ranges,
             precision,
             token_chunk_size,
             max_batch,
             embed_device,
+            checkpoint_interval,
+            context_length,
+            context_reserve,
+            max_restarts,
+            infer_timeout,
             tokenizer_path,
             bnf,
+            state_cache,
+            governor,
             adapter,
         })
     }
@@ -111,12 +156,426 @@ pub struct ListenerOption {
     pub expire_sec: Option<u32>,
     /// AppId with SecretKey pairs
     pub app_keys: Vec<AppKey>,
+    /// Additionally serve the API on this unix domain socket (unix only).
+    pub unix_socket: Option<PathBuf>,
+    /// Validate tokens issued by an external OIDC provider instead of the `slot` secret.
+    pub oidc: Option<OidcOption>,
+    /// Also accept requests signed with the secret of an app key, for machine callers without tokens.
+    pub signing: Option<SigningOption>,
+    /// Network access rules applied to every request before authentication.
+    pub security: Option<SecurityOption>,
+    /// Additional certs for other domains, selected by SNI. `assets/certs/cert.pem` serves the rest.
+    pub certs: Option<Vec<CertOption>>,
+    /// Seconds between checks of the cert files, which are reloaded when changed. Open connections keep their certs.
+    pub tls_reload: Option<u64>,
+    /// Path prefix of all routes, for serving behind a reverse proxy under a sub-path, e.g. `/ai00`.
+    pub base_path: Option<String>,
+    /// Accounts that users log in to with a password. Not available with `oidc`.
+    pub accounts: Option<AccountOption>,
+}
+
+impl ListenerOption {
+    /// The base path with a leading slash and no trailing one, or empty if routes are served at the root.
+    pub fn base_path(&self) -> String {
+        match self.base_path.as_deref().map(|path| path.trim_matches('/')) {
+            Some(path) if !path.is_empty() => format!("/{path}"),
+            _ => String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CertOption {
+    /// Server name the cert is presented for.
+    pub domain: String,
+    /// Path to the cert chain in PEM.
+    pub cert: PathBuf,
+    /// Path to the private key in PEM.
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct SecurityOption {
+    /// Networks (CIDR, or single addresses) allowed to connect. Leave empty to allow any.
+    pub allow: Vec<String>,
+    /// Networks refused, even if they are allowed.
+    pub deny: Vec<String>,
+    /// Proxies trusted to report the client address in `forwarded_header`.
+    pub trusted_proxies: Vec<String>,
+    /// Header the trusted proxies put the client address chain in.
+    #[derivative(Default(value = "String::from(\"X-Forwarded-For\")"))]
+    pub forwarded_header: String,
+    /// Maximum requests in flight from one client address. `0` means unlimited.
+    pub max_connections: usize,
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct CorsOption {
+    /// Origins allowed to make cross-origin requests. `"*"` allows any.
+    #[derivative(Default(value = "vec![\"*\".into()]"))]
+    pub allow_origins: Vec<String>,
+    /// Methods allowed in cross-origin requests. `"*"` allows any.
+    #[derivative(Default(value = "vec![\"GET\".into(), \"POST\".into(), \"DELETE\".into()]"))]
+    pub allow_methods: Vec<String>,
+    /// Headers allowed in cross-origin requests. `"*"` allows any.
+    #[derivative(Default(value = "vec![\"*\".into()]"))]
+    pub allow_headers: Vec<String>,
+    /// Allow cookies and other credentials. Cannot be combined with `"*"` in any of the lists.
+    pub allow_credentials: bool,
+    /// Seconds browsers may cache the result of a preflight request.
+    pub max_age: Option<u64>,
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct OidcOption {
+    /// Issuer url of the provider; the JWKS is discovered from its `.well-known/openid-configuration`.
+    pub issuer: String,
+    /// Accepted `aud` values of the token, usually the client id registered at the provider. Leave empty to skip the check.
+    pub audience: Vec<String>,
+    /// Claim holding the roles of the caller. Nested claims are separated by dots, e.g. `realm_access.roles`.
+    #[derivative(Default(value = "String::from(\"roles\")"))]
+    pub roles_claim: String,
+    /// Roles granted access to the admin APIs (adapters, models, states and files).
+    pub admin_roles: Vec<String>,
+    /// Roles granted access to the inference APIs. Leave empty to admit any valid token.
+    pub inference_roles: Vec<String>,
+}
+
+/// Requests are signed by sending the app id, a unix timestamp in seconds, a nonce and the signature in the
+/// `X-Ai00-App-Id`, `X-Ai00-Timestamp`, `X-Ai00-Nonce` and `X-Ai00-Signature` headers. The signature is the
+/// hex HMAC-SHA256, with the secret key, of the lines of the method, the path with the query, the timestamp,
+/// the nonce and the hex SHA-256 of the body, joined by `\n`.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct SigningOption {
+    /// Seconds the timestamp of a request may differ from the server clock. Nonces are remembered for as long.
+    #[derivative(Default(value = "300"))]
+    pub max_skew: u64,
+    /// Maximum bytes of the body of a signed request, which is read whole to be digested.
+    #[derivative(Default(value = "64 * 1024 * 1024"))]
+    pub max_body: usize,
+    /// Admit signed callers to the admin APIs too.
+    pub admin: bool,
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct AccountOption {
+    /// File the accounts and their settings are kept in.
+    #[derivative(Default(value = "\"assets/accounts.json\".into()"))]
+    pub path: PathBuf,
+    /// Whether anyone may register an account. Never with `ldap`, where the directory owns the names.
+    pub register: bool,
+    /// Minimum number of characters of passwords.
+    #[derivative(Default(value = "8"))]
+    pub min_password: usize,
+    /// Also log users in with the accounts of a directory. Needs the `ldap` feature.
+    pub ldap: Option<LdapOption>,
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct LdapOption {
+    /// Url of the directory server, e.g. `ldaps://ldap.example.com`.
+    pub url: String,
+    /// DN users bind as, with `{name}` replaced by the name they log in with,
+    /// e.g. `uid={name},ou=people,dc=example,dc=com`, or `{name}@example.com` for Active Directory.
+    pub bind_dn: String,
+    /// Upgrade `ldap://` connections with StartTLS.
+    pub starttls: bool,
+    /// Seconds to wait for the directory server.
+    #[derivative(Default(value = "10"))]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageOption {
+    /// Base64 encoded key of 32 bytes that the data written to disk is encrypted with. Not encrypted if not given.
+    pub key: Option<String>,
+}
+
+impl StorageOption {
+    pub fn sealer(&self) -> anyhow::Result<Option<Sealer>> {
+        self.key.as_deref().map(Sealer::new).transpose()
+    }
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct AuditOption {
+    /// File the calls to the admin APIs are appended to, one record per line. Not recorded if not given.
+    pub path: Option<PathBuf>,
+    /// Maximum bytes of a JSON body recorded in the parameters; bigger bodies are left out.
+    #[derivative(Default(value = "65536"))]
+    pub max_body: usize,
 }
 
 #[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
 #[derivative(Default)]
 #[serde(default)]
 pub struct WebOption {
+    /// Path to the WebUI, either a zip archive or a directory served as is.
     #[derivative(Default(value = "\"assets/www/index.zip\".into()"))]
     pub path: PathBuf,
+    /// Tell browsers not to cache the WebUI, and reload its pages once the files served change.
+    pub dev: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OverflowPolicy {
+    /// Merge pending deltas into one chunk once the client falls behind.
+    #[default]
+    Coalesce,
+    /// Pause generation of the request until the client catches up.
+    Pause,
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct StreamOption {
+    /// Number of undelivered chunks a stream may hold before the overflow policy applies.
+    #[derivative(Default(value = "64"))]
+    pub buffer: usize,
+    /// What to do if a client reads slower than the model generates.
+    pub overflow: OverflowPolicy,
+    /// Minimum milliseconds between the chunks of a stream, for an even typing pace. Tokens generated in between are merged.
+    pub pace: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum ReasoningFormat {
+    /// Leave reasoning blocks in the content as the model writes them.
+    #[default]
+    Keep,
+    /// Remove reasoning blocks from the content.
+    Strip,
+    /// Deliver reasoning blocks in `reasoning_content` apart from the content.
+    Separate,
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct EmbeddingOption {
+    /// Scale embeddings to unit length, unless the request says otherwise.
+    pub normalize: bool,
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct SamplerOption {
+    /// Dynamic libraries to load logits processors from. Needs the `plugins` feature.
+    pub plugins: Vec<PathBuf>,
+    /// Bounds of the sampler options of requests.
+    pub limits: SamplerLimits,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LimitPolicy {
+    /// Bring values out of bounds to the bound.
+    #[default]
+    Clamp,
+    /// Refuse requests with values out of bounds.
+    Reject,
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct SamplerLimits {
+    /// Highest temperature, including the spread of the dynamic temperature.
+    pub max_temperature: Option<f32>,
+    /// Highest presence, frequency and DRY penalty.
+    pub max_penalty: Option<f32>,
+    /// Highest output token limit. Requests that give none get this one.
+    pub max_tokens: Option<usize>,
+    /// What to do with requests out of bounds.
+    pub policy: LimitPolicy,
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct LimitOption {
+    /// Maximum generations a caller (API key, or client address without one) runs at once. `0` means unlimited.
+    /// Further generations wait in the queue while other callers go ahead.
+    pub max_concurrency: usize,
+    /// Output tokens per second a caller generates across its generations. `0` means unlimited.
+    /// Over the rate, streams of the caller are slowed down rather than refused.
+    pub max_token_rate: f32,
+    /// Rates of particular callers, overriding `max_token_rate`, e.g. of the keys of a free tier.
+    pub token_rates: HashMap<String, f32>,
+    /// Whether callers are held to their rates even when no one else uses the model.
+    pub rate_policy: RatePolicy,
+    /// Longest estimated wait for the first token a generation is taken with, in seconds. `0` means unlimited.
+    /// The estimate is the wait for a slot, then the prompts ahead over the recent prefill speed; requests over are refused with `503`.
+    pub max_first_token: f32,
+    /// Callers (API keys, app ids or accounts) that may ask for the `high` priority, which preempts others.
+    /// The requests of anyone else are held to `normal`.
+    pub high_priority: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RatePolicy {
+    /// Always hold callers to their rates.
+    #[default]
+    Hard,
+    /// Only slow callers down while the generations of others have work to do.
+    Soft,
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct ReasoningOption {
+    /// How chat completions deliver reasoning blocks, unless the request says otherwise.
+    pub format: ReasoningFormat,
+    /// Tag that opens a reasoning block.
+    #[derivative(Default(value = "String::from(\"<think>\")"))]
+    pub open: String,
+    /// Tag that closes a reasoning block.
+    #[derivative(Default(value = "String::from(\"</think>\")"))]
+    pub close: String,
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct WorkspaceOption {
+    /// Whether to serve the file APIs (`/api/files/*`) at all.
+    #[derivative(Default(value = "true"))]
+    pub enable: bool,
+    /// Directories the file APIs may read from and write configs into.
+    #[derivative(Default(value = "vec![
+        \"assets/models\".into(),
+        \"assets/tokenizer\".into(),
+        \"assets/configs\".into(),
+        \"assets/www\".into(),
+    ]"))]
+    pub permitted: Vec<PathBuf>,
+    /// Directories archives may be extracted into.
+    #[derivative(Default(value = "vec![\"assets/unzip\".into(), \"assets/temp\".into()]"))]
+    pub unzip: Vec<PathBuf>,
+    /// Maximum total uncompressed size of an archive to extract, in bytes.
+    #[derivative(Default(value = "1 << 30"))]
+    pub max_unzip_size: u64,
+    /// Maximum size of a config file to load or save, in bytes.
+    #[derivative(Default(value = "1 << 20"))]
+    pub max_config_size: u64,
+    /// Maximum size of a single upload chunk, in bytes.
+    #[derivative(Default(value = "64 << 20"))]
+    pub max_chunk_size: u64,
+    /// Log every file API call with its caller and outcome.
+    #[derivative(Default(value = "true"))]
+    pub audit: bool,
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct CacheOption {
+    /// Serve repeated non-streaming completions from the cache.
+    pub enable: bool,
+    /// Only cache requests whose sampler always picks the most likely token.
+    #[derivative(Default(value = "true"))]
+    pub deterministic_only: bool,
+    /// Seconds before a cached response expires.
+    #[derivative(Default(value = "600"))]
+    pub ttl: u64,
+    /// Maximum number of cached responses.
+    #[derivative(Default(value = "1024"))]
+    pub capacity: usize,
+    /// Serve cached responses of similar prompts.
+    pub semantic: SemanticCacheOption,
+    /// Resume chats that continue a conversation served before from the states left by it.
+    pub conversation: ConversationCacheOption,
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct ConversationCacheOption {
+    /// Remember the tokens of served conversations, so that a chat extending one reuses its cached states.
+    #[derivative(Default(value = "true"))]
+    pub enable: bool,
+    /// Maximum number of remembered conversations.
+    #[derivative(Default(value = "256"))]
+    pub capacity: usize,
+}
+
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct SemanticCacheOption {
+    /// Embed prompts and serve the cached response of a similar one.
+    pub enable: bool,
+    /// Minimum cosine similarity between two prompts for them to share a response.
+    #[derivative(Default(value = "0.95"))]
+    pub threshold: f32,
+    /// The (reversed) number of layer at which the output is taken as the embedding.
+    pub embed_layer: usize,
+    /// Maximum number of cached responses.
+    #[derivative(Default(value = "256"))]
+    pub capacity: usize,
+    /// Callers (app ids or OIDC subjects) whose requests never use the semantic cache.
+    pub exclude: Vec<String>,
+    /// Let all callers share the cached responses. Off by default, since a response may hold the private context of the caller it was made for.
+    pub shared: bool,
+}
+
+/// A prompt that is run periodically.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct JobOption {
+    /// Unique name of the job.
+    pub name: String,
+    /// Cron expression in local time with 5 fields: minute, hour, day of month, month and day of week.
+    pub cron: String,
+    /// The completion request to run.
+    pub request: CompletionRequest,
+    /// Url that the result is posted to as JSON.
+    pub webhook: Option<String>,
+    /// File under `assets/` that the result is appended to as a line of JSON.
+    #[salvo(schema(value_type = Option<String>))]
+    pub output: Option<PathBuf>,
+}
+
+/// A window during which the server drains, unloads the model, runs the tasks and loads the model back.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct MaintenanceOption {
+    /// Unique name of the window.
+    pub name: String,
+    /// Cron expression in local time of when the window opens, same as the jobs.
+    pub cron: String,
+    /// Seconds the running generations are given to finish before the model is unloaded anyway.
+    #[derivative(Default(value = "300"))]
+    pub drain_timeout: u64,
+    /// Tasks run while the model is unloaded, in order.
+    pub tasks: Vec<MaintenanceTask>,
+    /// Rotated files kept of each log by `RotateLogs`.
+    #[derivative(Default(value = "7"))]
+    pub keep_logs: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MaintenanceTask {
+    /// Drop the expired entries of the response caches, and the state files left in the offload directory.
+    CompactCaches,
+    /// Move the audit log and the outputs of the jobs aside, so that they start anew.
+    RotateLogs,
 }
@@ -2,36 +2,83 @@ use std::{
     io::Cursor,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
     time::Duration,
 };
 
-use ai00_core::{model_route, ThreadRequest};
-use anyhow::{bail, Result};
-use clap::{command, CommandFactory, Parser};
+use ai00_core::{engine::Engine, ThreadRequest};
+use anyhow::{anyhow, bail, Result};
+use clap::{CommandFactory, Parser};
+use futures_util::{stream::BoxStream, StreamExt};
+use itertools::Itertools;
 use memmap2::Mmap;
 use salvo::{
     affix,
-    conn::rustls::{Keycert, RustlsConfig},
-    cors::{AllowHeaders, AllowOrigin, Cors},
-    http::Method,
-    jwt_auth::{ConstDecoder, HeaderFinder, QueryFinder},
+    conn::{
+        rustls::{Keycert, RustlsConfig},
+        Acceptor,
+    },
+    cors::{AllowHeaders, AllowMethods, AllowOrigin, Cors, CorsHandler},
+    http::{
+        header::{HeaderName, HeaderValue},
+        Method,
+    },
+    jwt_auth::{ConstDecoder, HeaderFinder, JwtTokenFinder, OidcDecoder, QueryFinder, Validation},
     logging::Logger,
     prelude::*,
     serve_static::StaticDir,
     Router,
 };
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, BufReader},
-};
+use tokio::{fs::File, sync::broadcast::error::RecvError};
 
-use crate::types::{JwtClaims, ThreadState};
+use crate::{
+    api::event::{EventBus, ServerEvent, ServerNotice},
+    config::OidcOption,
+    types::{JwtClaims, OidcClaims, ThreadState},
+};
 
 mod api;
+mod cli;
 mod config;
+mod service;
 mod types;
+mod validate;
 
 const SLEEP: Duration = Duration::from_millis(500);
+/// How long the open connections are given to finish on shutdown.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+/// How many times the OIDC provider is tried at startup, waiting twice as long after each failure.
+const OIDC_ATTEMPTS: u32 = 5;
+/// How deep config files may include each other.
+const MAX_INCLUDE_DEPTH: usize = 8;
+/// Prefix of the environment variables that override config values, e.g. `AI00__MODEL__PATH`.
+const ENV_PREFIX: &str = "AI00__";
+const CERT_PATH: &str = "assets/certs/cert.pem";
+const KEY_PATH: &str = "assets/certs/key.pem";
+
+/// A directory of this process where the WebUI archive and the plugins are extracted, removed once dropped.
+struct WebTemp(PathBuf);
+
+impl WebTemp {
+    fn new() -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("ai00-www-{}", std::process::id()));
+        // left over by an earlier process that had the same id and did not exit cleanly
+        if path.exists() {
+            std::fs::remove_dir_all(&path)?;
+        }
+        std::fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for WebTemp {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.0) {
+            log::warn!("failed to remove {}: {err}", self.0.to_string_lossy());
+        }
+    }
+}
 
 pub fn build_path(path: impl AsRef<Path>, name: impl AsRef<Path>) -> Result<PathBuf> {
     let permitted = path.as_ref();
@@ -49,18 +96,6 @@ pub fn build_path(path: impl AsRef<Path>, name: impl AsRef<Path>) -> Result<Path
     }
 }
 
-pub fn check_path_permitted(path: impl AsRef<Path>, permitted: &[&str]) -> Result<()> {
-    let current_path = std::env::current_dir()?;
-    for sub in permitted {
-        let permitted = current_path.join(sub).canonicalize()?;
-        let path = path.as_ref().canonicalize()?;
-        if path.starts_with(permitted) {
-            return Ok(());
-        }
-    }
-    bail!("path not permitted");
-}
-
 pub async fn load_web(path: impl AsRef<Path>, target: &Path) -> Result<()> {
     let file = File::open(path).await?;
     let map = unsafe { Mmap::map(&file)? };
@@ -68,46 +103,496 @@ pub async fn load_web(path: impl AsRef<Path>, target: &Path) -> Result<()> {
     Ok(())
 }
 
-pub async fn load_plugin(path: impl AsRef<Path>, target: &Path, name: &String) -> Result<()> {
-    let file = File::open(path).await?;
-    let map = unsafe { Mmap::map(&file)? };
-    let root = target.join("plugins");
-    if !root.exists() {
-        std::fs::create_dir(&root)?;
+/// Serve until a shutdown is announced on the event bus, then close gracefully.
+async fn serve(acceptor: impl Acceptor + Send, service: Service, events: &Arc<EventBus>) {
+    let server = salvo::server::Server::new(acceptor);
+    let handle = server.handle();
+    let mut receiver = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(ServerEvent::Server(ServerNotice::ShutdownImminent { .. }))
+                | Err(RecvError::Closed) => break,
+                // lagging behind only drops the oldest events, so keep listening
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            }
+        }
+        handle.stop_graceful(SHUTDOWN_GRACE);
+    });
+    server.serve(service).await;
+}
+
+/// Serve the first-run setup API until the initial config is written.
+async fn setup(sender: flume::Sender<ThreadRequest>, path: &Path, addr: SocketAddr) {
+    log::warn!(
+        "config {} not found, serving the setup at {addr}",
+        path.to_string_lossy()
+    );
+    let (finish, finished) = flume::bounded(1);
+    let state = api::setup::SetupState {
+        config_path: path.into(),
+        finish,
+    };
+    let thread_state = ThreadState {
+        sender,
+        path: ai00_core::reload::Model::default().path,
+    };
+
+    let router = Router::new()
+        .hoop(affix::inject(thread_state).inject(state))
+        .push(
+            Router::with_path("/api/setup")
+                .push(Router::with_path("adapters").get(api::adapters_info))
+                .push(Router::with_path("models").get(api::setup::setup_models))
+                .push(Router::with_path("config").post(api::setup::setup_config)),
+        );
+    let doc = OpenApi::new("ai00_server setup", env!("CARGO_PKG_VERSION")).merge_router(&router);
+    let router = router
+        .push(doc.into_router("/api-doc/openapi.json"))
+        .push(SwaggerUi::new("/api-doc/openapi.json").into_router("swagger-ui"));
+    let web = config::WebOption::default();
+    let load = || async {
+        let temp = WebTemp::new()?;
+        load_web(&web.path, &temp.0).await?;
+        Ok::<_, anyhow::Error>(temp)
+    };
+    let temp = match web.path.is_file() {
+        true => load()
+            .await
+            .inspect_err(|err| log::error!("failed to load webui: {err}")),
+        false => Err(anyhow!("no webui archive")),
+    };
+    let router = match &temp {
+        Ok(temp) => router.push(
+            Router::with_path("<**path>")
+                .get(StaticDir::new([temp.0.clone()]).defaults(["index.html"])),
+        ),
+        Err(_) => router,
+    };
+
+    let cors = cors_handler(&Default::default()).expect("invalid cors config");
+    let acceptor = TcpListener::new(addr).bind().await;
+    let server = salvo::server::Server::new(acceptor);
+    let handle = server.handle();
+    tokio::spawn(async move {
+        let _ = finished.recv_async().await;
+        handle.stop_graceful(SHUTDOWN_GRACE);
+    });
+    server.serve(Service::new(router).hoop(cors)).await;
+}
+
+/// Announce the shutdown to the clients on ctrl-c or `SIGTERM`.
+async fn watch_shutdown(events: Arc<EventBus>) {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    announce_shutdown(&events);
+}
+
+/// Tell the clients and the listeners that the server stops once the grace period is over.
+fn announce_shutdown(events: &EventBus) {
+    log::info!("shutting down in {} seconds", SHUTDOWN_GRACE.as_secs());
+    events.send(ServerEvent::Server(ServerNotice::ShutdownImminent {
+        grace_period: SHUTDOWN_GRACE.as_secs(),
+    }));
+}
+
+/// Merge `overlay` into `base`. Tables are merged key by key; other values, arrays included, are replaced.
+fn merge_table(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(value)) => merge_table(base, value),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
     }
-    let dir = root.join(name);
-    std::fs::create_dir(&dir)?;
-    zip_extract::extract(Cursor::new(&map), &dir, false)?;
+}
+
+/// Read a config file, with the files it `include`s merged beneath it in order.
+/// Included paths are relative to the including file.
+fn read_config(path: &Path, depth: usize) -> Result<toml::Table> {
+    if depth > MAX_INCLUDE_DEPTH {
+        bail!("includes nested too deep at {}", path.to_string_lossy());
+    }
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| anyhow!("failed to read config {}: {err}", path.to_string_lossy()))?;
+    let mut table: toml::Table = toml::from_str(&contents)?;
+    let includes: Vec<PathBuf> = match table.remove("include") {
+        Some(value) => value.try_into()?,
+        None => vec![],
+    };
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut merged = toml::Table::new();
+    for include in includes {
+        merge_table(&mut merged, read_config(&dir.join(include), depth + 1)?);
+    }
+    merge_table(&mut merged, table);
+    Ok(merged)
+}
+
+/// A config value given as text: a TOML value if it parses as one, otherwise a string.
+fn parse_value(text: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {text}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(text.into()))
+}
+
+/// A config value given as text for the key at a dotted path, taken as a string if the key is one
+/// in the default config, so that e.g. `AI00__LISTEN__SLOT=123` stays a string.
+/// Keys of no known type, e.g. options unset by default, get a TOML value unless `untyped_as_text`.
+fn coerce_value(
+    defaults: &toml::Table,
+    keys: &[String],
+    text: &str,
+    untyped_as_text: bool,
+) -> toml::Value {
+    let target = keys.split_last().and_then(|(last, keys)| {
+        keys.iter()
+            .try_fold(defaults, |table, key| match table.get(key) {
+                Some(toml::Value::Table(table)) => Some(table),
+                _ => None,
+            })?
+            .get(last)
+    });
+    match target {
+        Some(toml::Value::String(_)) => toml::Value::String(text.into()),
+        None if untyped_as_text => toml::Value::String(text.into()),
+        _ => parse_value(text),
+    }
+}
+
+/// Set the value at a dotted path such as `model.path`, creating the tables on the way.
+fn set_value(table: &mut toml::Table, keys: &[String], value: toml::Value) -> Result<()> {
+    let Some((last, keys)) = keys.split_last() else {
+        bail!("empty config key");
+    };
+    let mut table = table;
+    for key in keys {
+        let entry = table
+            .entry(key.clone())
+            .or_insert_with(|| toml::Value::Table(Default::default()));
+        table = match entry {
+            toml::Value::Table(table) => table,
+            _ => bail!("config key {key} is not a table"),
+        };
+    }
+    table.insert(last.clone(), value);
     Ok(())
 }
 
+/// What goes on top of the config file, in order: a profile, the environment and the `--set` flags.
+#[derive(Debug, Default, Clone)]
+pub struct ConfigLayers {
+    /// Name of the `[profiles.<name>]` table to apply.
+    pub profile: Option<String>,
+    /// Apply `AI00__SECTION__KEY=value` environment variables.
+    pub env: bool,
+    /// `section.key=value` overrides.
+    pub overrides: Vec<String>,
+}
+
+impl ConfigLayers {
+    fn apply(&self, table: &mut toml::Table, untyped_as_text: bool) -> Result<()> {
+        let defaults = match toml::Value::try_from(config::Config::default()) {
+            Ok(toml::Value::Table(defaults)) => defaults,
+            _ => Default::default(),
+        };
+
+        let mut profiles = match table.remove("profiles") {
+            Some(toml::Value::Table(profiles)) => profiles,
+            Some(_) => bail!("profiles must be a table"),
+            None => Default::default(),
+        };
+        if let Some(name) = &self.profile {
+            let Some(toml::Value::Table(overlay)) = profiles.remove(name) else {
+                bail!(
+                    "profile {name} not found, available: {}",
+                    profiles.keys().join(", ")
+                );
+            };
+            log::info!("using profile {name}");
+            merge_table(table, overlay);
+        }
+
+        if self.env {
+            for (name, value) in std::env::vars() {
+                let Some(name) = name.strip_prefix(ENV_PREFIX) else {
+                    continue;
+                };
+                let keys = name.split("__").map(|key| key.to_lowercase()).collect_vec();
+                let value = coerce_value(&defaults, &keys, &value, untyped_as_text);
+                set_value(table, &keys, value)?;
+                log::info!("config {} set by the environment", keys.join("."));
+            }
+        }
+
+        for text in &self.overrides {
+            let Some((key, value)) = text.split_once('=') else {
+                bail!("override {text} must be in the form of key=value");
+            };
+            let keys = key.trim().split('.').map(String::from).collect_vec();
+            let value = coerce_value(&defaults, &keys, value.trim(), untyped_as_text);
+            set_value(table, &keys, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Load a config file alone, with its `include` list and profiles kept rather than applied,
+/// so that it can be written back as it was.
 pub async fn load_config(path: impl AsRef<Path>) -> Result<config::Config> {
-    let file = File::open(path).await?;
-    let mut reader = BufReader::new(file);
-    let mut contents = String::new();
-    reader.read_to_string(&mut contents).await?;
+    let contents = tokio::fs::read_to_string(path).await?;
     Ok(toml::from_str(&contents)?)
 }
 
+/// Read a config file as a table, and apply the layers on top of it.
+pub fn read_layered(path: impl AsRef<Path>, layers: &ConfigLayers) -> Result<toml::Table> {
+    let base = read_config(path.as_ref(), 0)?;
+    let mut table = base.clone();
+    layers.apply(&mut table, false)?;
+    let fits = |table: &toml::Table| {
+        toml::Value::Table(table.clone())
+            .try_into::<config::Config>()
+            .is_ok()
+    };
+    if !fits(&table) {
+        // values of the keys of no known type may be meant as strings even if they look like numbers
+        let mut retry = base;
+        layers.apply(&mut retry, true)?;
+        if fits(&retry) {
+            return Ok(retry);
+        }
+    }
+    Ok(table)
+}
+
+/// Load a config file, and apply the layers on top of it.
+pub async fn load_layered(path: impl AsRef<Path>, layers: &ConfigLayers) -> Result<config::Config> {
+    let table = read_layered(path, layers)?;
+    Ok(toml::Value::Table(table).try_into()?)
+}
+
+pub fn cors_handler(option: &config::CorsOption) -> Result<CorsHandler> {
+    let any = |list: &[String]| list.iter().any(|x| x == "*");
+    if option.allow_credentials
+        && (any(&option.allow_origins) || any(&option.allow_methods) || any(&option.allow_headers))
+    {
+        bail!("cors credentials cannot be allowed together with \"*\"");
+    }
+
+    let origin = match any(&option.allow_origins) {
+        true => AllowOrigin::any(),
+        false => AllowOrigin::list(
+            option
+                .allow_origins
+                .iter()
+                .map(|origin| HeaderValue::from_str(origin))
+                .try_collect::<_, Vec<_>, _>()?,
+        ),
+    };
+    let methods = match any(&option.allow_methods) {
+        true => AllowMethods::any(),
+        false => AllowMethods::list(
+            option
+                .allow_methods
+                .iter()
+                .map(|method| Method::from_bytes(method.to_uppercase().as_bytes()))
+                .try_collect::<_, Vec<_>, _>()?,
+        ),
+    };
+    let headers = match any(&option.allow_headers) {
+        true => AllowHeaders::any(),
+        false => AllowHeaders::list(
+            option
+                .allow_headers
+                .iter()
+                .map(|header| HeaderName::from_str(header))
+                .try_collect::<_, Vec<_>, _>()?,
+        ),
+    };
+
+    let cors = Cors::new()
+        .allow_origin(origin)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .allow_credentials(option.allow_credentials);
+    let cors = match option.max_age {
+        Some(max_age) => cors.max_age(max_age),
+        None => cors,
+    };
+    Ok(cors.into_handler())
+}
+
+/// Load the default cert and the ones selected by SNI.
+fn load_rustls_config(listen: &config::ListenerOption) -> Result<RustlsConfig> {
+    let keycert = |cert: &Path, key: &Path| -> Result<Keycert> {
+        let keycert = Keycert::new()
+            .cert_from_path(cert)
+            .map_err(|err| anyhow!("unable to read {}: {err}", cert.to_string_lossy()))?
+            .key_from_path(key)
+            .map_err(|err| anyhow!("unable to read {}: {err}", key.to_string_lossy()))?;
+        Ok(keycert)
+    };
+    let mut config = RustlsConfig::new(keycert(CERT_PATH.as_ref(), KEY_PATH.as_ref())?);
+    for cert in listen.certs.iter().flatten() {
+        config = config.keycert(&cert.domain, keycert(&cert.cert, &cert.key)?);
+    }
+    Ok(config)
+}
+
+/// Modification times of all cert files, to tell when they change.
+fn cert_stamps(listen: &config::ListenerOption) -> Vec<Option<std::time::SystemTime>> {
+    let paths = [PathBuf::from(CERT_PATH), PathBuf::from(KEY_PATH)]
+        .into_iter()
+        .chain(
+            listen
+                .certs
+                .iter()
+                .flatten()
+                .flat_map(|cert| [cert.cert.clone(), cert.key.clone()]),
+        );
+    paths
+        .map(|path| {
+            std::fs::metadata(path)
+                .and_then(|meta| meta.modified())
+                .ok()
+        })
+        .collect()
+}
+
+/// The TLS config, followed by a new one each time the cert files change if `tls_reload` is set.
+fn rustls_configs(listen: &config::ListenerOption) -> BoxStream<'static, RustlsConfig> {
+    let config = load_rustls_config(listen).expect("unable to load certs");
+    let first = futures_util::stream::once(std::future::ready(config));
+    let Some(interval) = listen.tls_reload.filter(|&x| x > 0) else {
+        return first.boxed();
+    };
+
+    let interval = Duration::from_secs(interval);
+    let state = (listen.clone(), cert_stamps(listen));
+    let reloads = futures_util::stream::unfold(state, move |(listen, mut stamps)| async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let current = cert_stamps(&listen);
+            if current == stamps {
+                continue;
+            }
+            stamps = current;
+            match load_rustls_config(&listen) {
+                Ok(config) => {
+                    log::info!("reloaded tls certs");
+                    return Some((config, (listen, stamps)));
+                }
+                Err(err) => log::warn!("failed to reload tls certs: {err}"),
+            }
+        }
+    });
+    first.chain(reloads).boxed()
+}
+
+/// Register the logits processors of the plugins in the config.
+fn load_plugins(option: &config::SamplerOption) {
+    for path in &option.plugins {
+        #[cfg(feature = "plugins")]
+        match unsafe { ai00_core::sampler::processor::load_plugin(path) } {
+            Ok(names) => log::info!(
+                "loaded plugin {}: {}",
+                path.to_string_lossy(),
+                names.join(", ")
+            ),
+            Err(err) => log::error!("failed to load plugin {}: {err}", path.to_string_lossy()),
+        }
+        #[cfg(not(feature = "plugins"))]
+        log::warn!(
+            "plugin {} skipped, the server is built without the `plugins` feature",
+            path.to_string_lossy()
+        );
+    }
+}
+
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
-    #[arg(long, short, value_name = "FILE")]
+    #[arg(long, short, value_name = "FILE", global = true)]
     config: Option<PathBuf>,
+    /// Apply the `[profiles.<NAME>]` table of the config on top of the rest.
+    #[arg(long, value_name = "NAME", global = true)]
+    profile: Option<String>,
+    /// Override a config value, e.g. `--set model.quant=32`. Applied after the `AI00__*` environment variables.
+    #[arg(long = "set", value_name = "KEY=VALUE", global = true)]
+    overrides: Vec<String>,
     #[arg(long, short)]
     ip: Option<IpAddr>,
     #[arg(long, short)]
     port: Option<u16>,
+    /// Refuse to start if the config has any issue, unknown keys included. Otherwise they are only logged.
+    #[arg(long)]
+    strict: bool,
+    /// Run under a service manager, reporting readiness once the model is loaded.
+    #[arg(long)]
+    service: bool,
+    #[command(subcommand)]
+    command: Option<cli::Command>,
+}
+
+/// Discover the keys of the OIDC provider, retrying while it is unreachable.
+async fn oidc_decoder(oidc: &OidcOption) -> Option<OidcDecoder> {
+    let mut validation = Validation::default();
+    validation.set_issuer(&[&oidc.issuer]);
+    match oidc.audience.is_empty() {
+        true => validation.validate_aud = false,
+        false => validation.set_audience(&oidc.audience),
+    }
+
+    let mut delay = Duration::from_secs(1);
+    for attempt in 1..=OIDC_ATTEMPTS {
+        let decoder = OidcDecoder::builder(&oidc.issuer)
+            .validation(validation.clone())
+            .build()
+            .await;
+        match decoder {
+            Ok(decoder) => return Some(decoder),
+            Err(err) => log::warn!(
+                "failed to fetch oidc provider configuration ({attempt}/{OIDC_ATTEMPTS}): {err}"
+            ),
+        }
+        if attempt < OIDC_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    None
 }
 
 #[tokio::main]
 async fn main() {
-    simple_logger::SimpleLogger::new()
+    let logger = simple_logger::SimpleLogger::new()
         .with_level(log::LevelFilter::Warn)
         .with_module_level("ai00_server", log::LevelFilter::Info)
         .with_module_level("ai00_core", log::LevelFilter::Info)
-        .with_module_level("web_rwkv", log::LevelFilter::Info)
-        .init()
+        .with_module_level("web_rwkv", log::LevelFilter::Info);
+    log::set_max_level(logger.max_level());
+    log::set_boxed_logger(Box::new(api::correlation::CorrelatedLogger(logger)))
         .expect("start logger");
 
     let args = Args::parse();
@@ -118,20 +603,85 @@ async fn main() {
 
     log::info!("{}\tversion: {}", bin_name, version);
 
-    let (sender, receiver) = flume::unbounded::<ThreadRequest>();
-    tokio::spawn(model_route(receiver));
+    let path = args
+        .config
+        .clone()
+        .unwrap_or("assets/configs/Config.toml".into());
+    let layers = ConfigLayers {
+        profile: args.profile.clone(),
+        env: true,
+        overrides: args.overrides.clone(),
+    };
+    match args.command.clone() {
+        None | Some(cli::Command::Serve) => {}
+        Some(command) => {
+            if let Err(err) = cli::run(command, &path, &layers).await {
+                log::error!("{err}");
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+
+    let engine = Engine::new();
+    let sender = engine.sender();
+    let events = EventBus::new(&sender);
+    tokio::spawn(watch_shutdown(events.clone()));
+    if args.service {
+        tokio::spawn(service::run(events.clone()));
+    }
+
+    if !path.exists() {
+        // the setup has no authentication, so it is only served to this machine
+        let listen = config::ListenerOption::default();
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), args.port.unwrap_or(listen.port));
+        setup(sender.clone(), &path, addr).await;
+    }
 
     let (listen, config) = {
-        let path = args
-            .config
-            .clone()
-            .unwrap_or("assets/configs/Config.toml".into());
         log::info!("reading config {}...", path.to_string_lossy());
-        let config = load_config(path).await.expect("load config failed");
+        let table = read_layered(path, &layers).expect("load config failed");
+        let issues = validate::validate(&table).await;
+        for issue in &issues {
+            log::warn!("config {issue}");
+        }
+        if args.strict && !issues.is_empty() {
+            log::error!("config has {} issue(s), refusing to start", issues.len());
+            std::process::exit(1);
+        }
+        let config: config::Config = toml::Value::Table(table)
+            .try_into()
+            .expect("load config failed");
         let listen = config.listen.clone();
         (listen, config)
     };
 
+    load_plugins(&config.sampler);
+
+    let sealer = config.storage.sealer().expect("invalid storage key");
+    let scheduler = api::schedule::Scheduler::new(
+        sender.clone(),
+        config.schedule.clone(),
+        sealer,
+        config.sampler.limits.clone(),
+    );
+    let sealer = config.storage.sealer().expect("invalid storage key");
+    let audit = api::audit::AuditLog::new(config.audit.clone(), sealer);
+
+    let cache = Arc::new(api::oai::cache::ResponseCache::new(config.cache.clone()));
+    let semantic_cache = Arc::new(api::oai::cache::SemanticCache::new(config.cache.clone()));
+    let conversation_cache = Arc::new(api::oai::cache::ConversationCache::new(
+        config.cache.clone(),
+    ));
+    let maintainer = api::maintenance::Maintainer::new(
+        sender.clone(),
+        &config,
+        scheduler.clone(),
+        cache.clone(),
+        semantic_cache.clone(),
+        conversation_cache.clone(),
+    );
+
     let request = Box::new(config.clone().try_into().expect("load model failed"));
     let _ = sender.send(ThreadRequest::Reload {
         request,
@@ -140,114 +690,271 @@ async fn main() {
 
     let serve_path = match config.web {
         Some(web) => {
-            let path = tempfile::tempdir()
-                .expect("create temp dir failed")
-                .into_path();
-            load_web(web.path, &path)
-                .await
-                .expect("load frontend failed");
-
-            // create `assets/www/plugins` if it doesn't exist
-            if !Path::new("assets/www/plugins").exists() {
-                std::fs::create_dir("assets/www/plugins").expect("create plugins dir failed");
-            }
+            let temp = WebTemp::new().expect("create web temp dir failed");
+            let path = temp.0.clone();
 
-            // extract and load all plugins under `assets/www/plugins`
-            match std::fs::read_dir("assets/www/plugins") {
-                Ok(dir) => {
-                    for x in dir
-                        .filter_map(|x| x.ok())
-                        .filter(|x| x.path().is_file())
-                        .filter(|x| x.path().extension().is_some_and(|ext| ext == "zip"))
-                        .filter(|x| x.path().file_stem().is_some_and(|stem| stem != "api"))
-                    {
-                        let name = x
-                            .path()
-                            .file_stem()
-                            .expect("this cannot happen")
-                            .to_string_lossy()
-                            .into();
-                        match load_plugin(x.path(), &path, &name).await {
-                            Ok(_) => log::info!("loaded plugin {}", name),
-                            Err(err) => log::error!("failed to load plugin {}, {}", name, err),
-                        }
-                    }
-                }
-                Err(err) => {
-                    log::error!("failed to read plugin directory: {}", err);
+            // a directory is served as is, so that changes show up at once; plugins still go to the temp dir
+            let mut roots = vec![];
+            match web.path.is_dir() {
+                true => {
+                    log::info!("serving webui from {}", web.path.to_string_lossy());
+                    roots.push(web.path);
                 }
-            };
+                false => load_web(web.path, &path)
+                    .await
+                    .expect("load frontend failed"),
+            }
+
+            let plugins =
+                api::plugin::PluginManager::new(&path).expect("create plugins dir failed");
+            if let Err(err) = plugins.load_all() {
+                log::error!("failed to read plugin directory: {}", err);
+            }
 
-            Some(path)
+            roots.push(path);
+            Some((roots, web.dev, plugins, temp))
         }
         None => None,
     };
 
-    let cors = Cors::new()
-        .allow_origin(AllowOrigin::any())
-        .allow_methods(vec![Method::GET, Method::POST, Method::DELETE])
-        .allow_headers(AllowHeaders::any())
-        .into_handler();
-
-    let auth_handler: JwtAuth<JwtClaims, _> =
-        JwtAuth::new(ConstDecoder::from_secret(config.listen.slot.as_bytes()))
-            .finders(vec![
-                Box::new(HeaderFinder::new()),
-                Box::new(QueryFinder::new("_token")),
-                // Box::new(CookieFinder::new("jwt_token")),
-            ])
-            .force_passed(listen.force_pass.unwrap_or_default());
-
-    let api_router = Router::with_hoop(auth_handler)
+    let cors = cors_handler(&config.cors).expect("invalid cors config");
+
+    let finders = || -> Vec<Box<dyn JwtTokenFinder>> {
+        vec![
+            Box::new(HeaderFinder::new()),
+            Box::new(QueryFinder::new("_token")),
+            // Box::new(CookieFinder::new("jwt_token")),
+        ]
+    };
+    // signed requests carry no token, so the signature checks whether either is valid
+    let force_pass = listen.force_pass.unwrap_or_default() || listen.signing.is_some();
+    let api_router = match &listen.oidc {
+        Some(oidc) => {
+            let Some(decoder) = oidc_decoder(oidc).await else {
+                log::error!("cannot validate tokens without the oidc provider, exiting");
+                return;
+            };
+            log::info!("validating tokens issued by {}", oidc.issuer);
+            let auth_handler: JwtAuth<OidcClaims, _> =
+                JwtAuth::new(decoder).finders(finders()).force_passed(true);
+            Router::with_hoop(auth_handler)
+        }
+        None => {
+            let auth_handler: JwtAuth<JwtClaims, _> =
+                JwtAuth::new(ConstDecoder::from_secret(config.listen.slot.as_bytes()))
+                    .finders(finders())
+                    .force_passed(force_pass);
+            Router::with_hoop(auth_handler)
+        }
+    };
+    let api_router = match &listen.signing {
+        Some(option) => api_router.hoop(api::signature::Signature::new(&listen, option)),
+        None => api_router,
+    };
+
+    let inference_router = Router::new()
+        .hoop(api::auth::inference_scope)
         .push(Router::with_path("/adapters").get(api::adapters))
+        .push(Router::with_path("/adapters/info").get(api::adapters_info))
+        .push(Router::with_path("/adapters/stats").get(api::adapters_stats))
         .push(Router::with_path("/models/info").get(api::info))
-        .push(Router::with_path("/models/save").post(api::save))
-        .push(Router::with_path("/models/load").post(api::load))
-        .push(Router::with_path("/models/unload").get(api::unload))
-        .push(Router::with_path("/models/state/load").post(api::load_state))
         .push(Router::with_path("/models/state").get(api::state))
         .push(Router::with_path("/models/list").get(api::models))
-        .push(Router::with_path("/files/unzip").post(api::unzip))
-        .push(Router::with_path("/files/dir").post(api::dir))
-        .push(Router::with_path("/files/ls").post(api::dir))
-        .push(Router::with_path("/files/config/load").post(api::load_config))
-        .push(Router::with_path("/files/config/save").post(api::save_config))
         .push(Router::with_path("/oai/models").get(api::oai::models))
         .push(Router::with_path("/oai/v1/models").get(api::oai::models))
-        .push(Router::with_path("/oai/completions").post(api::oai::completions))
-        .push(Router::with_path("/oai/v1/completions").post(api::oai::completions))
-        .push(Router::with_path("/oai/chat/completions").post(api::oai::chat_completions))
-        .push(Router::with_path("/oai/v1/chat/completions").post(api::oai::chat_completions))
-        .push(Router::with_path("/oai/embeddings").post(api::oai::embeddings))
-        .push(Router::with_path("/oai/v1/embeddings").post(api::oai::embeddings));
+        .push(
+            Router::new()
+                .hoop(api::maintenance::maintenance_gate)
+                .push(Router::with_path("/oai/completions").post(api::oai::completions))
+                .push(Router::with_path("/oai/v1/completions").post(api::oai::completions))
+                .push(Router::with_path("/oai/chat/completions").post(api::oai::chat_completions))
+                .push(
+                    Router::with_path("/oai/v1/chat/completions").post(api::oai::chat_completions),
+                )
+                .push(Router::with_path("/oai/embeddings").post(api::oai::embeddings))
+                .push(Router::with_path("/oai/v1/embeddings").post(api::oai::embeddings))
+                .push(Router::with_path("/experiments/sweep").post(api::experiment::sweep)),
+        )
+        .push(Router::with_path("/debug/<id>").get(api::debug::debug_record))
+        .push(Router::with_path("/events").get(api::event::events))
+        .push(
+            Router::with_path("/accounts/settings")
+                .get(api::account::get_settings)
+                .post(api::account::save_settings),
+        );
+    let admin_router = Router::new()
+        .hoop(api::audit::audit_trail)
+        .hoop(api::auth::admin_scope)
+        .push(Router::with_path("/admin/audit").get(api::audit::audit_records))
+        .push(Router::with_path("/adapters/select").post(api::select_adapter))
+        .push(Router::with_path("/models/save").post(api::save))
+        .push(Router::with_path("/models/load").post(api::load))
+        .push(Router::with_path("/models/unload").get(api::unload))
+        .push(Router::with_path("/models/state/load").post(api::load_state))
+        .push(Router::with_path("/admin/bench").post(api::bench::bench))
+        .push(Router::with_path("/privacy/purge").post(api::privacy::purge))
+        .push(
+            Router::with_path("/admin/jobs")
+                .get(api::schedule::list_jobs)
+                .post(api::schedule::add_job)
+                .delete(api::schedule::remove_job),
+        )
+        .push(Router::with_path("/admin/jobs/run").post(api::schedule::run_job))
+        .push(Router::with_path("/admin/maintenance/run").post(api::maintenance::run_maintenance));
+    let admin_router = match config.workspace.enable {
+        true => admin_router
+            .push(
+                Router::with_path("/files/upload")
+                    .get(api::upload_status)
+                    .post(api::upload)
+                    .delete(api::abort_upload),
+            )
+            .push(Router::with_path("/files/unzip").post(api::unzip))
+            .push(Router::with_path("/files/dir").post(api::dir))
+            .push(Router::with_path("/files/ls").post(api::dir))
+            .push(Router::with_path("/files/config/load").post(api::load_config))
+            .push(Router::with_path("/files/config/validate").post(api::validate_config))
+            .push(Router::with_path("/files/config/save").post(api::save_config)),
+        false => {
+            log::info!("file apis are disabled");
+            admin_router
+        }
+    };
+    let admin_router = match &serve_path {
+        Some((_, _, plugins, _)) => admin_router.push(
+            Router::with_path("/plugins")
+                .hoop(affix::insert("plugins", plugins.clone()))
+                .get(api::plugin::list_plugins)
+                .post(api::plugin::install_plugin)
+                .delete(api::plugin::remove_plugin)
+                .push(Router::with_path("enable").post(api::plugin::enable_plugin))
+                .push(Router::with_path("disable").post(api::plugin::disable_plugin)),
+        ),
+        None => admin_router,
+    };
+    let api_router = api_router.push(inference_router).push(admin_router);
 
     let app = Router::new()
         //.hoop(CorsLayer::permissive())
-        .hoop(Logger::new())
+        .hoop(api::correlation::correlate)
+        .hoop(Logger::new());
+    let app = match &listen.security {
+        Some(option) => {
+            let firewall = api::security::Firewall::new(option).expect("invalid security config");
+            app.hoop(firewall)
+        }
+        None => app,
+    };
+    let accounts = match (&listen.accounts, &listen.oidc) {
+        (Some(_), Some(_)) => {
+            log::warn!("accounts are not available with oidc, ignored");
+            None
+        }
+        (Some(option), None) => {
+            let store =
+                api::account::AccountStore::load(option.clone()).expect("load accounts failed");
+            Some(std::sync::Arc::new(store))
+        }
+        (None, _) => None,
+    };
+    let app = match accounts {
+        Some(store) => app.hoop(affix::insert("accounts", store)),
+        None => app,
+    };
+    let app = app
         .hoop(
             affix::inject(ThreadState {
                 sender,
                 path: config.model.path,
             })
-            .insert("listen", listen.clone()),
+            .insert("listen", listen.clone())
+            .insert("stream", config.stream.clone())
+            .insert("reasoning", config.reasoning.clone())
+            .insert("workspace", config.workspace.clone())
+            .insert(
+                "uploads",
+                std::sync::Arc::new(api::file::Uploads::default()),
+            )
+            .insert("limits", config.limits.clone())
+            .insert("sampler_limits", config.sampler.limits.clone())
+            .insert("embedding", config.embedding.clone())
+            .insert("cache", cache)
+            .insert("semantic_cache", semantic_cache)
+            .insert("conversation_cache", conversation_cache)
+            .insert("scheduler", scheduler)
+            .insert("maintainer", maintainer)
+            .insert("audit", std::sync::Arc::new(audit))
+            .insert("debug", std::sync::Arc::new(api::debug::DebugStore::new()))
+            .insert("events", events.clone()),
         )
+        .push(Router::with_path("/health/ready").get(api::maintenance::readiness))
         .push(
             Router::with_path("/api")
                 .push(Router::with_path("/auth/exchange").post(api::auth::exchange))
+                .push(Router::with_path("/accounts/register").post(api::account::register))
+                .push(Router::with_path("/accounts/login").post(api::account::login))
                 .push(api_router),
         );
 
+    let base_path = listen.base_path();
     let doc = OpenApi::new(bin_name, version).merge_router(&app);
+    let doc = match base_path.is_empty() {
+        true => doc,
+        false => doc.add_server(salvo::oapi::server::Server::new(&base_path)),
+    };
 
-    let app = app
-        .push(doc.into_router("/api-doc/openapi.json"))
-        .push(SwaggerUi::new("/api-doc/openapi.json").into_router("swagger-ui"));
+    let app = app.push(doc.into_router("/api-doc/openapi.json")).push(
+        SwaggerUi::new(format!("{base_path}/api-doc/openapi.json")).into_router("swagger-ui"),
+    );
     // this static serve should be after `swagger`
-    let app = match serve_path {
-        Some(path) => app
-            .push(Router::with_path("<**path>").get(StaticDir::new(path).defaults(["index.html"]))),
+    let app = match &serve_path {
+        Some((roots, dev, _, _)) => {
+            let router = Router::with_path("<**path>")
+                .get(StaticDir::new(roots.clone()).defaults(["index.html"]));
+            match dev {
+                true => {
+                    let url = format!("{base_path}/{}", api::web::WEB_VERSION_PATH);
+                    app.push(
+                        Router::with_path(api::web::WEB_VERSION_PATH)
+                            .get(api::web::WebVersion::new(roots.clone())),
+                    )
+                    .push(router.hoop(api::web::LiveReload::new(&url)))
+                }
+                false => app.push(router),
+            }
+        }
         None => app,
     };
+    let app = match base_path.is_empty() {
+        true => app,
+        false => {
+            log::info!("serving under {base_path}");
+            Router::with_path(&base_path).push(app)
+        }
+    };
+
+    let app = std::sync::Arc::new(app);
+
+    #[cfg(unix)]
+    if let Some(path) = listen.unix_socket.clone() {
+        use std::os::unix::fs::FileTypeExt;
+
+        let service = Service::new(app.clone()).hoop(cors.clone());
+        let events = events.clone();
+        tokio::spawn(async move {
+            // remove the socket left over by a previous run
+            if std::fs::metadata(&path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                let _ = std::fs::remove_file(&path);
+            }
+            let acceptor = UnixListener::new(path.clone()).bind().await;
+            log::info!("server started at {}", path.to_string_lossy());
+            serve(acceptor, service, &events).await;
+        });
+    }
+    #[cfg(not(unix))]
+    if listen.unix_socket.is_some() {
+        log::warn!("unix socket listener is not supported on this platform");
+    }
 
     let service = Service::new(app).hoop(cors);
     let ip_addr = args.ip.unwrap_or(listen.ip);
@@ -280,45 +987,38 @@ async fn main() {
             #[cfg(target_os = "windows")]
             let acceptor = listener.join(ipv6_listener).bind().await;
             log::info!("server started at {addr_v6} with acme and tls");
-            salvo::server::Server::new(acceptor).serve(service).await;
+            serve(acceptor, service, &events).await;
         } else {
             let acceptor = listener.bind().await;
             log::info!("server started at {addr} with acme and tls.");
-            salvo::server::Server::new(acceptor).serve(service).await;
+            serve(acceptor, service, &events).await;
         };
     } else if tls {
-        let config = RustlsConfig::new(
-            Keycert::new()
-                .cert_from_path("assets/certs/cert.pem")
-                .expect("unable to find cert.pem")
-                .key_from_path("assets/certs/key.pem")
-                .expect("unable to fine key.pem"),
-        );
-        let listener = TcpListener::new(addr).rustls(config.clone());
+        let listener = TcpListener::new(addr).rustls(rustls_configs(&listen));
         if let Some(ipv6_addr) = ipv6_addr {
             let addr_v6 = SocketAddr::new(IpAddr::V6(ipv6_addr), port);
-            let ipv6_listener = TcpListener::new(addr_v6).rustls(config.clone());
+            let ipv6_listener = TcpListener::new(addr_v6).rustls(rustls_configs(&listen));
             #[cfg(not(target_os = "windows"))]
-            let acceptor = QuinnListener::new(config.clone(), addr_v6)
+            let acceptor = QuinnListener::new(rustls_configs(&listen), addr_v6)
                 .join(ipv6_listener)
                 .bind()
                 .await;
             #[cfg(target_os = "windows")]
-            let acceptor = QuinnListener::new(config.clone(), addr)
-                .join(QuinnListener::new(config, addr_v6))
+            let acceptor = QuinnListener::new(rustls_configs(&listen), addr)
+                .join(QuinnListener::new(rustls_configs(&listen), addr_v6))
                 .join(ipv6_listener)
                 .join(listener)
                 .bind()
                 .await;
             log::info!("server started at {addr_v6} with tls");
-            salvo::server::Server::new(acceptor).serve(service).await;
+            serve(acceptor, service, &events).await;
         } else {
-            let acceptor = QuinnListener::new(config.clone(), addr)
+            let acceptor = QuinnListener::new(rustls_configs(&listen), addr)
                 .join(listener)
                 .bind()
                 .await;
             log::info!("server started at {addr} with tls");
-            salvo::server::Server::new(acceptor).serve(service).await;
+            serve(acceptor, service, &events).await;
         };
     } else if let Some(ipv6_addr) = ipv6_addr {
         let addr_v6 = SocketAddr::new(IpAddr::V6(ipv6_addr), port);
@@ -328,19 +1028,20 @@ async fn main() {
         #[cfg(not(target_os = "windows"))]
         if ipv6_addr.is_unspecified() {
             let acceptor = ipv6_listener.bind().await;
-            salvo::server::Server::new(acceptor).serve(service).await;
+            serve(acceptor, service, &events).await;
         } else {
             let acceptor = TcpListener::new(addr).join(ipv6_listener).bind().await;
-            salvo::server::Server::new(acceptor).serve(service).await;
+            serve(acceptor, service, &events).await;
         };
         #[cfg(target_os = "windows")]
         {
             let acceptor = TcpListener::new(addr).join(ipv6_listener).bind().await;
-            salvo::server::Server::new(acceptor).serve(service).await;
+            serve(acceptor, service, &events).await;
         }
     } else {
         log::info!("server started at {addr} without tls");
         let acceptor = TcpListener::new(addr).bind().await;
-        salvo::server::Server::new(acceptor).serve(service).await;
+        serve(acceptor, service, &events).await;
     };
+    service::stopped();
 }
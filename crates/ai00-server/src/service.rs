@@ -0,0 +1,351 @@
+//! Lifecycle notifications to the service manager in `--service` mode.
+//!
+//! On Linux the server speaks the systemd notify protocol, so that a unit of `Type=notify`
+//! is only reported started once the model is loaded, and stopping once the shutdown begins.
+//! On Windows it runs under the service control manager, which stops it through the same
+//! shutdown as ctrl-c.
+
+use std::{sync::Arc, time::Duration};
+
+use ai00_core::RuntimeEvent;
+use tokio::sync::broadcast::error::RecvError;
+#[cfg(windows)]
+use windows_sys::Win32::System::Services::{
+    SERVICE_RUNNING, SERVICE_START_PENDING, SERVICE_STOPPED, SERVICE_STOP_PENDING,
+};
+
+use crate::api::event::{EventBus, ServerEvent, ServerNotice};
+
+/// How often the start timeout is extended while the first model is loading.
+const EXTEND_INTERVAL: Duration = Duration::from_secs(10);
+
+#[cfg(target_os = "linux")]
+struct Notifier {
+    socket: std::os::unix::net::UnixDatagram,
+    addr: std::os::unix::net::SocketAddr,
+}
+
+#[cfg(target_os = "linux")]
+impl Notifier {
+    async fn new() -> Option<Self> {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let path = std::env::var("NOTIFY_SOCKET").ok()?;
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(&path),
+        };
+        let socket = std::os::unix::net::UnixDatagram::unbound();
+        match (addr, socket) {
+            (Ok(addr), Ok(socket)) => Some(Self { socket, addr }),
+            (Err(err), _) | (_, Err(err)) => {
+                log::error!("failed to open notify socket {path}: {err}");
+                None
+            }
+        }
+    }
+
+    fn notify(&self, state: &str) {
+        if let Err(err) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            log::warn!("failed to notify the service manager: {err}");
+        }
+    }
+
+    fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={status}"));
+    }
+
+    fn extend(&self, timeout: Duration) {
+        self.notify(&format!("EXTEND_TIMEOUT_USEC={}", timeout.as_micros()));
+    }
+
+    fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    /// systemd stops the server with `SIGTERM`, which is watched apart from the service.
+    async fn stop_requested(&self) {
+        std::future::pending().await
+    }
+}
+
+#[cfg(windows)]
+mod control {
+    use std::{
+        sync::{Mutex, OnceLock},
+        time::Duration,
+    };
+
+    use windows_sys::Win32::{
+        Foundation::{ERROR_CALL_NOT_IMPLEMENTED, NO_ERROR},
+        System::Services::{
+            RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+            SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP, SERVICE_CONTROL_INTERROGATE,
+            SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP, SERVICE_RUNNING, SERVICE_START_PENDING,
+            SERVICE_STATUS, SERVICE_STATUS_CURRENT_STATE, SERVICE_STATUS_HANDLE,
+            SERVICE_STOP_PENDING, SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+        },
+    };
+
+    /// Name of the service; not checked by the manager for a service that has its own process.
+    const NAME: &str = "ai00";
+
+    /// The status reported to the manager, and the handle it is reported through.
+    struct Control {
+        handle: SERVICE_STATUS_HANDLE,
+        status: Mutex<SERVICE_STATUS>,
+    }
+
+    static CONTROL: OnceLock<Control> = OnceLock::new();
+    /// Where the service main reports whether it registered with the manager.
+    static REGISTERED: OnceLock<flume::Sender<bool>> = OnceLock::new();
+    /// Where the stop and shutdown controls of the manager are sent.
+    static STOP: OnceLock<flume::Sender<()>> = OnceLock::new();
+
+    fn wide(text: &str) -> Vec<u16> {
+        text.encode_utf16().chain([0]).collect()
+    }
+
+    unsafe extern "system" fn handler(
+        control: u32,
+        _event: u32,
+        _data: *mut std::ffi::c_void,
+        _context: *mut std::ffi::c_void,
+    ) -> u32 {
+        match control {
+            SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+                if let Some(stop) = STOP.get() {
+                    let _ = stop.try_send(());
+                }
+                NO_ERROR
+            }
+            SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+            _ => ERROR_CALL_NOT_IMPLEMENTED,
+        }
+    }
+
+    unsafe extern "system" fn service_main(_argc: u32, _argv: *mut windows_sys::core::PWSTR) {
+        let name = wide(NAME);
+        let handle = RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(handler), std::ptr::null());
+        let registered = handle != 0 && {
+            let status = SERVICE_STATUS {
+                dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+                dwCurrentState: SERVICE_START_PENDING,
+                dwControlsAccepted: 0,
+                dwWin32ExitCode: NO_ERROR,
+                dwServiceSpecificExitCode: 0,
+                dwCheckPoint: 0,
+                dwWaitHint: 0,
+            };
+            let control = Control {
+                handle,
+                status: Mutex::new(status),
+            };
+            CONTROL.set(control).is_ok()
+        };
+        if registered {
+            report(SERVICE_START_PENDING, super::EXTEND_INTERVAL * 3);
+        }
+        // the dispatcher keeps serving the controls after this returns, until the service is stopped
+        if let Some(sender) = REGISTERED.get() {
+            let _ = sender.send(registered);
+        }
+    }
+
+    /// Connect to the manager on a thread of the dispatcher, and wait for the service to register.
+    pub async fn start(stop: flume::Sender<()>) -> bool {
+        let (sender, receiver) = flume::bounded(1);
+        if REGISTERED.set(sender.clone()).is_err() || STOP.set(stop).is_err() {
+            return false;
+        }
+        std::thread::spawn(move || {
+            let mut name = wide(NAME);
+            let table = [
+                SERVICE_TABLE_ENTRYW {
+                    lpServiceName: name.as_mut_ptr(),
+                    lpServiceProc: Some(service_main),
+                },
+                SERVICE_TABLE_ENTRYW {
+                    lpServiceName: std::ptr::null_mut(),
+                    lpServiceProc: None,
+                },
+            ];
+            // blocks until the service is stopped; fails at once if the process is not started by the manager
+            if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+                log::error!(
+                    "failed to connect to the service control manager: {}",
+                    std::io::Error::last_os_error()
+                );
+                let _ = sender.send(false);
+            }
+        });
+        receiver.recv_async().await.unwrap_or_default()
+    }
+
+    /// Report a state; `wait` is how long the manager should wait for the next report of a pending state.
+    pub fn report(state: SERVICE_STATUS_CURRENT_STATE, wait: Duration) {
+        let Some(control) = CONTROL.get() else {
+            return;
+        };
+        let mut status = control.status.lock().unwrap();
+        status.dwCheckPoint = match state {
+            SERVICE_START_PENDING | SERVICE_STOP_PENDING if status.dwCurrentState == state => {
+                status.dwCheckPoint + 1
+            }
+            _ => 0,
+        };
+        status.dwCurrentState = state;
+        // a long model load may be stopped too
+        status.dwControlsAccepted = match state {
+            SERVICE_START_PENDING | SERVICE_RUNNING => {
+                SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+            }
+            _ => 0,
+        };
+        status.dwWaitHint = wait.as_millis() as u32;
+        if unsafe { SetServiceStatus(control.handle, &*status) } == 0 {
+            log::warn!(
+                "failed to report to the service control manager: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[cfg(windows)]
+struct Notifier {
+    stop: flume::Receiver<()>,
+}
+
+#[cfg(windows)]
+impl Notifier {
+    async fn new() -> Option<Self> {
+        let (sender, stop) = flume::unbounded();
+        control::start(sender).await.then_some(Self { stop })
+    }
+
+    /// The manager shows no status text, so it is only logged.
+    fn status(&self, status: &str) {
+        log::info!("service {status}");
+    }
+
+    fn extend(&self, timeout: Duration) {
+        control::report(SERVICE_START_PENDING, timeout);
+    }
+
+    fn ready(&self) {
+        control::report(SERVICE_RUNNING, Duration::ZERO);
+    }
+
+    fn stopping(&self) {
+        control::report(SERVICE_STOP_PENDING, crate::SHUTDOWN_GRACE * 2);
+    }
+
+    async fn stop_requested(&self) {
+        let _ = self.stop.recv_async().await;
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+struct Notifier;
+
+#[cfg(not(any(target_os = "linux", windows)))]
+impl Notifier {
+    async fn new() -> Option<Self> {
+        log::warn!("service mode is only supported with systemd on linux and on windows");
+        None
+    }
+
+    fn status(&self, _status: &str) {}
+
+    fn extend(&self, _timeout: Duration) {}
+
+    fn ready(&self) {}
+
+    fn stopping(&self) {}
+
+    async fn stop_requested(&self) {
+        std::future::pending().await
+    }
+}
+
+/// Tell the manager the server has exited, once it is done serving.
+pub fn stopped() {
+    #[cfg(windows)]
+    control::report(SERVICE_STOPPED, Duration::ZERO);
+}
+
+/// Report the lifecycle of the server to the service manager, following the events on the bus,
+/// and start the shutdown when the manager asks to stop.
+pub async fn run(events: Arc<EventBus>) {
+    let mut receiver = events.subscribe();
+    let Some(notifier) = Notifier::new().await else {
+        return;
+    };
+    notifier.status("starting");
+
+    let mut ready = false;
+    loop {
+        let recv = async {
+            match ready {
+                true => Ok(receiver.recv().await),
+                false => tokio::time::timeout(EXTEND_INTERVAL, receiver.recv()).await,
+            }
+        };
+        let event = tokio::select! {
+            event = recv => event,
+            _ = notifier.stop_requested() => {
+                crate::announce_shutdown(&events);
+                continue;
+            }
+        };
+
+        let event = match event {
+            Ok(Ok(event)) => event,
+            Ok(Err(RecvError::Lagged(_))) => continue,
+            Ok(Err(RecvError::Closed)) => break,
+            Err(_) => {
+                // keep the manager from giving up on a long model load
+                notifier.extend(EXTEND_INTERVAL * 3);
+                continue;
+            }
+        };
+        match &event {
+            ServerEvent::Runtime(RuntimeEvent::ReloadStarted { model_path }) => {
+                notifier.status(&format!("loading {}", model_path.to_string_lossy()));
+            }
+            ServerEvent::Runtime(RuntimeEvent::ModelLoaded { model_path }) => {
+                notifier.status(&format!("serving {}", model_path.to_string_lossy()));
+            }
+            ServerEvent::Runtime(RuntimeEvent::LoadFailed { error, .. }) => {
+                notifier.status(&format!("failed to load model: {error}"));
+            }
+            ServerEvent::Runtime(RuntimeEvent::ModelUnloaded) => {
+                notifier.status("no model loaded");
+            }
+            ServerEvent::Runtime(RuntimeEvent::Crashed { error }) => {
+                notifier.status(&format!("model crashed: {error}"));
+            }
+            ServerEvent::Server(ServerNotice::ShutdownImminent { .. }) => {
+                notifier.stopping();
+                break;
+            }
+        }
+
+        // the server is up once the first load finishes, even if it fails, so that a model can be loaded by the api
+        if !ready
+            && matches!(
+                event,
+                ServerEvent::Runtime(RuntimeEvent::ModelLoaded { .. })
+                    | ServerEvent::Runtime(RuntimeEvent::LoadFailed { .. })
+            )
+        {
+            notifier.ready();
+            ready = true;
+        }
+    }
+}
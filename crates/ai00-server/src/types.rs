@@ -4,6 +4,7 @@ use ai00_core::ThreadRequest;
 use flume::Sender;
 use salvo::oapi::ToSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
 #[serde(untagged)]
@@ -34,4 +35,37 @@ pub struct ThreadState {
 pub struct JwtClaims {
     pub sid: String,
     pub exp: i64,
+    /// Whether the token may call the admin APIs. Only tokens of app keys do.
+    #[serde(default)]
+    pub admin: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcClaims {
+    #[serde(default)]
+    pub sub: String,
+    pub exp: i64,
+    #[serde(flatten)]
+    pub claims: Map<String, Value>,
+}
+
+impl OidcClaims {
+    /// Collect the roles under a (dot separated) claim path.
+    /// Accepts either an array of strings or a space separated string like `scope`.
+    pub fn roles(&self, path: &str) -> Vec<String> {
+        let mut keys = path.split('.');
+        let value = keys
+            .next()
+            .and_then(|key| self.claims.get(key))
+            .and_then(|value| keys.try_fold(value, |value, key| value.get(key)));
+        match value {
+            Some(Value::Array(roles)) => roles
+                .iter()
+                .filter_map(Value::as_str)
+                .map(String::from)
+                .collect(),
+            Some(Value::String(roles)) => roles.split_whitespace().map(String::from).collect(),
+            _ => vec![],
+        }
+    }
 }
@@ -0,0 +1,202 @@
+//! Checks a config before it is used: unknown keys, invalid values and missing files.
+
+use std::path::Path;
+
+use ai00_core::ReloadRequest;
+use salvo::oapi::ToSchema;
+use serde::Serialize;
+use web_rwkv::tokenizer::Tokenizer;
+
+use crate::{
+    api::{schedule::Cron, security::Firewall},
+    config::Config,
+};
+
+/// Keys accepted besides the ones a config serializes to: aliases, and tables taken as they are.
+const EXTRA_KEYS: &[&str] = &["model.model_path", "model.model_name", "schedule.request"];
+/// Keys of older configs that are still accepted but ignored, with what to use instead.
+const DEPRECATED_KEYS: &[(&str, &str)] = &[("model.stop", "give `stop` in the requests")];
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ConfigIssue {
+    /// Dotted key the issue is about, e.g. `model.max_batch`. Empty if it concerns the whole file.
+    pub key: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.key.as_str() {
+            "" => write!(f, "{}", self.message),
+            key => write!(f, "{key}: {}", self.message),
+        }
+    }
+}
+
+impl ConfigIssue {
+    pub fn new(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ConfigReport {
+    pub valid: bool,
+    pub issues: Vec<ConfigIssue>,
+}
+
+impl From<Vec<ConfigIssue>> for ConfigReport {
+    fn from(issues: Vec<ConfigIssue>) -> Self {
+        Self {
+            valid: issues.is_empty(),
+            issues,
+        }
+    }
+}
+
+/// Report the keys of `input` that `known` does not have. Indices of arrays are left out of `path`.
+fn unknown_keys(
+    input: &toml::Value,
+    known: &serde_json::Value,
+    path: &str,
+    issues: &mut Vec<ConfigIssue>,
+) {
+    match (input, known) {
+        (toml::Value::Table(input), serde_json::Value::Object(known)) => {
+            for (key, value) in input {
+                let path = match path {
+                    "" => key.clone(),
+                    path => format!("{path}.{key}"),
+                };
+                if EXTRA_KEYS.contains(&path.as_str()) {
+                    continue;
+                }
+                if let Some((_, instead)) = DEPRECATED_KEYS.iter().find(|(key, _)| *key == path) {
+                    log::warn!("config key {path} is deprecated and ignored, {instead}");
+                    continue;
+                }
+                match known.get(key) {
+                    Some(known) => unknown_keys(value, known, &path, issues),
+                    None => issues.push(ConfigIssue::new(path, "unknown key")),
+                }
+            }
+        }
+        (toml::Value::Array(input), serde_json::Value::Array(known)) => {
+            for (input, known) in input.iter().zip(known) {
+                unknown_keys(input, known, path, issues);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn check_values(config: &Config, issues: &mut Vec<ConfigIssue>) {
+    let model = &config.model;
+    if model.max_batch == 0 {
+        issues.push(ConfigIssue::new("model.max_batch", "must be at least 1"));
+    }
+    if model.token_chunk_size == 0 {
+        issues.push(ConfigIssue::new(
+            "model.token_chunk_size",
+            "must be at least 1",
+        ));
+    }
+    if model.context_length > 0 && model.context_reserve >= model.context_length {
+        issues.push(ConfigIssue::new(
+            "model.context_reserve",
+            "must be less than `context_length`",
+        ));
+    }
+    if !(0.0..=1.0).contains(&config.cache.semantic.threshold) {
+        issues.push(ConfigIssue::new(
+            "cache.semantic.threshold",
+            "must be between 0 and 1",
+        ));
+    }
+    if let Err(err) = crate::cors_handler(&config.cors) {
+        issues.push(ConfigIssue::new("cors", err.to_string()));
+    }
+    if let Some(Err(err)) = config.listen.security.as_ref().map(Firewall::new) {
+        issues.push(ConfigIssue::new("listen.security", err.to_string()));
+    }
+    if let Err(err) = config.storage.sealer() {
+        issues.push(ConfigIssue::new("storage.key", err.to_string()));
+    }
+    for job in &config.schedule {
+        if let Err(err) = job.cron.parse::<Cron>() {
+            let message = format!("invalid cron of job {}: {err}", job.name);
+            issues.push(ConfigIssue::new("schedule.cron", message));
+        }
+    }
+}
+
+async fn check_files(config: &Config, request: &ReloadRequest, issues: &mut Vec<ConfigIssue>) {
+    let mut check = |path: &Path, key: &str, name: &str| {
+        if !path.is_file() {
+            let message = format!("{name} {} not found", path.to_string_lossy());
+            issues.push(ConfigIssue::new(key, message));
+        }
+    };
+    check(&request.model_path, "model.name", "model");
+    for lora in &request.lora {
+        check(&lora.path, "lora.path", "lora");
+    }
+    for state in &request.state {
+        check(&state.path, "state.path", "state");
+    }
+    check(&request.tokenizer_path, "tokenizer.path", "tokenizer");
+    for path in &config.sampler.plugins {
+        check(path, "sampler.plugins", "plugin");
+    }
+    if let Some(web) = config.web.as_ref().filter(|web| !web.path.is_dir()) {
+        check(&web.path, "web.path", "web ui");
+    }
+    let (acme, tls) = match config.listen.domain.as_str() {
+        "local" => (false, config.listen.tls),
+        _ => (config.listen.acme, true),
+    };
+    if tls && !acme {
+        check(Path::new(crate::CERT_PATH), "listen.tls", "certificate");
+        check(Path::new(crate::KEY_PATH), "listen.tls", "private key");
+        for cert in config.listen.certs.iter().flatten() {
+            check(&cert.cert, "listen.certs.cert", "certificate");
+            check(&cert.key, "listen.certs.key", "private key");
+        }
+    }
+
+    if request.tokenizer_path.is_file() {
+        let tokenizer = tokio::fs::read_to_string(&request.tokenizer_path)
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| Ok(Tokenizer::new(&contents)?));
+        if let Err(err) = tokenizer {
+            let message = format!("failed to load tokenizer: {err}");
+            issues.push(ConfigIssue::new("tokenizer.path", message));
+        }
+    }
+}
+
+/// Check a config table, as read from the file with its includes and overrides applied.
+pub async fn validate(table: &toml::Table) -> Vec<ConfigIssue> {
+    let input = toml::Value::Table(table.clone());
+    let config: Config = match input.clone().try_into() {
+        Ok(config) => config,
+        Err(err) => return vec![ConfigIssue::new("", err.to_string())],
+    };
+
+    let mut issues = vec![];
+    // json keeps the empty options, which toml leaves out
+    match serde_json::to_value(&config) {
+        Ok(known) => unknown_keys(&input, &known, "", &mut issues),
+        Err(err) => log::warn!("failed to check unknown config keys: {err}"),
+    }
+    check_values(&config, &mut issues);
+    match ReloadRequest::try_from(config.clone()) {
+        Ok(request) => check_files(&config, &request, &mut issues).await,
+        Err(err) => issues.push(ConfigIssue::new("model", err.to_string())),
+    }
+    issues
+}
@@ -0,0 +1,42 @@
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use memmap2::Mmap;
+use web_rwkv_converter::convert_safetensors;
+
+pub const RENAME: [(&str, &str); 4] = [
+    ("time_faaaa", "time_first"),
+    ("time_maa", "time_mix"),
+    ("lora_A", "lora.0"),
+    ("lora_B", "lora.1"),
+];
+
+pub const TRANSPOSE: [&str; 6] = [
+    "time_mix_w1",
+    "time_mix_w2",
+    "time_decay_w1",
+    "time_decay_w2",
+    "time_state",
+    "lora.0",
+];
+
+/// Convert a PyTorch model into safetensors. If `output` is not given,
+/// the model is written next to the input with the extension `.st`.
+pub fn convert(input: impl AsRef<Path>, output: Option<PathBuf>) -> Result<PathBuf> {
+    let input = input.as_ref();
+    let file = File::open(input)?;
+    let map = unsafe { Mmap::map(&file)? };
+
+    let output = output.unwrap_or_else(|| {
+        let path = input.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+        let stem = input.file_stem().expect("please name the file");
+        let name: PathBuf = [&stem.to_string_lossy(), "st"].join(".").into();
+        path.join(name)
+    });
+    convert_safetensors(input, &map, &output, RENAME, TRANSPOSE)?;
+
+    Ok(output)
+}